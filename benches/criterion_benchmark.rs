@@ -1,6 +1,31 @@
-use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use maghemite::fhir::{self, SpecificationPackage};
 use maghemite::parser::*;
 use maghemite::Expression;
+use std::path::Path;
+use std::sync::Once;
+
+/// Reads resources with the R4B specification in the checkout, unless `MAGHEMITE_FHIR_PACKAGE`
+/// gives another package
+fn install_specification() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        if std::env::var_os(fhir::PACKAGE_PATH_VARIABLE).is_some() {
+            return;
+        }
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/fhir/specification/R4B");
+        SpecificationPackage::load_bundles(
+            "hl7.fhir.r4b.core",
+            "4.3.0",
+            &[
+                directory.join("profiles-resources.json"),
+                directory.join("profiles-types.json"),
+            ],
+        )
+        .and_then(SpecificationPackage::install)
+        .unwrap();
+    });
+}
 
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("Lexer/Patient.name.family.replace('er', 'iams')", |b| {
@@ -28,5 +53,84 @@ fn criterion_benchmark(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, criterion_benchmark);
+/// Compares the tree walker with compiled expressions, each parsed or compiled once and then
+/// evaluated against the same resource
+fn compiled_benchmark(c: &mut Criterion) {
+    install_specification();
+    let patient = fhir::load_resource(&serde_json::json!({
+        "resourceType": "Patient",
+        "birthDate": "1974-12-25",
+        "name": [
+            { "use": "official", "family": "Chalmers", "given": ["Peter", "James"] },
+            { "use": "usual", "given": ["Jim"] }
+        ]
+    }))
+    .unwrap();
+
+    for expr in [
+        "Patient.name.family.replace('er', 'iams') = 'Chalmiamss'",
+        "Patient.birthDate.duration(@2024-01-01, 'years') >= 18",
+        "Patient.name.given.ofType(FHIR.string)",
+    ] {
+        let expression = Expression::new(expr).unwrap();
+        c.bench_function(&format!("Evaluation/tree-walker/{}", expr), |b| {
+            b.iter(|| expression.evaluate_resource(black_box(&patient)).unwrap())
+        });
+
+        let compiled = expression.compile().unwrap();
+        c.bench_function(&format!("Evaluation/compiled/{}", expr), |b| {
+            b.iter(|| compiled.evaluate_resource(black_box(&patient)).unwrap())
+        });
+    }
+}
+
+/// Navigates a Bundle of a few thousand Patients, as large as a typical bulk export page
+fn bundle_benchmark(c: &mut Criterion) {
+    install_specification();
+    let entries: Vec<_> = (0..5000)
+        .map(|i| {
+            serde_json::json!({
+                "fullUrl": format!("urn:uuid:patient-{}", i),
+                "resource": {
+                    "resourceType": "Patient",
+                    "id": format!("patient-{}", i),
+                    "active": i % 2 == 0,
+                    "birthDate": "1974-12-25",
+                    "name": [
+                        { "use": "official", "family": "Chalmers", "given": ["Peter", "James"] },
+                        { "use": "usual", "given": ["Jim"] }
+                    ],
+                    "address": [{ "line": ["534 Erewhon St"], "city": "PleasantVille" }]
+                }
+            })
+        })
+        .collect();
+    let bundle = fhir::load_resource(&serde_json::json!({
+        "resourceType": "Bundle",
+        "type": "collection",
+        "entry": entries
+    }))
+    .unwrap();
+
+    let mut group = c.benchmark_group("Bundle");
+    group.sample_size(20);
+    for expr in [
+        "Bundle.entry.resource",
+        "Bundle.entry.resource.name.given",
+        "Bundle.entry.resource.where(active = true).id",
+    ] {
+        let expression = Expression::new(expr).unwrap();
+        group.bench_function(expr, |b| {
+            b.iter(|| expression.evaluate_resource(black_box(&bundle)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    criterion_benchmark,
+    compiled_benchmark,
+    bundle_benchmark
+);
 criterion_main!(benches);
@@ -0,0 +1,294 @@
+use crate::evaluation::{EvaluationContext, EvaluationError};
+use crate::fhir::{load_ndjson_line, NdjsonReader, ParseError};
+use crate::fhirpath::{Collection, DataNode};
+use crate::CompiledExpression;
+use rayon::prelude::*;
+use std::borrow::Borrow;
+use std::io::BufRead;
+use std::sync::Arc;
+
+/// The results of evaluating each expression of a batch against one resource, in the order the
+/// expressions were given
+pub type BatchResult = Vec<Result<Collection, EvaluationError>>;
+
+/// Evaluates a set of compiled expressions against many resources on a thread pool.  Resources
+/// are read from an iterator a chunk at a time, so only one chunk and its results are held in
+/// memory however many resources there are.
+///
+/// Evaluation runs on rayon's global thread pool, or on the pool `evaluate()` is called from
+/// within `ThreadPool::install()`.
+pub struct BatchEvaluator<'a> {
+    expressions: &'a [CompiledExpression],
+    context: &'a EvaluationContext,
+    chunk_size: usize,
+}
+
+impl<'a> BatchEvaluator<'a> {
+    pub fn new(expressions: &'a [CompiledExpression], context: &'a EvaluationContext) -> Self {
+        BatchEvaluator {
+            expressions,
+            context,
+            chunk_size: 1024,
+        }
+    }
+
+    /// The number of resources read, and evaluated in parallel, at a time
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Evaluates the expressions against each resource, giving the results for each resource in
+    /// the order the resources were read.  An error evaluating one expression against one
+    /// resource does not stop the others.
+    pub fn evaluate<I>(&self, resources: I) -> BatchResults<'a, I::IntoIter>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<Arc<DataNode>> + Send,
+    {
+        BatchResults {
+            expressions: self.expressions,
+            context: self.context,
+            chunk_size: self.chunk_size,
+            resources: resources.into_iter(),
+            results: Vec::new().into_iter(),
+        }
+    }
+
+    /// Evaluates the expressions against each resource of an NDJSON file, as it is read.  Each
+    /// chunk of lines is parsed in parallel as well as evaluated, and results are given with the
+    /// number of the line the resource was read from.  Lines that cannot be read or parsed give
+    /// a `ParseError::Line` error in place of their results, rather than ending the run.
+    /// Resources are read with the model of the context.
+    pub fn evaluate_ndjson<R: BufRead>(&self, reader: R) -> NdjsonResults<'a, R> {
+        NdjsonResults {
+            expressions: self.expressions,
+            context: self.context,
+            chunk_size: self.chunk_size,
+            reader: NdjsonReader::new(reader),
+            results: Vec::new().into_iter(),
+        }
+    }
+
+    /// Evaluates the expressions against a single resource
+    pub fn evaluate_resource(&self, resource: &Arc<DataNode>) -> BatchResult {
+        evaluate_all(self.expressions, resource, self.context)
+    }
+}
+
+/// The results of `BatchEvaluator::evaluate()`, one for each resource
+pub struct BatchResults<'a, I> {
+    expressions: &'a [CompiledExpression],
+    context: &'a EvaluationContext,
+    chunk_size: usize,
+    resources: I,
+    results: std::vec::IntoIter<BatchResult>,
+}
+
+impl<I> Iterator for BatchResults<'_, I>
+where
+    I: Iterator,
+    I::Item: Borrow<Arc<DataNode>> + Send,
+{
+    type Item = BatchResult;
+
+    fn next(&mut self) -> Option<BatchResult> {
+        if let Some(result) = self.results.next() {
+            return Some(result);
+        }
+
+        let chunk: Vec<I::Item> = self.resources.by_ref().take(self.chunk_size).collect();
+        let (expressions, context) = (self.expressions, self.context);
+        let results: Vec<BatchResult> = chunk
+            .into_par_iter()
+            .map(|resource| evaluate_all(expressions, resource.borrow(), context))
+            .collect();
+        self.results = results.into_iter();
+        self.results.next()
+    }
+}
+
+/// The results of `BatchEvaluator::evaluate_ndjson()`, one for each non-blank line
+pub struct NdjsonResults<'a, R> {
+    expressions: &'a [CompiledExpression],
+    context: &'a EvaluationContext,
+    chunk_size: usize,
+    reader: NdjsonReader<R>,
+    results: std::vec::IntoIter<Result<(usize, BatchResult), ParseError>>,
+}
+
+impl<R: BufRead> Iterator for NdjsonResults<'_, R> {
+    type Item = Result<(usize, BatchResult), ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(result) = self.results.next() {
+            return Some(result);
+        }
+
+        let chunk: Vec<_> = std::iter::from_fn(|| self.reader.next_line())
+            .take(self.chunk_size)
+            .collect();
+        let (expressions, context) = (self.expressions, self.context);
+        let results: Vec<_> = chunk
+            .into_par_iter()
+            .map(|line| {
+                let (line, text) = line?;
+                let resource = context
+                    .model()
+                    .map_err(|err| ParseError::Line(line, Box::new(err.into())))
+                    .and_then(|model| load_ndjson_line(model, line, &text))?;
+                Ok((line, evaluate_all(expressions, &resource, context)))
+            })
+            .collect();
+        self.results = results.into_iter();
+        self.results.next()
+    }
+}
+
+fn evaluate_all(
+    expressions: &[CompiledExpression],
+    resource: &Arc<DataNode>,
+    context: &EvaluationContext,
+) -> BatchResult {
+    expressions
+        .iter()
+        .map(|expression| expression.evaluate_resource_with(resource, context))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhir::load_resource;
+    use crate::fhirpath::Value;
+    use crate::Expression;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_thread_safety() {
+        assert_send_sync::<Expression>();
+        assert_send_sync::<CompiledExpression>();
+        assert_send_sync::<EvaluationContext>();
+        assert_send_sync::<DataNode>();
+        assert_send_sync::<BatchEvaluator>();
+    }
+
+    #[test]
+    fn test_batch_evaluation() {
+        let expressions: Vec<_> = ["Patient.id", "Patient.name.given.toLong()"]
+            .into_iter()
+            .map(|expr| Expression::new(expr).unwrap().compile().unwrap())
+            .collect();
+        let patients = (0..100).map(|i| {
+            // Every tenth patient has two given names, which toLong() cannot convert
+            let given = if i % 10 == 0 {
+                vec!["1", "2"]
+            } else {
+                vec!["1"]
+            };
+            load_resource(&serde_json::json!({
+                "resourceType": "Patient",
+                "id": format!("p{}", i),
+                "name": [{ "given": given }]
+            }))
+            .unwrap()
+        });
+
+        let context = EvaluationContext::new();
+        let results: Vec<_> = BatchEvaluator::new(&expressions, &context)
+            .with_chunk_size(7)
+            .evaluate(patients)
+            .collect();
+        assert_eq!(results.len(), 100);
+        for (i, result) in results.iter().enumerate() {
+            let [id, given] = result.as_slice() else {
+                panic!("expected a result for each expression");
+            };
+            let expected = Collection::from(Value::string(format!("p{}", i)));
+            assert_eq!(id.as_ref().ok(), Some(&expected));
+            match given {
+                Err(err) if i % 10 == 0 => assert!(
+                    matches!(err.kind(), EvaluationError::ExpectedSingleton { .. }),
+                    "{}",
+                    err
+                ),
+                Ok(value) => assert_eq!(value, &Collection::from(Value::long(1))),
+                Err(err) => panic!("unexpected error for p{}: {}", i, err),
+            }
+        }
+
+        assert!(BatchEvaluator::new(&expressions, &context)
+            .evaluate(Vec::<Arc<DataNode>>::new())
+            .next()
+            .is_none());
+    }
+
+    #[test]
+    fn test_ndjson_evaluation() {
+        let expressions = [Expression::new("Patient.name.family")
+            .unwrap()
+            .compile()
+            .unwrap()];
+        let data = [
+            r#"{"resourceType": "Patient", "name": [{ "family": "Chalmers" }]}"#,
+            "",
+            r#"{"resourceType": "Patient", "name": [{ "family": "#,
+            r#"{"resourceType": "Patient", "name": [{ "family": "Windsor" }]}"#,
+            r#"{"resourceType": "Observation", "status": "final"}"#,
+        ]
+        .join("\n");
+
+        let context = EvaluationContext::new();
+        let results: Vec<_> = BatchEvaluator::new(&expressions, &context)
+            .with_chunk_size(2)
+            .evaluate_ndjson(data.as_bytes())
+            .collect();
+        let family = |result: &Result<(usize, BatchResult), ParseError>| {
+            let (line, results) = result.as_ref().unwrap();
+            let family = results[0].as_ref().unwrap();
+            (
+                *line,
+                family.iter().map(|v| v.to_string()).collect::<Vec<_>>(),
+            )
+        };
+        assert_eq!(results.len(), 4);
+        assert_eq!(family(&results[0]), (1, vec!["'Chalmers'".to_string()]));
+        assert!(matches!(results[1], Err(ParseError::Line(3, _))));
+        assert_eq!(family(&results[2]), (4, vec!["'Windsor'".to_string()]));
+        assert_eq!(family(&results[3]), (5, vec![]));
+    }
+
+    #[test]
+    fn test_ndjson_model() {
+        let expressions =
+            [
+                Expression::new("Observation.instantiates = 'http://example.org/steps'")
+                    .unwrap()
+                    .compile()
+                    .unwrap(),
+            ];
+        let data = r#"{"resourceType": "Observation", "status": "final", "code": { "text": "Steps" }, "instantiatesCanonical": "http://example.org/steps"}"#;
+
+        // The element is only in R5, so the default R4B model cannot read it
+        let context = EvaluationContext::new();
+        let results: Vec<_> = BatchEvaluator::new(&expressions, &context)
+            .evaluate_ndjson(data.as_bytes())
+            .collect();
+        assert!(matches!(
+            &results[..],
+            [Err(ParseError::Line(1, err))] if matches!(**err, ParseError::UnknownElement(_))
+        ));
+
+        let context = EvaluationContext::new().with_model(crate::tests::r5_model());
+        let results: Vec<_> = BatchEvaluator::new(&expressions, &context)
+            .evaluate_ndjson(data.as_bytes())
+            .collect();
+        let (line, results) = results[0].as_ref().unwrap();
+        assert_eq!(*line, 1);
+        assert_eq!(
+            results[0].as_ref().unwrap(),
+            &Collection::from(Value::boolean(true))
+        );
+    }
+}
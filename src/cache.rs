@@ -0,0 +1,246 @@
+use crate::evaluation::EvaluationError;
+use crate::parser::ParserError;
+use crate::{CompiledExpression, Expression};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Options that change how an expression is compiled, so expressions compiled with different
+/// options are cached separately
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CompileOptions {
+    /// Whether to rewrite the expression with `Expression::optimize()` before compiling it
+    pub optimize: bool,
+}
+
+#[derive(Debug)]
+pub enum CompileError {
+    Parser(ParserError),
+    Evaluation(EvaluationError),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::Parser(err) => write!(f, "{}", err),
+            CompileError::Evaluation(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CompileError::Parser(err) => Some(err),
+            CompileError::Evaluation(err) => Some(err),
+        }
+    }
+}
+
+impl From<ParserError> for CompileError {
+    fn from(err: ParserError) -> Self {
+        CompileError::Parser(err)
+    }
+}
+
+impl From<EvaluationError> for CompileError {
+    fn from(err: EvaluationError) -> Self {
+        CompileError::Evaluation(err)
+    }
+}
+
+/// Counts of the lookups made in an `ExpressionCache`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// The number of expressions in the cache
+    pub size: usize,
+}
+
+type Key = (String, CompileOptions);
+
+/// Keeps the most recently used compiled expressions, so that expressions that are evaluated
+/// again and again are only parsed and compiled once.  The cache can be shared between threads;
+/// expressions are compiled without holding its lock, so a slow compilation does not hold up
+/// lookups of other expressions.  Expressions that fail to compile are not cached.
+pub struct ExpressionCache {
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<Key, (Arc<CompiledExpression>, u64)>,
+    /// The key of each entry by when it was last used, least recently used first
+    recency: BTreeMap<u64, Key>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &Key) -> Option<Arc<CompiledExpression>> {
+        self.clock += 1;
+        let (expression, used) = self.entries.get_mut(key)?;
+        let key = self.recency.remove(used)?;
+        *used = self.clock;
+        let expression = Arc::clone(expression);
+        self.recency.insert(self.clock, key);
+        Some(expression)
+    }
+}
+
+impl ExpressionCache {
+    /// A cache holding at most `capacity` expressions (at least one)
+    pub fn new(capacity: usize) -> Self {
+        ExpressionCache {
+            capacity: capacity.max(1),
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// The compiled expression, from the cache if it has been compiled with the same options
+    pub fn get(
+        &self,
+        expr: &str,
+        options: CompileOptions,
+    ) -> Result<Arc<CompiledExpression>, CompileError> {
+        let key = (expr.to_string(), options);
+        {
+            let mut state = self.state();
+            if let Some(expression) = state.touch(&key) {
+                state.stats.hits += 1;
+                return Ok(expression);
+            }
+            state.stats.misses += 1;
+        }
+
+        let expression = Arc::new(compile(expr, options)?);
+        let mut state = self.state();
+        // Another thread may have compiled the expression in the meantime
+        if let Some(expression) = state.touch(&key) {
+            return Ok(expression);
+        }
+        while state.entries.len() >= self.capacity {
+            let Some((_, oldest)) = state.recency.pop_first() else {
+                break;
+            };
+            state.entries.remove(&oldest);
+            state.stats.evictions += 1;
+        }
+        state.clock += 1;
+        let used = state.clock;
+        state.recency.insert(used, key.clone());
+        state.entries.insert(key, (Arc::clone(&expression), used));
+        Ok(expression)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state();
+        CacheStats {
+            size: state.entries.len(),
+            ..state.stats
+        }
+    }
+
+    /// Removes every expression, keeping the counts of earlier lookups
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.entries.clear();
+        state.recency.clear();
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        // The state is consistent between statements, so a panic elsewhere cannot corrupt it
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn compile(expr: &str, options: CompileOptions) -> Result<CompiledExpression, CompileError> {
+    let mut expression = Expression::new(expr)?;
+    if options.optimize {
+        expression = expression.optimize();
+    }
+    Ok(expression.compile()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhirpath::{Collection, Value};
+
+    #[test]
+    fn test_expression_cache() {
+        let cache = ExpressionCache::new(2);
+        let options = CompileOptions::default();
+
+        let first = cache.get("1 + 1", options).unwrap();
+        let second = cache.get("1 + 1", options).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(
+            second.evaluate().unwrap(),
+            Collection::from(Value::integer(2))
+        );
+
+        // Options are part of the key
+        let optimized = cache
+            .get("1 + 1", CompileOptions { optimize: true })
+            .unwrap();
+        assert!(!Arc::ptr_eq(&first, &optimized));
+
+        // The unoptimized expression was used least recently, so it is evicted first
+        cache
+            .get("1 + 1", CompileOptions { optimize: true })
+            .unwrap();
+        cache.get("2 + 2", options).unwrap();
+        assert!(!Arc::ptr_eq(&first, &cache.get("1 + 1", options).unwrap()));
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 2,
+                misses: 4,
+                evictions: 2,
+                size: 2,
+            }
+        );
+
+        assert!(matches!(
+            cache.get("'unterminated", options),
+            Err(CompileError::Parser(ParserError::InvalidString))
+        ));
+        assert!(matches!(
+            cache.get("unknownFunction()", options),
+            Err(CompileError::Evaluation(_))
+        ));
+        assert_eq!(cache.stats().size, 2);
+
+        cache.clear();
+        assert_eq!(cache.stats().size, 0);
+        assert_eq!(cache.stats().misses, 6);
+    }
+
+    #[test]
+    fn test_shared_expression_cache() {
+        let cache = ExpressionCache::new(16);
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for i in 0..100 {
+                        let expr = format!("{} * 2", i % 8);
+                        let expression = cache.get(&expr, CompileOptions::default()).unwrap();
+                        assert_eq!(
+                            expression.evaluate().unwrap(),
+                            Collection::from(Value::integer((i % 8) * 2))
+                        );
+                    }
+                });
+            }
+        });
+        let stats = cache.stats();
+        assert_eq!(stats.hits + stats.misses, 400);
+        assert_eq!((stats.size, stats.evictions), (8, 0));
+    }
+}
@@ -0,0 +1,226 @@
+use super::*;
+use crate::fhirpath::Collection;
+use crate::parser::ASTNode;
+
+type Step = Box<dyn Fn(&mut Visitor) -> Result<Collection, EvaluationError> + Send + Sync>;
+
+/// A function invoked on an input evaluated by an earlier step
+type Call =
+    Box<dyn Fn(&mut Visitor, &Collection) -> Result<Collection, EvaluationError> + Send + Sync>;
+
+impl Parameter for Step {
+    fn evaluate(&self, visitor: &mut Visitor) -> Result<Collection, EvaluationError> {
+        self(visitor)
+    }
+}
+
+/// An expression compiled into a tree of closures.  Literals are parsed and functions looked up
+/// once, when the plan is compiled, so a plan can be evaluated against any number of inputs,
+/// from any number of threads, without walking the AST again.
+pub struct Plan {
+    root: Step,
+}
+
+impl Plan {
+    pub fn compile(ast: &ASTNode) -> Result<Self, EvaluationError> {
+        Ok(Plan {
+            root: compile(ast)?,
+        })
+    }
+
+    pub fn evaluate(&self, visitor: &mut Visitor) -> Result<Collection, EvaluationError> {
+        (self.root)(visitor)
+    }
+}
+
+/// Compiles a node, recording where any error it raises was raised as evaluating it would
+fn compile(node: &ASTNode) -> Result<Step, EvaluationError> {
+    compile_node(node).map_err(|err| locate(err, node))
+}
+
+fn locate(err: EvaluationError, node: &ASTNode) -> EvaluationError {
+    match node.span() {
+        Some(span) => err.located(span, function_name(node)),
+        None => err,
+    }
+}
+
+fn compile_node(node: &ASTNode) -> Result<Step, EvaluationError> {
+    if let Some(literal) = literal(node) {
+        let value = literal?;
+        return Ok(Box::new(move |visitor| {
+            visitor.run(None, None, |_| Ok(value.clone()))
+        }));
+    }
+    let span = node.span();
+    let step: Step = match node {
+        ASTNode::Identifier(name) => {
+            let name = name.clone();
+            Box::new(move |visitor| visitor.run(None, None, |v| v.identifier(&name)))
+        }
+        ASTNode::Variable(name, _) => {
+            let name = name.clone();
+            Box::new(move |visitor| visitor.run(span, None, |v| v.variable(&name)))
+        }
+        ASTNode::InvocationExpression(left, right) => match (left.as_ref(), right.as_ref()) {
+            (ASTNode::Variable(name, _), _) if name == "terminologies" => {
+                let call = compile_terminology_call(right).map_err(|err| locate(err, right))?;
+                Box::new(move |visitor| {
+                    visitor.run(None, None, |v| {
+                        let input = Collection::new();
+                        call(v, &input)
+                    })
+                })
+            }
+            (_, ASTNode::Identifier(name)) => {
+                let name = name.clone();
+                let left = compile(left)?;
+                Box::new(move |visitor| {
+                    visitor.run(None, None, |v| {
+                        v.chain();
+                        Ok(navigate(&left(v)?, &name))
+                    })
+                })
+            }
+            (_, ASTNode::Function(..)) => {
+                let call = compile_call(right, Some(left)).map_err(|err| locate(err, right))?;
+                let left = compile(left)?;
+                Box::new(move |visitor| {
+                    visitor.run(None, None, |v| {
+                        v.chain();
+                        let input = left(v)?;
+                        v.chain();
+                        call(v, &input)
+                    })
+                })
+            }
+            _ => return Err(EvaluationError::InvalidAST),
+        },
+        ASTNode::Function(..) => {
+            let call = compile_call(node, None)?;
+            Box::new(move |visitor| {
+                let input = visitor.input().clone();
+                call(visitor, &input)
+            })
+        }
+        ASTNode::ParamList(None) => {
+            Box::new(|visitor| visitor.run(None, None, |_| Ok(Collection::new())))
+        }
+        ASTNode::ParamList(Some(param)) => {
+            let param = compile(param)?;
+            Box::new(move |visitor| visitor.run(None, None, |v| param(v)))
+        }
+        ASTNode::Union(left, right) => {
+            let (left, right) = (compile(left)?, compile(right)?);
+            Box::new(move |visitor| visitor.run(None, None, |v| Ok(union(left(v)?, right(v)?))))
+        }
+        ASTNode::UnionExpression(left, right, _) => {
+            let (left, right) = (compile(left)?, compile(right)?);
+            Box::new(move |visitor| {
+                visitor.run(span, None, |v| Ok(distinct_union(left(v)?, right(v)?)))
+            })
+        }
+        ASTNode::EqualityExpression(left, op, right, _) => {
+            let (left, op, right) = (compile(left)?, *op, compile(right)?);
+            Box::new(move |visitor| {
+                visitor.run(span, None, |v| Ok(equality(&left(v)?, op, &right(v)?)))
+            })
+        }
+        ASTNode::InequalityExpression(left, op, right, _) => {
+            let (left, op, right) = (compile(left)?, *op, compile(right)?);
+            Box::new(move |visitor| {
+                visitor.run(span, None, |v| inequality(&left(v)?, op, &right(v)?))
+            })
+        }
+        ASTNode::AdditiveExpression(left, op, right, _) => {
+            let (left, op, right) = (compile(left)?, *op, compile(right)?);
+            Box::new(move |visitor| {
+                visitor.run(span, None, |v| additive(&left(v)?, op, &right(v)?))
+            })
+        }
+        ASTNode::MultiplicativeExpression(left, op, right, _) => {
+            let (left, op, right) = (compile(left)?, *op, compile(right)?);
+            Box::new(move |visitor| {
+                visitor.run(span, None, |v| multiplicative(&left(v)?, op, &right(v)?))
+            })
+        }
+        ASTNode::BooleanExpression(left, op, right, _) => {
+            let (left, op, right) = (compile(left)?, *op, compile(right)?);
+            Box::new(move |visitor| {
+                visitor.run(span, None, |v| {
+                    let c1 = left(v)?;
+                    boolean(&c1, op, || right(v))
+                })
+            })
+        }
+        ASTNode::TypeExpression(left, op, specifier, _) => {
+            let (left, op, specifier) = (compile(left)?, *op, specifier.clone());
+            Box::new(move |visitor| {
+                visitor.run(span, None, |v| {
+                    let input = left(v)?;
+                    type_expression(v.context().model()?, &input, op, &specifier)
+                })
+            })
+        }
+        ASTNode::MembershipExpression(left, op, right, _) => {
+            let (left, op, right) = (compile(left)?, *op, compile(right)?);
+            Box::new(move |visitor| {
+                visitor.run(span, None, |v| membership(&left(v)?, op, &right(v)?))
+            })
+        }
+        _ => return Err(EvaluationError::InvalidAST),
+    };
+    Ok(step)
+}
+
+/// Compiles the invocation of a function, resolving it and compiling its parameters.  The
+/// expression that selected the input is kept only for functions that need its definition.
+fn compile_call(node: &ASTNode, input: Option<&ASTNode>) -> Result<Call, EvaluationError> {
+    let (Some(name), ASTNode::Function(_, params, span)) = (function_name(node), node) else {
+        return Err(EvaluationError::InvalidAST);
+    };
+    let callee = resolve_function(name, params)?;
+    let params = match callee {
+        Callee::Type(..) => Vec::new(),
+        _ => param_nodes(params)
+            .into_iter()
+            .map(compile)
+            .collect::<Result<Vec<_>, _>>()?,
+    };
+    let input = input.filter(|_| needs_definition(name)).cloned();
+    let (name, span) = (name.to_string(), *span);
+    Ok(Box::new(move |visitor, value| {
+        visitor.run(Some(span), Some(&name), |v| {
+            v.set_input_definition(&name, input.as_ref());
+            v.invoke(&callee, value, &params)
+        })
+    }))
+}
+
+/// Compiles the invocation of a function of `%terminologies`, which is given the value of each
+/// of its parameters
+fn compile_terminology_call(node: &ASTNode) -> Result<Call, EvaluationError> {
+    let ASTNode::Function(_, params, span) = node else {
+        return Err(EvaluationError::UnknownVariable(
+            "terminologies".to_string(),
+        ));
+    };
+    let name = function_name(node).ok_or(EvaluationError::InvalidAST)?;
+    let function = *TERMINOLOGY_FUNCTIONS
+        .get(name)
+        .ok_or_else(|| EvaluationError::FunctionUnavailable(format!("%terminologies.{}", name)))?;
+    let params = param_nodes(params)
+        .into_iter()
+        .map(compile)
+        .collect::<Result<Vec<_>, _>>()?;
+    let (name, span) = (name.to_string(), *span);
+    Ok(Box::new(move |visitor, _| {
+        visitor.run(Some(span), Some(&name), |v| {
+            let values = params
+                .iter()
+                .map(|param| param(v))
+                .collect::<Result<Vec<_>, _>>()?;
+            function(v, &values)
+        })
+    }))
+}
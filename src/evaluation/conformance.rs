@@ -0,0 +1,162 @@
+use super::*;
+use crate::fhir::{Discriminator, ElementDefinition, Model, StructureDefinition};
+use crate::fhirpath::{Collection, DataNode, Value};
+use crate::parser::{Lexer, Parser};
+
+/// Whether an element conforms to a profile: it must be of the profiled type, and each element
+/// in the snapshot must occur within its cardinality and match any `fixed[x]` or `pattern[x]`.
+/// Slices are not checked.
+pub fn conforms_to(model: &Model, node: &DataNode, profile: &StructureDefinition) -> bool {
+    let Some(type_name) = node.data_type().strip_prefix("FHIR.") else {
+        return false;
+    };
+    model.is_subtype(type_name, &profile.r#type)
+        && children_conform(model, node, profile, &profile.r#type)
+}
+
+fn children_conform(
+    model: &Model,
+    node: &DataNode,
+    profile: &StructureDefinition,
+    path: &str,
+) -> bool {
+    profile.child_elements(path).all(|element| {
+        let members: Vec<_> = node.shared_members(element.name()).collect();
+        let max = match element.max.as_deref() {
+            Some("*") | None => usize::MAX,
+            Some(max) => max.parse().unwrap_or(usize::MAX),
+        };
+        let has_children = profile.child_elements(&element.path).next().is_some();
+
+        members.len() >= element.min.unwrap_or(0) as usize
+            && members.len() <= max
+            && members.iter().all(|member| {
+                matches_fixed_and_pattern(model, &member.to_value(), element)
+                    && (!has_children || children_conform(model, member, profile, &element.path))
+            })
+    })
+}
+
+/// Whether an item belongs to a slice, judged by the discriminators of the sliced element
+pub fn in_slice(
+    context: &EvaluationContext,
+    item: &Value,
+    profile: &StructureDefinition,
+    slice: &ElementDefinition,
+) -> Result<bool, EvaluationError> {
+    let sliced_id = slice.id.rsplit_once(':').map_or("", |(id, _)| id);
+    let discriminators = profile
+        .element_by_id(sliced_id)
+        .and_then(|sliced| sliced.slicing.as_ref())
+        .map(|slicing| slicing.discriminator.as_slice())
+        .unwrap_or_default();
+    if discriminators.is_empty() {
+        return Ok(false);
+    }
+
+    for discriminator in discriminators {
+        if !matches_discriminator(context, item, profile, slice, discriminator)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn matches_discriminator(
+    context: &EvaluationContext,
+    item: &Value,
+    profile: &StructureDefinition,
+    slice: &ElementDefinition,
+    discriminator: &Discriminator,
+) -> Result<bool, EvaluationError> {
+    let (element, values) = if discriminator.path == "$this" {
+        (Some(slice), Collection::from(item.clone()))
+    } else {
+        let id = format!("{}.{}", slice.id, discriminator.path);
+        let element = profile
+            .element_by_id(&id)
+            .or_else(|| profile.element_by_id(&format!("{}[x]", id)));
+        (element, evaluate_path(context, item, &discriminator.path)?)
+    };
+    // Nothing in the slice constrains the discriminator, so nothing distinguishes its items
+    let Some(element) = element else {
+        return Ok(true);
+    };
+
+    let model = context.model()?;
+    match discriminator.r#type.as_str() {
+        "value" | "pattern" => Ok(values
+            .iter()
+            .any(|value| matches_fixed_and_pattern(model, value, element))),
+        "exists" => {
+            let exists = !values.is_empty();
+            Ok(match (element.min, element.max.as_deref()) {
+                (Some(min), _) if min > 0 => exists,
+                (_, Some("0")) => !exists,
+                _ => true,
+            })
+        }
+        "type" => Ok(values.iter().any(|value| {
+            element
+                .r#type
+                .iter()
+                .any(|t| value.is_type(&format!("FHIR.{}", t.code), model))
+        })),
+        "profile" => Ok(values.iter().any(|value| {
+            let Value::Complex(node) = value else {
+                return false;
+            };
+            element
+                .r#type
+                .iter()
+                .flat_map(|t| t.profile.iter())
+                .filter_map(|url| context.structure_definition(url))
+                .any(|profile| conforms_to(model, node, profile))
+        })),
+        other => Err(EvaluationError::InvalidDiscriminator(other.to_string())),
+    }
+}
+
+fn evaluate_path(
+    context: &EvaluationContext,
+    item: &Value,
+    path: &str,
+) -> Result<Collection, EvaluationError> {
+    let invalid = || EvaluationError::InvalidDiscriminator(path.to_string());
+    let tokens = Lexer::new(path).tokenize().map_err(|_| invalid())?;
+    let ast = Parser::new(tokens).parse().map_err(|_| invalid())?;
+    Visitor::new(context)
+        .with_input(Collection::from(item.clone()))
+        .visit_node(&ast)
+}
+
+/// Checks a value against the element's `fixed[x]` (which must match exactly) and
+/// `pattern[x]` (whose members must all be present)
+pub(crate) fn matches_fixed_and_pattern(
+    model: &Model,
+    value: &Value,
+    element: &ElementDefinition,
+) -> bool {
+    let expected =
+        |(code, json): (String, &serde_json::Value)| model.load_data_type(json, &code).ok();
+    let node = match value {
+        Value::Complex(node) => node.as_ref().clone(),
+        _ => DataNode::Value(value.clone()),
+    };
+
+    let fixed =
+        element
+            .fixed()
+            .and_then(expected)
+            .is_none_or(|fixed| match fixed.primitive_value() {
+                Some(fixed) => value.system_value().equal(fixed) == Some(true),
+                None => node.equal(&fixed) == Some(true),
+            });
+    let pattern = element.pattern().and_then(expected).is_none_or(|pattern| {
+        match pattern.primitive_value() {
+            Some(pattern) => value.system_value().equal(pattern) == Some(true),
+            None => node.matches_pattern(&pattern),
+        }
+    });
+    fixed && pattern
+}
@@ -0,0 +1,184 @@
+use super::*;
+use crate::fhir::{self, Model, PackageError, StructureDefinition};
+use crate::fhirpath::{Collection, DataNode};
+use chrono::{DateTime, FixedOffset, Local};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Source of the current time for `now()`, `today()` and `timeOfDay()`
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<FixedOffset>;
+}
+
+/// Reads the system clock in the local time zone
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<FixedOffset> {
+        let now = Local::now();
+        now.with_timezone(now.offset())
+    }
+}
+
+/// Always returns the same instant, for deterministic evaluation
+pub struct FixedClock(pub DateTime<FixedOffset>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<FixedOffset> {
+        self.0
+    }
+}
+
+/// Environment shared by evaluations, supplying services that expressions can depend on
+pub struct EvaluationContext {
+    clock: Box<dyn Clock>,
+    resolvers: Vec<Box<dyn ReferenceResolver>>,
+    strict_references: bool,
+    model: Option<&'static Model>,
+    profiles: HashMap<String, StructureDefinition>,
+    trace_sink: Box<dyn TraceSink>,
+    terminology: Option<Box<dyn TerminologyService>>,
+    variables: HashMap<String, Collection>,
+    limits: Limits,
+    cancellation_token: Option<CancellationToken>,
+}
+
+impl EvaluationContext {
+    pub fn new() -> Self {
+        EvaluationContext {
+            clock: Box::new(SystemClock),
+            resolvers: vec![Box::new(ContainedResolver), Box::new(BundleResolver)],
+            strict_references: false,
+            model: None,
+            profiles: HashMap::new(),
+            trace_sink: Box::new(LogTraceSink),
+            terminology: None,
+            variables: HashMap::new(),
+            limits: Limits::default(),
+            cancellation_token: None,
+        }
+    }
+
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Adds a resolver for `resolve()`, consulted after contained resources and Bundle entries
+    pub fn with_resolver(mut self, resolver: impl ReferenceResolver + 'static) -> Self {
+        self.resolvers.push(Box::new(resolver));
+        self
+    }
+
+    /// In strict mode `resolve()` fails on references that cannot be resolved, rather than
+    /// ignoring them
+    pub fn with_strict_references(mut self, strict: bool) -> Self {
+        self.strict_references = strict;
+        self
+    }
+
+    /// Evaluates against the resources and data types of another model than the default, e.g.
+    /// `fhir::model(FhirVersion::R5)`, for resources read with that model
+    pub fn with_model(mut self, model: &'static Model) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    /// Makes a profile available to `conformsTo()`, `slice()` and `elementDefinition()`, in
+    /// addition to the base resources and data types
+    pub fn with_profile(mut self, profile: StructureDefinition) -> Self {
+        self.profiles.insert(profile.url.clone(), profile);
+        self
+    }
+
+    pub fn with_trace_sink(mut self, sink: impl TraceSink + 'static) -> Self {
+        self.trace_sink = Box::new(sink);
+        self
+    }
+
+    pub fn with_terminology_service(mut self, service: impl TerminologyService + 'static) -> Self {
+        self.terminology = Some(Box::new(service));
+        self
+    }
+
+    /// Defines an environment variable, available to expressions as `%name`
+    pub fn with_variable(mut self, name: &str, value: Collection) -> Self {
+        self.variables.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Stops evaluations with this context once the token is cancelled
+    pub fn with_cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancellation_token = Some(token);
+        self
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    pub fn trace_sink(&self) -> &dyn TraceSink {
+        self.trace_sink.as_ref()
+    }
+
+    pub fn terminology(&self) -> Result<&dyn TerminologyService, EvaluationError> {
+        self.terminology
+            .as_deref()
+            .ok_or(EvaluationError::TerminologyUnavailable)
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&Collection> {
+        self.variables.get(name)
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation_token
+            .as_ref()
+            .is_some_and(CancellationToken::is_cancelled)
+    }
+
+    pub fn strict_references(&self) -> bool {
+        self.strict_references
+    }
+
+    /// The model that types and definitions are looked up in: the one given with
+    /// `with_model()`, or else the default model, which fails if it cannot be built
+    pub fn model(&self) -> Result<&'static Model, PackageError> {
+        self.model.map_or_else(fhir::default_model, Ok)
+    }
+
+    /// Looks up a profile, resource or data type by canonical URL or name
+    pub fn structure_definition(&self, reference: &str) -> Option<&StructureDefinition> {
+        let url = reference.split('|').next().unwrap_or(reference);
+        self.profiles
+            .get(url)
+            .or_else(|| self.profiles.values().find(|sd| sd.name == url))
+            .or_else(|| self.model().ok()?.find_structure_definition(url))
+    }
+
+    /// Resolves a reference with the first resolver that finds it
+    pub fn resolve_reference(
+        &self,
+        reference: &str,
+        root: Option<&DataNode>,
+    ) -> Option<Arc<DataNode>> {
+        self.resolvers
+            .iter()
+            .find_map(|resolver| resolver.resolve(reference, root))
+    }
+}
+
+impl Default for EvaluationContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,13 +1,323 @@
+use std::fmt;
 use std::num::ParseIntError;
 
-use crate::fhirpath::{Collection, Type};
+use super::{Limit, StaticType};
+use crate::fhir::PackageError;
+use crate::fhirpath::{Collection, Type, ValueError};
+use crate::parser::Span;
 
 #[derive(Debug)]
 pub enum EvaluationError {
     InvalidInteger(String, ParseIntError),
     InvalidDecimal(String),
+    InvalidValue(ValueError),
     InvalidAST,
-    ExpectedSingleton(Type),
+    /// A single item was expected, but the collection had `count`
+    ExpectedSingleton {
+        expected: Type,
+        count: usize,
+    },
+    UnexpectedType {
+        expected: Type,
+        actual: Type,
+    },
     FunctionUnavailable(String),
     InvalidFunctionArguments(Collection),
+    InvalidOperands(Collection, Collection),
+    UnresolvedReference(String),
+    UnknownStructure(String),
+    UnknownModifier(String),
+    InvalidDiscriminator(String),
+    InvalidDefinition(String),
+    UnknownVariable(String),
+    TerminologyUnavailable,
+    Terminology(TerminologyError),
+    /// The context has no model and the default model could not be built
+    NoModel(PackageError),
+    LimitExceeded(Limit),
+    /// An error raised while evaluating part of an expression, with the span of that part and
+    /// the function being invoked, if any
+    Located {
+        span: Span,
+        function: Option<String>,
+        error: Box<EvaluationError>,
+    },
+}
+
+#[derive(Debug)]
+pub enum TerminologyError {
+    UnknownCodeSystem(String),
+    UnknownValueSet(String),
+    UnknownConceptMap(String),
+    UnsupportedFilter(String, String),
+    /// A value set includes itself, directly or through others
+    CircularValueSet(String),
+}
+
+/// A problem found by the type checker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeCheckError {
+    UnknownType(String),
+    UnknownElement {
+        r#type: String,
+        element: String,
+    },
+    UnknownFunction(String),
+    UnknownVariable(String),
+    InvalidLiteral(String),
+    /// A function was given an input of none of the types it accepts
+    InvalidInput {
+        function: String,
+        expected: Vec<Type>,
+        actual: StaticType,
+    },
+    InvalidOperands {
+        operator: String,
+        left: StaticType,
+        right: StaticType,
+    },
+    /// A function or operator that requires a single item was given an input that may hold more
+    ExpectedSingleton {
+        operation: String,
+        actual: StaticType,
+    },
+    UnsupportedExpression,
+    /// No model was given and the default model could not be built, for the reason given
+    NoModel(String),
+    /// A problem found in part of an expression, with the span of that part and the function
+    /// being invoked, if any
+    Located {
+        span: Span,
+        function: Option<String>,
+        error: Box<TypeCheckError>,
+    },
+}
+
+impl EvaluationError {
+    /// The error, without where in the expression it was raised
+    pub fn kind(&self) -> &EvaluationError {
+        match self {
+            EvaluationError::Located { error, .. } => error.kind(),
+            error => error,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            EvaluationError::Located { span, .. } => Some(*span),
+            _ => None,
+        }
+    }
+
+    /// The function that raised the error, if it was raised by one
+    pub fn function(&self) -> Option<&str> {
+        match self {
+            EvaluationError::Located { function, .. } => function.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Records where an error was raised, unless a more specific location is already known
+    pub(crate) fn located(self, span: Span, function: Option<&str>) -> Self {
+        match self {
+            EvaluationError::Located { .. } => self,
+            error => EvaluationError::Located {
+                span,
+                function: function.map(str::to_string),
+                error: Box::new(error),
+            },
+        }
+    }
+}
+
+impl fmt::Display for EvaluationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EvaluationError::InvalidInteger(s, err) => write!(f, "invalid integer {}: {}", s, err),
+            EvaluationError::InvalidDecimal(s) => write!(f, "invalid decimal {}", s),
+            EvaluationError::InvalidValue(err) => write!(f, "{}", err),
+            EvaluationError::InvalidAST => write!(f, "unsupported expression"),
+            EvaluationError::ExpectedSingleton { expected, count } => write!(
+                f,
+                "expected a single {}, but found {} items",
+                expected, count
+            ),
+            EvaluationError::UnexpectedType { expected, actual } => {
+                write!(f, "expected a {}, but found a {}", expected, actual)
+            }
+            EvaluationError::FunctionUnavailable(name) => {
+                write!(f, "function {}() is not available", name)
+            }
+            EvaluationError::InvalidFunctionArguments(args) => {
+                write!(f, "invalid function arguments {}", args)
+            }
+            EvaluationError::InvalidOperands(left, right) => {
+                write!(f, "invalid operands {} and {}", left, right)
+            }
+            EvaluationError::UnresolvedReference(reference) => {
+                write!(f, "unable to resolve reference {}", reference)
+            }
+            EvaluationError::UnknownStructure(url) => write!(f, "unknown structure {}", url),
+            EvaluationError::UnknownModifier(url) => {
+                write!(f, "unknown modifier extension {}", url)
+            }
+            EvaluationError::InvalidDiscriminator(path) => {
+                write!(f, "invalid discriminator {}", path)
+            }
+            EvaluationError::InvalidDefinition(id) => write!(f, "invalid definition of {}", id),
+            EvaluationError::UnknownVariable(name) => write!(f, "unknown variable %{}", name),
+            EvaluationError::TerminologyUnavailable => {
+                write!(f, "no terminology service is available")
+            }
+            EvaluationError::Terminology(err) => write!(f, "{}", err),
+            EvaluationError::NoModel(err) => write!(f, "{}", err),
+            EvaluationError::LimitExceeded(limit) => match limit {
+                Limit::Steps(max) => write!(f, "evaluation exceeded {} steps", max),
+                Limit::CollectionSize(max) => {
+                    write!(f, "collection exceeded the maximum size of {}", max)
+                }
+                Limit::Depth(max) => write!(f, "evaluation exceeded a depth of {}", max),
+                Limit::StringLength(max) => {
+                    write!(f, "string exceeded the maximum length of {}", max)
+                }
+                Limit::Timeout(timeout) => write!(f, "evaluation timed out after {:?}", timeout),
+                Limit::Cancelled => write!(f, "evaluation was cancelled"),
+            },
+            EvaluationError::Located {
+                span,
+                function,
+                error,
+            } => {
+                write!(f, "{}", error)?;
+                if let Some(function) = function {
+                    write!(f, " in {}()", function)?;
+                }
+                write!(f, " at {}..{}", span.start, span.end)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EvaluationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EvaluationError::InvalidInteger(_, err) => Some(err),
+            EvaluationError::InvalidValue(err) => Some(err),
+            EvaluationError::Terminology(err) => Some(err),
+            EvaluationError::NoModel(err) => Some(err),
+            EvaluationError::Located { error, .. } => Some(error.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl TypeCheckError {
+    /// The problem, without where in the expression it was found
+    pub fn kind(&self) -> &TypeCheckError {
+        match self {
+            TypeCheckError::Located { error, .. } => error.kind(),
+            error => error,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            TypeCheckError::Located { span, .. } => Some(*span),
+            _ => None,
+        }
+    }
+
+    /// Records where a problem was found, unless a more specific location is already known
+    pub(crate) fn located(self, span: Span, function: Option<&str>) -> Self {
+        match self {
+            TypeCheckError::Located { .. } => self,
+            error => TypeCheckError::Located {
+                span,
+                function: function.map(str::to_string),
+                error: Box::new(error),
+            },
+        }
+    }
+}
+
+impl fmt::Display for TypeCheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TypeCheckError::UnknownType(name) => write!(f, "unknown type {}", name),
+            TypeCheckError::UnknownElement { r#type, element } => {
+                write!(f, "{} has no element {}", r#type, element)
+            }
+            TypeCheckError::UnknownFunction(name) => write!(f, "unknown function {}()", name),
+            TypeCheckError::UnknownVariable(name) => write!(f, "unknown variable %{}", name),
+            TypeCheckError::InvalidLiteral(err) => write!(f, "{}", err),
+            TypeCheckError::InvalidInput {
+                function,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{}() expects a {}, but its input is {}",
+                function,
+                expected.join(" or "),
+                actual
+            ),
+            TypeCheckError::InvalidOperands {
+                operator,
+                left,
+                right,
+            } => write!(
+                f,
+                "invalid operands {} and {} for {}",
+                left, right, operator
+            ),
+            TypeCheckError::ExpectedSingleton { operation, actual } => write!(
+                f,
+                "{} expects a single item, but its input is {}",
+                operation, actual
+            ),
+            TypeCheckError::UnsupportedExpression => write!(f, "unsupported expression"),
+            TypeCheckError::NoModel(err) => write!(f, "{}", err),
+            TypeCheckError::Located { span, error, .. } => {
+                write!(f, "{} at {}..{}", error, span.start, span.end)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TypeCheckError {}
+
+impl fmt::Display for TerminologyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerminologyError::UnknownCodeSystem(url) => write!(f, "unknown code system {}", url),
+            TerminologyError::UnknownValueSet(url) => write!(f, "unknown value set {}", url),
+            TerminologyError::UnknownConceptMap(url) => write!(f, "unknown concept map {}", url),
+            TerminologyError::UnsupportedFilter(property, op) => {
+                write!(f, "unsupported filter {} {}", property, op)
+            }
+            TerminologyError::CircularValueSet(url) => {
+                write!(f, "value set {} includes itself", url)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TerminologyError {}
+
+impl From<ValueError> for EvaluationError {
+    fn from(err: ValueError) -> Self {
+        EvaluationError::InvalidValue(err)
+    }
+}
+
+impl From<PackageError> for EvaluationError {
+    fn from(err: PackageError) -> Self {
+        EvaluationError::NoModel(err)
+    }
+}
+
+impl From<TerminologyError> for EvaluationError {
+    fn from(err: TerminologyError) -> Self {
+        EvaluationError::Terminology(err)
+    }
 }
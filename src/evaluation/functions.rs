@@ -1,27 +1,1140 @@
 use super::*;
-use crate::fhirpath::{Collection, Value, STRING};
+use crate::fhir::{self, Model};
+use crate::fhirpath::{
+    Collection, Compare, DataNode, DurationUnit, FhirDate, FhirDateTime, FhirTime, TypeInfo, Value,
+    ANY, STRING,
+};
 use lazy_static::lazy_static;
+use regex::RegexBuilder;
+use serde_json::json;
 use std::collections::HashMap;
 
-pub type Function =
-    fn(input: &Collection, params: &Collection) -> Result<Collection, EvaluationError>;
+pub type Function = fn(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError>;
+
+/// A function whose parameters are expressions that it evaluates itself, e.g. once for each item
+/// of its input
+pub type ExpressionFunction = fn(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &[&dyn Parameter],
+) -> Result<Collection, EvaluationError>;
+
+/// A function of `%terminologies`, given the value of each of its parameters
+pub type TerminologyFunction =
+    fn(visitor: &mut Visitor, params: &[Collection]) -> Result<Collection, EvaluationError>;
+
+lazy_static! {
+    pub static ref EXPRESSION_FUNCTIONS: HashMap<&'static str, ExpressionFunction> =
+        HashMap::from([
+            ("trace", trace as ExpressionFunction),
+            ("where", where_function as ExpressionFunction),
+            ("select", select as ExpressionFunction),
+            ("repeat", repeat as ExpressionFunction),
+            ("exists", exists as ExpressionFunction),
+            ("iif", iif as ExpressionFunction),
+            ("defineVariable", define_variable as ExpressionFunction),
+        ]);
+}
 
 lazy_static! {
-    pub static ref BUILTIN_FUNCTIONS: HashMap<&'static str, Function> =
-        HashMap::from([("replace", replace as Function)]);
+    pub static ref BUILTIN_FUNCTIONS: HashMap<&'static str, Function> = HashMap::from([
+        ("empty", empty as Function),
+        ("count", count as Function),
+        ("not", not as Function),
+        ("isDistinct", is_distinct as Function),
+        ("intersect", intersect as Function),
+        ("children", children as Function),
+        ("descendants", descendants as Function),
+        ("startsWith", starts_with as Function),
+        ("contains", contains as Function),
+        ("matches", matches as Function),
+        ("substring", substring as Function),
+        ("toString", to_string as Function),
+        ("toInteger", to_integer as Function),
+        ("replace", replace as Function),
+        ("now", now as Function),
+        ("today", today as Function),
+        ("timeOfDay", time_of_day as Function),
+        ("duration", duration as Function),
+        ("difference", difference as Function),
+        ("toLong", to_long as Function),
+        ("convertsToLong", converts_to_long as Function),
+        ("type", type_info as Function),
+        ("resolve", resolve as Function),
+        ("extension", extension as Function),
+        ("hasValue", has_value as Function),
+        ("getValue", get_value as Function),
+        ("htmlChecks", html_checks as Function),
+        ("elementDefinition", element_definition as Function),
+        ("slice", slice as Function),
+        ("checkModifiers", check_modifiers as Function),
+        ("conformsTo", conforms_to as Function),
+        ("comparable", comparable as Function),
+        ("memberOf", member_of as Function),
+        ("subsumes", subsumes as Function),
+        ("subsumedBy", subsumed_by as Function),
+    ]);
+}
+
+lazy_static! {
+    pub static ref TERMINOLOGY_FUNCTIONS: HashMap<&'static str, TerminologyFunction> =
+        HashMap::from([
+            ("expand", expand as TerminologyFunction),
+            ("lookup", lookup as TerminologyFunction),
+            ("validateVS", validate_vs as TerminologyFunction),
+            ("validateCS", validate_cs as TerminologyFunction),
+            ("subsumes", terminology_subsumes as TerminologyFunction),
+            ("translate", translate as TerminologyFunction),
+        ]);
+}
+
+/// Sends the input, or a projection of each of its items, to the context's trace sink and
+/// returns the input unchanged
+fn trace(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &[&dyn Parameter],
+) -> Result<Collection, EvaluationError> {
+    let (name, projection) = match params {
+        [name] => (name, None),
+        [name, projection] => (name, Some(projection)),
+        _ => return Err(EvaluationError::InvalidFunctionArguments(Collection::new())),
+    };
+    let name = name.evaluate(visitor)?;
+    let [Value::String(name)] = name.as_slice() else {
+        return Err(EvaluationError::InvalidFunctionArguments(name));
+    };
+
+    let traced = match projection {
+        Some(projection) => {
+            let mut traced = Collection::new();
+            for value in input.iter() {
+                let projected =
+                    visitor.visit_with_input(Collection::from(value.clone()), *projection)?;
+                traced.extend(projected.iter().cloned());
+            }
+            traced
+        }
+        None => input.clone(),
+    };
+    visitor.context().trace_sink().trace(name, &traced);
+    Ok(input.clone())
+}
+
+/// Keeps the items of the input for which the criterion is true
+fn where_function(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &[&dyn Parameter],
+) -> Result<Collection, EvaluationError> {
+    let [criterion] = params else {
+        return Err(EvaluationError::InvalidFunctionArguments(Collection::new()));
+    };
+    let mut matching = Collection::new();
+    for value in input.iter() {
+        let result = visitor.visit_with_input(Collection::from(value.clone()), *criterion)?;
+        if result.to_boolean()? == Some(true) {
+            matching.push(value.clone());
+        }
+    }
+    Ok(matching)
+}
+
+/// Evaluates the projection against each item of the input, flattening the results
+fn select(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &[&dyn Parameter],
+) -> Result<Collection, EvaluationError> {
+    let [projection] = params else {
+        return Err(EvaluationError::InvalidFunctionArguments(Collection::new()));
+    };
+    let mut selected = Collection::new();
+    for value in input.iter() {
+        let mut projected =
+            visitor.visit_with_input(Collection::from(value.clone()), *projection)?;
+        visitor.check_collection_size(selected.len() + projected.len())?;
+        selected.append(&mut projected);
+    }
+    Ok(selected)
+}
+
+/// Evaluates the projection against each item of the input, then against each item that gives,
+/// and so on.  Items already in the result are not added or projected again, so that cycles,
+/// such as references resolving back to a resource, come to an end.
+fn repeat(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &[&dyn Parameter],
+) -> Result<Collection, EvaluationError> {
+    let [projection] = params else {
+        return Err(EvaluationError::InvalidFunctionArguments(Collection::new()));
+    };
+    let mut repeated = Collection::new();
+    let mut pending = input.clone();
+    while !pending.is_empty() {
+        let mut found = Collection::new();
+        for value in pending.iter() {
+            let projected =
+                visitor.visit_with_input(Collection::from(value.clone()), *projection)?;
+            for item in projected.iter() {
+                if !includes(&repeated, item) {
+                    visitor.check_collection_size(repeated.len() + 1)?;
+                    repeated.push(item.clone());
+                    found.push(item.clone());
+                }
+            }
+        }
+        pending = found;
+    }
+    Ok(repeated)
+}
+
+/// Whether the input has any items, or any for which the criterion is true
+fn exists(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &[&dyn Parameter],
+) -> Result<Collection, EvaluationError> {
+    let exists = match params {
+        [] => !input.is_empty(),
+        [_] => !where_function(visitor, input, params)?.is_empty(),
+        _ => return Err(EvaluationError::InvalidFunctionArguments(Collection::new())),
+    };
+    Ok(Collection::from(Value::Boolean(exists)))
 }
 
-fn replace(input: &Collection, params: &Collection) -> Result<Collection, EvaluationError> {
-    let Value::String(str) = input.singleton(STRING)? else {return Err(EvaluationError::InvalidFunctionArguments(params.clone()))};
+/// Evaluates only the branch chosen by the criterion, so the other may not be valid for the
+/// input
+fn iif(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &[&dyn Parameter],
+) -> Result<Collection, EvaluationError> {
+    let (criterion, then, otherwise) = match params {
+        [criterion, then] => (criterion, then, None),
+        [criterion, then, otherwise] => (criterion, then, Some(otherwise)),
+        _ => return Err(EvaluationError::InvalidFunctionArguments(Collection::new())),
+    };
+    let result = visitor.visit_with_input(input.clone(), *criterion)?;
+    match (result.to_boolean()?, otherwise) {
+        (Some(true), _) => visitor.visit_with_input(input.clone(), *then),
+        (_, Some(otherwise)) => visitor.visit_with_input(input.clone(), *otherwise),
+        (_, None) => Ok(Collection::new()),
+    }
+}
+
+/// Defines a variable with the value of an expression, or of the input, and returns the input
+fn define_variable(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &[&dyn Parameter],
+) -> Result<Collection, EvaluationError> {
+    let (name, value) = match params {
+        [name] => (name, None),
+        [name, value] => (name, Some(value)),
+        _ => return Err(EvaluationError::InvalidFunctionArguments(Collection::new())),
+    };
+    let name = name.evaluate(visitor)?;
+    let [Value::String(name)] = name.as_slice() else {
+        return Err(EvaluationError::InvalidFunctionArguments(name));
+    };
+    let value = match value {
+        Some(value) => visitor.visit_with_input(input.clone(), *value)?,
+        None => input.clone(),
+    };
+    visitor.define_variable(name, value)?;
+    Ok(input.clone())
+}
+
+fn replace(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let Value::String(str) = input.singleton(STRING)? else {
+        return Err(EvaluationError::InvalidFunctionArguments(params.clone()));
+    };
     if let (Some(Value::String(pattern)), Some(Value::String(substitution))) =
-        (params.get(0), params.get(1))
+        (params.first(), params.get(1))
     {
+        visitor.check_string_length(replaced_length(str, pattern, substitution))?;
         Ok(Collection::from(Value::String(
             str.replace(pattern, substitution),
         )))
     } else {
         let mut params = input.clone();
-        params.extend(input.iter().map(|v| v.clone()));
+        params.extend(input.iter().cloned());
         Err(EvaluationError::InvalidFunctionArguments(params))
     }
 }
+
+fn empty(
+    _: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    Ok(Collection::from(Value::Boolean(input.is_empty())))
+}
+
+fn count(
+    _: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let count = i32::try_from(input.len()).unwrap_or(i32::MAX);
+    Ok(Collection::from(Value::Integer(count)))
+}
+
+fn not(_: &mut Visitor, input: &Collection, _: &Collection) -> Result<Collection, EvaluationError> {
+    Ok(input
+        .to_boolean()?
+        .map(|b| Collection::from(Value::Boolean(!b)))
+        .unwrap_or_default())
+}
+
+fn is_distinct(
+    _: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let distinct = distinct_union(input.clone(), Collection::new());
+    Ok(Collection::from(Value::Boolean(
+        distinct.len() == input.len(),
+    )))
+}
+
+/// The distinct items of the input that are also in the parameter
+fn intersect(
+    _: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let common = input
+        .iter()
+        .filter(|value| includes(params, value))
+        .cloned()
+        .collect();
+    Ok(distinct_union(common, Collection::new()))
+}
+
+fn children(
+    _: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    Ok(child_values(input.iter()))
+}
+
+/// The children of the input, their children, and so on, level by level
+fn descendants(
+    visitor: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let mut descendants = child_values(input.iter());
+    visitor.check_collection_size(descendants.len())?;
+    let mut level = 0..descendants.len();
+    while !level.is_empty() {
+        let mut next = child_values(descendants[level.clone()].iter());
+        visitor.check_collection_size(descendants.len() + next.len())?;
+        level = descendants.len()..descendants.len() + next.len();
+        descendants.append(&mut next);
+    }
+    Ok(descendants)
+}
+
+fn child_values<'a>(values: impl Iterator<Item = &'a Value>) -> Collection {
+    values
+        .filter_map(|value| match value {
+            Value::Complex(node) => Some(node.child_values()),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+fn starts_with(
+    _: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let prefix = string_param(params)?;
+    Ok(string_input(input)?
+        .map(|s| Collection::from(Value::Boolean(s.starts_with(prefix))))
+        .unwrap_or_default())
+}
+
+fn contains(
+    _: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let substring = string_param(params)?;
+    Ok(string_input(input)?
+        .map(|s| Collection::from(Value::Boolean(s.contains(substring))))
+        .unwrap_or_default())
+}
+
+/// The most memory, in bytes, that a regular expression given to `matches()` may compile to,
+/// so that patterns such as `a{50000}` are refused rather than built
+const REGEX_SIZE_LIMIT: usize = 1 << 20;
+
+/// Whether the input matches a regular expression anywhere within it
+fn matches(
+    _: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let pattern = string_param(params)?;
+    let regex = RegexBuilder::new(pattern)
+        .size_limit(REGEX_SIZE_LIMIT)
+        .dfa_size_limit(REGEX_SIZE_LIMIT)
+        .build()
+        .map_err(|_| EvaluationError::InvalidFunctionArguments(params.clone()))?;
+    Ok(string_input(input)?
+        .map(|s| Collection::from(Value::Boolean(regex.is_match(s))))
+        .unwrap_or_default())
+}
+
+/// The characters from a position to the end of the input, or of the length given; a start
+/// outside the input gives empty
+fn substring(
+    _: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let invalid = || EvaluationError::InvalidFunctionArguments(params.clone());
+    let (start, length) = match params.as_slice() {
+        [start] => (long_value(start).ok_or_else(invalid)?, None),
+        [start, length] => (
+            long_value(start).ok_or_else(invalid)?,
+            Some(long_value(length).ok_or_else(invalid)?),
+        ),
+        _ => return Err(invalid()),
+    };
+    let Some(s) = string_input(input)? else {
+        return Ok(Collection::new());
+    };
+    let chars = s.chars().count() as i64;
+    if start < 0 || start >= chars {
+        return Ok(Collection::new());
+    }
+    let length = length.unwrap_or(chars).max(0);
+    let substring: String = s
+        .chars()
+        .skip(start as usize)
+        .take(length as usize)
+        .collect();
+    Ok(Collection::from(Value::String(substring)))
+}
+
+/// Converts a single primitive value to a String, as it would be written in JSON
+fn to_string(
+    _: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let string = match conversion_input(input)?.map(Value::system_value) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Long(n)) => n.to_string(),
+        Some(Value::Date(date)) => date.to_string(),
+        Some(Value::Time(time)) => time.to_string(),
+        Some(Value::DateTime(datetime)) => datetime.to_string(),
+        Some(value @ (Value::Boolean(_) | Value::Integer(_) | Value::Decimal(_))) => {
+            value.to_string()
+        }
+        Some(Value::Quantity(quantity)) => quantity.to_string(),
+        _ => return Ok(Collection::new()),
+    };
+    Ok(Collection::from(Value::String(string)))
+}
+
+fn to_integer(
+    _: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    Ok(integer_or_empty(
+        conversion_input(input)?.and_then(long_value),
+    ))
+}
+
+fn now(
+    visitor: &mut Visitor,
+    _: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    Ok(Collection::from(Value::DateTime(FhirDateTime::from(
+        visitor.now(),
+    ))))
+}
+
+fn today(
+    visitor: &mut Visitor,
+    _: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    Ok(Collection::from(Value::Date(FhirDate::from(
+        visitor.now().date_naive(),
+    ))))
+}
+
+fn time_of_day(
+    visitor: &mut Visitor,
+    _: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    Ok(Collection::from(Value::Time(FhirTime::from(
+        visitor.now().time(),
+    ))))
+}
+
+fn duration(
+    _: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let (start, end, unit) = span_operands(input, params)?;
+    let span = match (start, end) {
+        (Value::Date(d1), Value::Date(d2)) => d1.duration(d2, unit),
+        (Value::DateTime(dt1), Value::DateTime(dt2)) => dt1.duration(dt2, unit),
+        (Value::Time(t1), Value::Time(t2)) => t1.duration(t2, unit),
+        _ => return Err(EvaluationError::InvalidFunctionArguments(params.clone())),
+    };
+    Ok(integer_or_empty(span))
+}
+
+fn difference(
+    _: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let (start, end, unit) = span_operands(input, params)?;
+    let span = match (start, end) {
+        (Value::Date(d1), Value::Date(d2)) => d1.difference(d2, unit),
+        (Value::DateTime(dt1), Value::DateTime(dt2)) => dt1.difference(dt2, unit),
+        (Value::Time(t1), Value::Time(t2)) => t1.difference(t2, unit),
+        _ => return Err(EvaluationError::InvalidFunctionArguments(params.clone())),
+    };
+    Ok(integer_or_empty(span))
+}
+
+fn to_long(
+    _: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    Ok(conversion_input(input)?
+        .and_then(long_value)
+        .map(|n| Collection::from(Value::Long(n)))
+        .unwrap_or_default())
+}
+
+fn converts_to_long(
+    _: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    Ok(conversion_input(input)?
+        .map(|value| Collection::from(Value::Boolean(long_value(value).is_some())))
+        .unwrap_or_default())
+}
+
+fn type_info(
+    visitor: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let model = visitor.context().model()?;
+    Ok(input
+        .iter()
+        .map(|value| TypeInfo::of(value, model).to_value())
+        .collect())
+}
+
+/// Resolves each Reference, or string holding a reference, to the resource it points to
+fn resolve(
+    visitor: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let mut resources = Vec::new();
+    for value in input.iter() {
+        let reference = match value.system_value() {
+            Value::String(reference) => reference,
+            Value::Complex(node) => match node.member_value("reference") {
+                Some(Value::String(reference)) => reference,
+                _ => continue,
+            },
+            _ => continue,
+        };
+        match visitor.resolve_reference(reference) {
+            Some(resource) => resources.push(resource.to_value()),
+            None if visitor.context().strict_references() => {
+                return Err(EvaluationError::UnresolvedReference(reference.clone()))
+            }
+            None => {}
+        }
+    }
+    Ok(Collection::from_iter(resources))
+}
+
+fn extension(
+    _: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let [Value::String(url)] = params.as_slice() else {
+        return Err(EvaluationError::InvalidFunctionArguments(params.clone()));
+    };
+    Ok(input
+        .iter()
+        .filter_map(|value| match value {
+            Value::Complex(node) => Some(node.shared_members("extension")),
+            _ => None,
+        })
+        .flatten()
+        .filter(
+            |extension| matches!(extension.member_value("url"), Some(Value::String(u)) if u == url),
+        )
+        .map(DataNode::to_value)
+        .collect())
+}
+
+/// The System value of a single primitive in the input, which FHIR primitive elements only
+/// have if they are not just extensions
+fn primitive_input(input: &Collection) -> Option<&Value> {
+    match input.as_slice() {
+        [value] => match value.system_value() {
+            Value::Complex(_) | Value::Any(_) => None,
+            value => Some(value),
+        },
+        _ => None,
+    }
+}
+
+fn has_value(
+    _: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    Ok(Collection::from(Value::Boolean(
+        primitive_input(input).is_some(),
+    )))
+}
+
+fn get_value(
+    _: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    Ok(primitive_input(input)
+        .map(|value| Collection::from(value.clone()))
+        .unwrap_or_default())
+}
+
+fn html_checks(
+    _: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    Ok(match conversion_input(input)?.map(Value::system_value) {
+        Some(Value::String(xhtml)) => {
+            Collection::from(Value::Boolean(fhir::is_valid_narrative(xhtml)))
+        }
+        Some(_) => Collection::from(Value::Boolean(false)),
+        None => Collection::new(),
+    })
+}
+
+fn element_definition(
+    visitor: &mut Visitor,
+    input: &Collection,
+    _: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let Some((definition, path)) = visitor.input_definition() else {
+        return Ok(Collection::new());
+    };
+    let Some(element) = definition.element(path) else {
+        return Ok(Collection::new());
+    };
+
+    let invalid = || EvaluationError::InvalidDefinition(element.id.clone());
+    let json = serde_json::to_value(element).map_err(|_| invalid())?;
+    let element = visitor
+        .context()
+        .model()?
+        .load_data_type(&json, "ElementDefinition")
+        .map_err(|_| invalid())?;
+    Ok(input.iter().map(|_| element.to_value()).collect())
+}
+
+/// Reads the structure that the `slice()` and `conformsTo()` functions refer to
+fn structure_param<'a>(
+    visitor: &'a Visitor,
+    param: &Value,
+) -> Result<&'a fhir::StructureDefinition, EvaluationError> {
+    let Value::String(structure) = param else {
+        return Err(EvaluationError::InvalidFunctionArguments(Collection::from(
+            param.clone(),
+        )));
+    };
+    visitor
+        .context()
+        .structure_definition(structure)
+        .ok_or_else(|| EvaluationError::UnknownStructure(structure.clone()))
+}
+
+fn slice(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let [structure, Value::String(name)] = params.as_slice() else {
+        return Err(EvaluationError::InvalidFunctionArguments(params.clone()));
+    };
+    let profile = structure_param(visitor, structure)?;
+    let slice = profile
+        .slice(name)
+        .ok_or_else(|| EvaluationError::UnknownStructure(format!("{}:{}", profile.url, name)))?;
+
+    let mut items = Vec::new();
+    for value in input.iter() {
+        if in_slice(visitor.context(), value, profile, slice)? {
+            items.push(value.clone());
+        }
+    }
+    Ok(Collection::from_iter(items))
+}
+
+fn check_modifiers(
+    _: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let mut known = Vec::new();
+    for param in params.iter() {
+        let Value::String(url) = param else {
+            return Err(EvaluationError::InvalidFunctionArguments(params.clone()));
+        };
+        known.push(url);
+    }
+
+    let modifiers = input
+        .iter()
+        .filter_map(|value| match value {
+            Value::Complex(node) => Some(node.members("modifierExtension")),
+            _ => None,
+        })
+        .flatten();
+    for modifier in modifiers {
+        match modifier.member_value("url") {
+            Some(Value::String(url)) if known.contains(&url) => {}
+            Some(Value::String(url)) => return Err(EvaluationError::UnknownModifier(url.clone())),
+            _ => return Err(EvaluationError::UnknownModifier(String::new())),
+        }
+    }
+    Ok(input.clone())
+}
+
+fn conforms_to(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let [structure] = params.as_slice() else {
+        return Err(EvaluationError::InvalidFunctionArguments(params.clone()));
+    };
+    let profile = structure_param(visitor, structure)?;
+    Ok(match conversion_input(input)? {
+        Some(Value::Complex(node)) => {
+            let model = visitor.context().model()?;
+            Collection::from(Value::Boolean(super::conforms_to(model, node, profile)))
+        }
+        Some(_) => Collection::from(Value::Boolean(false)),
+        None => Collection::new(),
+    })
+}
+
+/// Whether the input and parameter quantities have commensurable units
+fn comparable(
+    _: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let (Some(value), [other]) = (conversion_input(input)?, params.as_slice()) else {
+        return Err(EvaluationError::InvalidFunctionArguments(params.clone()));
+    };
+    let comparable = match (value.to_quantity(), other.to_quantity()) {
+        (Some(q1), Some(q2)) => q1.compare(&q2).is_some(),
+        _ => false,
+    };
+    Ok(Collection::from(Value::Boolean(comparable)))
+}
+
+fn member_of(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let [Value::String(value_set)] = params.as_slice() else {
+        return Err(EvaluationError::InvalidFunctionArguments(params.clone()));
+    };
+    let Some(value) = conversion_input(input)? else {
+        return Ok(Collection::new());
+    };
+    let codings = codings(value);
+    if codings.is_empty() {
+        return Ok(Collection::new());
+    }
+    let terminology = visitor.context().terminology()?;
+    let mut member = false;
+    for coding in codings {
+        member = member || terminology.member_of(value_set, &coding)?;
+    }
+    Ok(Collection::from(Value::Boolean(member)))
+}
+
+fn subsumes(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    subsumption(visitor, input, params, SubsumptionOutcome::Subsumes)
+}
+
+fn subsumed_by(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+) -> Result<Collection, EvaluationError> {
+    subsumption(visitor, input, params, SubsumptionOutcome::SubsumedBy)
+}
+
+/// Whether any code of the input is equivalent to, or related as `expected` to, any code of the
+/// parameter from the same code system
+fn subsumption(
+    visitor: &mut Visitor,
+    input: &Collection,
+    params: &Collection,
+    expected: SubsumptionOutcome,
+) -> Result<Collection, EvaluationError> {
+    let [other] = params.as_slice() else {
+        return Err(EvaluationError::InvalidFunctionArguments(params.clone()));
+    };
+    let Some(value) = conversion_input(input)? else {
+        return Ok(Collection::new());
+    };
+    let terminology = visitor.context().terminology()?;
+    let others = codings(other);
+    for a in codings(value) {
+        for b in &others {
+            let (Some(system), true) = (&a.system, a.system == b.system) else {
+                continue;
+            };
+            let outcome = terminology.subsumes(system, &a.code, &b.code)?;
+            if outcome == Some(SubsumptionOutcome::Equivalent) || outcome == Some(expected) {
+                return Ok(Collection::from(Value::Boolean(true)));
+            }
+        }
+    }
+    Ok(Collection::from(Value::Boolean(false)))
+}
+
+fn expand(visitor: &mut Visitor, params: &[Collection]) -> Result<Collection, EvaluationError> {
+    let value_set = url_param(params, 0)?;
+    let timestamp = visitor.now().to_rfc3339();
+    let codings = visitor.context().terminology()?.expand(value_set)?;
+    let contains: Vec<_> = codings.iter().map(coding_json).collect();
+    load_result(
+        visitor.context().model()?,
+        json!({
+            "resourceType": "ValueSet",
+            "url": value_set,
+            "status": "active",
+            "expansion": {
+                "timestamp": timestamp,
+                "total": contains.len(),
+                "contains": contains,
+            }
+        }),
+    )
+}
+
+fn lookup(visitor: &mut Visitor, params: &[Collection]) -> Result<Collection, EvaluationError> {
+    let coding = coding_param(params, 0)?;
+    let Some(result) = visitor.context().terminology()?.lookup(&coding)? else {
+        return Ok(Collection::new());
+    };
+    let mut parameters = vec![json!({ "name": "name", "valueString": result.name })];
+    if let Some(version) = result.version {
+        parameters.push(json!({ "name": "version", "valueString": version }));
+    }
+    if let Some(display) = result.display {
+        parameters.push(json!({ "name": "display", "valueString": display }));
+    }
+    load_parameters(visitor.context().model()?, parameters)
+}
+
+fn validate_vs(
+    visitor: &mut Visitor,
+    params: &[Collection],
+) -> Result<Collection, EvaluationError> {
+    let value_set = url_param(params, 0)?;
+    let coding = coding_param(params, 1)?;
+    let result = visitor
+        .context()
+        .terminology()?
+        .validate_code(value_set, &coding)?;
+    validation_parameters(visitor.context().model()?, result)
+}
+
+fn validate_cs(
+    visitor: &mut Visitor,
+    params: &[Collection],
+) -> Result<Collection, EvaluationError> {
+    let code_system = url_param(params, 0)?;
+    let coding = coding_param(params, 1)?;
+    let result = visitor
+        .context()
+        .terminology()?
+        .validate_code_system(code_system, &coding)?;
+    validation_parameters(visitor.context().model()?, result)
+}
+
+fn terminology_subsumes(
+    visitor: &mut Visitor,
+    params: &[Collection],
+) -> Result<Collection, EvaluationError> {
+    let system = url_param(params, 0)?;
+    let a = coding_param(params, 1)?;
+    let b = coding_param(params, 2)?;
+    Ok(visitor
+        .context()
+        .terminology()?
+        .subsumes(system, &a.code, &b.code)?
+        .map(|outcome| Collection::from(Value::String(outcome.code().to_string())))
+        .unwrap_or_default())
+}
+
+fn translate(visitor: &mut Visitor, params: &[Collection]) -> Result<Collection, EvaluationError> {
+    let concept_map = url_param(params, 0)?;
+    let coding = coding_param(params, 1)?;
+    let translations = visitor
+        .context()
+        .terminology()?
+        .translate(concept_map, &coding)?;
+
+    let mut parameters =
+        vec![json!({ "name": "result", "valueBoolean": !translations.is_empty() })];
+    parameters.extend(translations.iter().map(|translation| {
+        json!({
+            "name": "match",
+            "part": [
+                { "name": "equivalence", "valueCode": translation.equivalence },
+                { "name": "concept", "valueCoding": coding_json(&translation.concept) },
+            ]
+        })
+    }));
+    load_parameters(visitor.context().model()?, parameters)
+}
+
+/// The codes of a string, FHIR `code`, Coding or CodeableConcept
+pub(crate) fn codings(value: &Value) -> Vec<Coding> {
+    let string = |node: &DataNode, name| match node.member_value(name) {
+        Some(Value::String(s)) => Some(s.clone()),
+        _ => None,
+    };
+    let coding = |node: &DataNode| {
+        Some(Coding {
+            system: string(node, "system"),
+            version: string(node, "version"),
+            code: string(node, "code")?,
+            display: string(node, "display"),
+        })
+    };
+    match value.system_value() {
+        Value::String(code) => vec![Coding {
+            system: None,
+            version: None,
+            code: code.clone(),
+            display: None,
+        }],
+        Value::Complex(node) => match node.data_type() {
+            "FHIR.Coding" => coding(node).into_iter().collect(),
+            "FHIR.CodeableConcept" => node.members("coding").filter_map(coding).collect(),
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+fn coding_json(coding: &Coding) -> serde_json::Value {
+    let mut json = serde_json::Map::new();
+    let fields = [
+        ("system", &coding.system),
+        ("version", &coding.version),
+        ("code", &Some(coding.code.clone())),
+        ("display", &coding.display),
+    ];
+    for (name, value) in fields {
+        if let Some(value) = value {
+            json.insert(name.to_string(), json!(value));
+        }
+    }
+    serde_json::Value::Object(json)
+}
+
+/// Reads the canonical URL of a value set, code system or concept map
+fn url_param(params: &[Collection], index: usize) -> Result<&String, EvaluationError> {
+    match params.get(index).map(|param| param.as_slice()) {
+        Some([value]) => match value.system_value() {
+            Value::String(url) => Ok(url),
+            _ => Err(EvaluationError::InvalidFunctionArguments(Collection::from(
+                value.clone(),
+            ))),
+        },
+        _ => Err(EvaluationError::InvalidFunctionArguments(
+            params.get(index).cloned().unwrap_or_default(),
+        )),
+    }
+}
+
+/// Reads a single coded value; the first code of a CodeableConcept is used
+fn coding_param(params: &[Collection], index: usize) -> Result<Coding, EvaluationError> {
+    let invalid = || {
+        EvaluationError::InvalidFunctionArguments(params.get(index).cloned().unwrap_or_default())
+    };
+    match params.get(index).map(|param| param.as_slice()) {
+        Some([value]) => codings(value).into_iter().next().ok_or_else(invalid),
+        _ => Err(invalid()),
+    }
+}
+
+fn validation_parameters(
+    model: &Model,
+    result: ValidationResult,
+) -> Result<Collection, EvaluationError> {
+    let mut parameters = vec![json!({ "name": "result", "valueBoolean": result.result })];
+    if let Some(message) = result.message {
+        parameters.push(json!({ "name": "message", "valueString": message }));
+    }
+    if let Some(display) = result.display {
+        parameters.push(json!({ "name": "display", "valueString": display }));
+    }
+    load_parameters(model, parameters)
+}
+
+fn load_parameters(
+    model: &Model,
+    parameters: Vec<serde_json::Value>,
+) -> Result<Collection, EvaluationError> {
+    load_result(
+        model,
+        json!({ "resourceType": "Parameters", "parameter": parameters }),
+    )
+}
+
+/// Loads a resource built as the result of a terminology operation
+fn load_result(model: &Model, json: serde_json::Value) -> Result<Collection, EvaluationError> {
+    let resource = model.load_resource(&json).map_err(|_| {
+        EvaluationError::InvalidDefinition(
+            json["resourceType"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        )
+    })?;
+    Ok(Collection::from(resource.to_value()))
+}
+
+/// Integers, Longs, Booleans and Strings of digits with an optional sign can be converted to Long
+fn long_value(value: &Value) -> Option<i64> {
+    match value {
+        Value::Integer(n) => Some(i64::from(*n)),
+        Value::Long(n) => Some(*n),
+        Value::Boolean(b) => Some(i64::from(*b)),
+        Value::String(s) => s.parse().ok(),
+        Value::Any(value) => long_value(value),
+        Value::Complex(_) if value.is_fhir_primitive() => long_value(value.system_value()),
+        _ => None,
+    }
+}
+
+/// The conversion functions operate on a single item, or return empty for an empty input
+fn conversion_input(input: &Collection) -> Result<Option<&Value>, EvaluationError> {
+    match input.as_slice() {
+        [] => Ok(None),
+        [value] => Ok(Some(value)),
+        items => Err(EvaluationError::ExpectedSingleton {
+            expected: ANY,
+            count: items.len(),
+        }),
+    }
+}
+
+/// The number of characters `replace()` gives, worked out without building its result.  An
+/// empty pattern matches before every character and at the end.
+fn replaced_length(s: &str, pattern: &str, substitution: &str) -> usize {
+    let length = s.chars().count();
+    let occurrences = if pattern.is_empty() {
+        length + 1
+    } else {
+        s.matches(pattern).count()
+    };
+    (length - occurrences * pattern.chars().count())
+        .saturating_add(occurrences.saturating_mul(substitution.chars().count()))
+}
+
+/// The string functions operate on a single String, or return empty for an empty input
+fn string_input(input: &Collection) -> Result<Option<&str>, EvaluationError> {
+    if input.is_empty() {
+        return Ok(None);
+    }
+    match input.singleton(STRING)? {
+        Value::String(s) => Ok(Some(s)),
+        _ => Ok(None),
+    }
+}
+
+fn string_param(params: &Collection) -> Result<&str, EvaluationError> {
+    match params.as_slice() {
+        [value] => match value.system_value() {
+            Value::String(s) => Ok(s),
+            _ => Err(EvaluationError::InvalidFunctionArguments(params.clone())),
+        },
+        _ => Err(EvaluationError::InvalidFunctionArguments(params.clone())),
+    }
+}
+
+/// Reads the input and `(value, precision)` arguments of `duration()` and `difference()`
+fn span_operands<'a>(
+    input: &'a Collection,
+    params: &'a Collection,
+) -> Result<(&'a Value, &'a Value, DurationUnit), EvaluationError> {
+    let [start] = input.as_slice() else {
+        return Err(EvaluationError::ExpectedSingleton {
+            expected: ANY,
+            count: input.len(),
+        });
+    };
+    let [end, Value::String(precision)] = params.as_slice() else {
+        return Err(EvaluationError::InvalidFunctionArguments(params.clone()));
+    };
+    Ok((
+        start.system_value(),
+        end.system_value(),
+        DurationUnit::from_unit(precision)?,
+    ))
+}
+
+fn integer_or_empty(n: Option<i64>) -> Collection {
+    n.and_then(|n| i32::try_from(n).ok())
+        .map(|n| Collection::from(Value::Integer(n)))
+        .unwrap_or_default()
+}
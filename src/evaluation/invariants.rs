@@ -0,0 +1,486 @@
+use super::*;
+use crate::fhir::{Constraint, ElementDefinition, StructureDefinition, SYSTEM_TYPE_PREFIX};
+use crate::fhirpath::{Collection, DataNode, Value};
+use crate::parser::{Lexer, Parser};
+use crate::CompileError;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A constraint that does not hold, or could not be checked, at an element of a resource
+#[derive(Debug)]
+pub struct InvariantFailure {
+    pub key: String,
+    pub severity: IssueSeverity,
+    /// The constraint's description, e.g. "All FHIR elements must have a @value or children"
+    pub human: String,
+    pub expression: String,
+    /// Where the element is in the resource, e.g. `Patient.contact[0]`
+    pub location: String,
+    /// Why the expression could not be compiled or evaluated, if it could not
+    pub error: Option<CompileError>,
+}
+
+impl fmt::Display for InvariantFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.error {
+            Some(err) => write!(f, "cannot check {} at {}: {}", self.key, self.location, err),
+            None => write!(
+                f,
+                "{} {} at {}: {}",
+                self.key, self.severity, self.location, self.human
+            ),
+        }
+    }
+}
+
+/// Checks the constraints (`ElementDefinition.constraint`) of the definitions of resources.
+/// Each constraint is evaluated once for each item of its element, with that item as `$this`
+/// and `%context`, the resource it is in as `%resource`, and the resource at the root of a
+/// chain of contained resources as `%rootResource`.  Elements of data types are also checked
+/// against the constraints on their types.  A constraint fails when its expression gives
+/// `false`; one that gives an empty result does not apply.
+///
+/// Expressions are compiled once and kept for the life of the checker, which can be shared
+/// between threads.
+pub struct InvariantChecker<'a> {
+    context: &'a EvaluationContext,
+    suppressed: HashSet<String>,
+    plans: Mutex<HashMap<String, Arc<Plan>>>,
+}
+
+impl<'a> InvariantChecker<'a> {
+    pub fn new(context: &'a EvaluationContext) -> Self {
+        InvariantChecker {
+            context,
+            suppressed: HashSet::new(),
+            plans: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Skips the constraint with a key, e.g. `dom-6`, wherever it applies
+    pub fn with_suppressed(mut self, key: &str) -> Self {
+        self.suppressed.insert(key.to_string());
+        self
+    }
+
+    /// Checks a resource against the constraints of the base definition of its type
+    pub fn check(&self, resource: &Arc<DataNode>) -> Vec<InvariantFailure> {
+        let mut check = Check::new(self);
+        check.resource(resource, None, None, None);
+        check.failures
+    }
+
+    /// Checks a resource against the constraints of a profile of its type, or of the base
+    /// definition of its type if the profile is of another
+    pub fn check_profile(
+        &self,
+        resource: &Arc<DataNode>,
+        profile: &StructureDefinition,
+    ) -> Vec<InvariantFailure> {
+        let mut check = Check::new(self);
+        check.resource(resource, Some(profile), None, None);
+        check.failures
+    }
+
+    fn plan(&self, expression: &str) -> Result<Arc<Plan>, CompileError> {
+        if let Some(plan) = self.plans().get(expression) {
+            return Ok(Arc::clone(plan));
+        }
+        let tokens = Lexer::new(expression).tokenize_with_spans()?;
+        let ast = Parser::with_spans(tokens).parse()?;
+        let plan = Arc::new(Plan::compile(&ast)?);
+        self.plans()
+            .insert(expression.to_string(), Arc::clone(&plan));
+        Ok(plan)
+    }
+
+    fn plans(&self) -> MutexGuard<'_, HashMap<String, Arc<Plan>>> {
+        self.plans.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// The failures found so far in checking a resource
+struct Check<'c, 'a> {
+    checker: &'c InvariantChecker<'a>,
+    /// The resources `%resource` and `%rootResource` stand for
+    resources: (Collection, Collection),
+    /// The keys reported at each location, so constraints a slice repeats are reported once
+    reported: HashSet<(String, String)>,
+    failures: Vec<InvariantFailure>,
+}
+
+impl<'c, 'a> Check<'c, 'a> {
+    fn new(checker: &'c InvariantChecker<'a>) -> Self {
+        Check {
+            checker,
+            resources: (Collection::new(), Collection::new()),
+            reported: HashSet::new(),
+            failures: Vec::new(),
+        }
+    }
+
+    /// Checks a resource, at the root or at a location in another.  Contained resources share
+    /// the `%rootResource` of their container; others, like the entries of a Bundle, are roots
+    /// themselves.
+    fn resource(
+        &mut self,
+        node: &Arc<DataNode>,
+        profile: Option<&StructureDefinition>,
+        location: Option<&str>,
+        root_resource: Option<Collection>,
+    ) {
+        let Some(resource_type) = node.data_type().strip_prefix("FHIR.") else {
+            return;
+        };
+        let definition = match profile {
+            Some(profile) if profile.r#type == resource_type => profile,
+            // Without a model there is no base definition to check against, only a profile
+            _ => match self.checker.context.model() {
+                Ok(model) => match model.resources().get(resource_type) {
+                    Some(base) => base,
+                    None => return,
+                },
+                Err(_) => return,
+            },
+        };
+        let Some(root) = definition.element_by_id(&definition.r#type) else {
+            return;
+        };
+
+        let resource = Collection::from(node.to_value());
+        let root_resource = root_resource.unwrap_or_else(|| resource.clone());
+        let outer = std::mem::replace(&mut self.resources, (resource, root_resource));
+        let location = location.unwrap_or(resource_type);
+        self.item(&node.to_value(), definition, root, resource_type, location);
+        self.resources = outer;
+    }
+
+    /// Checks an item of an element against its constraints and those of its type, then its
+    /// children
+    fn item(
+        &mut self,
+        value: &Value,
+        definition: &StructureDefinition,
+        element: &ElementDefinition,
+        code: &str,
+        location: &str,
+    ) {
+        let data_type = self.data_type(element, code);
+        let type_constraints = data_type
+            .and_then(|data_type| data_type.element_by_id(code))
+            .map(|root| root.constraint.as_slice())
+            .unwrap_or_default();
+        for constraint in &element.constraint {
+            self.constraint(value, constraint, location);
+        }
+        for constraint in type_constraints {
+            if !element.constraint.iter().any(|c| c.key == constraint.key) {
+                self.constraint(value, constraint, location);
+            }
+        }
+
+        let Value::Complex(node) = value else {
+            return;
+        };
+        if let Some(reference) = &element.content_reference {
+            let reference = reference.rsplit('#').next().unwrap_or(reference);
+            return self.children(node, definition, reference, location);
+        }
+        match code {
+            "BackboneElement" | "Element" => self.children(node, definition, &element.id, location),
+            "Resource" => {
+                let root_resource = element
+                    .path
+                    .ends_with(".contained")
+                    .then(|| self.resources.1.clone());
+                self.resource(node, None, Some(location), root_resource);
+            }
+            _ if !child_elements(definition, &element.id).is_empty() => {
+                self.children(node, definition, &element.id, location)
+            }
+            _ => {
+                if let Some(data_type) = data_type {
+                    self.children(node, data_type, code, location);
+                }
+            }
+        }
+    }
+
+    /// The definition of the type of an item, or of the profile on it the element requires
+    fn data_type(
+        &self,
+        element: &ElementDefinition,
+        code: &str,
+    ) -> Option<&'a StructureDefinition> {
+        let context = self.checker.context;
+        if code.starts_with(SYSTEM_TYPE_PREFIX) || code == "BackboneElement" || code == "Element" {
+            return None;
+        }
+        element
+            .r#type
+            .iter()
+            .find(|t| t.code == code)
+            .and_then(|t| match t.profile.as_slice() {
+                [profile] => context.structure_definition(profile),
+                _ => None,
+            })
+            .filter(|profile| profile.r#type == code)
+            .or_else(|| context.model().ok()?.data_types().get(code))
+    }
+
+    /// Checks the members of a node against the children of an element of a definition
+    fn children(
+        &mut self,
+        node: &DataNode,
+        definition: &StructureDefinition,
+        id: &str,
+        location: &str,
+    ) {
+        for element in child_elements(definition, id) {
+            let repeats = match &element.base {
+                Some(base) => base.max != "1",
+                None => element.max.as_deref() != Some("1"),
+            };
+            let slices = slices(definition, &element.id);
+            for (i, value) in node.member_values(element.name()).enumerate() {
+                let code = match value.data_type().strip_prefix("FHIR.") {
+                    Some(code) if element.is_choice() || element.r#type.is_empty() => {
+                        code.to_string()
+                    }
+                    _ => element
+                        .r#type
+                        .first()
+                        .map_or(String::new(), |t| t.code.clone()),
+                };
+                let mut item_location = format!("{}.{}", location, element.name());
+                if element.is_choice() {
+                    item_location = format!("{}.ofType({})", item_location, code);
+                }
+                if repeats {
+                    item_location = format!("{}[{}]", item_location, i);
+                }
+
+                self.item(&value, definition, element, &code, &item_location);
+                for slice in &slices {
+                    let context = self.checker.context;
+                    if in_slice(context, &value, definition, slice).unwrap_or(false) {
+                        self.item(&value, definition, slice, &code, &item_location);
+                    }
+                }
+            }
+        }
+    }
+
+    fn constraint(&mut self, value: &Value, constraint: &Constraint, location: &str) {
+        let Some(expression) = &constraint.expression else {
+            return;
+        };
+        if self.checker.suppressed.contains(&constraint.key)
+            || self
+                .reported
+                .contains(&(constraint.key.clone(), location.to_string()))
+        {
+            return;
+        }
+
+        let result = self.checker.plan(expression).and_then(|plan| {
+            let (resource, root_resource) = self.resources.clone();
+            let mut visitor = Visitor::new(self.checker.context)
+                .with_input(Collection::from(value.clone()))
+                .with_resources(resource, root_resource);
+            Ok(plan.evaluate(&mut visitor)?.to_boolean()?)
+        });
+        let error = match result {
+            Ok(Some(false)) => None,
+            Ok(_) => return,
+            Err(err) => Some(err),
+        };
+
+        self.reported
+            .insert((constraint.key.clone(), location.to_string()));
+        self.failures.push(InvariantFailure {
+            key: constraint.key.clone(),
+            severity: match constraint.severity.as_str() {
+                "error" => IssueSeverity::Error,
+                "warning" => IssueSeverity::Warning,
+                _ => IssueSeverity::Information,
+            },
+            human: constraint.human.clone(),
+            expression: expression.clone(),
+            location: location.to_string(),
+            error,
+        });
+    }
+}
+
+/// The slices of the element with an id
+fn slices<'d>(definition: &'d StructureDefinition, id: &str) -> Vec<&'d ElementDefinition> {
+    definition
+        .snapshot
+        .iter()
+        .flat_map(|snapshot| snapshot.element.iter())
+        .filter(|el| {
+            el.id
+                .strip_prefix(id)
+                .and_then(|rest| rest.strip_prefix(':'))
+                .is_some_and(|name| !name.contains('.'))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhir::load_resource;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    /// The key and location of each failure
+    fn failures(failures: &[InvariantFailure]) -> Vec<(&str, &str)> {
+        failures
+            .iter()
+            .map(|failure| (failure.key.as_str(), failure.location.as_str()))
+            .collect()
+    }
+
+    const DIV: &str = "<div xmlns=\"http://www.w3.org/1999/xhtml\">Peter</div>";
+
+    #[test]
+    fn test_check_invariants() {
+        let context = EvaluationContext::new();
+        let checker = InvariantChecker::new(&context);
+        let patient = load_resource(&json!({
+            "resourceType": "Patient",
+            "text": { "status": "generated", "div": DIV },
+            "extension": [{
+                "url": "http://example.org/eye-colour",
+                "valueString": "blue",
+                "extension": [{ "url": "shade", "valueString": "light" }]
+            }],
+            "gender": "male",
+            "_birthDate": { "id": "dob" },
+            "contact": [{ "name": { "family": "Chalmers" } }, { "gender": "female" }],
+            "managingOrganization": { "reference": "#org" }
+        }))
+        .unwrap();
+        let found = checker.check(&patient);
+        assert_eq!(
+            failures(&found),
+            [
+                ("ext-1", "Patient.extension[0]"),
+                ("ele-1", "Patient.birthDate"),
+                ("pat-1", "Patient.contact[1]"),
+                ("ref-1", "Patient.managingOrganization"),
+            ]
+        );
+        assert_eq!(found[2].severity, IssueSeverity::Error);
+        assert_eq!(
+            found[2].to_string(),
+            "pat-1 error at Patient.contact[1]: SHALL at least contain a contact's details or a \
+             reference to an organization"
+        );
+
+        // Without a narrative, dom-6 warns about the resource as a whole
+        let patient =
+            load_resource(&json!({ "resourceType": "Patient", "_birthDate": { "id": "dob" } }))
+                .unwrap();
+        let found = checker.check(&patient);
+        assert_eq!(
+            failures(&found),
+            [("dom-6", "Patient"), ("ele-1", "Patient.birthDate")]
+        );
+        assert_eq!(found[0].severity, IssueSeverity::Warning);
+
+        let checker = InvariantChecker::new(&context)
+            .with_suppressed("dom-6")
+            .with_suppressed("ele-1");
+        assert_eq!(failures(&checker.check(&patient)), []);
+    }
+
+    #[test]
+    fn test_resource_variables() {
+        let context = EvaluationContext::new();
+        let checker = InvariantChecker::new(&context).with_suppressed("dom-6");
+        let code = json!({ "coding": [{ "system": "http://loinc.org", "code": "8480-6" }] });
+        let observation = json!({
+            "resourceType": "Observation",
+            "id": "bp",
+            "status": "final",
+            "code": code,
+            "valueString": "high",
+            "component": [{ "code": code, "valueString": "high" }],
+            "subject": { "reference": "#p2" }
+        });
+
+        // %resource is the contained Observation, whose code its component repeats, while
+        // %rootResource is the Patient, which contains the Patient the Observation references
+        let patient = load_resource(&json!({
+            "resourceType": "Patient",
+            "contained": [observation, { "resourceType": "Patient", "id": "p2", "active": true }],
+            "link": [{ "other": { "reference": "#bp" }, "type": "seealso" }]
+        }))
+        .unwrap();
+        let found = checker.check(&patient);
+        assert_eq!(failures(&found), [("obs-7", "Patient.contained[0]")]);
+
+        // An unreferenced contained resource breaks dom-3 on its container
+        let patient = load_resource(&json!({
+            "resourceType": "Patient",
+            "contained": [{ "resourceType": "Patient", "id": "p2", "active": true }]
+        }))
+        .unwrap();
+        assert_eq!(failures(&checker.check(&patient)), [("dom-3", "Patient")]);
+
+        // Entries of a Bundle are resources of their own
+        let bundle = load_resource(&json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": [{ "resource": { "resourceType": "Observation", "status": "final", "code": code } }]
+        }))
+        .unwrap();
+        let found = InvariantChecker::new(&context).check(&bundle);
+        assert_eq!(failures(&found), [("dom-6", "Bundle.entry[0].resource")]);
+    }
+
+    #[test]
+    fn test_invalid_expressions() {
+        let context = EvaluationContext::new();
+        let checker = InvariantChecker::new(&context).with_suppressed("dom-6");
+        let mut profile = context.model().unwrap().resources()["Patient"].clone();
+        profile.url = "http://example.org/StructureDefinition/odd-patient".to_string();
+        let element = profile
+            .snapshot
+            .as_mut()
+            .unwrap()
+            .element
+            .iter_mut()
+            .find(|element| element.id == "Patient.active")
+            .unwrap();
+        element.constraint.push(Constraint {
+            key: "odd-1".to_string(),
+            severity: "error".to_string(),
+            human: "Unparseable".to_string(),
+            expression: Some("hasValue(".to_string()),
+        });
+        element.constraint.push(Constraint {
+            key: "odd-2".to_string(),
+            severity: "warning".to_string(),
+            human: "Unknown function".to_string(),
+            expression: Some("frobnicate()".to_string()),
+        });
+
+        let patient = load_resource(&json!({ "resourceType": "Patient", "active": true })).unwrap();
+        let found = checker.check_profile(&patient, &profile);
+        assert_eq!(
+            failures(&found),
+            [("odd-1", "Patient.active"), ("odd-2", "Patient.active")]
+        );
+        assert!(matches!(found[0].error, Some(CompileError::Parser(_))));
+        assert!(matches!(found[1].error, Some(CompileError::Evaluation(_))));
+        assert!(found[1]
+            .to_string()
+            .starts_with("cannot check odd-2 at Patient.active: "));
+        assert!(checker.check(&patient).is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Bounds on the work an evaluation may do, so that expressions from untrusted authors cannot
+/// tie up a worker.  Every limit is unset by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// The number of nodes of the expression that may be evaluated, counting each time a node
+    /// is evaluated again (e.g. once per item by `trace()`)
+    pub max_steps: Option<usize>,
+    pub max_collection_size: Option<usize>,
+    /// How deeply the evaluation of nodes may nest
+    pub max_depth: Option<usize>,
+    /// The number of characters any string produced by the evaluation may have
+    pub max_string_length: Option<usize>,
+    /// How long the evaluation may run for
+    pub timeout: Option<Duration>,
+}
+
+impl Limits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_steps(mut self, steps: usize) -> Self {
+        self.max_steps = Some(steps);
+        self
+    }
+
+    pub fn with_max_collection_size(mut self, size: usize) -> Self {
+        self.max_collection_size = Some(size);
+        self
+    }
+
+    pub fn with_max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    pub fn with_max_string_length(mut self, length: usize) -> Self {
+        self.max_string_length = Some(length);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// The limit that stopped an evaluation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Steps(usize),
+    CollectionSize(usize),
+    Depth(usize),
+    StringLength(usize),
+    Timeout(Duration),
+    Cancelled,
+}
+
+/// Lets another thread stop evaluations in progress.  Clones share the same state, so one can
+/// be kept to cancel the evaluations given the others.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancellation_token() {
+        let token = CancellationToken::new();
+        let shared = token.clone();
+        assert!(!shared.is_cancelled());
+
+        let handle = std::thread::spawn(move || token.cancel());
+        handle.join().unwrap();
+        assert!(shared.is_cancelled());
+    }
+}
@@ -1,7 +1,29 @@
+mod compiler;
+mod conformance;
+mod context;
 mod errors;
 mod functions;
+mod invariants;
+mod limits;
+mod optimizer;
+mod resolver;
+mod terminology;
+mod trace;
+mod type_checker;
+mod validation;
 mod visitor;
 
+pub use compiler::*;
+pub use conformance::*;
+pub use context::*;
 pub use errors::*;
 pub use functions::*;
+pub use invariants::*;
+pub use limits::*;
+pub use optimizer::*;
+pub use resolver::*;
+pub use terminology::*;
+pub use trace::*;
+pub use type_checker::*;
+pub use validation::*;
 pub use visitor::*;
@@ -0,0 +1,635 @@
+use super::*;
+use crate::fhirpath::{Collection, Value};
+use crate::parser::{ASTNode, BooleanOperator, Span, TypeOperator};
+
+/// Functions without an input or parameters, whose result does not depend on where in the
+/// expression they are evaluated
+const CONTEXT_FREE_FUNCTIONS: [&str; 3] = ["today", "now", "timeOfDay"];
+
+/// Functions whose result depends only on their input and parameters, so that they can be
+/// evaluated ahead of time when those are literals
+const PURE_FUNCTIONS: [&str; 9] = [
+    "replace",
+    "toLong",
+    "convertsToLong",
+    "toInteger",
+    "toString",
+    "startsWith",
+    "contains",
+    "matches",
+    "substring",
+];
+
+/// Functions that evaluate their parameter against each item of their input
+const LAMBDA_FUNCTIONS: [&str; 2] = ["where", "select"];
+
+/// Functions that give at most a single Boolean
+const BOOLEAN_FUNCTIONS: [&str; 15] = [
+    "empty",
+    "exists",
+    "not",
+    "isDistinct",
+    "startsWith",
+    "contains",
+    "matches",
+    "hasValue",
+    "htmlChecks",
+    "conformsTo",
+    "comparable",
+    "memberOf",
+    "subsumes",
+    "subsumedBy",
+    "convertsToLong",
+];
+
+/// Rewrites an expression into one that gives the same result with less work.  Operators and
+/// pure functions applied to literals are evaluated, boolean identities (e.g. `true and X`) and
+/// functions that do nothing (e.g. `where(true)`) are removed, `iif()` with a literal criterion
+/// is replaced by its branch, and subexpressions of `where()` and `select()` criteria that do
+/// not depend on the item are evaluated once, beforehand, with `defineVariable()`.
+///
+/// Only rewrites that cannot change the result, or any error raised, are made.
+pub fn optimize(node: &ASTNode) -> ASTNode {
+    Optimizer { hoisted: 0 }.optimize(node)
+}
+
+struct Optimizer {
+    /// The number of subexpressions hoisted out of criteria, to name their variables
+    hoisted: usize,
+}
+
+impl Optimizer {
+    fn optimize(&mut self, node: &ASTNode) -> ASTNode {
+        let node = self.optimize_children(node);
+        self.simplify(node)
+    }
+
+    fn optimize_children(&mut self, node: &ASTNode) -> ASTNode {
+        let mut optimize = |node: &ASTNode| Box::new(self.optimize(node));
+        match node {
+            ASTNode::MemberInvocation(node) => ASTNode::MemberInvocation(optimize(node)),
+            // A function is simplified together with the input it is invoked on
+            ASTNode::InvocationExpression(left, right) => ASTNode::InvocationExpression(
+                Box::new(self.optimize(left)),
+                Box::new(self.optimize_children(right)),
+            ),
+            ASTNode::Function(name, params, span) => {
+                ASTNode::Function(name.clone(), optimize(params), *span)
+            }
+            ASTNode::ParamList(Some(param)) => ASTNode::ParamList(Some(optimize(param))),
+            ASTNode::Union(left, right) => ASTNode::Union(optimize(left), optimize(right)),
+            ASTNode::UnionExpression(left, right, span) => {
+                ASTNode::UnionExpression(optimize(left), optimize(right), *span)
+            }
+            ASTNode::EqualityExpression(left, op, right, span) => {
+                ASTNode::EqualityExpression(optimize(left), *op, optimize(right), *span)
+            }
+            ASTNode::InequalityExpression(left, op, right, span) => {
+                ASTNode::InequalityExpression(optimize(left), *op, optimize(right), *span)
+            }
+            ASTNode::AdditiveExpression(left, op, right, span) => {
+                ASTNode::AdditiveExpression(optimize(left), *op, optimize(right), *span)
+            }
+            ASTNode::MultiplicativeExpression(left, op, right, span) => {
+                ASTNode::MultiplicativeExpression(optimize(left), *op, optimize(right), *span)
+            }
+            ASTNode::BooleanExpression(left, op, right, span) => {
+                ASTNode::BooleanExpression(optimize(left), *op, optimize(right), *span)
+            }
+            ASTNode::TypeExpression(left, op, specifier, span) => {
+                ASTNode::TypeExpression(optimize(left), *op, specifier.clone(), *span)
+            }
+            ASTNode::MembershipExpression(left, op, right, span) => {
+                ASTNode::MembershipExpression(optimize(left), *op, optimize(right), *span)
+            }
+            node => node.clone(),
+        }
+    }
+
+    /// Simplifies a node whose children have already been optimized
+    fn simplify(&mut self, node: ASTNode) -> ASTNode {
+        match node {
+            ASTNode::BooleanExpression(left, op, right, span) => {
+                match boolean_identity(&left, op, &right) {
+                    Some(simplified) => simplified,
+                    None => fold(ASTNode::BooleanExpression(left, op, right, span)),
+                }
+            }
+            ASTNode::InvocationExpression(input, function) => {
+                self.simplify_function(Some(input), *function)
+            }
+            ASTNode::Function(..) => self.simplify_function(None, node),
+            node => fold(node),
+        }
+    }
+
+    /// Simplifies the invocation of a function, on an input or (if there is none) the input of
+    /// the expression
+    fn simplify_function(&mut self, input: Option<Box<ASTNode>>, function: ASTNode) -> ASTNode {
+        let invocation = |input: Option<Box<ASTNode>>, function: ASTNode| match input {
+            Some(input) => ASTNode::InvocationExpression(input, Box::new(function)),
+            None => function,
+        };
+        let (Some(name), ASTNode::Function(_, params, _)) = (function_name(&function), &function)
+        else {
+            return invocation(input, function);
+        };
+        let params = param_nodes(params);
+
+        let no_op = match (name, params.as_slice()) {
+            ("where", [ASTNode::BooleanLiteral(true)]) => true,
+            ("select", [ASTNode::Variable(name, _)]) => name == "$this",
+            _ => false,
+        };
+        if no_op {
+            return match input {
+                Some(input) => *input,
+                None => ASTNode::Variable("$this".to_string(), Span::default()),
+            };
+        }
+
+        if name == "iif" {
+            let branch = match params.as_slice() {
+                [ASTNode::BooleanLiteral(true), then, ..] => Some(*then),
+                [ASTNode::BooleanLiteral(false), _, otherwise] => Some(*otherwise),
+                _ => None,
+            };
+            // The branch is evaluated against the input of `iif()`, so it can only replace the
+            // invocation on another input if it does not depend on it
+            let replaceable = input
+                .as_deref()
+                .is_none_or(|input| is_infallible(input) && is_invariant(branch.unwrap_or(input)));
+            if let (Some(branch), true) = (branch, replaceable) {
+                return branch.clone();
+            }
+        }
+
+        if LAMBDA_FUNCTIONS.contains(&name) {
+            return self.hoist(input, function);
+        }
+        fold(invocation(input, function))
+    }
+
+    /// Moves the subexpressions of a function's criterion that do not depend on the item into
+    /// variables defined before the function is invoked
+    fn hoist(&mut self, input: Option<Box<ASTNode>>, function: ASTNode) -> ASTNode {
+        let ASTNode::Function(name, params, span) = function else {
+            return function;
+        };
+        let mut definitions = Vec::new();
+        let params = self.extract(*params, &mut definitions);
+
+        let mut input = input;
+        for (variable, value) in definitions {
+            let define = ASTNode::function(
+                ASTNode::identifier("defineVariable"),
+                ASTNode::params(ASTNode::union(ASTNode::string(variable), value)),
+            );
+            input = Some(match input {
+                Some(input) => ASTNode::invocation(input, define),
+                None => define,
+            });
+        }
+        let function = ASTNode::Function(name, Box::new(params), span);
+        match input {
+            Some(input) => ASTNode::InvocationExpression(input, Box::new(function)),
+            None => function,
+        }
+    }
+
+    fn extract(&mut self, node: ASTNode, definitions: &mut Vec<(String, Box<ASTNode>)>) -> ASTNode {
+        let is_trivial = literal(&node).is_some() || matches!(node, ASTNode::Variable(..));
+        if !is_trivial && is_invariant(&node) && is_infallible(&node) {
+            self.hoisted += 1;
+            let variable = hoisted_variable(self.hoisted);
+            definitions.push((variable.clone(), Box::new(node)));
+            return ASTNode::Variable(variable, Span::default());
+        }
+
+        let mut extract = |node: Box<ASTNode>| Box::new(self.extract(*node, definitions));
+        match node {
+            // Variables that have already been hoisted are left where they are defined
+            ASTNode::Function(..) if function_name(&node) == Some("defineVariable") => node,
+            ASTNode::Function(name, params, span) => ASTNode::Function(name, extract(params), span),
+            ASTNode::InvocationExpression(left, right) => {
+                ASTNode::InvocationExpression(extract(left), extract(right))
+            }
+            ASTNode::ParamList(Some(param)) => ASTNode::ParamList(Some(extract(param))),
+            ASTNode::Union(left, right) => ASTNode::Union(extract(left), extract(right)),
+            ASTNode::UnionExpression(left, right, span) => {
+                ASTNode::UnionExpression(extract(left), extract(right), span)
+            }
+            ASTNode::EqualityExpression(left, op, right, span) => {
+                ASTNode::EqualityExpression(extract(left), op, extract(right), span)
+            }
+            ASTNode::InequalityExpression(left, op, right, span) => {
+                ASTNode::InequalityExpression(extract(left), op, extract(right), span)
+            }
+            ASTNode::AdditiveExpression(left, op, right, span) => {
+                ASTNode::AdditiveExpression(extract(left), op, extract(right), span)
+            }
+            ASTNode::MultiplicativeExpression(left, op, right, span) => {
+                ASTNode::MultiplicativeExpression(extract(left), op, extract(right), span)
+            }
+            ASTNode::BooleanExpression(left, op, right, span) => {
+                ASTNode::BooleanExpression(extract(left), op, extract(right), span)
+            }
+            ASTNode::TypeExpression(left, op, specifier, span) => {
+                ASTNode::TypeExpression(extract(left), op, specifier, span)
+            }
+            ASTNode::MembershipExpression(left, op, right, span) => {
+                ASTNode::MembershipExpression(extract(left), op, extract(right), span)
+            }
+            node => node,
+        }
+    }
+}
+
+/// Evaluates an operator, or a pure function, whose operands are all literals, if the result
+/// can be written as a literal.  Nodes that raise an error are left to raise it on evaluation.
+fn fold(node: ASTNode) -> ASTNode {
+    let operands = match &node {
+        ASTNode::EqualityExpression(left, _, right, _)
+        | ASTNode::InequalityExpression(left, _, right, _)
+        | ASTNode::AdditiveExpression(left, _, right, _)
+        | ASTNode::MultiplicativeExpression(left, _, right, _)
+        | ASTNode::BooleanExpression(left, _, right, _)
+        | ASTNode::MembershipExpression(left, _, right, _) => vec![left.as_ref(), right.as_ref()],
+        ASTNode::TypeExpression(left, ..) => vec![left.as_ref()],
+        ASTNode::InvocationExpression(input, function) => match function.as_ref() {
+            ASTNode::Function(_, params, _)
+                if function_name(function).is_some_and(|name| PURE_FUNCTIONS.contains(&name)) =>
+            {
+                let mut operands = param_nodes(params);
+                operands.push(input);
+                operands
+            }
+            _ => return node,
+        },
+        _ => return node,
+    };
+    if !operands
+        .iter()
+        .all(|operand| literal(operand).is_some_and(|value| value.is_ok()))
+    {
+        return node;
+    }
+
+    let context = EvaluationContext::new();
+    let result = Visitor::new(&context).visit_node(&node);
+    result
+        .ok()
+        .and_then(|values| to_literal(&values))
+        .unwrap_or(node)
+}
+
+/// The name of the variable holding the nth hoisted subexpression.  It contains both of the
+/// characters that can delimit a variable name, so no variable written in an expression, nor
+/// any sensibly named context variable, can be the same one.
+fn hoisted_variable(n: usize) -> String {
+    format!("`hoisted'{}", n)
+}
+
+/// A literal for a single value, if one reads back as the same value
+fn to_literal(values: &Collection) -> Option<ASTNode> {
+    let node = match values.as_slice() {
+        [Value::Boolean(b)] => ASTNode::BooleanLiteral(*b),
+        [Value::String(s)] => ASTNode::StringLiteral(s.clone()),
+        [Value::Integer(i)] => ASTNode::NumberLiteral(i.to_string(), Span::default()),
+        [Value::Long(l)] => ASTNode::LongNumberLiteral(l.to_string(), Span::default()),
+        [Value::Decimal(d)] => ASTNode::NumberLiteral(d.to_string(), Span::default()),
+        _ => return None,
+    };
+    matches!(literal(&node), Some(Ok(value)) if value == *values).then_some(node)
+}
+
+/// Simplifies a boolean operator with a literal operand.  An operand that is removed must not
+/// raise an error, and one that is kept as the result must already be a Boolean.
+fn boolean_identity(left: &ASTNode, op: BooleanOperator, right: &ASTNode) -> Option<ASTNode> {
+    let constant = |node: &ASTNode| match node {
+        ASTNode::BooleanLiteral(b) => Some(*b),
+        _ => None,
+    };
+    let removable = |node: &ASTNode| is_boolean(node) && is_infallible(node);
+    let result = match (constant(left), op, constant(right)) {
+        // The right operand is not evaluated when the left one decides the result
+        (Some(false), BooleanOperator::And, _) => ASTNode::BooleanLiteral(false),
+        (Some(true), BooleanOperator::Or, _) => ASTNode::BooleanLiteral(true),
+        (Some(false), BooleanOperator::Implies, _) => ASTNode::BooleanLiteral(true),
+        (Some(true), BooleanOperator::And, _)
+        | (Some(false), BooleanOperator::Or, _)
+        | (Some(true), BooleanOperator::Implies, _)
+        | (Some(false), BooleanOperator::Xor, _)
+            if is_boolean(right) =>
+        {
+            right.clone()
+        }
+        (_, BooleanOperator::And, Some(true))
+        | (_, BooleanOperator::Or, Some(false))
+        | (_, BooleanOperator::Xor, Some(false))
+            if is_boolean(left) =>
+        {
+            left.clone()
+        }
+        (_, BooleanOperator::And, Some(false)) if removable(left) => ASTNode::BooleanLiteral(false),
+        (_, BooleanOperator::Or | BooleanOperator::Implies, Some(true)) if removable(left) => {
+            ASTNode::BooleanLiteral(true)
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// Whether a node gives at most a single Boolean
+fn is_boolean(node: &ASTNode) -> bool {
+    match node {
+        ASTNode::BooleanLiteral(_)
+        | ASTNode::EqualityExpression(..)
+        | ASTNode::InequalityExpression(..)
+        | ASTNode::BooleanExpression(..)
+        | ASTNode::MembershipExpression(..)
+        | ASTNode::TypeExpression(_, TypeOperator::Is, ..) => true,
+        ASTNode::Function(..) => {
+            function_name(node).is_some_and(|name| BOOLEAN_FUNCTIONS.contains(&name))
+        }
+        ASTNode::InvocationExpression(_, function) => {
+            matches!(function.as_ref(), ASTNode::Function(..)) && is_boolean(function)
+        }
+        _ => false,
+    }
+}
+
+/// Whether a node cannot raise an error, other than by exceeding the limits of the evaluation
+fn is_infallible(node: &ASTNode) -> bool {
+    if let Some(value) = literal(node) {
+        return value.is_ok();
+    }
+    match node {
+        ASTNode::Identifier(_) => true,
+        ASTNode::Variable(name, _) => {
+            matches!(
+                name.as_str(),
+                "resource" | "rootResource" | "context" | "ucum" | "sct" | "loinc" | "$this"
+            ) || name.starts_with("vs-")
+                || name.starts_with("ext-")
+        }
+        ASTNode::InvocationExpression(input, right) => {
+            matches!(right.as_ref(), ASTNode::Identifier(_)) && is_infallible(input)
+        }
+        ASTNode::Function(_, params, _) => {
+            matches!(params.as_ref(), ASTNode::ParamList(None))
+                && function_name(node).is_some_and(|name| CONTEXT_FREE_FUNCTIONS.contains(&name))
+        }
+        ASTNode::EqualityExpression(left, _, right, _) => {
+            is_infallible(left) && is_infallible(right)
+        }
+        _ => false,
+    }
+}
+
+/// Whether a node gives the same result whatever input it is evaluated against
+fn is_invariant(node: &ASTNode) -> bool {
+    if literal(node).is_some() {
+        return true;
+    }
+    match node {
+        ASTNode::Variable(name, _) => name != "$this",
+        ASTNode::InvocationExpression(input, right) => {
+            is_invariant(input)
+                && match right.as_ref() {
+                    // Functions that evaluate their own parameters do so against their input
+                    ASTNode::Function(..)
+                        if function_name(right)
+                            .is_some_and(|name| EXPRESSION_FUNCTIONS.contains_key(name)) =>
+                    {
+                        true
+                    }
+                    ASTNode::Function(_, params, _) => is_invariant(params),
+                    _ => true,
+                }
+        }
+        ASTNode::Function(_, params, _) => {
+            matches!(params.as_ref(), ASTNode::ParamList(None))
+                && function_name(node).is_some_and(|name| CONTEXT_FREE_FUNCTIONS.contains(&name))
+        }
+        ASTNode::ParamList(None) => true,
+        ASTNode::ParamList(Some(param)) => is_invariant(param),
+        ASTNode::TypeExpression(left, ..) => is_invariant(left),
+        ASTNode::Union(left, right)
+        | ASTNode::UnionExpression(left, right, _)
+        | ASTNode::MembershipExpression(left, _, right, _)
+        | ASTNode::EqualityExpression(left, _, right, _)
+        | ASTNode::InequalityExpression(left, _, right, _)
+        | ASTNode::AdditiveExpression(left, _, right, _)
+        | ASTNode::MultiplicativeExpression(left, _, right, _)
+        | ASTNode::BooleanExpression(left, _, right, _) => {
+            is_invariant(left) && is_invariant(right)
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Lexer, Parser};
+    use quickcheck::{Arbitrary, Gen, QuickCheck, TestResult};
+
+    fn parse(expr: &str) -> ASTNode {
+        *Parser::new(Lexer::new(expr).tokenize().unwrap())
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_simplification() {
+        let cases = [
+            ("'a' & 'b'", "'ab'"),
+            ("1 + 2 * 3", "7"),
+            ("1.5 * 2", "3.0"),
+            ("'abc'.replace('b', 'x') = 'axc'", "true"),
+            ("1 is Integer", "true"),
+            ("true and active = true", "active = true"),
+            ("false and active", "false"),
+            ("active = true and false", "false"),
+            ("true or active", "true"),
+            ("gender = 'male' or false", "gender = 'male'"),
+            ("false implies active", "true"),
+            ("name.where(true).given", "name.given"),
+            ("name.select($this)", "name"),
+            ("where(true)", "$this"),
+            ("iif(1 = 1, name, gender)", "name"),
+            ("iif(false, name, gender)", "gender"),
+            ("active.iif(true, 'yes', 'no')", "'yes'"),
+            (
+                "name.where(family = %resource.name.family)",
+                "name.defineVariable('hoisted1', %resource.name.family).where(family = %hoisted1)",
+            ),
+            (
+                "name.select(given = today())",
+                "name.defineVariable('hoisted1', today()).select(given = %hoisted1)",
+            ),
+        ];
+        // The names of hoisted variables cannot be written in an expression, so `hoisted1` stands
+        // in for the first of them
+        let hoisted = format!("{:?}", hoisted_variable(1));
+        for (expr, expected) in cases {
+            let expected = format!("{:?}", parse(expected)).replace("\"hoisted1\"", &hoisted);
+            assert_eq!(
+                format!("{:?}", optimize(&parse(expr))),
+                expected,
+                "{}",
+                expr
+            );
+        }
+
+        // Rewrites that could change the result, or lose an error, are not made
+        for expr in [
+            "active and false",
+            "true and active",
+            "1 / 0",
+            "'a' + 1",
+            "name.iif(true, given, family)",
+            "name.where(given = %missing.id)",
+            "name.where(given.replace('a', 'b') = 'x')",
+        ] {
+            assert_eq!(optimize(&parse(expr)), parse(expr), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn test_hoisted_variables_do_not_clash() {
+        let patient = crate::fhir::load_resource(&serde_json::json!({
+            "resourceType": "Patient",
+            "name": [{ "family": "Chalmers", "given": ["Peter"] }]
+        }))
+        .unwrap();
+        // Named as hoisted variables used to be, which would have been redefined
+        let context = EvaluationContext::new()
+            .with_variable("__hoisted1", Collection::from(Value::integer(1)));
+        let ast = parse(
+            "name.where(family = %resource.name.family).select(given.exists() | %__hoisted1)",
+        );
+        let optimized = optimize(&ast);
+        assert_ne!(optimized, ast);
+
+        let evaluate = |node: &ASTNode| {
+            Visitor::new(&context)
+                .with_input(Collection::from(patient.to_value()))
+                .visit_node(node)
+                .unwrap()
+        };
+        let expected: Collection = [Value::boolean(true), Value::integer(1)]
+            .into_iter()
+            .collect();
+        assert_eq!(evaluate(&ast), expected);
+        assert_eq!(evaluate(&optimized), expected);
+    }
+
+    /// An expression built from parts that the optimizer rewrites
+    #[derive(Clone, Debug)]
+    struct Expr(String);
+
+    impl Expr {
+        fn generate(g: &mut Gen, depth: usize) -> String {
+            // The parser does not support the empty collection literal `{}` yet, so a leaf that
+            // is always empty stands in for it
+            const LEAVES: [&str; 16] = [
+                "true",
+                "false",
+                "1",
+                "2",
+                "1.5",
+                "'a'",
+                "'b'",
+                "active",
+                "gender",
+                "name",
+                "name.given",
+                "birthDate",
+                "$this",
+                "%resource.gender",
+                "today()",
+                "%resource.deceased",
+            ];
+            let sub = |g: &mut Gen| Self::generate(g, depth.saturating_sub(1));
+            let choice = if depth == 0 { 0 } else { u8::arbitrary(g) % 9 };
+            match choice {
+                0 | 1 => g.choose(&LEAVES).unwrap().to_string(),
+                2 => {
+                    let op = g.choose(&["and", "or", "xor", "implies"]).unwrap();
+                    format!("{} {} {}", sub(g), op, sub(g))
+                }
+                3 => {
+                    let op = g.choose(&["=", "!=", "~", "<"]).unwrap();
+                    format!("{} {} {}", sub(g), op, sub(g))
+                }
+                4 => {
+                    let op = g.choose(&["+", "-", "&", "*"]).unwrap();
+                    format!("{} {} {}", sub(g), op, sub(g))
+                }
+                5 => format!("{}.where({})", sub(g), sub(g)),
+                6 => format!("{}.select({})", sub(g), sub(g)),
+                7 => format!("iif({}, {}, {})", sub(g), sub(g), sub(g)),
+                _ => {
+                    let suffix = g.choose(&[".where(true)", ".select($this)", " is Boolean"]);
+                    format!("{}{}", sub(g), suffix.unwrap())
+                }
+            }
+        }
+    }
+
+    impl Arbitrary for Expr {
+        fn arbitrary(g: &mut Gen) -> Self {
+            Expr(Self::generate(g, 4))
+        }
+    }
+
+    /// Whether an expression gives the same result, or raises the same error, once optimized
+    fn evaluates_the_same(expr: Expr) -> TestResult {
+        let Ok(tokens) = Lexer::new(&expr.0).tokenize() else {
+            return TestResult::discard();
+        };
+        let Ok(ast) = Parser::new(tokens).parse() else {
+            return TestResult::discard();
+        };
+        let optimized = optimize(&ast);
+
+        let patient = crate::fhir::load_resource(&serde_json::json!({
+            "resourceType": "Patient",
+            "active": true,
+            "gender": "male",
+            "birthDate": "1974-12-25",
+            "name": [
+                { "family": "Chalmers", "given": ["Peter", "James"] },
+                { "given": ["Jim"] }
+            ]
+        }))
+        .unwrap();
+        let context = EvaluationContext::new().with_clock(FixedClock(
+            chrono::DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap(),
+        ));
+        let evaluate = |node: &ASTNode| {
+            Visitor::new(&context)
+                .with_input(Collection::from(patient.to_value()))
+                .visit_node(node)
+                .map_err(|err| err.kind().to_string())
+        };
+        let (expected, actual) = (evaluate(&ast), evaluate(&optimized));
+        if expected == actual {
+            TestResult::passed()
+        } else {
+            TestResult::error(format!(
+                "{}: {:?} became {:?}, giving {:?} rather than {:?}",
+                expr.0, ast, optimized, actual, expected
+            ))
+        }
+    }
+
+    #[test]
+    fn test_optimized_expressions_evaluate_the_same() {
+        QuickCheck::new()
+            .tests(2000)
+            .quickcheck(evaluates_the_same as fn(Expr) -> TestResult);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::fhirpath::{DataNode, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Finds the resource that a reference points to, for `resolve()`.  `root` is the resource the
+/// expression is being evaluated against, if any.  Resources are returned shared, so that
+/// resolving one does not copy it.
+pub trait ReferenceResolver: Send + Sync {
+    fn resolve(&self, reference: &str, root: Option<&DataNode>) -> Option<Arc<DataNode>>;
+}
+
+/// Resolves local references (`#id`) to resources contained in the root resource, or in the
+/// resources of its entries when the root is a Bundle
+pub struct ContainedResolver;
+
+impl ReferenceResolver for ContainedResolver {
+    fn resolve(&self, reference: &str, root: Option<&DataNode>) -> Option<Arc<DataNode>> {
+        let id = reference.strip_prefix('#')?;
+        let root = root?;
+        let containers = std::iter::once(root).chain(entry_resources(root));
+        containers
+            .flat_map(|resource| resource.shared_members("contained"))
+            .find(|resource| has_id(resource, id))
+            .cloned()
+    }
+}
+
+/// Resolves references to the entries of a root Bundle, either by their `fullUrl` (including
+/// `urn:uuid:` URLs) or by `Type/id`
+pub struct BundleResolver;
+
+impl ReferenceResolver for BundleResolver {
+    fn resolve(&self, reference: &str, root: Option<&DataNode>) -> Option<Arc<DataNode>> {
+        let root = root?;
+        let entries = root.members("entry");
+        let mut resources = entries.filter_map(|entry| {
+            let resource = entry.shared_members("resource").next()?;
+            Some((entry, resource))
+        });
+
+        let key = resource_key(reference);
+        resources
+            .find(|(entry, resource)| {
+                matches!(entry.member_value("fullUrl"), Some(Value::String(url)) if url == reference)
+                    || key.is_some_and(|(resource_type, id)| {
+                        resource.data_type().strip_prefix("FHIR.") == Some(resource_type)
+                            && has_id(resource, id)
+                    })
+            })
+            .map(|(_, resource)| resource.clone())
+    }
+}
+
+/// Resolves references against a user-supplied store of resources, keyed by reference
+/// (e.g. `Patient/123` or a full URL)
+#[derive(Default)]
+pub struct MapResolver(HashMap<String, Arc<DataNode>>);
+
+impl MapResolver {
+    pub fn new(resources: HashMap<String, Arc<DataNode>>) -> Self {
+        MapResolver(resources)
+    }
+
+    /// Adds a resource under its `Type/id` key
+    pub fn insert(&mut self, resource: Arc<DataNode>) {
+        let resource_type = resource.data_type().trim_start_matches("FHIR.");
+        if let Some(Value::String(id)) = resource.member_value("id") {
+            let key = format!("{}/{}", resource_type, id);
+            self.0.insert(key, resource);
+        }
+    }
+}
+
+impl From<HashMap<String, Arc<DataNode>>> for MapResolver {
+    fn from(resources: HashMap<String, Arc<DataNode>>) -> Self {
+        MapResolver::new(resources)
+    }
+}
+
+impl ReferenceResolver for MapResolver {
+    fn resolve(&self, reference: &str, _: Option<&DataNode>) -> Option<Arc<DataNode>> {
+        self.0
+            .get(reference)
+            .or_else(|| {
+                let (resource_type, id) = resource_key(reference)?;
+                self.0.get(&format!("{}/{}", resource_type, id))
+            })
+            .cloned()
+    }
+}
+
+/// The resource type and id of a relative or absolute RESTful reference, ignoring any version
+/// (e.g. `http://example.org/fhir/Patient/123/_history/2` gives `("Patient", "123")`)
+fn resource_key(reference: &str) -> Option<(&str, &str)> {
+    let reference = match reference.find("/_history/") {
+        Some(i) => &reference[..i],
+        None => reference,
+    };
+    let mut segments = reference.rsplit('/');
+    let id = segments.next()?;
+    let resource_type = segments.next()?;
+    if resource_type.starts_with(char::is_uppercase) && !id.is_empty() {
+        Some((resource_type, id))
+    } else {
+        None
+    }
+}
+
+fn entry_resources(root: &DataNode) -> impl Iterator<Item = &DataNode> {
+    root.members("entry")
+        .filter_map(|entry| entry.member("resource"))
+}
+
+fn has_id(resource: &DataNode, id: &str) -> bool {
+    matches!(resource.member_value("id"), Some(Value::String(s)) if s == id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhir::load_resource;
+    use serde_json::json;
+
+    fn id(resource: Option<Arc<DataNode>>) -> Option<String> {
+        match resource?.member_value("id") {
+            Some(Value::String(id)) => Some(id.clone()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_resource_key() {
+        assert_eq!(resource_key("Patient/123"), Some(("Patient", "123")));
+        assert_eq!(
+            resource_key("http://example.org/fhir/Patient/123/_history/2"),
+            Some(("Patient", "123"))
+        );
+        assert_eq!(resource_key("urn:uuid:5d2c1f3e"), None);
+        assert_eq!(resource_key("#p1"), None);
+    }
+
+    #[test]
+    fn test_resolvers() {
+        let bundle = load_resource(&json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": [
+                {
+                    "fullUrl": "urn:uuid:04121321-4af5-424c-a0e1-ed3aab1c349d",
+                    "resource": { "resourceType": "Patient", "id": "a" }
+                },
+                {
+                    "fullUrl": "http://example.org/fhir/Patient/b",
+                    "resource": {
+                        "resourceType": "Patient",
+                        "id": "b",
+                        "contained": [{ "resourceType": "Patient", "id": "c" }]
+                    }
+                }
+            ]
+        }))
+        .unwrap();
+
+        let resolve = |reference| BundleResolver.resolve(reference, Some(&bundle));
+        assert_eq!(
+            id(resolve("urn:uuid:04121321-4af5-424c-a0e1-ed3aab1c349d")),
+            Some("a".to_string())
+        );
+        assert_eq!(id(resolve("Patient/b")), Some("b".to_string()));
+        assert_eq!(
+            id(resolve("http://example.org/fhir/Patient/b")),
+            Some("b".to_string())
+        );
+        assert_eq!(id(resolve("Patient/x")), None);
+        assert_eq!(id(resolve("Observation/a")), None);
+
+        let contained = ContainedResolver.resolve("#c", Some(&bundle));
+        assert_eq!(id(contained), Some("c".to_string()));
+        assert_eq!(id(ContainedResolver.resolve("#a", Some(&bundle))), None);
+
+        let mut store = MapResolver::default();
+        store.insert(load_resource(&json!({ "resourceType": "Patient", "id": "d" })).unwrap());
+        assert_eq!(id(store.resolve("Patient/d", None)), Some("d".to_string()));
+        assert_eq!(
+            id(store.resolve("http://example.org/fhir/Patient/d", None)),
+            Some("d".to_string())
+        );
+        assert_eq!(id(store.resolve("Patient/e", None)), None);
+    }
+}
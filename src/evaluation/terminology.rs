@@ -0,0 +1,638 @@
+use super::*;
+use crate::fhir::{
+    load_bundle_resources, CodeSystem, ConceptFilter, ConceptMap, ConceptSet, ParseError, ValueSet,
+};
+use std::collections::HashMap;
+
+/// A coded value: a code, optionally qualified by the system it is from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Coding {
+    pub system: Option<String>,
+    pub version: Option<String>,
+    pub code: String,
+    pub display: Option<String>,
+}
+
+/// How two concepts from a code system are related, as given by `subsumes`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubsumptionOutcome {
+    Equivalent,
+    Subsumes,
+    SubsumedBy,
+    NotSubsumed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LookupResult {
+    pub name: String,
+    pub version: Option<String>,
+    pub display: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationResult {
+    pub result: bool,
+    pub message: Option<String>,
+    pub display: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Translation {
+    pub equivalence: String,
+    pub concept: Coding,
+}
+
+/// Terminology operations used by `memberOf()`, `subsumes()`, `subsumedBy()` and the
+/// `%terminologies` functions.  Code systems, value sets and concept maps are identified by their
+/// canonical URLs.
+pub trait TerminologyService: Send + Sync {
+    /// The codes in a value set
+    fn expand(&self, value_set: &str) -> Result<Vec<Coding>, TerminologyError>;
+
+    /// Details of a code from its code system, or `None` if the system does not define it
+    fn lookup(&self, coding: &Coding) -> Result<Option<LookupResult>, TerminologyError>;
+
+    /// Whether a code is in a value set.  A coding without a system matches on the code alone.
+    fn validate_code(
+        &self,
+        value_set: &str,
+        coding: &Coding,
+    ) -> Result<ValidationResult, TerminologyError> {
+        let expansion = self.expand(value_set)?;
+        let found = expansion.into_iter().find(|concept| {
+            concept.code == coding.code
+                && (coding.system.is_none() || concept.system == coding.system)
+        });
+        Ok(match found {
+            Some(concept) => ValidationResult {
+                result: true,
+                message: None,
+                display: concept.display,
+            },
+            None => ValidationResult {
+                result: false,
+                message: Some(format!(
+                    "The code '{}' is not in the value set {}",
+                    coding.code, value_set
+                )),
+                display: None,
+            },
+        })
+    }
+
+    /// Whether a code is defined by a code system
+    fn validate_code_system(
+        &self,
+        code_system: &str,
+        coding: &Coding,
+    ) -> Result<ValidationResult, TerminologyError> {
+        let coding = Coding {
+            system: Some(code_system.to_string()),
+            ..coding.clone()
+        };
+        Ok(match self.lookup(&coding)? {
+            Some(lookup) => ValidationResult {
+                result: true,
+                message: None,
+                display: lookup.display,
+            },
+            None => ValidationResult {
+                result: false,
+                message: Some(format!(
+                    "The code '{}' is not defined by {}",
+                    coding.code, code_system
+                )),
+                display: None,
+            },
+        })
+    }
+
+    /// How code `a` relates to code `b`, or `None` if either is unknown
+    fn subsumes(
+        &self,
+        system: &str,
+        a: &str,
+        b: &str,
+    ) -> Result<Option<SubsumptionOutcome>, TerminologyError>;
+
+    /// The concepts that a concept map maps a code to
+    fn translate(
+        &self,
+        concept_map: &str,
+        coding: &Coding,
+    ) -> Result<Vec<Translation>, TerminologyError>;
+
+    fn member_of(&self, value_set: &str, coding: &Coding) -> Result<bool, TerminologyError> {
+        Ok(self.validate_code(value_set, coding)?.result)
+    }
+}
+
+/// A terminology service over code systems, value sets and concept maps held in memory, so that
+/// terminology functions work offline
+#[derive(Default)]
+pub struct InMemoryTerminology {
+    code_systems: HashMap<String, CodeSystem>,
+    value_sets: HashMap<String, ValueSet>,
+    concept_maps: HashMap<String, ConceptMap>,
+}
+
+impl InMemoryTerminology {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the CodeSystem, ValueSet and ConceptMap resources in a Bundle in the JSON format
+    pub fn load_bundle(&mut self, data: &[u8]) -> Result<(), ParseError> {
+        for code_system in load_bundle_resources(data, "CodeSystem")? {
+            self.add_code_system(code_system);
+        }
+        for value_set in load_bundle_resources(data, "ValueSet")? {
+            self.add_value_set(value_set);
+        }
+        for concept_map in load_bundle_resources(data, "ConceptMap")? {
+            self.add_concept_map(concept_map);
+        }
+        Ok(())
+    }
+
+    pub fn add_code_system(&mut self, code_system: CodeSystem) {
+        self.code_systems
+            .insert(code_system.url.clone(), code_system);
+    }
+
+    pub fn add_value_set(&mut self, value_set: ValueSet) {
+        self.value_sets.insert(value_set.url.clone(), value_set);
+    }
+
+    pub fn add_concept_map(&mut self, concept_map: ConceptMap) {
+        self.concept_maps
+            .insert(concept_map.url.clone(), concept_map);
+    }
+
+    fn code_system(&self, url: &str) -> Result<&CodeSystem, TerminologyError> {
+        self.code_systems
+            .get(canonical(url))
+            .ok_or_else(|| TerminologyError::UnknownCodeSystem(url.to_string()))
+    }
+
+    /// The codes selected by an `include` or `exclude` of a value set's compose
+    fn concept_set(
+        &self,
+        set: &ConceptSet,
+        expanding: &mut Vec<String>,
+    ) -> Result<Vec<Coding>, TerminologyError> {
+        let mut codings = match &set.system {
+            Some(system) if !set.concept.is_empty() => {
+                let code_system = self.code_systems.get(canonical(system));
+                set.concept
+                    .iter()
+                    .map(|concept| Coding {
+                        system: Some(system.clone()),
+                        version: set.version.clone(),
+                        code: concept.code.clone(),
+                        display: concept
+                            .display
+                            .clone()
+                            .or_else(|| code_system?.concept(&concept.code)?.display.clone()),
+                    })
+                    .collect()
+            }
+            Some(system) => {
+                let code_system = self.code_system(system)?;
+                let mut concepts: Vec<_> = code_system
+                    .concept
+                    .iter()
+                    .flat_map(|concept| concept.descendants())
+                    .collect();
+                for filter in &set.filter {
+                    if !SUPPORTED_FILTERS.contains(&filter.op.as_str()) {
+                        return Err(TerminologyError::UnsupportedFilter(
+                            filter.property.clone(),
+                            filter.op.clone(),
+                        ));
+                    }
+                    concepts.retain(|concept| matches_filter(code_system, filter, &concept.code));
+                }
+                concepts
+                    .into_iter()
+                    .map(|concept| Coding {
+                        system: Some(system.clone()),
+                        version: set.version.clone().or_else(|| code_system.version.clone()),
+                        code: concept.code.clone(),
+                        display: concept.display.clone(),
+                    })
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+
+        // Value sets restrict the codes from the system, and are intersected with each other
+        for (i, value_set) in set.value_set.iter().enumerate() {
+            let expansion = self.expand_value_set(value_set, expanding)?;
+            if set.system.is_none() && i == 0 {
+                codings = expansion;
+            } else {
+                codings.retain(|coding| expansion.iter().any(|e| same_concept(e, coding)));
+            }
+        }
+        Ok(codings)
+    }
+
+    /// Expands a value set, given the URLs of those already being expanded that include it
+    fn expand_value_set(
+        &self,
+        value_set: &str,
+        expanding: &mut Vec<String>,
+    ) -> Result<Vec<Coding>, TerminologyError> {
+        let definition = self
+            .value_sets
+            .get(canonical(value_set))
+            .ok_or_else(|| TerminologyError::UnknownValueSet(value_set.to_string()))?;
+        if expanding.contains(&definition.url) {
+            return Err(TerminologyError::CircularValueSet(definition.url.clone()));
+        }
+
+        if let Some(expansion) = &definition.expansion {
+            return Ok(expansion
+                .contains
+                .iter()
+                .flat_map(|entry| entry.flatten())
+                .filter_map(|entry| {
+                    Some(Coding {
+                        system: entry.system.clone(),
+                        version: entry.version.clone(),
+                        code: entry.code.clone()?,
+                        display: entry.display.clone(),
+                    })
+                })
+                .collect());
+        }
+
+        let Some(compose) = &definition.compose else {
+            return Ok(Vec::new());
+        };
+        expanding.push(definition.url.clone());
+        let mut codings: Vec<Coding> = Vec::new();
+        for include in &compose.include {
+            for coding in self.concept_set(include, expanding)? {
+                if !codings.iter().any(|c| same_concept(c, &coding)) {
+                    codings.push(coding);
+                }
+            }
+        }
+        for exclude in &compose.exclude {
+            let excluded = self.concept_set(exclude, expanding)?;
+            codings.retain(|coding| !excluded.iter().any(|e| same_concept(e, coding)));
+        }
+        expanding.pop();
+        Ok(codings)
+    }
+}
+
+impl TerminologyService for InMemoryTerminology {
+    fn expand(&self, value_set: &str) -> Result<Vec<Coding>, TerminologyError> {
+        self.expand_value_set(value_set, &mut Vec::new())
+    }
+
+    fn lookup(&self, coding: &Coding) -> Result<Option<LookupResult>, TerminologyError> {
+        let Some(system) = &coding.system else {
+            return Ok(None);
+        };
+        let code_system = self.code_system(system)?;
+        Ok(code_system
+            .concept(&coding.code)
+            .map(|concept| LookupResult {
+                name: code_system.name.clone().unwrap_or_else(|| system.clone()),
+                version: code_system.version.clone(),
+                display: concept.display.clone(),
+            }))
+    }
+
+    fn subsumes(
+        &self,
+        system: &str,
+        a: &str,
+        b: &str,
+    ) -> Result<Option<SubsumptionOutcome>, TerminologyError> {
+        let code_system = self.code_system(system)?;
+        let (Some(concept_a), Some(concept_b)) = (code_system.concept(a), code_system.concept(b))
+        else {
+            return Ok(None);
+        };
+        Ok(Some(if a == b {
+            SubsumptionOutcome::Equivalent
+        } else if concept_a.subsumes(b) {
+            SubsumptionOutcome::Subsumes
+        } else if concept_b.subsumes(a) {
+            SubsumptionOutcome::SubsumedBy
+        } else {
+            SubsumptionOutcome::NotSubsumed
+        }))
+    }
+
+    fn translate(
+        &self,
+        concept_map: &str,
+        coding: &Coding,
+    ) -> Result<Vec<Translation>, TerminologyError> {
+        let map = self
+            .concept_maps
+            .get(canonical(concept_map))
+            .ok_or_else(|| TerminologyError::UnknownConceptMap(concept_map.to_string()))?;
+
+        let groups = map.group.iter().filter(|group| {
+            coding.system.is_none() || group.source.is_none() || group.source == coding.system
+        });
+        Ok(groups
+            .flat_map(|group| {
+                group
+                    .element
+                    .iter()
+                    .filter(|element| element.code.as_deref() == Some(coding.code.as_str()))
+                    .flat_map(|element| element.target.iter())
+                    .filter_map(move |target| {
+                        Some(Translation {
+                            equivalence: target.equivalence.clone(),
+                            concept: Coding {
+                                system: group.target.clone(),
+                                version: None,
+                                code: target.code.clone()?,
+                                display: target.display.clone(),
+                            },
+                        })
+                    })
+            })
+            .collect())
+    }
+}
+
+impl SubsumptionOutcome {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SubsumptionOutcome::Equivalent => "equivalent",
+            SubsumptionOutcome::Subsumes => "subsumes",
+            SubsumptionOutcome::SubsumedBy => "subsumed-by",
+            SubsumptionOutcome::NotSubsumed => "not-subsumed",
+        }
+    }
+}
+
+const SUPPORTED_FILTERS: [&str; 5] = ["is-a", "descendent-of", "is-not-a", "=", "in"];
+
+fn matches_filter(code_system: &CodeSystem, filter: &ConceptFilter, code: &str) -> bool {
+    let subsumed = || {
+        code_system
+            .concept(&filter.value)
+            .is_some_and(|parent| parent.subsumes(code))
+    };
+    match filter.op.as_str() {
+        "is-a" => subsumed(),
+        "descendent-of" => filter.value != code && subsumed(),
+        "is-not-a" => !subsumed(),
+        "=" => filter.value == code,
+        "in" => filter.value.split(',').any(|value| value.trim() == code),
+        _ => false,
+    }
+}
+
+/// A canonical URL without any `|version`
+fn canonical(url: &str) -> &str {
+    url.split('|').next().unwrap_or(url)
+}
+
+fn same_concept(c1: &Coding, c2: &Coding) -> bool {
+    c1.system == c2.system && c1.code == c2.code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn terminology() -> InMemoryTerminology {
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": [
+                {
+                    "resource": {
+                        "resourceType": "CodeSystem",
+                        "url": "http://example.org/animals",
+                        "version": "1.0",
+                        "name": "Animals",
+                        "concept": [{
+                            "code": "animal",
+                            "display": "Animal",
+                            "concept": [
+                                {
+                                    "code": "mammal",
+                                    "display": "Mammal",
+                                    "concept": [
+                                        { "code": "dog", "display": "Dog" },
+                                        { "code": "cat", "display": "Cat" }
+                                    ]
+                                },
+                                { "code": "bird", "display": "Bird" }
+                            ]
+                        }]
+                    }
+                },
+                {
+                    "resource": {
+                        "resourceType": "ValueSet",
+                        "url": "http://example.org/mammals",
+                        "compose": {
+                            "include": [{
+                                "system": "http://example.org/animals",
+                                "filter": [{ "property": "concept", "op": "descendent-of", "value": "mammal" }]
+                            }]
+                        }
+                    }
+                },
+                {
+                    "resource": {
+                        "resourceType": "ValueSet",
+                        "url": "http://example.org/pets",
+                        "compose": {
+                            "include": [
+                                { "valueSet": ["http://example.org/mammals|1.0"] },
+                                {
+                                    "system": "http://example.org/animals",
+                                    "concept": [{ "code": "bird" }]
+                                }
+                            ],
+                            "exclude": [{
+                                "system": "http://example.org/animals",
+                                "concept": [{ "code": "cat" }]
+                            }]
+                        }
+                    }
+                },
+                {
+                    "resource": {
+                        "resourceType": "ValueSet",
+                        "url": "http://example.org/unsupported",
+                        "compose": {
+                            "include": [{
+                                "system": "http://example.org/animals",
+                                "filter": [{ "property": "concept", "op": "regex", "value": "d.*" }]
+                            }]
+                        }
+                    }
+                },
+                {
+                    "resource": {
+                        "resourceType": "ValueSet",
+                        "url": "http://example.org/loop",
+                        "compose": { "include": [{ "valueSet": ["http://example.org/loop-back"] }] }
+                    }
+                },
+                {
+                    "resource": {
+                        "resourceType": "ValueSet",
+                        "url": "http://example.org/loop-back",
+                        "compose": {
+                            "include": [
+                                { "valueSet": ["http://example.org/mammals"] },
+                                { "valueSet": ["http://example.org/loop"] }
+                            ]
+                        }
+                    }
+                }
+            ]
+        });
+
+        let mut terminology = InMemoryTerminology::new();
+        terminology
+            .load_bundle(&serde_json::to_vec(&bundle).unwrap())
+            .unwrap();
+        terminology
+            .load_bundle(include_bytes!("../fhir/specification/R4B/conceptmaps.json"))
+            .unwrap();
+        terminology
+    }
+
+    fn coding(system: Option<&str>, code: &str) -> Coding {
+        Coding {
+            system: system.map(str::to_string),
+            version: None,
+            code: code.to_string(),
+            display: None,
+        }
+    }
+
+    fn codes(codings: Vec<Coding>) -> Vec<String> {
+        codings.into_iter().map(|coding| coding.code).collect()
+    }
+
+    #[test]
+    fn test_expand() {
+        let terminology = terminology();
+        assert_eq!(
+            codes(terminology.expand("http://example.org/mammals").unwrap()),
+            vec!["dog", "cat"]
+        );
+        assert_eq!(
+            codes(terminology.expand("http://example.org/pets").unwrap()),
+            vec!["dog", "bird"]
+        );
+
+        let bird = &terminology.expand("http://example.org/pets").unwrap()[1];
+        assert_eq!(bird.display.as_deref(), Some("Bird"));
+
+        assert!(matches!(
+            terminology.expand("http://example.org/unsupported"),
+            Err(TerminologyError::UnsupportedFilter(_, op)) if op == "regex"
+        ));
+        assert!(matches!(
+            terminology.expand("http://example.org/unknown"),
+            Err(TerminologyError::UnknownValueSet(_))
+        ));
+        assert!(matches!(
+            terminology.expand("http://example.org/loop"),
+            Err(TerminologyError::CircularValueSet(url)) if url == "http://example.org/loop"
+        ));
+    }
+
+    #[test]
+    fn test_validate_and_lookup() {
+        let terminology = terminology();
+        let system = Some("http://example.org/animals");
+
+        let valid = terminology
+            .validate_code("http://example.org/pets", &coding(system, "dog"))
+            .unwrap();
+        assert!(valid.result);
+        assert_eq!(valid.display.as_deref(), Some("Dog"));
+
+        assert!(terminology
+            .member_of("http://example.org/pets", &coding(None, "bird"))
+            .unwrap());
+        assert!(!terminology
+            .member_of("http://example.org/pets", &coding(system, "cat"))
+            .unwrap());
+        assert!(!terminology
+            .member_of(
+                "http://example.org/pets",
+                &coding(Some("http://other.org"), "dog")
+            )
+            .unwrap());
+
+        let lookup = terminology.lookup(&coding(system, "cat")).unwrap().unwrap();
+        assert_eq!(lookup.name, "Animals");
+        assert_eq!(lookup.version.as_deref(), Some("1.0"));
+        assert_eq!(lookup.display.as_deref(), Some("Cat"));
+        assert_eq!(terminology.lookup(&coding(system, "fish")).unwrap(), None);
+
+        let invalid = terminology
+            .validate_code_system("http://example.org/animals", &coding(None, "fish"))
+            .unwrap();
+        assert!(!invalid.result);
+        assert!(invalid.message.is_some());
+    }
+
+    #[test]
+    fn test_subsumes() {
+        let terminology = terminology();
+        let subsumes = |a, b| {
+            terminology
+                .subsumes("http://example.org/animals", a, b)
+                .unwrap()
+        };
+        assert_eq!(
+            subsumes("animal", "dog"),
+            Some(SubsumptionOutcome::Subsumes)
+        );
+        assert_eq!(
+            subsumes("cat", "mammal"),
+            Some(SubsumptionOutcome::SubsumedBy)
+        );
+        assert_eq!(
+            subsumes("bird", "bird"),
+            Some(SubsumptionOutcome::Equivalent)
+        );
+        assert_eq!(
+            subsumes("bird", "cat"),
+            Some(SubsumptionOutcome::NotSubsumed)
+        );
+        assert_eq!(subsumes("bird", "fish"), None);
+    }
+
+    #[test]
+    fn test_translate() {
+        let terminology = terminology();
+        let translations = terminology
+            .translate(
+                "http://hl7.org/fhir/ConceptMap/cm-administrative-gender-v2",
+                &coding(Some("http://hl7.org/fhir/administrative-gender"), "male"),
+            )
+            .unwrap();
+        assert_eq!(translations.len(), 1);
+        assert_eq!(translations[0].equivalence, "equivalent");
+        assert_eq!(
+            translations[0].concept.system.as_deref(),
+            Some("http://terminology.hl7.org/CodeSystem/v2-0001")
+        );
+        assert_eq!(translations[0].concept.code, "M");
+    }
+}
@@ -0,0 +1,56 @@
+use crate::fhirpath::Collection;
+use log::debug;
+use std::sync::{Arc, Mutex};
+
+/// Receives the collections labelled by `trace()` during evaluation
+pub trait TraceSink: Send + Sync {
+    fn trace(&self, name: &str, collection: &Collection);
+}
+
+impl<T: TraceSink + ?Sized> TraceSink for Arc<T> {
+    fn trace(&self, name: &str, collection: &Collection) {
+        self.as_ref().trace(name, collection)
+    }
+}
+
+/// Writes traces to the `log` crate at debug level
+pub struct LogTraceSink;
+
+impl TraceSink for LogTraceSink {
+    fn trace(&self, name: &str, collection: &Collection) {
+        debug!("trace {}: {}", name, collection);
+    }
+}
+
+/// Discards traces
+pub struct NoopTraceSink;
+
+impl TraceSink for NoopTraceSink {
+    fn trace(&self, _: &str, _: &Collection) {}
+}
+
+/// Keeps traces in memory, e.g. for tests to inspect; share it with the context through an `Arc`
+#[derive(Default)]
+pub struct VecTraceSink(Mutex<Vec<(String, Collection)>>);
+
+impl VecTraceSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The traces received so far, in order
+    pub fn traces(&self) -> Vec<(String, Collection)> {
+        self.0
+            .lock()
+            .map(|traces| traces.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl TraceSink for VecTraceSink {
+    fn trace(&self, name: &str, collection: &Collection) {
+        if let Ok(mut traces) = self.0.lock() {
+            traces.push((name.to_string(), collection.clone()));
+        }
+    }
+}
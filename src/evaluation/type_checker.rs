@@ -0,0 +1,990 @@
+use super::*;
+use crate::fhir::{self, ElementDefinition, Model, SYSTEM_TYPE_PREFIX};
+use crate::fhirpath::{
+    Type, ANY, BOOLEAN, DATE, DATETIME, DECIMAL, FHIR_QUANTITY_TYPES, INTEGER, LONG, QUANTITY,
+    STRING, TIME,
+};
+use crate::parser::{
+    ASTNode, AdditiveOperator, EqualityOperator, MembershipOperator, MultiplicativeOperator,
+    TypeOperator,
+};
+use std::collections::HashMap;
+use std::fmt;
+
+const SYSTEM_TYPES: [Type; 10] = [
+    BOOLEAN, STRING, INTEGER, LONG, DECIMAL, DATE, DATETIME, TIME, QUANTITY, ANY,
+];
+
+/// How many items a collection may hold, with no upper bound if `max` is `None`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cardinality {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+impl Cardinality {
+    pub const EMPTY: Cardinality = Cardinality::new(0, Some(0));
+    pub const SINGLE: Cardinality = Cardinality::new(1, Some(1));
+    pub const OPTIONAL: Cardinality = Cardinality::new(0, Some(1));
+    pub const MANY: Cardinality = Cardinality::new(0, None);
+
+    pub const fn new(min: u32, max: Option<u32>) -> Self {
+        Cardinality { min, max }
+    }
+
+    pub fn is_singleton(&self) -> bool {
+        self.max.is_some_and(|max| max <= 1)
+    }
+
+    /// The cardinality of a child element selected from each item of a collection
+    fn times(self, other: Cardinality) -> Cardinality {
+        let max = match (self.max, other.max) {
+            (Some(0), _) | (_, Some(0)) => Some(0),
+            (Some(a), Some(b)) => Some(a.saturating_mul(b)),
+            _ => None,
+        };
+        Cardinality::new(self.min.saturating_mul(other.min), max)
+    }
+
+    /// The cardinality of two collections combined
+    fn plus(self, other: Cardinality) -> Cardinality {
+        let max = self.max.zip(other.max).map(|(a, b)| a.saturating_add(b));
+        Cardinality::new(self.min.saturating_add(other.min), max)
+    }
+
+    /// The cardinality of one collection or the other
+    fn either(self, other: Cardinality) -> Cardinality {
+        let max = self.max.zip(other.max).map(|(a, b)| a.max(b));
+        Cardinality::new(self.min.min(other.min), max)
+    }
+
+    fn optional(self) -> Cardinality {
+        Cardinality::new(0, self.max)
+    }
+}
+
+/// The type of each item of a collection and how many items it may hold, as inferred by the
+/// type checker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticType {
+    types: Vec<ItemType>,
+    cardinality: Cardinality,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ItemType {
+    name: Type,
+    /// The element that the children of a BackboneElement are defined beneath
+    element: Option<&'static str>,
+}
+
+impl StaticType {
+    pub fn new(name: Type, cardinality: Cardinality) -> Self {
+        StaticType {
+            types: vec![ItemType::new(name)],
+            cardinality,
+        }
+    }
+
+    pub fn any() -> Self {
+        Self::new(ANY, Cardinality::MANY)
+    }
+
+    /// The type of a resource or data type, or of an element given by its path (e.g.
+    /// `Patient.contact`), for evaluating an expression against a single item of that type
+    pub fn of(name: &str) -> Option<Self> {
+        Self::in_model(fhir::default_model().ok()?, name)
+    }
+
+    /// The type of a resource, data type or element in a model other than the default
+    pub fn in_model(model: &'static Model, name: &str) -> Option<Self> {
+        let types = match name.split_once('.') {
+            Some((root, _)) => {
+                let element = model.structure_definition(root)?.element(name)?;
+                element_types(model, element)
+            }
+            None => vec![ItemType::new(model.fhir_type(name)?)],
+        };
+        Some(StaticType {
+            types,
+            cardinality: Cardinality::SINGLE,
+        })
+    }
+
+    /// The possible types of each item, several for a choice element
+    pub fn types(&self) -> Vec<Type> {
+        self.types.iter().map(|t| t.name).collect()
+    }
+
+    pub fn cardinality(&self) -> Cardinality {
+        self.cardinality
+    }
+
+    fn with_cardinality(&self, cardinality: Cardinality) -> Self {
+        StaticType {
+            types: self.types.clone(),
+            cardinality,
+        }
+    }
+
+    fn is_any(&self) -> bool {
+        self.types.iter().any(|t| t.name == ANY)
+    }
+
+    /// The System types the items convert to, e.g. `System.String` for a `FHIR.code`
+    fn system_types(&self, model: &'static Model) -> Vec<Type> {
+        self.types.iter().map(|t| t.system_type(model)).collect()
+    }
+
+    fn union(&self, other: &StaticType, cardinality: Cardinality) -> Self {
+        let mut types = self.types.clone();
+        for item in &other.types {
+            if !types.contains(item) {
+                types.push(item.clone());
+            }
+        }
+        StaticType { types, cardinality }
+    }
+}
+
+impl fmt::Display for StaticType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.types().as_slice() {
+            [name] => write!(f, "{}", name)?,
+            names => write!(f, "({})", names.join(" | "))?,
+        }
+        write!(f, "[{}..", self.cardinality.min)?;
+        match self.cardinality.max {
+            Some(max) => write!(f, "{}]", max),
+            None => write!(f, "*]"),
+        }
+    }
+}
+
+impl ItemType {
+    fn new(name: Type) -> Self {
+        ItemType {
+            name,
+            element: None,
+        }
+    }
+
+    /// The definitions of the children of items of this type
+    fn child_elements(&self, model: &'static Model) -> Vec<&'static ElementDefinition> {
+        let name = self.name.strip_prefix("FHIR.").unwrap_or(self.name);
+        let path = self.element.unwrap_or(name);
+        let root = path.split('.').next().unwrap_or(path);
+        match model.structure_definition(root) {
+            Some(definition) if self.name.starts_with("FHIR.") => {
+                definition.child_elements(path).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn system_type(&self, model: &'static Model) -> Type {
+        if self.name.starts_with("System.") {
+            return self.name;
+        }
+        if FHIR_QUANTITY_TYPES.contains(&self.name) {
+            return QUANTITY;
+        }
+        // `integer64` values are Longs, whichever System type the snapshot gives them
+        if self.name == "FHIR.integer64" {
+            return LONG;
+        }
+        let value = self
+            .child_elements(model)
+            .into_iter()
+            .find(|element| element.name() == "value");
+        match value.map(|value| element_types(model, value)).as_deref() {
+            Some([value]) if value.name.starts_with("System.") => value.name,
+            _ => self.name,
+        }
+    }
+}
+
+/// The types an element may have
+fn element_types(model: &Model, element: &'static ElementDefinition) -> Vec<ItemType> {
+    if let Some(reference) = &element.content_reference {
+        let reference = reference.rsplit('#').next().unwrap_or(reference);
+        return vec![ItemType {
+            name: "FHIR.BackboneElement",
+            element: Some(reference),
+        }];
+    }
+    let types: Vec<ItemType> = element
+        .r#type
+        .iter()
+        .map(|t| match t.code.as_str() {
+            "BackboneElement" | "Element" => ItemType {
+                name: model.fhir_type(&t.code).unwrap_or(ANY),
+                element: Some(&element.path),
+            },
+            code => match code.strip_prefix(SYSTEM_TYPE_PREFIX) {
+                Some(system) => ItemType::new(system_type(system).unwrap_or(ANY)),
+                None => ItemType::new(model.fhir_type(code).unwrap_or(ANY)),
+            },
+        })
+        .collect();
+    if types.is_empty() {
+        vec![ItemType::new(ANY)]
+    } else {
+        types
+    }
+}
+
+fn element_cardinality(element: &ElementDefinition) -> Cardinality {
+    let max = match element.max.as_deref() {
+        Some("*") => None,
+        Some(max) => max.parse().ok(),
+        None => None,
+    };
+    Cardinality::new(element.min.unwrap_or(0), max)
+}
+
+fn system_type(name: &str) -> Option<Type> {
+    let qualified = format!("System.{}", name.strip_prefix("System.").unwrap_or(name));
+    SYSTEM_TYPES.iter().find(|t| **t == qualified).copied()
+}
+
+/// Looks up the type named by a type specifier, as in `ofType(Quantity)`.  Unqualified names are
+/// looked up in the FHIR model before the System types.
+fn specifier_type(model: &Model, specifier: &str) -> Option<ItemType> {
+    let (namespace, name) = match specifier.split_once('.') {
+        Some((namespace, name)) => (Some(namespace), name),
+        None => (None, specifier),
+    };
+    let fhir = || model.fhir_type(name).map(ItemType::new);
+    let system = || system_type(name).map(ItemType::new);
+    match namespace {
+        Some("FHIR") => fhir(),
+        Some("System") => system(),
+        Some(_) => None,
+        None => fhir().or_else(system),
+    }
+}
+
+/// What the type checker knows about a function: the input it accepts, and the result it gives
+struct Signature {
+    /// The System types the input may have, or none if it may have any
+    input: &'static [Type],
+    /// Whether the input must hold at most one item
+    singleton: bool,
+    /// Whether the parameters are evaluated against each item of the input, as by `where()`
+    iterates: bool,
+    result: Output,
+}
+
+enum Output {
+    /// A single item of the type, e.g. from `count()`
+    Single(Type),
+    /// At most one item of the type, e.g. from `toInteger()`
+    Optional(Type),
+    Many(Type),
+    /// The input unchanged, e.g. from `trace()`
+    Input,
+    /// Some of the items of the input, e.g. from `where()`
+    Subset,
+    /// One of the items of the input, e.g. from `first()`
+    Item,
+    /// At most one item of the input's type, e.g. from `abs()`
+    SameType,
+    /// The value of the first parameter for each item, e.g. from `select()`
+    Projection,
+    /// The values of the first parameter, applied repeatedly, from `repeat()`
+    Repeat,
+    /// The input with the values of the first parameter, e.g. from `union()`
+    Union,
+    /// The value of the second or third parameter, from `iif()`
+    Branches,
+}
+
+const NUMBERS: &[Type] = &[INTEGER, LONG, DECIMAL, QUANTITY];
+const STRINGS: &[Type] = &[STRING];
+const TEMPORAL: &[Type] = &[DATE, DATETIME, TIME];
+
+fn signature(name: &str) -> Option<Signature> {
+    let (input, singleton, iterates, result) = match name {
+        "empty" | "allTrue" | "anyTrue" | "allFalse" | "anyFalse" | "isDistinct" | "hasValue"
+        | "htmlChecks" | "subsetOf" | "supersetOf" | "conformsTo" => {
+            (&[][..], false, false, Output::Single(BOOLEAN))
+        }
+        "exists" | "all" => (&[][..], false, true, Output::Single(BOOLEAN)),
+        "count" => (&[][..], false, false, Output::Single(INTEGER)),
+        "where" => (&[][..], false, true, Output::Subset),
+        "select" => (&[][..], false, true, Output::Projection),
+        "repeat" => (&[][..], false, true, Output::Repeat),
+        "distinct" | "tail" | "skip" | "take" | "intersect" | "exclude" | "slice" => {
+            (&[][..], false, false, Output::Subset)
+        }
+        "first" | "last" => (&[][..], false, false, Output::Item),
+        "single" => (&[][..], true, false, Output::Item),
+        "union" | "combine" => (&[][..], false, false, Output::Union),
+        "iif" => (&[][..], false, false, Output::Branches),
+        "trace" => (&[][..], false, true, Output::Input),
+        "checkModifiers" | "defineVariable" => (&[][..], false, false, Output::Input),
+        "not" => (&[BOOLEAN][..], true, false, Output::Optional(BOOLEAN)),
+        "toBoolean" => (&[][..], true, false, Output::Optional(BOOLEAN)),
+        "toInteger" => (&[][..], true, false, Output::Optional(INTEGER)),
+        "toLong" => (&[][..], true, false, Output::Optional(LONG)),
+        "toDecimal" => (&[][..], true, false, Output::Optional(DECIMAL)),
+        "toString" => (&[][..], true, false, Output::Optional(STRING)),
+        "toDate" => (&[][..], true, false, Output::Optional(DATE)),
+        "toDateTime" => (&[][..], true, false, Output::Optional(DATETIME)),
+        "toTime" => (&[][..], true, false, Output::Optional(TIME)),
+        "toQuantity" => (&[][..], true, false, Output::Optional(QUANTITY)),
+        name if name.starts_with("convertsTo") => (&[][..], true, false, Output::Optional(BOOLEAN)),
+        "indexOf" | "length" => (STRINGS, true, false, Output::Optional(INTEGER)),
+        "substring" | "upper" | "lower" | "replace" | "replaceMatches" | "trim" => {
+            (STRINGS, true, false, Output::Optional(STRING))
+        }
+        "startsWith" | "endsWith" | "contains" | "matches" => {
+            (STRINGS, true, false, Output::Optional(BOOLEAN))
+        }
+        "split" | "toChars" => (STRINGS, true, false, Output::Many(STRING)),
+        "join" => (STRINGS, false, false, Output::Optional(STRING)),
+        "abs" => (NUMBERS, true, false, Output::SameType),
+        "ceiling" | "floor" | "truncate" => (NUMBERS, true, false, Output::Optional(INTEGER)),
+        "exp" | "ln" | "log" | "power" | "round" | "sqrt" => {
+            (NUMBERS, true, false, Output::Optional(DECIMAL))
+        }
+        "now" => (&[][..], false, false, Output::Single(DATETIME)),
+        "today" => (&[][..], false, false, Output::Single(DATE)),
+        "timeOfDay" => (&[][..], false, false, Output::Single(TIME)),
+        "duration" | "difference" => (TEMPORAL, true, false, Output::Optional(INTEGER)),
+        "children" | "descendants" | "type" => (&[][..], false, false, Output::Many(ANY)),
+        "extension" => (&[][..], false, false, Output::Many("FHIR.Extension")),
+        "getValue" => (&[][..], true, false, Output::Optional(ANY)),
+        "resolve" => (&[][..], false, false, Output::Many("FHIR.Resource")),
+        "elementDefinition" => (
+            &[][..],
+            false,
+            false,
+            Output::Many("FHIR.ElementDefinition"),
+        ),
+        "memberOf" | "subsumes" | "subsumedBy" | "comparable" => {
+            (&[][..], true, false, Output::Optional(BOOLEAN))
+        }
+        _ => return None,
+    };
+    Some(Signature {
+        input,
+        singleton,
+        iterates,
+        result,
+    })
+}
+
+/// Infers the type and cardinality of expressions from the FHIR model without evaluating them,
+/// reporting the problems it finds along the way
+pub struct TypeChecker {
+    model: &'static Model,
+    root: StaticType,
+    input: StaticType,
+    variables: HashMap<String, StaticType>,
+    errors: Vec<TypeCheckError>,
+}
+
+impl TypeChecker {
+    /// Checks expressions against a resource or data type, or an element given by its path
+    pub fn new(root: &str) -> Result<Self, TypeCheckError> {
+        let model =
+            fhir::default_model().map_err(|err| TypeCheckError::NoModel(err.to_string()))?;
+        Self::for_model(model, root)
+    }
+
+    /// Checks expressions against a resource, data type or element of a model other than the
+    /// default
+    pub fn for_model(model: &'static Model, root: &str) -> Result<Self, TypeCheckError> {
+        let root = StaticType::in_model(model, root)
+            .ok_or_else(|| TypeCheckError::UnknownType(root.to_string()))?;
+        Ok(TypeChecker {
+            model,
+            input: root.clone(),
+            root,
+            variables: HashMap::new(),
+            errors: Vec::new(),
+        })
+    }
+
+    /// Declares the type of an environment variable, available to expressions as `%name`
+    pub fn with_variable(mut self, name: &str, static_type: StaticType) -> Self {
+        self.variables.insert(name.to_string(), static_type);
+        self
+    }
+
+    /// Checks an expression, giving its type if no problems were found
+    pub fn check(mut self, node: &ASTNode) -> Result<StaticType, Vec<TypeCheckError>> {
+        let result = self.infer(node);
+        if self.errors.is_empty() {
+            Ok(result)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    /// Infers the type of a node, recording any problems found.  Parts of the expression that
+    /// cannot be typed are given the type `System.Any[0..*]`, so that one problem is not
+    /// reported again by every expression containing it.
+    pub fn infer(&mut self, node: &ASTNode) -> StaticType {
+        let start = self.errors.len();
+        let result = self.infer_node(node);
+        self.locate_errors(start, node);
+        result
+    }
+
+    /// Records where the errors found since `start` were found, unless they are already located
+    fn locate_errors(&mut self, start: usize, node: &ASTNode) {
+        let Some(span) = node.span() else {
+            return;
+        };
+        for error in &mut self.errors[start..] {
+            let found = std::mem::replace(error, TypeCheckError::UnsupportedExpression);
+            *error = found.located(span, function_name(node));
+        }
+    }
+
+    pub fn errors(&self) -> &[TypeCheckError] {
+        &self.errors
+    }
+
+    fn infer_node(&mut self, node: &ASTNode) -> StaticType {
+        if let Some(literal) = literal(node) {
+            return match literal {
+                Ok(values) => StaticType {
+                    types: values
+                        .iter()
+                        .map(|v| ItemType::new(v.data_type()))
+                        .collect(),
+                    cardinality: Cardinality::SINGLE,
+                },
+                Err(err) => self.error(TypeCheckError::InvalidLiteral(err.to_string())),
+            };
+        }
+        match node {
+            ASTNode::Identifier(name) => {
+                let input = self.input.clone();
+                // An expression may start with the type of its input, as in `Patient.name`
+                let is_input_type = name.starts_with(char::is_uppercase)
+                    && input.types.iter().any(|t| {
+                        t.name
+                            .strip_prefix("FHIR.")
+                            .is_some_and(|actual| self.model.is_subtype(actual, name))
+                    });
+                if is_input_type {
+                    return input;
+                }
+                self.navigate(&input, name)
+            }
+            ASTNode::Variable(name, _) => self.variable(name),
+            ASTNode::InvocationExpression(left, right) => {
+                if matches!(left.as_ref(), ASTNode::Variable(name, _) if name == "terminologies") {
+                    if let ASTNode::Function(_, params, _) = right.as_ref() {
+                        for param in param_nodes(params) {
+                            self.infer(param);
+                        }
+                    }
+                    return StaticType::any();
+                }
+                let input = self.infer(left);
+                match right.as_ref() {
+                    ASTNode::Identifier(name) => self.navigate(&input, name),
+                    ASTNode::Function(..) => {
+                        let start = self.errors.len();
+                        let result = self.function(&input, right);
+                        self.locate_errors(start, right);
+                        result
+                    }
+                    _ => self.error(TypeCheckError::UnsupportedExpression),
+                }
+            }
+            ASTNode::Function(..) => {
+                let input = self.input.clone();
+                self.function(&input, node)
+            }
+            ASTNode::ParamList(None) => StaticType::new(ANY, Cardinality::EMPTY),
+            ASTNode::ParamList(Some(param)) => self.infer(param),
+            ASTNode::Union(left, right) => {
+                let (left, right) = (self.infer(left), self.infer(right));
+                left.union(&right, left.cardinality.plus(right.cardinality))
+            }
+            ASTNode::UnionExpression(left, right, _) => {
+                let (left, right) = (self.infer(left), self.infer(right));
+                // Duplicates are removed, so all the items may be the same one
+                let max = left.cardinality.plus(right.cardinality).max;
+                let min = left.cardinality.min.max(right.cardinality.min).min(1);
+                left.union(&right, Cardinality::new(min, max))
+            }
+            ASTNode::MembershipExpression(left, op, right, _) => {
+                let (left, right) = (self.infer(left), self.infer(right));
+                match op {
+                    MembershipOperator::In => self.expect_singleton(&op.to_string(), &left),
+                    MembershipOperator::Contains => self.expect_singleton(&op.to_string(), &right),
+                }
+                StaticType::new(BOOLEAN, Cardinality::OPTIONAL)
+            }
+            ASTNode::EqualityExpression(left, op, right, _) => {
+                self.infer(left);
+                self.infer(right);
+                let cardinality = match op {
+                    EqualityOperator::Equal | EqualityOperator::NotEqual => Cardinality::OPTIONAL,
+                    _ => Cardinality::SINGLE,
+                };
+                StaticType::new(BOOLEAN, cardinality)
+            }
+            ASTNode::InequalityExpression(left, op, right, _) => {
+                let (left, right) = (self.infer(left), self.infer(right));
+                self.expect_singleton(&op.to_string(), &left);
+                self.expect_singleton(&op.to_string(), &right);
+                if !left.is_any() && !right.is_any() && !comparable(self.model, &left, &right) {
+                    self.errors.push(TypeCheckError::InvalidOperands {
+                        operator: op.to_string(),
+                        left,
+                        right,
+                    });
+                }
+                StaticType::new(BOOLEAN, Cardinality::OPTIONAL)
+            }
+            ASTNode::AdditiveExpression(left, op, right, _) => {
+                let (left, right) = (self.infer(left), self.infer(right));
+                match op {
+                    AdditiveOperator::Concatenate => {
+                        self.expect_singleton(&op.to_string(), &left);
+                        self.expect_singleton(&op.to_string(), &right);
+                        StaticType::new(STRING, Cardinality::SINGLE)
+                    }
+                    _ => self.arithmetic(&op.to_string(), &left, &right, |l, r| {
+                        additive_type(*op, l, r)
+                    }),
+                }
+            }
+            ASTNode::MultiplicativeExpression(left, op, right, _) => {
+                let (left, right) = (self.infer(left), self.infer(right));
+                self.arithmetic(&op.to_string(), &left, &right, |l, r| {
+                    multiplicative_type(*op, l, r)
+                })
+            }
+            ASTNode::BooleanExpression(left, op, right, _) => {
+                let (left, right) = (self.infer(left), self.infer(right));
+                self.expect_singleton(&op.to_string(), &left);
+                self.expect_singleton(&op.to_string(), &right);
+                StaticType::new(BOOLEAN, Cardinality::OPTIONAL)
+            }
+            ASTNode::TypeExpression(left, op, specifier, _) => {
+                let input = self.infer(left);
+                self.expect_singleton(&op.to_string(), &input);
+                let Some(item) = specifier_type(self.model, specifier) else {
+                    return self.error(TypeCheckError::UnknownType(specifier.clone()));
+                };
+                match op {
+                    TypeOperator::Is => StaticType::new(BOOLEAN, Cardinality::OPTIONAL),
+                    TypeOperator::As => StaticType {
+                        types: vec![item],
+                        cardinality: Cardinality::OPTIONAL,
+                    },
+                }
+            }
+            _ => self.error(TypeCheckError::UnsupportedExpression),
+        }
+    }
+
+    fn error(&mut self, error: TypeCheckError) -> StaticType {
+        self.errors.push(error);
+        StaticType::any()
+    }
+
+    fn expect_singleton(&mut self, operation: &str, input: &StaticType) {
+        if !input.cardinality.is_singleton() {
+            self.errors.push(TypeCheckError::ExpectedSingleton {
+                operation: operation.to_string(),
+                actual: input.clone(),
+            });
+        }
+    }
+
+    fn variable(&mut self, name: &str) -> StaticType {
+        match name {
+            "resource" | "rootResource" | "context" => self.root.clone(),
+            "$this" => self.input.clone(),
+            "ucum" | "sct" | "loinc" => StaticType::new(STRING, Cardinality::SINGLE),
+            "terminologies" => StaticType::new(ANY, Cardinality::SINGLE),
+            name if name.starts_with("vs-") || name.starts_with("ext-") => {
+                StaticType::new(STRING, Cardinality::SINGLE)
+            }
+            name => match self.variables.get(name) {
+                Some(static_type) => static_type.clone(),
+                None => self.error(TypeCheckError::UnknownVariable(name.to_string())),
+            },
+        }
+    }
+
+    /// The type of the children with a name of each item of the input
+    fn navigate(&mut self, input: &StaticType, name: &str) -> StaticType {
+        if input.is_any() {
+            return StaticType::any();
+        }
+        let mut result: Option<StaticType> = None;
+        for item in &input.types {
+            let Some(element) = item
+                .child_elements(self.model)
+                .into_iter()
+                .find(|el| el.name() == name)
+            else {
+                continue;
+            };
+            let child = StaticType {
+                types: element_types(self.model, element),
+                cardinality: element_cardinality(element),
+            };
+            result = Some(match result {
+                Some(result) => {
+                    let cardinality = result.cardinality.either(child.cardinality);
+                    result.union(&child, cardinality)
+                }
+                None => child,
+            });
+        }
+        match result {
+            Some(child) => child.with_cardinality(input.cardinality.times(child.cardinality)),
+            // Which elements an abstract resource has depends on the actual resource
+            None if input.types.iter().any(|t| {
+                t.name
+                    .strip_prefix("FHIR.")
+                    .is_some_and(|name| self.model.is_abstract_resource(name))
+            }) =>
+            {
+                StaticType::any()
+            }
+            None => self.error(TypeCheckError::UnknownElement {
+                r#type: input.types().join(" | "),
+                element: name.to_string(),
+            }),
+        }
+    }
+
+    fn function(&mut self, input: &StaticType, node: &ASTNode) -> StaticType {
+        let (Some(name), ASTNode::Function(_, params, _)) = (function_name(node), node) else {
+            return self.error(TypeCheckError::UnsupportedExpression);
+        };
+
+        if matches!(name, "ofType" | "is" | "as") {
+            let Some(specifier) = type_specifier(params) else {
+                return self.error(TypeCheckError::UnsupportedExpression);
+            };
+            let Some(item) = specifier_type(self.model, &specifier) else {
+                return self.error(TypeCheckError::UnknownType(specifier));
+            };
+            if name == "is" {
+                self.expect_singleton("is()", input);
+            }
+            return match name {
+                "is" => StaticType::new(BOOLEAN, Cardinality::OPTIONAL),
+                _ => StaticType {
+                    types: vec![item],
+                    cardinality: input.cardinality.optional(),
+                },
+            };
+        }
+
+        let Some(signature) = signature(name) else {
+            return self.error(TypeCheckError::UnknownFunction(name.to_string()));
+        };
+        if !signature.input.is_empty() && !input.is_any() {
+            let accepted = input.system_types(self.model).iter().any(|actual| {
+                signature.input.contains(actual)
+                    || (*actual == INTEGER && signature.input.contains(&DECIMAL))
+            });
+            if !accepted {
+                self.errors.push(TypeCheckError::InvalidInput {
+                    function: name.to_string(),
+                    expected: signature.input.to_vec(),
+                    actual: input.clone(),
+                });
+            }
+        }
+        if signature.singleton {
+            self.expect_singleton(&format!("{}()", name), input);
+        }
+
+        // Functions that evaluate their own parameters do so against their input, or each item
+        // of it, rather than the input of the expression
+        let param_nodes = param_nodes(params);
+        let context = if signature.iterates {
+            input.with_cardinality(Cardinality::SINGLE)
+        } else if EXPRESSION_FUNCTIONS.contains_key(name) {
+            input.clone()
+        } else {
+            self.input.clone()
+        };
+        let outer = std::mem::replace(&mut self.input, context);
+        let params: Vec<StaticType> = param_nodes.iter().map(|p| self.infer(p)).collect();
+        self.input = outer;
+        let param = |index: usize| params.get(index).cloned().unwrap_or_else(StaticType::any);
+
+        if let ("defineVariable", Some(ASTNode::StringLiteral(variable))) =
+            (name, param_nodes.first())
+        {
+            let value = params.get(1).cloned().unwrap_or_else(|| input.clone());
+            self.variables.insert(variable.clone(), value);
+        }
+
+        match signature.result {
+            Output::Single(name) => StaticType::new(name, Cardinality::SINGLE),
+            Output::Optional(name) => StaticType::new(name, Cardinality::OPTIONAL),
+            Output::Many(name) => StaticType::new(name, Cardinality::MANY),
+            Output::Input => input.clone(),
+            Output::Subset => input.with_cardinality(input.cardinality.optional()),
+            Output::Item => input.with_cardinality(Cardinality::new(
+                input.cardinality.min.min(1),
+                Some(input.cardinality.max.map_or(1, |max| max.min(1))),
+            )),
+            Output::SameType => input.with_cardinality(Cardinality::OPTIONAL),
+            Output::Projection => {
+                let projection = param(0);
+                projection.with_cardinality(input.cardinality.times(projection.cardinality))
+            }
+            Output::Repeat => param(0).with_cardinality(Cardinality::MANY),
+            Output::Union => {
+                let other = param(0);
+                input.union(&other, input.cardinality.plus(other.cardinality))
+            }
+            Output::Branches => {
+                let (then, otherwise) = (param(1), param(2));
+                then.union(&otherwise, then.cardinality.either(otherwise.cardinality))
+            }
+        }
+    }
+
+    /// The type of an arithmetic expression, from the type of each pair of operand items
+    fn arithmetic(
+        &mut self,
+        operator: &str,
+        left: &StaticType,
+        right: &StaticType,
+        result: impl Fn(Type, Type) -> Option<Type>,
+    ) -> StaticType {
+        self.expect_singleton(operator, left);
+        self.expect_singleton(operator, right);
+        if left.is_any() || right.is_any() {
+            return StaticType::new(ANY, Cardinality::OPTIONAL);
+        }
+        let mut types: Vec<Type> = Vec::new();
+        for l in left.system_types(self.model) {
+            for r in right.system_types(self.model) {
+                if let Some(t) = result(l, r).filter(|t| !types.contains(t)) {
+                    types.push(t);
+                }
+            }
+        }
+        if types.is_empty() {
+            return self.error(TypeCheckError::InvalidOperands {
+                operator: operator.to_string(),
+                left: left.clone(),
+                right: right.clone(),
+            });
+        }
+        StaticType {
+            types: types.into_iter().map(ItemType::new).collect(),
+            cardinality: Cardinality::OPTIONAL,
+        }
+    }
+}
+
+/// The type of the sum or difference of two numbers, or of a number and a number-like type
+fn numeric_type(left: Type, right: Type) -> Option<Type> {
+    let numbers = [INTEGER, LONG, DECIMAL];
+    if !numbers.contains(&left) || !numbers.contains(&right) {
+        return None;
+    }
+    if left == DECIMAL || right == DECIMAL {
+        Some(DECIMAL)
+    } else if left == LONG || right == LONG {
+        Some(LONG)
+    } else {
+        Some(INTEGER)
+    }
+}
+
+fn additive_type(op: AdditiveOperator, left: Type, right: Type) -> Option<Type> {
+    match (left, right) {
+        (STRING, STRING) if op == AdditiveOperator::Plus => Some(STRING),
+        (QUANTITY, QUANTITY) => Some(QUANTITY),
+        (DATE | DATETIME | TIME, QUANTITY) => Some(left),
+        _ => numeric_type(left, right),
+    }
+}
+
+fn multiplicative_type(op: MultiplicativeOperator, left: Type, right: Type) -> Option<Type> {
+    let numeric = numeric_type(left, right);
+    let scalable = |t: Type| t == QUANTITY || numeric_type(t, INTEGER).is_some();
+    match op {
+        MultiplicativeOperator::Multiply | MultiplicativeOperator::Divide
+            if left == QUANTITY || right == QUANTITY =>
+        {
+            (scalable(left) && scalable(right)).then_some(QUANTITY)
+        }
+        MultiplicativeOperator::Divide => numeric.map(|_| DECIMAL),
+        MultiplicativeOperator::Div => numeric.map(|t| if t == LONG { LONG } else { INTEGER }),
+        _ => numeric,
+    }
+}
+
+/// Whether some items of the two collections can be compared with each other
+fn comparable(model: &'static Model, left: &StaticType, right: &StaticType) -> bool {
+    let group = |t: Type| match t {
+        INTEGER | LONG | DECIMAL => "number",
+        DATE | DATETIME => "date",
+        t => t,
+    };
+    left.system_types(model).iter().any(|l| {
+        right
+            .system_types(model)
+            .iter()
+            .any(|r| group(l) == group(r))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Lexer, Parser};
+
+    fn check(root: &str, expr: &str) -> Result<StaticType, Vec<TypeCheckError>> {
+        let tokens = Lexer::new(expr).tokenize_with_spans().unwrap();
+        let ast = Parser::with_spans(tokens).parse().unwrap();
+        TypeChecker::new(root).unwrap().check(&ast)
+    }
+
+    fn infer(root: &str, expr: &str) -> String {
+        check(root, expr).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_cardinality() {
+        assert_eq!(
+            Cardinality::new(1, Some(1)).times(Cardinality::MANY),
+            Cardinality::MANY
+        );
+        assert_eq!(
+            Cardinality::new(1, Some(2)).times(Cardinality::new(1, Some(3))),
+            Cardinality::new(1, Some(6))
+        );
+        assert_eq!(
+            Cardinality::SINGLE.plus(Cardinality::OPTIONAL),
+            Cardinality::new(1, Some(2))
+        );
+        assert!(Cardinality::OPTIONAL.is_singleton());
+        assert!(!Cardinality::MANY.is_singleton());
+    }
+
+    #[test]
+    fn test_inference() {
+        assert_eq!(infer("Patient", "Patient"), "FHIR.Patient[1..1]");
+        assert_eq!(infer("Patient", "Patient.name.family"), "FHIR.string[0..*]");
+        assert_eq!(infer("Patient", "name.given"), "FHIR.string[0..*]");
+        assert_eq!(infer("Patient", "birthDate"), "FHIR.date[0..1]");
+        assert_eq!(infer("Patient", "birthDate.value"), "System.Date[0..1]");
+        assert_eq!(infer("Patient", "id"), "System.String[0..1]");
+        assert_eq!(infer("Patient", "contact.name.family"), "FHIR.string[0..*]");
+        assert_eq!(
+            infer("Patient", "deceased"),
+            "(FHIR.boolean | FHIR.dateTime)[0..1]"
+        );
+        assert_eq!(infer("Patient.contact", "name"), "FHIR.HumanName[0..1]");
+        assert_eq!(
+            infer("Observation", "value.ofType(Quantity).value"),
+            "FHIR.decimal[0..1]"
+        );
+        assert_eq!(
+            infer("Observation", "value as Quantity"),
+            "FHIR.Quantity[0..1]"
+        );
+        assert_eq!(
+            infer("Patient", "name.where(use = 'official')"),
+            "FHIR.HumanName[0..*]"
+        );
+        assert_eq!(infer("Patient", "name.select(given)"), "FHIR.string[0..*]");
+        assert_eq!(infer("Patient", "name.first().family"), "FHIR.string[0..1]");
+        assert_eq!(infer("Patient", "name.exists()"), "System.Boolean[1..1]");
+        assert_eq!(infer("Patient", "name.count() + 1"), "System.Integer[0..1]");
+        assert_eq!(infer("Patient", "1.5 * 2"), "System.Decimal[0..1]");
+        assert_eq!(infer("Patient", "birthDate + 1 year"), "System.Date[0..1]");
+        assert_eq!(infer("Patient", "%resource.active"), "FHIR.boolean[0..1]");
+        assert_eq!(
+            infer("Patient", "iif(active, 'yes', 0)"),
+            "(System.String | System.Integer)[1..1]"
+        );
+        assert_eq!(infer("Bundle", "entry.resource.name"), "System.Any[0..*]");
+    }
+
+    #[test]
+    fn test_errors() {
+        let errors = check("Patient", "Patient.nmae.family").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0],
+            TypeCheckError::UnknownElement {
+                r#type: "FHIR.Patient".to_string(),
+                element: "nmae".to_string(),
+            }
+        );
+
+        let errors = check("Patient", "Observation.status").unwrap_err();
+        assert!(matches!(
+            &errors[0],
+            TypeCheckError::UnknownElement { element, .. } if element == "Observation"
+        ));
+
+        let errors = check("Patient", "name.given.replace('a', 'b')").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].to_string(),
+            "replace() expects a single item, but its input is FHIR.string[0..*] at 11..28"
+        );
+
+        let errors = check("Patient", "name.single().family").unwrap_err();
+        assert!(matches!(
+            errors[0].kind(),
+            TypeCheckError::ExpectedSingleton { operation, .. } if operation == "single()"
+        ));
+        assert!(check("Patient", "name.first().family.single()").is_ok());
+
+        let errors = check("Patient", "birthDate.upper()").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "upper() expects a System.String, but its input is FHIR.date[0..1] at 10..17"
+        );
+
+        let errors = check("Patient", "active < 1").unwrap_err();
+        assert!(matches!(
+            errors[0].kind(),
+            TypeCheckError::InvalidOperands { operator, .. } if operator == "<"
+        ));
+
+        let errors = check("Patient", "name.frobnicate() = %missing").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert_eq!(
+            errors[0].kind(),
+            &TypeCheckError::UnknownFunction("frobnicate".to_string())
+        );
+        assert_eq!(
+            errors[1].kind(),
+            &TypeCheckError::UnknownVariable("missing".to_string())
+        );
+
+        assert!(TypeChecker::new("Patient")
+            .unwrap()
+            .with_variable("missing", StaticType::new(STRING, Cardinality::SINGLE))
+            .check(
+                &Parser::new(Lexer::new("%missing.length()").tokenize().unwrap())
+                    .parse()
+                    .unwrap()
+            )
+            .is_ok());
+        assert!(matches!(
+            TypeChecker::new("Patient.nmae"),
+            Err(TypeCheckError::UnknownType(_))
+        ));
+    }
+}
@@ -0,0 +1,952 @@
+use super::*;
+use crate::fhir::{self, ElementDefinition, Model, StructureDefinition, SYSTEM_TYPE_PREFIX};
+use crate::fhirpath::Value;
+use serde::Serialize;
+use serde_json::Value as Json;
+use std::collections::HashSet;
+use std::fmt;
+
+/// The result of validating a resource, in the form of a FHIR OperationOutcome
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[serde(tag = "resourceType")]
+pub struct OperationOutcome {
+    pub issue: Vec<Issue>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Issue {
+    pub severity: IssueSeverity,
+    pub code: IssueType,
+    pub diagnostics: String,
+    /// FHIRPath expressions locating the problem in the resource, e.g. `Patient.name[0].given`
+    pub expression: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IssueSeverity {
+    Fatal,
+    Error,
+    Warning,
+    Information,
+}
+
+/// The kinds of issue that validation reports, a subset of the FHIR `issue-type` codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IssueType {
+    /// The resource does not have the elements or shape its definition gives it
+    Structure,
+    /// A required element is missing
+    Required,
+    /// An element has an invalid value, or not the one the profile fixes
+    Value,
+    /// A code is not in the value set it is bound to
+    CodeInvalid,
+    /// A constraint on an element does not hold
+    Invariant,
+    /// The validator cannot check something, such as a binding to an unknown value set
+    NotSupported,
+}
+
+impl OperationOutcome {
+    /// Whether there are no errors, only warnings and information
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &Issue> {
+        self.issue
+            .iter()
+            .filter(|issue| issue.severity <= IssueSeverity::Error)
+    }
+
+    /// The outcome as an OperationOutcome resource in the FHIR JSON format
+    pub fn to_json(&self) -> Json {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for IssueSeverity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self {
+            IssueSeverity::Fatal => "fatal",
+            IssueSeverity::Error => "error",
+            IssueSeverity::Warning => "warning",
+            IssueSeverity::Information => "information",
+        };
+        write!(f, "{}", severity)
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.severity)?;
+        if !self.expression.is_empty() {
+            write!(f, " at {}", self.expression.join(", "))?;
+        }
+        write!(f, ": {}", self.diagnostics)
+    }
+}
+
+/// Validates resources in the FHIR JSON format against the base definitions of their types or
+/// against profiles.  Each element is checked for its cardinality, that it is of one of the
+/// types the definition allows, that its value is valid for its type and matches any
+/// `fixed[x]` or `pattern[x]`, and that its codes are in the value sets of required bindings;
+/// elements the definition does not have are reported as unknown.  Elements of data types are
+/// checked against the definitions of their types, unless the profile constrains their
+/// children itself.  Slices are not checked.
+///
+/// Models, profiles and terminology come from the evaluation context.  Bindings are only
+/// checked if the context has a terminology service, and constraints only if the validator is
+/// given an `InvariantChecker`.
+pub struct Validator<'a> {
+    context: &'a EvaluationContext,
+    invariants: Option<InvariantChecker<'a>>,
+}
+
+/// Validates a resource against a profile with the default evaluation context
+pub fn validate(resource: &Json, profile: &StructureDefinition) -> OperationOutcome {
+    Validator::new(&EvaluationContext::default()).validate(resource, profile)
+}
+
+impl<'a> Validator<'a> {
+    pub fn new(context: &'a EvaluationContext) -> Self {
+        Validator {
+            context,
+            invariants: None,
+        }
+    }
+
+    /// Also checks the constraints of the definitions, other than those the checker suppresses.
+    /// They are only checked in resources that are otherwise structurally valid.
+    pub fn with_invariants(mut self, checker: InvariantChecker<'a>) -> Self {
+        self.invariants = Some(checker);
+        self
+    }
+
+    /// Validates a resource against the base definition of its type
+    pub fn validate_resource(&self, resource: &Json) -> OperationOutcome {
+        self.run(resource, None)
+    }
+
+    /// Validates a resource against a profile, or the definition of a resource type it is
+    /// derived from
+    pub fn validate(&self, resource: &Json, profile: &StructureDefinition) -> OperationOutcome {
+        self.run(resource, Some(profile))
+    }
+
+    fn run(&self, resource: &Json, profile: Option<&StructureDefinition>) -> OperationOutcome {
+        let model = match self.context.model() {
+            Ok(model) => model,
+            // Nothing can be checked without the definitions of the resource's type
+            Err(err) => {
+                return OperationOutcome {
+                    issue: vec![Issue {
+                        severity: IssueSeverity::Fatal,
+                        code: IssueType::NotSupported,
+                        diagnostics: format!("cannot validate: {}", err),
+                        expression: Vec::new(),
+                    }],
+                }
+            }
+        };
+        let mut validation = Validation::new(self.context, model);
+        validation.resource(resource, profile, None);
+        self.invariants(&mut validation, resource, profile);
+        validation.outcome()
+    }
+
+    fn invariants(
+        &self,
+        validation: &mut Validation,
+        resource: &Json,
+        profile: Option<&StructureDefinition>,
+    ) {
+        let Some(checker) = &self.invariants else {
+            return;
+        };
+        // Resources that cannot be read have already been reported
+        let Ok(node) = validation.model.load_resource(resource) else {
+            return;
+        };
+        let failures = match profile {
+            Some(profile) => checker.check_profile(&node, profile),
+            None => checker.check(&node),
+        };
+        for failure in failures {
+            match &failure.error {
+                Some(err) => validation.issue(
+                    IssueSeverity::Warning,
+                    IssueType::NotSupported,
+                    &failure.location,
+                    format!("cannot check {}: {}", failure.key, err),
+                ),
+                None => validation.issue(
+                    failure.severity,
+                    IssueType::Invariant,
+                    &failure.location,
+                    format!("{}: {}", failure.key, failure.human),
+                ),
+            }
+        }
+    }
+}
+
+/// The issues found so far in validating a resource
+struct Validation<'a> {
+    context: &'a EvaluationContext,
+    model: &'static Model,
+    issues: Vec<Issue>,
+}
+
+impl<'a> Validation<'a> {
+    fn new(context: &'a EvaluationContext, model: &'static Model) -> Self {
+        Validation {
+            context,
+            model,
+            issues: Vec::new(),
+        }
+    }
+
+    fn outcome(self) -> OperationOutcome {
+        OperationOutcome { issue: self.issues }
+    }
+
+    fn issue(&mut self, severity: IssueSeverity, code: IssueType, location: &str, message: String) {
+        self.issues.push(Issue {
+            severity,
+            code,
+            diagnostics: message,
+            expression: vec![location.to_string()],
+        });
+    }
+
+    fn error(&mut self, code: IssueType, location: &str, message: String) {
+        self.issue(IssueSeverity::Error, code, location, message);
+    }
+
+    /// Checks a resource, at the root or contained in another at a location
+    fn resource(
+        &mut self,
+        json: &Json,
+        profile: Option<&StructureDefinition>,
+        location: Option<&str>,
+    ) {
+        let model = self.model;
+        let Some(resource_type) = json.get("resourceType").and_then(Json::as_str) else {
+            let location = location.unwrap_or("Resource");
+            self.error(
+                IssueType::Structure,
+                location,
+                "missing resourceType".to_string(),
+            );
+            return;
+        };
+        let location = location.unwrap_or(resource_type);
+        let Some(base) = model.resources().get(resource_type) else {
+            self.error(
+                IssueType::Structure,
+                location,
+                format!("unknown resource type {}", resource_type),
+            );
+            return;
+        };
+
+        let definition = match profile {
+            Some(profile) if profile.r#type == resource_type => profile,
+            // A profile of an abstract type, such as DomainResource, says nothing of the
+            // elements of the resource's own type
+            Some(profile) if model.is_subtype(resource_type, &profile.r#type) => base,
+            Some(profile) => {
+                self.error(
+                    IssueType::Structure,
+                    location,
+                    format!(
+                        "a {} cannot conform to {}, a profile of {}",
+                        resource_type, profile.url, profile.r#type
+                    ),
+                );
+                return;
+            }
+            None => base,
+        };
+        self.object(json, definition, resource_type, location);
+    }
+
+    /// Checks the members of an object against the children of an element of a definition
+    fn object(&mut self, json: &Json, definition: &StructureDefinition, id: &str, location: &str) {
+        let Some(object) = json.as_object() else {
+            self.error(
+                IssueType::Structure,
+                location,
+                format!("expected an object, not {}", json),
+            );
+            return;
+        };
+
+        let mut consumed = HashSet::new();
+        if definition.kind == "resource" && id == definition.r#type {
+            consumed.insert("resourceType");
+        }
+        for element in child_elements(definition, id) {
+            self.element(object, definition, element, location, &mut consumed);
+        }
+        for key in object.keys().filter(|key| !consumed.contains(key.as_str())) {
+            let name = key.strip_prefix('_').unwrap_or(key);
+            self.error(
+                IssueType::Structure,
+                &format!("{}.{}", location, name),
+                format!("unknown element {}", name),
+            );
+        }
+    }
+
+    /// Checks the occurrences of an element among the members of an object
+    fn element<'j>(
+        &mut self,
+        object: &'j serde_json::Map<String, Json>,
+        definition: &StructureDefinition,
+        element: &ElementDefinition,
+        location: &str,
+        consumed: &mut HashSet<&'j str>,
+    ) {
+        let model = self.model;
+        let name = element.name();
+        // Whether an element is an array in JSON depends on the base definition, not profiles
+        let repeats = match &element.base {
+            Some(base) => base.max != "1",
+            None => element.max.as_deref() != Some("1"),
+        };
+
+        // The JSON properties the element can be in, e.g. `valueQuantity`, with their types
+        let mut properties = Vec::new();
+        if element.is_choice() {
+            for key in object.keys() {
+                let Some(suffix) = key
+                    .strip_prefix('_')
+                    .unwrap_or(key)
+                    .strip_prefix(name)
+                    .filter(|suffix| suffix.starts_with(char::is_uppercase))
+                else {
+                    continue;
+                };
+                let plain = key.strip_prefix('_').unwrap_or(key);
+                match element
+                    .r#type
+                    .iter()
+                    .find(|t| capitalize(&t.code) == suffix)
+                {
+                    Some(t) if plain == key || !object.contains_key(plain) => {
+                        properties.push((plain, t.code.as_str()))
+                    }
+                    Some(_) => {}
+                    None if model.fhir_type(&uncapitalize(suffix)).is_some()
+                        || model.fhir_type(suffix).is_some() =>
+                    {
+                        consumed.insert(key);
+                        self.error(
+                            IssueType::Structure,
+                            &format!("{}.{}", location, name),
+                            format!("{} cannot be of type {}", element.path, suffix),
+                        );
+                    }
+                    None => {}
+                }
+            }
+        } else {
+            let code = element.r#type.first().map_or("", |t| t.code.as_str());
+            if let Some((key, _)) = object.get_key_value(name) {
+                properties.push((key.as_str(), code));
+            } else if let Some((key, _)) = object.get_key_value(&format!("_{}", name)) {
+                properties.push((&key[1..], code));
+            }
+        }
+
+        let mut count = 0;
+        for (key, code) in properties {
+            let extension_key = format!("_{}", key);
+            let value = object.get(key);
+            let extras = object.get_key_value(&extension_key);
+            consumed.insert(key);
+            if let Some((extension_key, _)) = extras {
+                consumed.insert(extension_key);
+            }
+
+            let mut item_location = format!("{}.{}", location, name);
+            if element.is_choice() {
+                item_location = format!("{}.ofType({})", item_location, code);
+            }
+            for json in value.into_iter().chain(extras.map(|(_, extras)| extras)) {
+                if json.is_array() != repeats {
+                    let message = if repeats {
+                        format!("{} must be an array", element.path)
+                    } else {
+                        format!("{} must not be an array", element.path)
+                    };
+                    self.error(IssueType::Structure, &item_location, message);
+                }
+            }
+
+            let items = fhir::items(value, extras.map(|(_, extras)| extras));
+            count += items.len();
+            for (i, (value, extras)) in items.into_iter().enumerate() {
+                let location = if repeats {
+                    format!("{}[{}]", item_location, i)
+                } else {
+                    item_location.clone()
+                };
+                self.item(value, extras, definition, element, code, &location);
+            }
+        }
+
+        let location = format!("{}.{}", location, name);
+        let min = element.min.unwrap_or(0) as usize;
+        if count < min {
+            self.error(
+                IssueType::Required,
+                &location,
+                format!(
+                    "{} requires at least {} {}, but has {}",
+                    element.path,
+                    min,
+                    if min == 1 { "item" } else { "items" },
+                    count
+                ),
+            );
+        }
+        let max = match element.max.as_deref() {
+            Some("*") | None => usize::MAX,
+            Some(max) => max.parse().unwrap_or(usize::MAX),
+        };
+        if count > max {
+            self.error(
+                IssueType::Structure,
+                &location,
+                format!(
+                    "{} allows at most {} {}, but has {}",
+                    element.path,
+                    max,
+                    if max == 1 { "item" } else { "items" },
+                    count
+                ),
+            );
+        }
+    }
+
+    /// Checks one item of an element, given by its value and its `_element` extras
+    fn item(
+        &mut self,
+        value: Option<&Json>,
+        extras: Option<&Json>,
+        definition: &StructureDefinition,
+        element: &ElementDefinition,
+        code: &str,
+        location: &str,
+    ) {
+        let model = self.model;
+
+        if let Some(reference) = &element.content_reference {
+            let reference = reference.rsplit('#').next().unwrap_or(reference);
+            return self.complex(value, definition, reference, location);
+        }
+        match code {
+            "BackboneElement" | "Element" => {
+                return self.complex(value, definition, &element.id, location)
+            }
+            "Resource" => {
+                if let Some(value) = value {
+                    self.resource(value, None, Some(location));
+                }
+                return;
+            }
+            _ if code.starts_with(SYSTEM_TYPE_PREFIX) || model.is_primitive_type(code) => {
+                if let Some(extras) = extras {
+                    // The extras of a primitive are its id and extensions
+                    match model.structure_definition(code) {
+                        Some(primitive) => self.object(extras, primitive, code, location),
+                        None => self.error(
+                            IssueType::Structure,
+                            location,
+                            format!("{} cannot have an id or extensions", element.path),
+                        ),
+                    }
+                }
+            }
+            _ if !child_elements(definition, &element.id).is_empty() => {
+                self.complex(value, definition, &element.id, location)
+            }
+            _ => {
+                let data_type = element
+                    .r#type
+                    .iter()
+                    .find(|t| t.code == code)
+                    .and_then(|t| match t.profile.as_slice() {
+                        [profile] => self.context.structure_definition(profile),
+                        _ => None,
+                    })
+                    .filter(|profile| profile.r#type == code)
+                    .or_else(|| model.structure_definition(code));
+                match data_type {
+                    Some(data_type) => self.complex(value, data_type, code, location),
+                    None => self.issue(
+                        IssueSeverity::Warning,
+                        IssueType::NotSupported,
+                        location,
+                        format!("cannot check {}, of unknown type {}", element.path, code),
+                    ),
+                }
+            }
+        }
+
+        let Some(value) = value else {
+            return;
+        };
+        let node = match model.load_data_type(value, code) {
+            Ok(node) => node,
+            Err(_) if !model.is_primitive_type(code) && !code.starts_with(SYSTEM_TYPE_PREFIX) => {
+                // Already reported by checking its elements
+                return;
+            }
+            Err(_) => {
+                self.error(
+                    IssueType::Value,
+                    location,
+                    format!("{} is not a valid {}", value, code),
+                );
+                return;
+            }
+        };
+        let value = node.to_value();
+        if !matches_fixed_and_pattern(model, &value, element) {
+            let message = match (element.fixed(), element.pattern()) {
+                (Some((_, fixed)), _) => format!("{} must be {}", element.path, fixed),
+                (_, Some((_, pattern))) => format!("{} must match {}", element.path, pattern),
+                _ => format!(
+                    "{} does not have the value the profile requires",
+                    element.path
+                ),
+            };
+            self.error(IssueType::Value, location, message);
+        }
+        self.binding(&value, element, location);
+    }
+
+    fn complex(
+        &mut self,
+        value: Option<&Json>,
+        definition: &StructureDefinition,
+        id: &str,
+        location: &str,
+    ) {
+        match value {
+            Some(value) => self.object(value, definition, id, location),
+            None => self.error(
+                IssueType::Structure,
+                location,
+                format!("{} cannot have extras", id),
+            ),
+        }
+    }
+
+    /// Checks that the codes of a value are in the value set of a required binding
+    fn binding(&mut self, value: &Value, element: &ElementDefinition, location: &str) {
+        let Some(binding) = &element.binding else {
+            return;
+        };
+        let Some(value_set) = binding.value_set.as_deref() else {
+            return;
+        };
+        if binding.strength != "required" {
+            return;
+        }
+        let Ok(terminology) = self.context.terminology() else {
+            return;
+        };
+        let codings = codings(value);
+        if codings.is_empty() {
+            return;
+        }
+
+        let value_set = value_set.split('|').next().unwrap_or(value_set);
+        let mut valid = false;
+        for coding in &codings {
+            match terminology.member_of(value_set, coding) {
+                Ok(member) => valid |= member,
+                Err(err) => {
+                    self.issue(
+                        IssueSeverity::Warning,
+                        IssueType::NotSupported,
+                        location,
+                        format!("cannot check the binding to {}: {}", value_set, err),
+                    );
+                    return;
+                }
+            }
+        }
+        if !valid {
+            let codes: Vec<_> = codings.iter().map(|c| c.code.as_str()).collect();
+            self.error(
+                IssueType::CodeInvalid,
+                location,
+                format!(
+                    "'{}' is not in the value set {}, which {} is bound to",
+                    codes.join("', '"),
+                    value_set,
+                    element.path
+                ),
+            );
+        }
+    }
+}
+
+/// The elements beneath the element with an id, excluding slices
+pub(crate) fn child_elements<'d>(
+    definition: &'d StructureDefinition,
+    id: &str,
+) -> Vec<&'d ElementDefinition> {
+    definition
+        .snapshot
+        .iter()
+        .flat_map(|snapshot| snapshot.element.iter())
+        .filter(|el| {
+            el.id
+                .strip_prefix(id)
+                .and_then(|rest| rest.strip_prefix('.'))
+                .is_some_and(|name| !name.contains('.') && !name.contains(':'))
+        })
+        .collect()
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn uncapitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhir::{default_model, SnapshotGenerator, ValueSet};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    /// The location, code and severity of each issue
+    fn issues(outcome: &OperationOutcome) -> Vec<(&str, IssueType, IssueSeverity)> {
+        outcome
+            .issue
+            .iter()
+            .map(|issue| (issue.expression[0].as_str(), issue.code, issue.severity))
+            .collect()
+    }
+
+    fn errors(outcome: &OperationOutcome) -> Vec<(&str, IssueType)> {
+        outcome
+            .errors()
+            .map(|issue| (issue.expression[0].as_str(), issue.code))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_resources() {
+        let context = EvaluationContext::new();
+        let validator = Validator::new(&context);
+        let patient = json!({
+            "resourceType": "Patient",
+            "id": "example",
+            "meta": { "lastUpdated": "2023-05-01T13:45:30Z" },
+            "identifier": [{ "system": "http://example.org/mrn", "value": "12345" }],
+            "name": [{ "family": "Chalmers", "given": ["Peter", "James"] }],
+            "gender": "male",
+            "_gender": { "id": "g" },
+            "birthDate": "1974-12-25",
+            "deceasedBoolean": false,
+            "extension": [{
+                "url": "http://example.org/eye-colour",
+                "valueString": "blue"
+            }],
+            "contact": [{ "name": { "family": "Chalmers" } }],
+            "contained": [{ "resourceType": "Patient", "active": true }]
+        });
+        let outcome = validator.validate_resource(&patient);
+        assert_eq!(issues(&outcome), []);
+        assert!(outcome.is_valid());
+
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": [{ "resource": patient, "link": [{ "relation": "self", "url": "http://example.org" }] }]
+        });
+        assert_eq!(issues(&validator.validate_resource(&bundle)), []);
+    }
+
+    #[test]
+    fn test_invalid_resources() {
+        let context = EvaluationContext::new();
+        let validator = Validator::new(&context);
+
+        let outcome = validator.validate_resource(&json!({
+            "resourceType": "Patient",
+            "colour": "blue",
+            "name": { "family": "Chalmers" },
+            "gender": 1,
+            "birthDate": "1974-13-45",
+            "deceasedString": "yes",
+            "communication": [{ "preferred": true }],
+            "telecom": [{ "system": "phone", "period": { "start": "yesterday" } }],
+            "_active": { "colour": "blue" },
+            "contained": [{ "resourceType": "Patient", "colour": "blue" }, { "active": true }]
+        }));
+        assert_eq!(
+            errors(&outcome),
+            [
+                ("Patient.contained[0].colour", IssueType::Structure),
+                ("Patient.contained[1]", IssueType::Structure),
+                ("Patient.active.colour", IssueType::Structure),
+                ("Patient.name", IssueType::Structure),
+                ("Patient.telecom[0].period.start", IssueType::Value),
+                ("Patient.gender", IssueType::Value),
+                ("Patient.birthDate", IssueType::Value),
+                ("Patient.deceased", IssueType::Structure),
+                ("Patient.communication[0].language", IssueType::Required),
+                ("Patient.colour", IssueType::Structure),
+            ]
+        );
+        assert!(!outcome.is_valid());
+        assert_eq!(
+            outcome.issue[2].to_string(),
+            "error at Patient.active.colour: unknown element colour"
+        );
+
+        let outcome = validator.validate_resource(&json!({
+            "resourceType": "Observation",
+            "status": ["final", "amended"],
+            "valueQuantity": { "value": 1, "unit": "kg" },
+            "valueString": "1 kg"
+        }));
+        assert_eq!(
+            errors(&outcome),
+            [
+                ("Observation.status", IssueType::Structure),
+                ("Observation.status", IssueType::Structure),
+                ("Observation.code", IssueType::Required),
+                ("Observation.value", IssueType::Structure),
+            ]
+        );
+
+        let unknown = validator.validate_resource(&json!({ "resourceType": "Colour" }));
+        assert_eq!(errors(&unknown), [("Colour", IssueType::Structure)]);
+        let untyped = validator.validate_resource(&json!({ "active": true }));
+        assert_eq!(errors(&untyped), [("Resource", IssueType::Structure)]);
+    }
+
+    #[test]
+    fn test_profile_validation() {
+        let profile: StructureDefinition = serde_json::from_value(json!({
+            "id": "mrn-patient",
+            "url": "http://example.org/StructureDefinition/mrn-patient",
+            "name": "MrnPatient",
+            "status": "draft",
+            "kind": "resource",
+            "abstract": false,
+            "type": "Patient",
+            "baseDefinition": "http://hl7.org/fhir/StructureDefinition/Patient",
+            "derivation": "constraint",
+            "differential": { "element": [
+                { "id": "Patient.identifier", "path": "Patient.identifier", "min": 1, "max": "1" },
+                {
+                    "id": "Patient.identifier.system",
+                    "path": "Patient.identifier.system",
+                    "min": 1,
+                    "fixedUri": "http://example.org/mrn"
+                },
+                {
+                    "id": "Patient.maritalStatus",
+                    "path": "Patient.maritalStatus",
+                    "patternCodeableConcept": {
+                        "coding": [{ "system": "http://terminology.hl7.org/CodeSystem/v3-MaritalStatus", "code": "M" }]
+                    }
+                },
+                { "id": "Patient.deceased[x]", "path": "Patient.deceased[x]", "type": [{ "code": "boolean" }] },
+                { "id": "Patient.photo", "path": "Patient.photo", "max": "0" }
+            ]}
+        }))
+        .unwrap();
+        let mut profile = profile;
+        profile.snapshot = Some(
+            SnapshotGenerator::new(default_model().unwrap())
+                .generate(&profile)
+                .unwrap(),
+        );
+
+        let married = json!({
+            "coding": [
+                { "system": "http://terminology.hl7.org/CodeSystem/v3-MaritalStatus", "code": "M", "display": "Married" }
+            ]
+        });
+        let valid = json!({
+            "resourceType": "Patient",
+            "identifier": [{ "system": "http://example.org/mrn", "value": "12345" }],
+            "maritalStatus": married,
+            "deceasedBoolean": false
+        });
+        assert_eq!(issues(&validate(&valid, &profile)), []);
+
+        let outcome = validate(
+            &json!({
+                "resourceType": "Patient",
+                "identifier": [{ "system": "http://example.org/other" }, { "value": "12345" }],
+                "maritalStatus": { "text": "Married" },
+                "deceasedDateTime": "2023-05-01",
+                "photo": [{ "url": "http://example.org/photo.png" }]
+            }),
+            &profile,
+        );
+        assert_eq!(
+            errors(&outcome),
+            [
+                ("Patient.identifier[0].system", IssueType::Value),
+                ("Patient.identifier[1].system", IssueType::Required),
+                ("Patient.identifier", IssueType::Structure),
+                ("Patient.deceased", IssueType::Structure),
+                ("Patient.maritalStatus", IssueType::Value),
+                ("Patient.photo", IssueType::Structure),
+            ]
+        );
+        assert_eq!(
+            outcome.issue[0].diagnostics,
+            "Patient.identifier.system must be \"http://example.org/mrn\""
+        );
+
+        // Resources of other types cannot conform
+        let observation = json!({ "resourceType": "Observation", "status": "final" });
+        assert_eq!(
+            errors(&validate(&observation, &profile)),
+            [("Observation", IssueType::Structure)]
+        );
+    }
+
+    #[test]
+    fn test_required_bindings() {
+        let value_set: ValueSet = serde_json::from_value(json!({
+            "url": "http://hl7.org/fhir/ValueSet/administrative-gender",
+            "compose": { "include": [{
+                "system": "http://hl7.org/fhir/administrative-gender",
+                "concept": [{ "code": "male" }, { "code": "female" }, { "code": "other" }, { "code": "unknown" }]
+            }]}
+        }))
+        .unwrap();
+        let mut terminology = InMemoryTerminology::new();
+        terminology.add_value_set(value_set);
+        let context = EvaluationContext::new().with_terminology_service(terminology);
+        let validator = Validator::new(&context);
+
+        let outcome = validator.validate_resource(&json!({
+            "resourceType": "Patient",
+            "gender": "woman",
+            "contact": [{ "gender": "female", "name": { "use": "usual" } }],
+            "link": [{ "other": { "reference": "Patient/1" }, "type": "seealso" }]
+        }));
+        assert_eq!(
+            issues(&outcome),
+            [
+                (
+                    "Patient.gender",
+                    IssueType::CodeInvalid,
+                    IssueSeverity::Error
+                ),
+                // The other value sets are unknown, so the codes bound to them cannot be checked
+                (
+                    "Patient.contact[0].name.use",
+                    IssueType::NotSupported,
+                    IssueSeverity::Warning
+                ),
+                (
+                    "Patient.link[0].type",
+                    IssueType::NotSupported,
+                    IssueSeverity::Warning
+                ),
+            ]
+        );
+        assert_eq!(
+            outcome.issue[0].diagnostics,
+            "'woman' is not in the value set http://hl7.org/fhir/ValueSet/administrative-gender, \
+             which Patient.gender is bound to"
+        );
+
+        assert_eq!(
+            outcome.to_json(),
+            json!({
+                "resourceType": "OperationOutcome",
+                "issue": [
+                    {
+                        "severity": "error",
+                        "code": "code-invalid",
+                        "diagnostics": outcome.issue[0].diagnostics,
+                        "expression": ["Patient.gender"]
+                    },
+                    {
+                        "severity": "warning",
+                        "code": "not-supported",
+                        "diagnostics": outcome.issue[1].diagnostics,
+                        "expression": ["Patient.contact[0].name.use"]
+                    },
+                    {
+                        "severity": "warning",
+                        "code": "not-supported",
+                        "diagnostics": outcome.issue[2].diagnostics,
+                        "expression": ["Patient.link[0].type"]
+                    }
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_invariants() {
+        let context = EvaluationContext::new();
+        let validator = Validator::new(&context)
+            .with_invariants(InvariantChecker::new(&context).with_suppressed("ele-1"));
+        let patient = json!({
+            "resourceType": "Patient",
+            "_birthDate": { "id": "dob" },
+            "contact": [{ "gender": "female" }]
+        });
+        let outcome = validator.validate_resource(&patient);
+        assert_eq!(
+            issues(&outcome),
+            [
+                ("Patient", IssueType::Invariant, IssueSeverity::Warning),
+                (
+                    "Patient.contact[0]",
+                    IssueType::Invariant,
+                    IssueSeverity::Error
+                ),
+            ]
+        );
+        assert!(outcome.issue[1].diagnostics.starts_with("pat-1: "));
+
+        // Without a checker, constraints are not checked
+        assert_eq!(
+            issues(&Validator::new(&context).validate_resource(&patient)),
+            []
+        );
+
+        // Nor are they in resources that cannot be read
+        let invalid = json!({ "resourceType": "Patient", "contact": [{}], "colour": "blue" });
+        assert_eq!(
+            errors(&validator.validate_resource(&invalid)),
+            [("Patient.colour", IssueType::Structure)]
+        );
+    }
+}
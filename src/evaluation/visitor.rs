@@ -1,56 +1,274 @@
 use super::*;
-use crate::fhirpath::{Collection, Value};
-use std::collections::HashMap;
+use crate::fhir::{Model, StructureDefinition};
+use crate::fhirpath::{Collection, Compare, DataNode, Quantity, Value, ANY};
+use chrono::{DateTime, FixedOffset};
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
 
 use rust_decimal::Decimal;
 
-use crate::parser::ASTNode;
+use crate::parser::{
+    ASTNode, AdditiveOperator, BooleanOperator, EqualityOperator, InequalityOperator,
+    MembershipOperator, MultiplicativeOperator, Span, TypeOperator,
+};
 
-pub struct Visitor {
-    functions: HashMap<&'static str, Function>,
+pub struct Visitor<'a> {
+    context: &'a EvaluationContext,
+    /// The collection the expression is evaluated against, which `%resource` refers to
+    root: Collection,
+    /// The resources that `%resource` and `%rootResource` refer to, if the expression is
+    /// evaluated against an element within a resource rather than the resource itself
+    resources: Option<(Collection, Collection)>,
+    input: Collection,
+    /// Variables defined by `defineVariable()`, in the order they were defined.  Each step
+    /// forgets the variables defined within it, unless it continues an invocation chain.
+    defined: Vec<(String, Collection)>,
+    /// Whether the next step continues an invocation chain, whose later invocations see the
+    /// variables it defines
+    chained: bool,
+    input_definition: Option<ElementPath>,
+    now: Option<DateTime<FixedOffset>>,
+    started: Instant,
+    steps: usize,
+    depth: usize,
 }
 
-impl Visitor {
-    pub fn new() -> Self {
+/// A structure definition and the path of an element within it
+pub type ElementPath = (&'static StructureDefinition, String);
+
+/// Functions whose parameter is a type specifier rather than an expression to evaluate
+const TYPE_FUNCTIONS: [&str; 3] = ["ofType", "is", "as"];
+
+/// Functions that use the definition of their input, which is found from the expression
+const DEFINITION_FUNCTIONS: [&str; 1] = ["elementDefinition"];
+
+/// A function looked up by name
+pub(crate) enum Callee {
+    /// `ofType()`, `is()` or `as()`, with the type specifier it was given
+    Type(&'static str, String),
+    Expression(ExpressionFunction),
+    Builtin(Function),
+}
+
+/// A parameter that a function evaluates: a node of the AST, or a step of a compiled plan
+pub trait Parameter {
+    fn evaluate(&self, visitor: &mut Visitor) -> Result<Collection, EvaluationError>;
+}
+
+impl Parameter for ASTNode {
+    fn evaluate(&self, visitor: &mut Visitor) -> Result<Collection, EvaluationError> {
+        visitor.visit_node(self)
+    }
+}
+
+impl<T: Parameter + ?Sized> Parameter for &T {
+    fn evaluate(&self, visitor: &mut Visitor) -> Result<Collection, EvaluationError> {
+        (**self).evaluate(visitor)
+    }
+}
+
+/// Functions that select some of their input, leaving the definition of the items unchanged
+const FILTER_FUNCTIONS: [&str; 10] = [
+    "ofType", "as", "where", "first", "last", "single", "tail", "skip", "take", "distinct",
+];
+
+impl<'a> Visitor<'a> {
+    pub fn new(context: &'a EvaluationContext) -> Self {
         Visitor {
-            functions: BUILTIN_FUNCTIONS.clone(),
+            context,
+            root: Collection::new(),
+            resources: None,
+            input: Collection::new(),
+            defined: Vec::new(),
+            chained: false,
+            input_definition: None,
+            now: None,
+            started: Instant::now(),
+            steps: 0,
+            depth: 0,
         }
     }
 
+    /// Sets the collection that the expression is evaluated against, e.g. a resource
+    pub fn with_input(mut self, input: Collection) -> Self {
+        self.root = input.clone();
+        self.input = input;
+        self
+    }
+
+    /// Sets the resource holding the input and, for a contained resource, the resource holding
+    /// that, as `%resource` and `%rootResource`
+    pub fn with_resources(mut self, resource: Collection, root_resource: Collection) -> Self {
+        self.resources = Some((resource, root_resource));
+        self
+    }
+
+    pub fn context(&self) -> &EvaluationContext {
+        self.context
+    }
+
+    /// Resolves a reference in the context of the resource the expression is evaluated against,
+    /// or of the resource that contains it
+    pub fn resolve_reference(&self, reference: &str) -> Option<Arc<DataNode>> {
+        let root = match self.root_resource().first() {
+            Some(Value::Complex(node)) => Some(node.as_ref()),
+            _ => None,
+        };
+        self.context.resolve_reference(reference, root)
+    }
+
+    /// Evaluates an expression against another input, such as an item of the collection a
+    /// function was invoked on
+    pub fn visit_with_input(
+        &mut self,
+        input: Collection,
+        param: &dyn Parameter,
+    ) -> Result<Collection, EvaluationError> {
+        let outer = std::mem::replace(&mut self.input, input);
+        let defined = self.defined.len();
+        let result = param.evaluate(self);
+        self.defined.truncate(defined);
+        self.input = outer;
+        result
+    }
+
+    /// The definition of the items that the function being invoked operates on, if the
+    /// expression reached them by navigating the model
+    pub fn input_definition(&self) -> Option<&ElementPath> {
+        self.input_definition.as_ref()
+    }
+
+    /// The current time, read from the context's clock once per evaluation so that every call
+    /// to `now()` within an expression agrees
+    pub fn now(&mut self) -> DateTime<FixedOffset> {
+        *self.now.get_or_insert_with(|| self.context.clock().now())
+    }
+
+    /// The collection the expression is evaluated against
+    pub(crate) fn input(&self) -> &Collection {
+        &self.input
+    }
+
     pub fn visit_node(&mut self, node: &ASTNode) -> Result<Collection, EvaluationError> {
-        return match node {
-            ASTNode::BooleanLiteral(val) => Ok(Collection::from(Value::Boolean(*val))),
-            ASTNode::StringLiteral(str) => Ok(Collection::from(Value::String(str.to_owned()))),
-            ASTNode::NumberLiteral(str) => {
-                if str.contains('.') {
-                    let n = Decimal::from_str_radix(str, 10)
-                        .map_err(|_| EvaluationError::InvalidDecimal(str.to_string()))?;
-
-                    Ok(Collection::from(Value::Decimal(n)))
-                } else {
-                    let n = str::parse::<i32>(str)
-                        .map_err(|e| EvaluationError::InvalidInteger(str.to_string(), e))?;
+        self.run(node.span(), function_name(node), |visitor| {
+            visitor.evaluate_node(node)
+        })
+    }
 
-                    Ok(Collection::from(Value::Integer(n)))
-                }
+    /// Evaluates a step of an expression within the limits of the context, recording the span
+    /// of the step and the function it invokes in any error it raises
+    pub(crate) fn run(
+        &mut self,
+        span: Option<Span>,
+        function: Option<&str>,
+        step: impl FnOnce(&mut Self) -> Result<Collection, EvaluationError>,
+    ) -> Result<Collection, EvaluationError> {
+        let chained = std::mem::take(&mut self.chained);
+        let defined = self.defined.len();
+        let result = self.enter_node().and_then(|_| {
+            let result = step(self);
+            self.depth -= 1;
+            result.and_then(|collection| self.check_result(collection))
+        });
+        if !chained {
+            self.defined.truncate(defined);
+        }
+        match span {
+            Some(span) => result.map_err(|err| err.located(span, function)),
+            None => result,
+        }
+    }
+
+    /// Marks the next step as continuing an invocation chain, so that the variables it defines
+    /// remain visible to the rest of the chain
+    pub(crate) fn chain(&mut self) {
+        self.chained = true;
+    }
+
+    /// Counts a step of the evaluation, checking that it may go on
+    fn enter_node(&mut self) -> Result<(), EvaluationError> {
+        let limits = self.context.limits();
+        let exceeded = |limit| Err(EvaluationError::LimitExceeded(limit));
+        self.steps += 1;
+        if let Some(max) = limits.max_steps.filter(|&max| self.steps > max) {
+            return exceeded(Limit::Steps(max));
+        }
+        if let Some(max) = limits.max_depth.filter(|&max| self.depth >= max) {
+            return exceeded(Limit::Depth(max));
+        }
+        if self.context.is_cancelled() {
+            return exceeded(Limit::Cancelled);
+        }
+        if let Some(timeout) = limits.timeout.filter(|&t| self.started.elapsed() > t) {
+            return exceeded(Limit::Timeout(timeout));
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn check_result(&self, collection: Collection) -> Result<Collection, EvaluationError> {
+        self.check_collection_size(collection.len())?;
+        for value in collection.iter() {
+            if let Value::String(s) = value.system_value() {
+                self.check_string_length(s.chars().count())?;
+            }
+        }
+        Ok(collection)
+    }
+
+    /// Checks that a collection may have as many items, so that functions building one can
+    /// stop before it grows any further
+    pub(crate) fn check_collection_size(&self, size: usize) -> Result<(), EvaluationError> {
+        match self.context.limits().max_collection_size {
+            Some(max) if size > max => {
+                Err(EvaluationError::LimitExceeded(Limit::CollectionSize(max)))
             }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that a string may have as many characters, so that functions building one can
+    /// stop before building it
+    pub(crate) fn check_string_length(&self, length: usize) -> Result<(), EvaluationError> {
+        match self.context.limits().max_string_length {
+            Some(max) if length > max => {
+                Err(EvaluationError::LimitExceeded(Limit::StringLength(max)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn evaluate_node(&mut self, node: &ASTNode) -> Result<Collection, EvaluationError> {
+        if let Some(literal) = literal(node) {
+            return literal;
+        }
+        match node {
             ASTNode::InvocationExpression(left, right) => {
+                if matches!(left.as_ref(), ASTNode::Variable(name, _) if name == "terminologies") {
+                    return self.run(right.span(), function_name(right), |visitor| {
+                        visitor.invoke_terminology_function(right)
+                    });
+                }
+                self.chain();
                 let input = self.visit_node(left)?;
                 match right.as_ref() {
-                    ASTNode::Function(left, right) => {
-                        let ASTNode::Identifier(name) = left.as_ref() else {return Err(EvaluationError::InvalidAST)};
-                        let param_list = self.visit_node(&right)?;
-
-                        if let Some(func) = self.functions.get(name.as_str()) {
-                            func(&input, &param_list)
-                        } else {
-                            Err(EvaluationError::FunctionUnavailable(name.to_string()))
-                        }
+                    ASTNode::Function(..) => {
+                        self.chain();
+                        self.run(right.span(), function_name(right), |visitor| {
+                            visitor.invoke_function(&input, right, Some(left))
+                        })
                     }
-                    ASTNode::Identifier(_) => todo!(),
+                    ASTNode::Identifier(name) => Ok(navigate(&input, name)),
                     _ => Err(EvaluationError::InvalidAST),
                 }
             }
+            ASTNode::Identifier(name) => self.identifier(name),
+            ASTNode::Function(..) => {
+                let input = self.input.clone();
+                self.invoke_function(&input, node, None)
+            }
+            ASTNode::Variable(name, _) => self.variable(name),
             ASTNode::ParamList(inner) => {
                 if let Some(params) = inner {
                     self.visit_node(params)
@@ -61,12 +279,544 @@ impl Visitor {
             ASTNode::Union(left, right) => {
                 let c1 = self.visit_node(left)?;
                 let c2 = self.visit_node(right)?;
+                Ok(union(c1, c2))
+            }
+            ASTNode::UnionExpression(left, right, _) => {
+                let c1 = self.visit_node(left)?;
+                let c2 = self.visit_node(right)?;
+                Ok(distinct_union(c1, c2))
+            }
+            ASTNode::EqualityExpression(left, op, right, _) => {
+                let c1 = self.visit_node(left)?;
+                let c2 = self.visit_node(right)?;
+                Ok(equality(&c1, *op, &c2))
+            }
+            ASTNode::InequalityExpression(left, op, right, _) => {
+                let c1 = self.visit_node(left)?;
+                let c2 = self.visit_node(right)?;
+                inequality(&c1, *op, &c2)
+            }
+            ASTNode::AdditiveExpression(left, op, right, _) => {
+                let c1 = self.visit_node(left)?;
+                let c2 = self.visit_node(right)?;
+                additive(&c1, *op, &c2)
+            }
+            ASTNode::MultiplicativeExpression(left, op, right, _) => {
+                let c1 = self.visit_node(left)?;
+                let c2 = self.visit_node(right)?;
+                multiplicative(&c1, *op, &c2)
+            }
+            ASTNode::BooleanExpression(left, op, right, _) => {
+                let c1 = self.visit_node(left)?;
+                boolean(&c1, *op, || self.visit_node(right))
+            }
+            ASTNode::TypeExpression(left, op, specifier, _) => {
+                let input = self.visit_node(left)?;
+                type_expression(self.context.model()?, &input, *op, specifier)
+            }
+            ASTNode::MembershipExpression(left, op, right, _) => {
+                let c1 = self.visit_node(left)?;
+                let c2 = self.visit_node(right)?;
+                membership(&c1, *op, &c2)
+            }
+            _ => Err(EvaluationError::InvalidAST),
+        }
+    }
+
+    /// Selects the children of the input with a name, or the input itself if the name is its
+    /// type (as in `Patient.name`)
+    pub(crate) fn identifier(&self, name: &str) -> Result<Collection, EvaluationError> {
+        if name.starts_with(char::is_uppercase) {
+            let matching = self.input.of_type(name, self.context.model()?);
+            if !matching.is_empty() {
+                return Ok(matching);
+            }
+        }
+        Ok(navigate(&self.input, name))
+    }
 
-                Ok(Collection::from_iter(
-                    c1.iter().chain(c2.iter()).map(|v| v.clone()),
-                ))
+    /// The value of an environment variable: the root input, one of the well-known URLs, or a
+    /// variable defined in the context
+    pub(crate) fn variable(&self, name: &str) -> Result<Collection, EvaluationError> {
+        let url = |url: String| Ok(Collection::from(Value::String(url)));
+        match name {
+            "resource" => Ok(self
+                .resources
+                .as_ref()
+                .map_or(&self.root, |(resource, _)| resource)
+                .clone()),
+            "rootResource" => Ok(self.root_resource().clone()),
+            "context" => Ok(self.root.clone()),
+            "$this" => Ok(self.input.clone()),
+            "ucum" => url("http://unitsofmeasure.org".to_string()),
+            "sct" => url("http://snomed.info/sct".to_string()),
+            "loinc" => url("http://loinc.org".to_string()),
+            _ => {
+                if let Some(id) = name.strip_prefix("vs-") {
+                    url(format!("http://hl7.org/fhir/ValueSet/{}", id))
+                } else if let Some(id) = name.strip_prefix("ext-") {
+                    url(format!("http://hl7.org/fhir/StructureDefinition/{}", id))
+                } else if let Some((_, value)) = self
+                    .defined
+                    .iter()
+                    .rev()
+                    .find(|(defined, _)| defined == name)
+                {
+                    Ok(value.clone())
+                } else {
+                    self.context
+                        .variable(name)
+                        .cloned()
+                        .ok_or_else(|| EvaluationError::UnknownVariable(name.to_string()))
+                }
             }
-            _ => panic!("Unsupported node type {:?}", node),
+        }
+    }
+
+    fn root_resource(&self) -> &Collection {
+        self.resources
+            .as_ref()
+            .map_or(&self.root, |(_, root_resource)| root_resource)
+    }
+
+    /// Defines a variable for the rest of the invocation chain, and any expressions evaluated
+    /// within it.  Neither the environment variables nor a variable already in scope can be
+    /// redefined.
+    pub fn define_variable(
+        &mut self,
+        name: &str,
+        value: Collection,
+    ) -> Result<(), EvaluationError> {
+        if self.variable(name).is_ok() {
+            return Err(EvaluationError::InvalidFunctionArguments(Collection::from(
+                Value::String(name.to_string()),
+            )));
+        }
+        self.defined.push((name.to_string(), value));
+        Ok(())
+    }
+
+    /// Invokes one of the functions of `%terminologies`, each of whose parameters is evaluated
+    /// separately
+    fn invoke_terminology_function(
+        &mut self,
+        node: &ASTNode,
+    ) -> Result<Collection, EvaluationError> {
+        let ASTNode::Function(name, params, _) = node else {
+            return Err(EvaluationError::UnknownVariable(
+                "terminologies".to_string(),
+            ));
+        };
+        let ASTNode::Identifier(name) = name.as_ref() else {
+            return Err(EvaluationError::InvalidAST);
+        };
+        let func = TERMINOLOGY_FUNCTIONS
+            .get(name.as_str())
+            .copied()
+            .ok_or_else(|| {
+                EvaluationError::FunctionUnavailable(format!("%terminologies.{}", name))
+            })?;
+
+        let mut param_values = Vec::new();
+        for param in param_nodes(params) {
+            param_values.push(self.visit_node(param)?);
+        }
+        func(self, &param_values)
+    }
+
+    /// Records the definition of the input of a function that needs it, from the expression the
+    /// input was selected by, or the root input if there is none
+    pub(crate) fn set_input_definition(&mut self, function: &str, input: Option<&ASTNode>) {
+        self.input_definition = if !needs_definition(function) {
+            None
+        } else if let Some(input) = input {
+            self.definition_path(input)
+        } else {
+            self.root_definition()
+        };
+    }
+
+    fn root_definition(&self) -> Option<ElementPath> {
+        let root = self.input.first()?.data_type().strip_prefix("FHIR.")?;
+        Some((
+            self.context.model().ok()?.structure_definition(root)?,
+            root.to_string(),
+        ))
+    }
+
+    /// Follows the names in an expression through the model from the root input, to find the
+    /// definition of the elements it selects
+    fn definition_path(&self, node: &ASTNode) -> Option<ElementPath> {
+        let model = self.context.model().ok()?;
+        match node {
+            ASTNode::Identifier(name) => {
+                let (definition, root) = self.root_definition()?;
+                if name.starts_with(char::is_uppercase) && model.is_subtype(&root, name) {
+                    Some((definition, root))
+                } else {
+                    child_definition(model, definition, &root, name)
+                }
+            }
+            ASTNode::InvocationExpression(left, right) => {
+                let (definition, path) = self.definition_path(left)?;
+                match right.as_ref() {
+                    ASTNode::Identifier(name) => child_definition(model, definition, &path, name),
+                    ASTNode::Function(function, ..) => match function.as_ref() {
+                        ASTNode::Identifier(name) if FILTER_FUNCTIONS.contains(&name.as_str()) => {
+                            Some((definition, path))
+                        }
+                        ASTNode::Identifier(name) if name == "extension" => {
+                            child_definition(model, definition, &path, name)
+                        }
+                        _ => None,
+                    },
+                    _ => None,
+                }
+            }
+            ASTNode::TypeExpression(left, TypeOperator::As, ..) => self.definition_path(left),
+            _ => None,
+        }
+    }
+
+    fn invoke_function(
+        &mut self,
+        input: &Collection,
+        node: &ASTNode,
+        input_node: Option<&ASTNode>,
+    ) -> Result<Collection, EvaluationError> {
+        let ASTNode::Function(name, params, _) = node else {
+            return Err(EvaluationError::InvalidAST);
+        };
+        let ASTNode::Identifier(name) = name.as_ref() else {
+            return Err(EvaluationError::InvalidAST);
         };
+        let callee = resolve_function(name, params)?;
+        self.set_input_definition(name, input_node);
+        self.invoke(&callee, input, &param_nodes(params))
+    }
+
+    /// Invokes a function on an input.  Builtin functions are given the values of all of their
+    /// parameters together.
+    pub(crate) fn invoke<P: Parameter>(
+        &mut self,
+        callee: &Callee,
+        input: &Collection,
+        params: &[P],
+    ) -> Result<Collection, EvaluationError> {
+        match callee {
+            Callee::Type(name, specifier) => {
+                let model = self.context.model()?;
+                match *name {
+                    // Unlike the `as` operator, the function filters collections of any size,
+                    // which the invariants of the FHIR specification rely on
+                    "is" => input.is_type(specifier, model),
+                    _ => Ok(input.of_type(specifier, model)),
+                }
+            }
+            Callee::Expression(function) => {
+                let params: Vec<&dyn Parameter> = params.iter().map(|p| p as _).collect();
+                function(self, input, &params)
+            }
+            Callee::Builtin(function) => {
+                let mut values = Collection::new();
+                for param in params {
+                    let mut value = param.evaluate(self)?;
+                    values.append(&mut value);
+                }
+                function(self, input, &values)
+            }
+        }
+    }
+}
+
+/// Looks up a function, reading the type specifier if it takes one
+pub(crate) fn resolve_function(name: &str, params: &ASTNode) -> Result<Callee, EvaluationError> {
+    if let Some(name) = TYPE_FUNCTIONS.iter().find(|f| **f == name) {
+        let specifier = type_specifier(params).ok_or(EvaluationError::InvalidAST)?;
+        return Ok(Callee::Type(name, specifier));
+    }
+    if let Some(function) = EXPRESSION_FUNCTIONS.get(name) {
+        return Ok(Callee::Expression(*function));
+    }
+    BUILTIN_FUNCTIONS
+        .get(name)
+        .map(|function| Callee::Builtin(*function))
+        .ok_or_else(|| EvaluationError::FunctionUnavailable(name.to_string()))
+}
+
+/// Whether a function uses the definition of its input
+pub(crate) fn needs_definition(function: &str) -> bool {
+    DEFINITION_FUNCTIONS.contains(&function)
+}
+
+/// The name of the function a node invokes, if it is a function invocation
+pub(crate) fn function_name(node: &ASTNode) -> Option<&str> {
+    match node {
+        ASTNode::Function(name, ..) => match name.as_ref() {
+            ASTNode::Identifier(name) => Some(name.as_str()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The value of a literal node, or `None` if the node is not a literal
+pub(crate) fn literal(node: &ASTNode) -> Option<Result<Collection, EvaluationError>> {
+    let value = |value: Result<Value, EvaluationError>| Some(value.map(Collection::from));
+    match node {
+        ASTNode::BooleanLiteral(val) => value(Ok(Value::Boolean(*val))),
+        ASTNode::StringLiteral(str) => value(Ok(Value::String(str.to_owned()))),
+        ASTNode::NumberLiteral(str, _) if str.contains('.') => {
+            value(parse_decimal(str).map(Value::Decimal))
+        }
+        ASTNode::NumberLiteral(str, _) => value(
+            str.parse::<i32>()
+                .map(Value::Integer)
+                .map_err(|e| EvaluationError::InvalidInteger(str.to_string(), e)),
+        ),
+        ASTNode::LongNumberLiteral(str, _) => value(
+            str.parse::<i64>()
+                .map(Value::Long)
+                .map_err(|e| EvaluationError::InvalidInteger(str.to_string(), e)),
+        ),
+        ASTNode::DateLiteral(str, _) => value(str.parse().map(Value::Date).map_err(Into::into)),
+        ASTNode::DateTimeLiteral(str, _) => {
+            value(str.parse().map(Value::DateTime).map_err(Into::into))
+        }
+        ASTNode::TimeLiteral(str, _) => value(str.parse().map(Value::Time).map_err(Into::into)),
+        ASTNode::QuantityLiteral(number, unit, _) => {
+            value(parse_decimal(number).map(|number| Value::Quantity(Quantity::new(number, unit))))
+        }
+        _ => None,
+    }
+}
+
+pub(crate) fn union(mut c1: Collection, mut c2: Collection) -> Collection {
+    c1.append(&mut c2);
+    c1
+}
+
+/// Merges two collections as the `|` operator does, without duplicate items
+pub(crate) fn distinct_union(c1: Collection, c2: Collection) -> Collection {
+    let mut merged = Collection::new();
+    for value in c1.iter().chain(c2.iter()) {
+        if !includes(&merged, value) {
+            merged.push(value.clone());
+        }
     }
+    merged
+}
+
+/// Whether a collection has an item equal to a value
+pub(crate) fn includes(collection: &Collection, value: &Value) -> bool {
+    collection
+        .iter()
+        .any(|item| item.equal(value) == Some(true))
+}
+
+/// Whether the single item of one operand is equal to an item of the other; the result is empty
+/// if that operand is
+pub(crate) fn membership(
+    c1: &Collection,
+    op: MembershipOperator,
+    c2: &Collection,
+) -> Result<Collection, EvaluationError> {
+    let (item, collection) = match op {
+        MembershipOperator::In => (c1, c2),
+        MembershipOperator::Contains => (c2, c1),
+    };
+    let value = match item.as_slice() {
+        [] => return Ok(Collection::new()),
+        [value] => value,
+        items => {
+            return Err(EvaluationError::ExpectedSingleton {
+                expected: ANY,
+                count: items.len(),
+            })
+        }
+    };
+    Ok(Collection::from(Value::Boolean(includes(
+        collection, value,
+    ))))
+}
+
+pub(crate) fn equality(c1: &Collection, op: EqualityOperator, c2: &Collection) -> Collection {
+    let result = match op {
+        EqualityOperator::Equal => c1.equal(c2),
+        EqualityOperator::NotEqual => c1.equal(c2).map(|b| !b),
+        EqualityOperator::Equivalent => Some(c1.equivalent(c2)),
+        EqualityOperator::NotEquivalent => Some(!c1.equivalent(c2)),
+    };
+    result
+        .map(|b| Collection::from(Value::Boolean(b)))
+        .unwrap_or_default()
+}
+
+pub(crate) fn inequality(
+    c1: &Collection,
+    op: InequalityOperator,
+    c2: &Collection,
+) -> Result<Collection, EvaluationError> {
+    c1.check_comparable(c2)?;
+    let result = c1.compare(c2).map(|ordering| match op {
+        InequalityOperator::LessThan => ordering == Ordering::Less,
+        InequalityOperator::LessOrEqual => ordering != Ordering::Greater,
+        InequalityOperator::GreaterThan => ordering == Ordering::Greater,
+        InequalityOperator::GreaterOrEqual => ordering != Ordering::Less,
+    });
+    Ok(result
+        .map(|b| Collection::from(Value::Boolean(b)))
+        .unwrap_or_default())
+}
+
+pub(crate) fn additive(
+    c1: &Collection,
+    op: AdditiveOperator,
+    c2: &Collection,
+) -> Result<Collection, EvaluationError> {
+    match op {
+        AdditiveOperator::Plus => c1.arithmetic(c2, Value::add),
+        AdditiveOperator::Minus => c1.arithmetic(c2, Value::subtract),
+        AdditiveOperator::Concatenate => c1.concatenate(c2),
+    }
+}
+
+pub(crate) fn multiplicative(
+    c1: &Collection,
+    op: MultiplicativeOperator,
+    c2: &Collection,
+) -> Result<Collection, EvaluationError> {
+    match op {
+        MultiplicativeOperator::Multiply => c1.arithmetic(c2, Value::multiply),
+        MultiplicativeOperator::Divide => c1.arithmetic(c2, Value::divide),
+        MultiplicativeOperator::Div => c1.arithmetic(c2, Value::div),
+        MultiplicativeOperator::Mod => c1.arithmetic(c2, Value::modulo),
+    }
+}
+
+/// Evaluates a boolean operator with three-valued logic.  The right operand is only evaluated
+/// if the left one does not decide the result, as `false` does for `and`.
+pub(crate) fn boolean(
+    c1: &Collection,
+    op: BooleanOperator,
+    right: impl FnOnce() -> Result<Collection, EvaluationError>,
+) -> Result<Collection, EvaluationError> {
+    let left = c1.to_boolean()?;
+    let result = match (op, left) {
+        (BooleanOperator::And, Some(false)) => Some(false),
+        (BooleanOperator::Or, Some(true)) => Some(true),
+        (BooleanOperator::Implies, Some(false)) => Some(true),
+        _ => {
+            let right = right()?.to_boolean()?;
+            match op {
+                BooleanOperator::And => match (left, right) {
+                    (_, Some(false)) => Some(false),
+                    (Some(true), Some(true)) => Some(true),
+                    _ => None,
+                },
+                BooleanOperator::Or => match (left, right) {
+                    (_, Some(true)) => Some(true),
+                    (Some(false), Some(false)) => Some(false),
+                    _ => None,
+                },
+                BooleanOperator::Xor => left.zip(right).map(|(l, r)| l != r),
+                BooleanOperator::Implies => match (left, right) {
+                    (Some(true), right) => right,
+                    (None, Some(true)) => Some(true),
+                    _ => None,
+                },
+            }
+        }
+    };
+    Ok(result
+        .map(|b| Collection::from(Value::Boolean(b)))
+        .unwrap_or_default())
+}
+
+pub(crate) fn type_expression(
+    model: &Model,
+    input: &Collection,
+    op: TypeOperator,
+    specifier: &str,
+) -> Result<Collection, EvaluationError> {
+    match op {
+        TypeOperator::Is => input.is_type(specifier, model),
+        TypeOperator::As => input.as_type(specifier, model),
+    }
+}
+
+/// Selects the child elements with the given name from each item of the input
+pub(crate) fn navigate(input: &Collection, name: &str) -> Collection {
+    input
+        .iter()
+        .filter_map(|value| match value {
+            Value::Complex(node) => Some(node.member_values(name)),
+            _ => None,
+        })
+        .flatten()
+        .collect()
+}
+
+/// The definition of a named child of an element, which may be defined in the same structure,
+/// at a content reference, or by the element's type
+fn child_definition(
+    model: &Model,
+    definition: &'static StructureDefinition,
+    path: &str,
+    name: &str,
+) -> Option<ElementPath> {
+    if let Some(child) = definition.child_elements(path).find(|el| el.name() == name) {
+        return Some((definition, child.path.clone()));
+    }
+
+    let element = definition.element(path)?;
+    if let Some(reference) = &element.content_reference {
+        let reference = reference.rsplit('#').next()?;
+        return child_definition(model, definition, reference, name);
+    }
+    match element.r#type.as_slice() {
+        [t] => child_definition(model, model.structure_definition(&t.code)?, &t.code, name),
+        _ => None,
+    }
+}
+
+/// The expressions passed as parameters, which the parser joins with unions
+pub(crate) fn param_nodes(params: &ASTNode) -> Vec<&ASTNode> {
+    fn flatten<'a>(node: &'a ASTNode, nodes: &mut Vec<&'a ASTNode>) {
+        match node {
+            ASTNode::Union(left, right) => {
+                flatten(left, nodes);
+                flatten(right, nodes);
+            }
+            _ => nodes.push(node),
+        }
+    }
+
+    let mut nodes = Vec::new();
+    if let ASTNode::ParamList(Some(param)) = params {
+        flatten(param, &mut nodes);
+    }
+    nodes
+}
+
+/// Reads a type specifier passed as a function parameter, e.g. `ofType(FHIR.Quantity)`
+pub(crate) fn type_specifier(params: &ASTNode) -> Option<String> {
+    let ASTNode::ParamList(Some(param)) = params else {
+        return None;
+    };
+    match param.as_ref() {
+        ASTNode::Identifier(name) => Some(name.clone()),
+        ASTNode::InvocationExpression(namespace, name) => match (namespace.as_ref(), name.as_ref())
+        {
+            (ASTNode::Identifier(namespace), ASTNode::Identifier(name)) => {
+                Some(format!("{}.{}", namespace, name))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn parse_decimal(str: &str) -> Result<Decimal, EvaluationError> {
+    Decimal::from_str_radix(str, 10).map_err(|_| EvaluationError::InvalidDecimal(str.to_string()))
 }
@@ -1,5 +1,153 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use super::PACKAGE_PATH_VARIABLE;
+
 #[derive(Debug)]
 pub enum ParseError {
     InvalidJSON(serde_json::Error),
     MalformedBundle,
+    MissingResourceType,
+    UnknownType(String),
+    UnknownElement(String),
+    InvalidElement(String),
+    InvalidPrimitive(String, String),
+    Io(std::io::Error),
+    /// The default model could not be built to read the resource with
+    NoModel(Box<PackageError>),
+    /// An error reading the resource on a line (numbered from 1) of an NDJSON file
+    Line(usize, Box<ParseError>),
 }
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::InvalidJSON(err) => write!(f, "invalid JSON: {}", err),
+            ParseError::MalformedBundle => write!(f, "malformed Bundle"),
+            ParseError::MissingResourceType => write!(f, "missing resourceType"),
+            ParseError::UnknownType(name) => write!(f, "unknown type {}", name),
+            ParseError::UnknownElement(path) => write!(f, "unknown element {}", path),
+            ParseError::InvalidElement(path) => write!(f, "invalid element {}", path),
+            ParseError::InvalidPrimitive(path, value) => {
+                write!(f, "invalid value {} for {}", value, path)
+            }
+            ParseError::Io(err) => write!(f, "{}", err),
+            ParseError::NoModel(err) => write!(f, "{}", err),
+            ParseError::Line(line, err) => write!(f, "line {}: {}", line, err),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<PackageError> for ParseError {
+    fn from(err: PackageError) -> Self {
+        ParseError::NoModel(Box::new(err))
+    }
+}
+
+/// An error reading a `SpecificationPackage`
+#[derive(Debug)]
+pub enum PackageError {
+    Io(PathBuf, std::io::Error),
+    InvalidArchive(PathBuf, std::io::Error),
+    InvalidManifest(PathBuf, serde_json::Error),
+    InvalidFile(PathBuf, ParseError),
+    /// The package does not define the base resources and data types, so cannot be the
+    /// specification the model is read from
+    MissingDefinitions(String),
+    /// The package is not for a version of FHIR that models can be built for
+    UnsupportedVersion(String),
+    AlreadyInstalled,
+    /// No specification was installed, given by `MAGHEMITE_FHIR_PACKAGE` or embedded
+    NoSpecification,
+}
+
+impl fmt::Display for PackageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PackageError::Io(path, err) => write!(f, "cannot read {}: {}", path.display(), err),
+            PackageError::InvalidArchive(path, err) => {
+                write!(f, "{} is not a package archive: {}", path.display(), err)
+            }
+            PackageError::InvalidManifest(path, err) => {
+                write!(f, "invalid package manifest {}: {}", path.display(), err)
+            }
+            PackageError::InvalidFile(path, err) => write!(f, "{}: {}", path.display(), err),
+            PackageError::MissingDefinitions(name) => write!(
+                f,
+                "package {} does not define the base resources and data types",
+                name
+            ),
+            PackageError::UnsupportedVersion(name) => {
+                write!(f, "package {} is not for FHIR R4, R4B or R5", name)
+            }
+            PackageError::AlreadyInstalled => {
+                write!(f, "a FHIR specification has already been installed or used")
+            }
+            PackageError::NoSpecification => write!(
+                f,
+                "no FHIR specification is available; install one with \
+                 SpecificationPackage::install(), set {} to the path of a package, or enable \
+                 the `embedded-specification` feature",
+                PACKAGE_PATH_VARIABLE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PackageError {}
+
+/// An error generating the snapshot of a StructureDefinition from its differential
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The base definition, given by URL, is not known to the generator
+    UnknownBase(String),
+    /// The definition is, through its bases, derived from itself
+    CircularDefinition(String),
+    /// An element of the differential, given by id, is neither in the base nor a slice or an
+    /// element that can be added
+    UnknownElement(String),
+    /// The children of an element, given by id, are constrained, but its type is not known or
+    /// is a choice of several
+    UnknownType(String),
+    /// An element allows fewer or more items than its base allows
+    InvalidCardinality(String),
+    /// An element has a type, given by code, that its base does not allow
+    InvalidType(String, String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::UnknownBase(url) => write!(f, "unknown base definition {}", url),
+            SnapshotError::CircularDefinition(url) => {
+                write!(f, "{} is derived from itself", url)
+            }
+            SnapshotError::UnknownElement(id) => write!(f, "unknown element {}", id),
+            SnapshotError::UnknownType(id) => {
+                write!(
+                    f,
+                    "cannot find the children of {}, as its type is unknown",
+                    id
+                )
+            }
+            SnapshotError::InvalidCardinality(id) => {
+                write!(
+                    f,
+                    "the cardinality of {} is not within that of its base",
+                    id
+                )
+            }
+            SnapshotError::InvalidType(id, code) => {
+                write!(
+                    f,
+                    "{} cannot be of type {}, which its base does not allow",
+                    id, code
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
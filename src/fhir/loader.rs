@@ -0,0 +1,370 @@
+use super::*;
+use crate::fhirpath::{DataNode, Type, Value};
+use rust_decimal::Decimal;
+use serde::de::DeserializeOwned;
+use serde_json::Value as Json;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Converts a resource in the FHIR JSON format into a data tree, with the default model
+pub fn load_resource(json: &Json) -> Result<Arc<DataNode>, ParseError> {
+    default_model()?.load_resource(json)
+}
+
+/// Converts a value of the named data type with the default model
+pub fn load_data_type(json: &Json, code: &str) -> Result<Arc<DataNode>, ParseError> {
+    default_model()?.load_data_type(json, code)
+}
+
+impl Model {
+    /// Converts a resource in the FHIR JSON format into a data tree.  The model is used to type
+    /// each element, to name choice elements without their type suffix (`valueQuantity` becomes
+    /// `value`), and to convert primitives into FHIRPath values; `_element` properties are
+    /// merged into the primitives they extend.  The tree is shared, so that evaluating
+    /// expressions against it does not copy it.
+    pub fn load_resource(&self, json: &Json) -> Result<Arc<DataNode>, ParseError> {
+        load_resource_object(self, json).map(Arc::new)
+    }
+
+    /// Converts a value of the named data type, such as a `fixed[x]` value from a profile, or
+    /// of a System type given by its URL
+    pub fn load_data_type(&self, json: &Json, code: &str) -> Result<Arc<DataNode>, ParseError> {
+        if code.starts_with(SYSTEM_TYPE_PREFIX) {
+            return system_value(code, json)
+                .map(|value| Arc::new(DataNode::Value(value)))
+                .ok_or_else(|| ParseError::InvalidPrimitive(code.to_string(), json.to_string()));
+        }
+        if self.is_primitive_type(code) {
+            return load_primitive(self, code, Some(json), None, code).map(Arc::new);
+        }
+        let definition = self
+            .data_types()
+            .get(code)
+            .ok_or_else(|| ParseError::UnknownType(code.to_string()))?;
+        load_object(self, json, definition, code, type_name(self, code)?).map(Arc::new)
+    }
+}
+
+/// Reads the resources of one type from the entries of a Bundle, skipping any others
+pub fn load_bundle_resources<T: DeserializeOwned>(
+    data: &[u8],
+    resource_type: &str,
+) -> Result<Vec<T>, ParseError> {
+    let bundle: Json = serde_json::from_slice(data).map_err(ParseError::InvalidJSON)?;
+    let Some(entries) = bundle["entry"].as_array() else {
+        return Err(ParseError::MalformedBundle);
+    };
+
+    let mut resources = Vec::new();
+    for entry in entries {
+        let Some(resource) = entry["resource"].as_object() else {
+            return Err(ParseError::MalformedBundle);
+        };
+        if resource["resourceType"].as_str().unwrap_or("") == resource_type {
+            let resource = serde_json::from_value(entry["resource"].clone())
+                .map_err(ParseError::InvalidJSON)?;
+            resources.push(resource);
+        }
+    }
+
+    Ok(resources)
+}
+
+/// Converts a resource, whether at the root or contained in another
+fn load_resource_object(model: &Model, json: &Json) -> Result<DataNode, ParseError> {
+    let resource_type = json
+        .get("resourceType")
+        .and_then(Json::as_str)
+        .ok_or(ParseError::MissingResourceType)?;
+    let definition = model
+        .resources()
+        .get(resource_type)
+        .ok_or_else(|| ParseError::UnknownType(resource_type.to_string()))?;
+    load_object(
+        model,
+        json,
+        definition,
+        resource_type,
+        type_name(model, resource_type)?,
+    )
+}
+
+fn load_object(
+    model: &Model,
+    json: &Json,
+    definition: &StructureDefinition,
+    path: &str,
+    data_type: Type,
+) -> Result<DataNode, ParseError> {
+    let object = json
+        .as_object()
+        .ok_or_else(|| ParseError::InvalidElement(path.to_string()))?;
+
+    let mut members = Vec::new();
+    let mut consumed = vec!["resourceType".to_string()];
+    for element in definition.child_elements(path) {
+        let candidates: Vec<(String, &str)> = if element.is_choice() {
+            element
+                .r#type
+                .iter()
+                .map(|t| {
+                    (
+                        format!("{}{}", element.name(), capitalize(&t.code)),
+                        t.code.as_str(),
+                    )
+                })
+                .collect()
+        } else {
+            let code = element.r#type.first().map_or("", |t| t.code.as_str());
+            vec![(element.name().to_string(), code)]
+        };
+
+        for (key, code) in candidates {
+            let extension_key = format!("_{}", key);
+            let (value, extras) = (object.get(&key), object.get(&extension_key));
+            if value.is_none() && extras.is_none() {
+                continue;
+            }
+
+            let element_path = format!("{}.{}", path, key);
+            for (value, extras) in items(value, extras) {
+                let node = load_element(
+                    model,
+                    element,
+                    code,
+                    definition,
+                    value,
+                    extras,
+                    &element_path,
+                )?;
+                members.push((element.name().to_string(), Arc::new(node)));
+            }
+            consumed.extend([key, extension_key]);
+        }
+    }
+
+    if let Some(key) = object.keys().find(|key| !consumed.contains(key)) {
+        return Err(ParseError::UnknownElement(format!("{}.{}", path, key)));
+    }
+    Ok(DataNode::Object(data_type, members))
+}
+
+fn load_element(
+    model: &Model,
+    element: &ElementDefinition,
+    code: &str,
+    definition: &StructureDefinition,
+    value: Option<&Json>,
+    extras: Option<&Json>,
+    path: &str,
+) -> Result<DataNode, ParseError> {
+    let complex_value = || value.ok_or_else(|| ParseError::InvalidElement(path.to_string()));
+
+    if let Some(reference) = &element.content_reference {
+        let reference = reference.rsplit('#').next().unwrap_or(reference);
+        return load_object(
+            model,
+            complex_value()?,
+            definition,
+            reference,
+            type_name(model, "BackboneElement")?,
+        );
+    }
+
+    match code {
+        "BackboneElement" | "Element" => load_object(
+            model,
+            complex_value()?,
+            definition,
+            &element.path,
+            type_name(model, code)?,
+        ),
+        "Resource" => load_resource_object(model, complex_value()?),
+        _ if code.starts_with(SYSTEM_TYPE_PREFIX) => {
+            let value = complex_value()?;
+            system_value(code, value)
+                .map(DataNode::Value)
+                .ok_or_else(|| ParseError::InvalidPrimitive(path.to_string(), value.to_string()))
+        }
+        _ if model.is_primitive_type(code) => load_primitive(model, code, value, extras, path),
+        _ => {
+            let definition = model
+                .data_types()
+                .get(code)
+                .ok_or_else(|| ParseError::UnknownType(code.to_string()))?;
+            load_object(
+                model,
+                complex_value()?,
+                definition,
+                code,
+                type_name(model, code)?,
+            )
+        }
+    }
+}
+
+/// Primitives are objects with an optional System `value`, alongside any `id` and `extension`
+fn load_primitive(
+    model: &Model,
+    code: &str,
+    value: Option<&Json>,
+    extras: Option<&Json>,
+    path: &str,
+) -> Result<DataNode, ParseError> {
+    let data_type = type_name(model, code)?;
+    let definition = &model.data_types()[code];
+    let mut members = match extras {
+        Some(extras) => match load_object(model, extras, definition, code, data_type)? {
+            DataNode::Object(_, members) => members,
+            DataNode::Value(_) => Vec::new(),
+        },
+        None => Vec::new(),
+    };
+
+    if let Some(value) = value {
+        let value = primitive_value(code, value)
+            .ok_or_else(|| ParseError::InvalidPrimitive(path.to_string(), value.to_string()))?;
+        members.push(("value".to_string(), Arc::new(DataNode::Value(value))));
+    }
+    Ok(DataNode::Object(data_type, members))
+}
+
+fn primitive_value(code: &str, json: &Json) -> Option<Value> {
+    match code {
+        "boolean" => json.as_bool().map(Value::Boolean),
+        "integer" | "positiveInt" | "unsignedInt" => json
+            .as_i64()
+            .and_then(|n| i32::try_from(n).ok())
+            .map(Value::Integer),
+        "integer64" => match json {
+            Json::String(s) => s.parse().ok().map(Value::Long),
+            _ => json.as_i64().map(Value::Long),
+        },
+        "decimal" => match json {
+            Json::Number(n) => {
+                let s = n.to_string();
+                Decimal::from_str(&s)
+                    .or_else(|_| Decimal::from_scientific(&s))
+                    .ok()
+                    .map(Value::Decimal)
+            }
+            _ => None,
+        },
+        "date" => json.as_str()?.parse().ok().map(Value::Date),
+        "dateTime" | "instant" => json.as_str()?.parse().ok().map(Value::DateTime),
+        "time" => json.as_str()?.parse().ok().map(Value::Time),
+        _ => json.as_str().map(Value::string),
+    }
+}
+
+/// Values of elements typed directly as FHIRPath System types, like `Element.id`
+fn system_value(code: &str, json: &Json) -> Option<Value> {
+    let primitive = match code.strip_prefix(SYSTEM_TYPE_PREFIX)? {
+        "System.Boolean" => "boolean",
+        "System.Integer" => "integer",
+        "System.Decimal" => "decimal",
+        "System.Date" => "date",
+        "System.DateTime" => "dateTime",
+        "System.Time" => "time",
+        "System.Long" | "System.Integer64" => "integer64",
+        _ => "string",
+    };
+    primitive_value(primitive, json)
+}
+
+/// Pairs up the items of an element and of its `_element` extension, which are either both
+/// single values or both arrays in which `null` marks a missing entry
+pub(crate) fn items<'a>(
+    value: Option<&'a Json>,
+    extras: Option<&'a Json>,
+) -> Vec<(Option<&'a Json>, Option<&'a Json>)> {
+    let non_null = |json: Option<&'a Json>| json.filter(|json| !json.is_null());
+    let (values, extras): (&[Json], &[Json]) = match (value, extras) {
+        (Some(Json::Array(values)), Some(Json::Array(extras))) => (values, extras),
+        (Some(Json::Array(values)), None) => (values, &[]),
+        (None, Some(Json::Array(extras))) => (&[], extras),
+        _ => {
+            return Some((non_null(value), non_null(extras)))
+                .filter(|(value, extras)| value.is_some() || extras.is_some())
+                .into_iter()
+                .collect()
+        }
+    };
+
+    (0..values.len().max(extras.len()))
+        .map(|i| (non_null(values.get(i)), non_null(extras.get(i))))
+        .filter(|(value, extras)| value.is_some() || extras.is_some())
+        .collect()
+}
+
+fn type_name(model: &Model, name: &str) -> Result<Type, ParseError> {
+    model
+        .fhir_type(name)
+        .ok_or_else(|| ParseError::UnknownType(name.to_string()))
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_load_resource() {
+        let patient = load_resource(&json!({
+            "resourceType": "Patient",
+            "birthDate": "1974-12-25",
+            "_birthDate": { "id": "dob" },
+            "deceasedBoolean": false,
+            "name": [{ "given": ["Peter", null], "_given": [null, { "id": "g2" }] }]
+        }))
+        .unwrap();
+
+        assert_eq!(patient.data_type(), "FHIR.Patient");
+        let birth_date = patient.member("birthDate").unwrap();
+        assert_eq!(birth_date.data_type(), "FHIR.date");
+        assert_eq!(
+            birth_date.primitive_value(),
+            Some(&Value::Date("1974-12-25".parse().unwrap()))
+        );
+        assert_eq!(birth_date.member_value("id"), Some(&Value::string("dob")));
+        assert_eq!(
+            patient.member_value("deceased"),
+            Some(&Value::boolean(false))
+        );
+
+        let name = patient.member("name").unwrap();
+        let given: Vec<_> = name.members("given").collect();
+        assert_eq!(given.len(), 2);
+        assert_eq!(given[0].primitive_value(), Some(&Value::string("Peter")));
+        assert_eq!(given[1].primitive_value(), None);
+        assert_eq!(given[1].member_value("id"), Some(&Value::string("g2")));
+    }
+
+    #[test]
+    fn test_invalid_resources() {
+        assert!(matches!(
+            load_resource(&json!({ "id": "x" })),
+            Err(ParseError::MissingResourceType)
+        ));
+        assert!(matches!(
+            load_resource(&json!({ "resourceType": "Unicorn" })),
+            Err(ParseError::UnknownType(_))
+        ));
+        assert!(matches!(
+            load_resource(&json!({ "resourceType": "Patient", "colour": "blue" })),
+            Err(ParseError::UnknownElement(_))
+        ));
+        assert!(matches!(
+            load_resource(&json!({ "resourceType": "Patient", "birthDate": "yesterday" })),
+            Err(ParseError::InvalidPrimitive(_, _))
+        ));
+    }
+}
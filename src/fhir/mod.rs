@@ -1,7 +1,21 @@
 mod errors;
+mod loader;
+mod model;
+mod narrative;
+mod ndjson;
+mod package;
+mod snapshot;
 mod static_data;
 mod structure_definition;
+mod terminology;
 
 pub use errors::*;
+pub use loader::*;
+pub use model::*;
+pub use narrative::*;
+pub use ndjson::*;
+pub use package::*;
+pub use snapshot::*;
 pub use static_data::*;
 pub use structure_definition::*;
+pub use terminology::*;
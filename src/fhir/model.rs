@@ -0,0 +1,341 @@
+use super::*;
+use crate::fhirpath::Type;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Mutex;
+
+/// Prefix of the type codes used in snapshots for elements holding FHIRPath System values, such
+/// as `Element.id` and the `value` of primitive types
+pub const SYSTEM_TYPE_PREFIX: &str = "http://hl7.org/fhirpath/";
+
+/// The releases of FHIR that models can be built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FhirVersion {
+    R4,
+    R4B,
+    R5,
+}
+
+impl FhirVersion {
+    /// The release a version number such as `4.0.1` belongs to
+    pub fn from_version(version: &str) -> Option<Self> {
+        let mut parts = version.split('.');
+        match (parts.next()?, parts.next()?) {
+            ("4", "0") => Some(FhirVersion::R4),
+            ("4", "1" | "3") => Some(FhirVersion::R4B),
+            ("5", "0") => Some(FhirVersion::R5),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FhirVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+/// The resources and data types of one version of FHIR, which resources are read and typed
+/// against.  Models live for the rest of the program, so that evaluations can hold on to the
+/// definitions they look up; see `default_model()` and `Model::install()`.
+#[derive(Debug)]
+pub struct Model {
+    version: FhirVersion,
+    package: &'static SpecificationPackage,
+    fields: HashMap<&'static str, &'static ElementDefinition>,
+    /// Qualified names of all resources and data types, e.g. `FHIR.Patient`
+    type_names: HashMap<&'static str, Type>,
+}
+
+impl Model {
+    /// Builds the model defined by a package, which must define the base resources and data
+    /// types of a version of FHIR
+    pub fn new(package: &'static SpecificationPackage) -> Result<Self, PackageError> {
+        package.check_core()?;
+        let version = fhir_version(package)?;
+
+        let fields = package
+            .resources()
+            .values()
+            .flat_map(|sd| {
+                sd.snapshot
+                    .iter()
+                    .flat_map(|snapshot| snapshot.element.iter())
+            })
+            .map(|el| (el.path.as_str(), el))
+            .collect();
+        let type_names = package
+            .resources()
+            .keys()
+            .chain(package.data_types().keys())
+            .map(|name| (name.as_str(), intern(&format!("FHIR.{}", name))))
+            .collect();
+        Ok(Model {
+            version,
+            package,
+            fields,
+            type_names,
+        })
+    }
+
+    pub fn version(&self) -> FhirVersion {
+        self.version
+    }
+
+    /// The package the model was built from
+    pub fn package(&self) -> &'static SpecificationPackage {
+        self.package
+    }
+
+    pub fn resources(&self) -> &'static HashMap<String, StructureDefinition> {
+        self.package.resources()
+    }
+
+    pub fn data_types(&self) -> &'static HashMap<String, StructureDefinition> {
+        self.package.data_types()
+    }
+
+    /// Looks up an element of a resource by its path, e.g. `Patient.birthDate`
+    pub fn field(&self, path: &str) -> Option<&'static ElementDefinition> {
+        self.fields.get(path).copied()
+    }
+
+    /// Looks up the definition of a resource or data type by name
+    pub fn structure_definition(&self, name: &str) -> Option<&'static StructureDefinition> {
+        self.resources()
+            .get(name)
+            .or_else(|| self.data_types().get(name))
+    }
+
+    /// Looks up a resource or data type by its canonical URL (optionally with a `|version`) or
+    /// name
+    pub fn find_structure_definition(
+        &self,
+        reference: &str,
+    ) -> Option<&'static StructureDefinition> {
+        let url = reference.split('|').next().unwrap_or(reference);
+        self.structure_definition(url).or_else(|| {
+            self.resources()
+                .values()
+                .chain(self.data_types().values())
+                .find(|sd| sd.url == url)
+        })
+    }
+
+    /// The qualified FHIRPath type name for a resource or data type, e.g. `FHIR.Patient`
+    pub fn fhir_type(&self, name: &str) -> Option<Type> {
+        self.type_names.get(name).copied()
+    }
+
+    /// The name of the type that a resource or data type is derived from
+    pub fn base_type(&self, name: &str) -> Option<&'static str> {
+        let base_definition = self.structure_definition(name)?.base_definition.as_ref()?;
+        base_definition.rsplit('/').next()
+    }
+
+    /// Whether a type is the same as, or derived from, another
+    pub fn is_subtype(&self, name: &str, ancestor: &str) -> bool {
+        let mut current = Some(name);
+        while let Some(name) = current {
+            if name == ancestor {
+                return true;
+            }
+            current = self.base_type(name);
+        }
+        false
+    }
+
+    pub fn is_primitive_type(&self, name: &str) -> bool {
+        self.data_types()
+            .get(name)
+            .is_some_and(|sd| sd.kind == "primitive-type")
+    }
+
+    /// Whether a resource is abstract, so that which elements its instances have depends on
+    /// their actual type
+    pub fn is_abstract_resource(&self, name: &str) -> bool {
+        self.resources().get(name).is_some_and(|sd| sd.r#abstract)
+    }
+}
+
+/// The version of FHIR a package is for.  Core packages may not list the versions they are
+/// for, being the version itself.
+pub(crate) fn fhir_version(package: &SpecificationPackage) -> Result<FhirVersion, PackageError> {
+    package
+        .fhir_versions()
+        .iter()
+        .map(String::as_str)
+        .chain([package.version()])
+        .find_map(FhirVersion::from_version)
+        .ok_or_else(|| PackageError::UnsupportedVersion(package.name().to_string()))
+}
+
+/// Type names are shared between models, so that a type such as `FHIR.Patient` is the same
+/// whichever model a data tree was read with
+fn intern(name: &str) -> Type {
+    static NAMES: Mutex<Option<HashSet<Type>>> = Mutex::new(None);
+    let mut names = NAMES
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let names = names.get_or_insert_with(HashSet::new);
+    match names.get(name) {
+        Some(name) => name,
+        None => {
+            let name: Type = Box::leak(name.to_string().into_boxed_str());
+            names.insert(name);
+            name
+        }
+    }
+}
+
+impl StructureDefinition {
+    /// The elements defined directly beneath the element at `path`, excluding slices and their
+    /// contents
+    pub fn child_elements<'a>(
+        &'a self,
+        path: &'a str,
+    ) -> impl Iterator<Item = &'a ElementDefinition> + 'a {
+        self.snapshot
+            .iter()
+            .flat_map(|snapshot| snapshot.element.iter())
+            .filter(move |el| {
+                !el.id.contains(':')
+                    && el
+                        .path
+                        .strip_prefix(path)
+                        .and_then(|rest| rest.strip_prefix('.'))
+                        .is_some_and(|name| !name.contains('.'))
+            })
+    }
+
+    pub fn element(&self, path: &str) -> Option<&ElementDefinition> {
+        self.snapshot
+            .as_ref()?
+            .element
+            .iter()
+            .find(|el| el.path == path && !el.id.contains(':'))
+    }
+
+    /// Looks up an element by its id, e.g. `Observation.component:systolic.code`
+    pub fn element_by_id(&self, id: &str) -> Option<&ElementDefinition> {
+        self.snapshot
+            .as_ref()?
+            .element
+            .iter()
+            .find(|el| el.id == id)
+    }
+
+    /// The definition of the slice with the given name
+    pub fn slice(&self, name: &str) -> Option<&ElementDefinition> {
+        self.snapshot
+            .as_ref()?
+            .element
+            .iter()
+            .find(|el| el.slice_name.as_deref() == Some(name))
+    }
+}
+
+impl ElementDefinition {
+    /// The name of the element, which for choice types omits the `[x]` suffix
+    pub fn name(&self) -> &str {
+        let name = self.path.rsplit('.').next().unwrap_or(&self.path);
+        name.strip_suffix("[x]").unwrap_or(name)
+    }
+
+    pub fn is_choice(&self) -> bool {
+        self.path.ends_with("[x]")
+    }
+
+    /// The type and value of the element's `fixed[x]` property
+    pub fn fixed(&self) -> Option<(String, &serde_json::Value)> {
+        self.choice_property("fixed")
+    }
+
+    /// The type and value of the element's `pattern[x]` property
+    pub fn pattern(&self) -> Option<(String, &serde_json::Value)> {
+        self.choice_property("pattern")
+    }
+
+    fn choice_property(&self, prefix: &str) -> Option<(String, &serde_json::Value)> {
+        self.properties.iter().find_map(|(key, value)| {
+            let suffix = key.strip_prefix(prefix)?;
+            // The value is of one of the element's types, whose code is the suffix with its
+            // first letter in either case (`fixedCode` for a `code`, `fixedCoding` for a
+            // `Coding`)
+            let code = self
+                .r#type
+                .iter()
+                .map(|t| t.code.as_str())
+                .find(|code| {
+                    let mut chars = code.chars();
+                    chars.next().is_some_and(|first| {
+                        suffix.chars().next() == first.to_uppercase().next()
+                            && suffix.get(first.len_utf8()..) == Some(chars.as_str())
+                    })
+                })
+                .unwrap_or(suffix);
+            Some((code.to_string(), value))
+        })
+    }
+
+    pub fn is_repeating(&self) -> bool {
+        self.max
+            .as_deref()
+            .is_some_and(|max| max != "0" && max != "1")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_hierarchy() {
+        let model = default_model().unwrap();
+        assert_eq!(model.version(), FhirVersion::R4B);
+        assert_eq!(model.base_type("Patient"), Some("DomainResource"));
+        assert!(model.is_subtype("Patient", "Resource"));
+        assert!(model.is_subtype("code", "string"));
+        assert!(model.is_subtype("SimpleQuantity", "Quantity"));
+        assert!(!model.is_subtype("HumanName", "Resource"));
+        assert!(model.is_primitive_type("date"));
+        assert!(!model.is_primitive_type("HumanName"));
+        assert!(model.is_abstract_resource("DomainResource"));
+        assert!(!model.is_abstract_resource("Patient"));
+        assert_eq!(model.fhir_type("Patient"), Some("FHIR.Patient"));
+    }
+
+    #[test]
+    fn test_fhir_versions() {
+        assert_eq!(FhirVersion::from_version("4.0.1"), Some(FhirVersion::R4));
+        assert_eq!(FhirVersion::from_version("4.3.0"), Some(FhirVersion::R4B));
+        assert_eq!(FhirVersion::from_version("5.0.0"), Some(FhirVersion::R5));
+        assert_eq!(FhirVersion::from_version("3.0.2"), None);
+        assert_eq!(FhirVersion::from_version("5"), None);
+        assert_eq!(FhirVersion::R4B.to_string(), "R4B");
+    }
+
+    #[test]
+    fn test_child_elements() {
+        let model = default_model().unwrap();
+        let names: Vec<_> = model.data_types()["Quantity"]
+            .child_elements("Quantity")
+            .map(|el| el.name())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                "id",
+                "extension",
+                "value",
+                "comparator",
+                "unit",
+                "system",
+                "code"
+            ]
+        );
+        assert!(model.resources()["Observation"]
+            .element("Observation.value[x]")
+            .is_some_and(|el| el.is_choice() && !el.is_repeating()));
+    }
+}
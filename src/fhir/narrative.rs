@@ -0,0 +1,244 @@
+/// Elements allowed in narrative XHTML: the basic formatting elements of HTML 4.0, anchors and
+/// images
+const ALLOWED_ELEMENTS: [&str; 52] = [
+    "a",
+    "abbr",
+    "acronym",
+    "address",
+    "b",
+    "big",
+    "blockquote",
+    "br",
+    "caption",
+    "cite",
+    "code",
+    "col",
+    "colgroup",
+    "dd",
+    "del",
+    "dfn",
+    "div",
+    "dl",
+    "dt",
+    "em",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "i",
+    "img",
+    "ins",
+    "kbd",
+    "li",
+    "ol",
+    "p",
+    "pre",
+    "q",
+    "samp",
+    "small",
+    "span",
+    "strong",
+    "sub",
+    "sup",
+    "table",
+    "tbody",
+    "td",
+    "tfoot",
+    "th",
+    "thead",
+    "tr",
+    "tt",
+    "ul",
+    "var",
+];
+
+/// Checks the rules for narrative XHTML (txt-1 and txt-2): the content must be a `div` using
+/// only basic formatting elements, with no scripts, event handlers or active content
+pub fn is_valid_narrative(xhtml: &str) -> bool {
+    let xhtml = xhtml.trim();
+    if !xhtml.starts_with("<div") {
+        return false;
+    }
+
+    let mut rest = xhtml;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start + 1..];
+        if let Some(comment) = rest.strip_prefix("!--") {
+            match comment.find("-->") {
+                Some(end) => rest = &comment[end + 3..],
+                None => return false,
+            }
+            continue;
+        }
+
+        let Some(end) = tag_end(rest) else {
+            return false;
+        };
+        let tag = &rest[..end];
+        rest = &rest[end + 1..];
+
+        let tag = tag.strip_prefix('/').unwrap_or(tag);
+        let tag = tag.strip_suffix('/').unwrap_or(tag);
+        let name_end = tag.find(|c: char| c.is_whitespace()).unwrap_or(tag.len());
+        let (name, attributes) = tag.split_at(name_end);
+        let name = name.to_ascii_lowercase();
+        if !ALLOWED_ELEMENTS.contains(&name.as_str()) || !attributes_allowed(attributes) {
+            return false;
+        }
+    }
+    true
+}
+
+/// The position of the `>` closing a tag, skipping any inside quoted attribute values
+fn tag_end(tag: &str) -> Option<usize> {
+    let mut quote = None;
+    for (i, c) in tag.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '>') => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Event handler attributes and `javascript:` URLs are not allowed
+fn attributes_allowed(attributes: &str) -> bool {
+    let mut rest = attributes.trim_start();
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '=')
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        if name.starts_with("on") {
+            return false;
+        }
+        rest = rest[name_end..].trim_start();
+
+        let Some(value) = rest.strip_prefix('=') else {
+            continue;
+        };
+        let value = value.trim_start();
+        let (value, after) = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => match value[1..].find(quote) {
+                Some(end) => (&value[1..end + 1], &value[end + 2..]),
+                None => (&value[1..], ""),
+            },
+            _ => {
+                let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                value.split_at(end)
+            }
+        };
+        // Browsers ignore whitespace and control characters within a URL's scheme
+        let url: String = decode_entities(value)
+            .chars()
+            .filter(|c| !c.is_whitespace() && !c.is_control())
+            .collect();
+        if url.to_ascii_lowercase().contains("javascript:") {
+            return false;
+        }
+        rest = after.trim_start();
+    }
+    true
+}
+
+/// Replaces character references, such as `&#58;`, `&#x3A;` or `&colon;`, with the characters
+/// they stand for
+fn decode_entities(value: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let reference = rest[1..].find(';').map(|end| &rest[1..end + 1]);
+        let c = reference.and_then(|reference| match reference.strip_prefix('#') {
+            Some(number) => match number.strip_prefix(['x', 'X']) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok(),
+                None => number.parse().ok(),
+            }
+            .and_then(char::from_u32),
+            None => match reference.to_ascii_lowercase().as_str() {
+                "colon" => Some(':'),
+                "tab" => Some('\t'),
+                "newline" => Some('\n'),
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => None,
+            },
+        });
+        match (c, reference) {
+            (Some(c), Some(reference)) => {
+                decoded.push(c);
+                rest = &rest[reference.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_narrative_checks() {
+        let cases = vec![
+            (
+                r#"<div xmlns="http://www.w3.org/1999/xhtml"><p>Hello <b>world</b></p></div>"#,
+                true,
+            ),
+            (
+                r#"<div xmlns="http://www.w3.org/1999/xhtml"><!-- note --><img src="x.png"/><a href="http://example.org">link</a></div>"#,
+                true,
+            ),
+            (
+                r#"<div xmlns="http://www.w3.org/1999/xhtml"><table><tr><td title="a > b">1</td></tr></table></div>"#,
+                true,
+            ),
+            ("<p>Not a div</p>", false),
+            (r#"<div><script>alert(1)</script></div>"#, false),
+            (r#"<div><p onclick="alert(1)">Hi</p></div>"#, false),
+            (r#"<div><a href="javascript:alert(1)">Hi</a></div>"#, false),
+            (r#"<div><img src="x" onerror ="alert(1)"/></div>"#, false),
+            (r#"<div><img src="x" ONLOAD = 'alert(1)'/></div>"#, false),
+            (r#"<div><p onclick>Hi</p></div>"#, false),
+            (
+                r#"<div><a href="java&#115;cript&#58;alert(1)">Hi</a></div>"#,
+                false,
+            ),
+            (
+                r#"<div><a href="&#x6A;avascript&colon;alert(1)">Hi</a></div>"#,
+                false,
+            ),
+            (
+                r#"<div><a href=" java&#9;script:alert(1)">Hi</a></div>"#,
+                false,
+            ),
+            (r#"<div><a href=javascript:alert(1)>Hi</a></div>"#, false),
+            (
+                r#"<div><a href="http://example.org/?a=1&amp;b=2" title="one &amp; only">x</a></div>"#,
+                true,
+            ),
+            (r#"<div><p title="on the ward">Hi</p></div>"#, true),
+            (r#"<div><form action="x"></form></div>"#, false),
+            (r#"<div><p>Unclosed"#, true),
+            (r#"<div><p"#, false),
+        ];
+
+        for (xhtml, expected) in cases {
+            assert_eq!(is_valid_narrative(xhtml), expected, "{}", xhtml);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use super::*;
+use crate::fhirpath::DataNode;
+use std::io::{self, BufRead};
+use std::sync::Arc;
+
+/// Reads resources from NDJSON, one per line, as FHIR Bulk Data exports are written.  Lines are
+/// read as they are needed, so files of any size can be read in bounded memory.  Blank lines are
+/// skipped, and a line that cannot be read or parsed gives a `ParseError::Line` error without
+/// stopping the lines after it.
+pub struct NdjsonReader<R> {
+    lines: io::Lines<R>,
+    line: usize,
+    failed: bool,
+    model: Option<&'static Model>,
+}
+
+impl<R: BufRead> NdjsonReader<R> {
+    /// Reads resources with the default model
+    pub fn new(reader: R) -> Self {
+        NdjsonReader {
+            lines: reader.lines(),
+            line: 0,
+            failed: false,
+            model: None,
+        }
+    }
+
+    /// Reads resources with a model other than the default, e.g. one for another version of FHIR
+    pub fn with_model(mut self, model: &'static Model) -> Self {
+        self.model = Some(model);
+        self
+    }
+
+    /// The next non-blank line and its number, without parsing it.  Reading stops after an
+    /// error other than a line that is not valid UTF-8.
+    pub fn next_line(&mut self) -> Option<Result<(usize, String), ParseError>> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            self.line += 1;
+            match self.lines.next()? {
+                Ok(text) if text.trim().is_empty() => continue,
+                Ok(text) => return Some(Ok((self.line, text))),
+                Err(err) => {
+                    self.failed = err.kind() != io::ErrorKind::InvalidData;
+                    return Some(Err(ParseError::Line(
+                        self.line,
+                        Box::new(ParseError::Io(err)),
+                    )));
+                }
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for NdjsonReader<R> {
+    type Item = Result<Arc<DataNode>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (line, text) = match self.next_line()? {
+            Ok(line) => line,
+            Err(err) => return Some(Err(err)),
+        };
+        let model = self.model.map_or_else(default_model, Ok);
+        Some(
+            model
+                .map_err(|err| ParseError::Line(line, Box::new(err.into())))
+                .and_then(|model| load_ndjson_line(model, line, &text)),
+        )
+    }
+}
+
+/// Converts a line of NDJSON into a data tree with a model, recording the line's number with
+/// any error
+pub fn load_ndjson_line(
+    model: &Model,
+    line: usize,
+    text: &str,
+) -> Result<Arc<DataNode>, ParseError> {
+    serde_json::from_str(text)
+        .map_err(ParseError::InvalidJSON)
+        .and_then(|json| model.load_resource(&json))
+        .map_err(|err| ParseError::Line(line, Box::new(err)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ndjson_reader() {
+        let mut data = b"{\"resourceType\": \"Patient\", \"id\": \"a\"}\n\n".to_vec();
+        data.extend(b"{\"resourceType\": \"Patient\", \n");
+        data.extend(b"{\"resourceType\": \"Unicorn\"}\n");
+        data.extend(b"\xff\xfe\n");
+        data.extend(b"{\"resourceType\": \"Observation\", \"status\": \"final\"}");
+
+        let results: Vec<_> = NdjsonReader::new(data.as_slice()).collect();
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0].as_ref().unwrap().data_type(), "FHIR.Patient");
+        assert!(matches!(
+            &results[1],
+            Err(ParseError::Line(3, err)) if matches!(**err, ParseError::InvalidJSON(_))
+        ));
+        assert!(matches!(
+            &results[2],
+            Err(ParseError::Line(4, err)) if matches!(**err, ParseError::UnknownType(_))
+        ));
+        assert!(matches!(
+            &results[3],
+            Err(ParseError::Line(5, err)) if matches!(**err, ParseError::Io(_))
+        ));
+        assert_eq!(results[4].as_ref().unwrap().data_type(), "FHIR.Observation");
+
+        let data = br#"{"resourceType": "Observation", "status": "final", "code": { "text": "Steps" }, "instantiatesCanonical": "http://example.org/steps"}"#;
+        assert!(NdjsonReader::new(data.as_slice()).all(|result| result.is_err()));
+        let r5 = crate::tests::r5_model();
+        assert!(NdjsonReader::new(data.as_slice())
+            .with_model(r5)
+            .all(|result| result.is_ok()));
+    }
+}
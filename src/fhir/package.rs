@@ -0,0 +1,479 @@
+use super::*;
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use serde_json::Value as Json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Environment variable giving the path of the package to read the specification from, when
+/// none has been installed
+pub const PACKAGE_PATH_VARIABLE: &str = "MAGHEMITE_FHIR_PACKAGE";
+
+/// Prefix of the URLs of the definitions in the specification itself
+const CORE_URL_PREFIX: &str = "http://hl7.org/fhir/StructureDefinition/";
+
+static SPECIFICATION: OnceLock<SpecificationPackage> = OnceLock::new();
+
+/// The StructureDefinitions of a FHIR specification or implementation guide, as published in an
+/// NPM package.  Resources and data types are what the model is built from; constraints on
+/// them (other than those in the specification itself, such as `SimpleQuantity`) are profiles,
+/// which can be given to `EvaluationContext::with_profile()`.
+#[derive(Debug, Clone)]
+pub struct SpecificationPackage {
+    name: String,
+    version: String,
+    fhir_versions: Vec<String>,
+    resources: HashMap<String, StructureDefinition>,
+    data_types: HashMap<String, StructureDefinition>,
+    profiles: Vec<StructureDefinition>,
+}
+
+/// The parts of `package.json` that describe the package
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Manifest {
+    name: String,
+    version: String,
+    #[serde(default)]
+    fhir_versions: Vec<String>,
+}
+
+impl SpecificationPackage {
+    /// Reads a package from the `.tgz` file it is published as, or from a directory it has been
+    /// unpacked into: either its `package` directory or the directory containing that.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PackageError> {
+        let path = path.as_ref();
+        let metadata = fs::metadata(path).map_err(|err| PackageError::Io(path.into(), err))?;
+        if metadata.is_dir() {
+            Self::load_directory(path)
+        } else {
+            Self::load_archive(path)
+        }
+    }
+
+    /// Reads the definitions from Bundles downloaded from the specification, such as
+    /// `profiles-resources.json` and `profiles-types.json`
+    pub fn load_bundles(
+        name: &str,
+        version: &str,
+        paths: &[impl AsRef<Path>],
+    ) -> Result<Self, PackageError> {
+        let files = paths
+            .iter()
+            .map(|path| {
+                let path = path.as_ref();
+                fs::read(path)
+                    .map(|data| (path.to_path_buf(), data))
+                    .map_err(|err| PackageError::Io(path.into(), err))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut package = Self::new(name, version);
+        package.add_files(files)?;
+        Ok(package)
+    }
+
+    /// A package of definitions that have already been read, e.g. ones derived from another
+    /// package's
+    pub fn from_definitions(
+        name: &str,
+        version: &str,
+        definitions: impl IntoIterator<Item = StructureDefinition>,
+    ) -> Self {
+        let mut package = Self::new(name, version);
+        for definition in definitions {
+            package.add(definition);
+        }
+        package
+    }
+
+    /// The R4B resources and data types compiled into the crate
+    #[cfg(feature = "embedded-specification")]
+    pub fn embedded() -> Result<Self, PackageError> {
+        let mut package = Self::new("hl7.fhir.r4b.core", "4.3.0");
+        package.add_files([
+            (
+                PathBuf::from("profiles-resources.json"),
+                include_bytes!("specification/R4B/profiles-resources.json").to_vec(),
+            ),
+            (
+                PathBuf::from("profiles-types.json"),
+                include_bytes!("specification/R4B/profiles-types.json").to_vec(),
+            ),
+        ])?;
+        Ok(package)
+    }
+
+    fn new(name: &str, version: &str) -> Self {
+        SpecificationPackage {
+            name: name.to_string(),
+            version: version.to_string(),
+            fhir_versions: Vec::new(),
+            resources: HashMap::new(),
+            data_types: HashMap::new(),
+            profiles: Vec::new(),
+        }
+    }
+
+    fn load_directory(path: &Path) -> Result<Self, PackageError> {
+        let package = path.join("package");
+        let directory = if package.is_dir() { &package } else { path };
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(directory).map_err(io_error(directory))? {
+            let path = entry.map_err(io_error(directory))?.path();
+            if path.is_file() && is_json(&path) {
+                let data = fs::read(&path).map_err(io_error(&path))?;
+                files.push((path, data));
+            }
+        }
+        files.sort_by(|(p1, _), (p2, _)| p1.cmp(p2));
+
+        let name = directory.file_name().unwrap_or_default().to_string_lossy();
+        let mut package = Self::new(&name, "");
+        package.add_files(files)?;
+        Ok(package)
+    }
+
+    fn load_archive(path: &Path) -> Result<Self, PackageError> {
+        let invalid = |err| PackageError::InvalidArchive(path.into(), err);
+        let file = fs::File::open(path).map_err(|err| PackageError::Io(path.into(), err))?;
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+
+        // Only the files directly in `package/` are read; examples and other content in its
+        // subdirectories are not definitions
+        let mut files = Vec::new();
+        for entry in archive.entries().map_err(invalid)? {
+            let mut entry = entry.map_err(invalid)?;
+            let name = entry.path().map_err(invalid)?.to_path_buf();
+            if name.parent() == Some(Path::new("package")) && is_json(&name) {
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).map_err(invalid)?;
+                files.push((name, data));
+            }
+        }
+
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        let mut package = Self::new(&name, "");
+        package.add_files(files)?;
+        Ok(package)
+    }
+
+    /// Reads `package.json` and the StructureDefinitions, on their own or in Bundles, among the
+    /// package's files.  Other resources are ignored.
+    fn add_files(
+        &mut self,
+        files: impl IntoIterator<Item = (PathBuf, Vec<u8>)>,
+    ) -> Result<(), PackageError> {
+        for (path, data) in files {
+            let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+            if file_name == "package.json" {
+                let manifest: Manifest = serde_json::from_slice(&data)
+                    .map_err(|err| PackageError::InvalidManifest(path.clone(), err))?;
+                self.name = manifest.name;
+                self.version = manifest.version;
+                self.fhir_versions = manifest.fhir_versions;
+                continue;
+            } else if file_name.starts_with('.') {
+                continue;
+            }
+
+            let invalid =
+                |err| PackageError::InvalidFile(path.clone(), ParseError::InvalidJSON(err));
+            let json: Json = serde_json::from_slice(&data).map_err(invalid)?;
+            match json["resourceType"].as_str() {
+                Some("StructureDefinition") => {
+                    self.add(serde_json::from_value(json).map_err(invalid)?)
+                }
+                Some("Bundle") => {
+                    for definition in StructureDefinition::load_bundle(&data)
+                        .map_err(|err| PackageError::InvalidFile(path.clone(), err))?
+                    {
+                        self.add(definition);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, definition: StructureDefinition) {
+        let is_constraint = definition.derivation.as_deref() == Some("constraint");
+        match definition.kind.as_str() {
+            "resource" if !is_constraint => {
+                self.resources.insert(definition.name.clone(), definition);
+            }
+            "primitive-type" | "complex-type"
+                if !is_constraint || definition.url.starts_with(CORE_URL_PREFIX) =>
+            {
+                self.data_types.insert(definition.name.clone(), definition);
+            }
+            _ if is_constraint => self.profiles.push(definition),
+            // Logical models describe no instances that can be read
+            _ => {}
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+
+    /// The versions of FHIR the package is for, from its `package.json`
+    pub fn fhir_versions(&self) -> &[String] {
+        &self.fhir_versions
+    }
+
+    /// The resources the package defines, by name
+    pub fn resources(&self) -> &HashMap<String, StructureDefinition> {
+        &self.resources
+    }
+
+    /// The primitive and complex data types the package defines, by name
+    pub fn data_types(&self) -> &HashMap<String, StructureDefinition> {
+        &self.data_types
+    }
+
+    /// The profiles the package defines, which constrain resources and data types
+    pub fn profiles(&self) -> &[StructureDefinition] {
+        &self.profiles
+    }
+
+    /// Makes this package the specification that the default model is built from.  It must
+    /// define the base resources and data types, and can only be installed before the default
+    /// model is first used.  See `Model::install()` for using other versions alongside it.
+    pub fn install(self) -> Result<(), PackageError> {
+        self.check_core()?;
+        SPECIFICATION
+            .set(self)
+            .map_err(|_| PackageError::AlreadyInstalled)
+    }
+
+    pub(crate) fn check_core(&self) -> Result<(), PackageError> {
+        let is_core = ["Resource", "DomainResource"]
+            .iter()
+            .all(|name| self.resources.contains_key(*name))
+            && ["Element", "string"]
+                .iter()
+                .all(|name| self.data_types.contains_key(*name));
+        if is_core {
+            Ok(())
+        } else {
+            Err(PackageError::MissingDefinitions(self.name.clone()))
+        }
+    }
+}
+
+/// The specification the default model is built from: the package installed with
+/// `SpecificationPackage::install()` or, if none has been, the package at the path given by
+/// `MAGHEMITE_FHIR_PACKAGE`, then the R4B specification compiled in with the
+/// `embedded-specification` feature.  A specification that cannot be read is read again on
+/// the next call, so one can still be installed after the error.
+pub fn specification() -> Result<&'static SpecificationPackage, PackageError> {
+    if let Some(package) = SPECIFICATION.get() {
+        return Ok(package);
+    }
+    let package = default_specification()?;
+    Ok(SPECIFICATION.get_or_init(|| package))
+}
+
+fn default_specification() -> Result<SpecificationPackage, PackageError> {
+    let package = match std::env::var_os(PACKAGE_PATH_VARIABLE) {
+        Some(path) => SpecificationPackage::load(path)?,
+        None => bundled_specification()?,
+    };
+    package.check_core()?;
+    Ok(package)
+}
+
+#[cfg(feature = "embedded-specification")]
+fn bundled_specification() -> Result<SpecificationPackage, PackageError> {
+    SpecificationPackage::embedded()
+}
+
+#[cfg(all(not(feature = "embedded-specification"), not(test)))]
+fn bundled_specification() -> Result<SpecificationPackage, PackageError> {
+    Err(PackageError::NoSpecification)
+}
+
+/// The crate's own tests read the R4B specification from its source when it is not embedded
+#[cfg(all(not(feature = "embedded-specification"), test))]
+fn bundled_specification() -> Result<SpecificationPackage, PackageError> {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/fhir/specification/R4B");
+    SpecificationPackage::load_bundles(
+        "hl7.fhir.r4b.core",
+        "4.3.0",
+        &[
+            directory.join("profiles-resources.json"),
+            directory.join("profiles-types.json"),
+        ],
+    )
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> PackageError + '_ {
+    move |err| PackageError::Io(path.into(), err)
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension == "json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use serde_json::json;
+
+    /// A directory for a test's files, removed when the test ends
+    struct TestDirectory(PathBuf);
+
+    impl TestDirectory {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("maghemite-{}-{}", name, std::process::id()));
+            fs::create_dir_all(path.join("package")).unwrap();
+            TestDirectory(path)
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn package_files() -> Vec<(&'static str, Json)> {
+        let definition = |name: &str| {
+            let mut json =
+                serde_json::to_value(&default_model().unwrap().data_types()[name]).unwrap();
+            json["resourceType"] = json!("StructureDefinition");
+            json
+        };
+        vec![
+            (
+                "package.json",
+                json!({ "name": "example.fhir.core", "version": "1.0.0", "fhirVersions": ["4.3.0"] }),
+            ),
+            (".index.json", json!({ "index-version": 1, "files": [] })),
+            ("StructureDefinition-Quantity.json", definition("Quantity")),
+            (
+                "StructureDefinition-SimpleQuantity.json",
+                definition("SimpleQuantity"),
+            ),
+            (
+                "StructureDefinition-example-patient.json",
+                json!({
+                    "resourceType": "StructureDefinition",
+                    "id": "example-patient",
+                    "url": "http://example.org/StructureDefinition/example-patient",
+                    "name": "ExamplePatient",
+                    "status": "active",
+                    "kind": "resource",
+                    "abstract": false,
+                    "type": "Patient",
+                    "baseDefinition": "http://hl7.org/fhir/StructureDefinition/Patient",
+                    "derivation": "constraint",
+                    "differential": { "element": [{ "id": "Patient.name", "path": "Patient.name", "min": 1 }] }
+                }),
+            ),
+            (
+                "ValueSet-example.json",
+                json!({ "resourceType": "ValueSet", "status": "active" }),
+            ),
+        ]
+    }
+
+    fn check_package(package: &SpecificationPackage) {
+        assert_eq!(package.name(), "example.fhir.core");
+        assert_eq!(package.version(), "1.0.0");
+        assert_eq!(package.fhir_versions(), ["4.3.0"]);
+        let mut data_types: Vec<_> = package.data_types().keys().collect();
+        data_types.sort();
+        assert_eq!(data_types, ["Quantity", "SimpleQuantity"]);
+        assert!(package.resources().is_empty());
+        assert_eq!(package.profiles().len(), 1);
+        assert_eq!(package.profiles()[0].name, "ExamplePatient");
+        assert!(matches!(
+            package.clone().install(),
+            Err(PackageError::MissingDefinitions(_))
+        ));
+    }
+
+    #[test]
+    fn test_load_package() {
+        let directory = TestDirectory::new("directory");
+        for (name, json) in package_files() {
+            fs::write(directory.0.join("package").join(name), json.to_string()).unwrap();
+        }
+        check_package(&SpecificationPackage::load(&directory.0).unwrap());
+        check_package(&SpecificationPackage::load(directory.0.join("package")).unwrap());
+
+        let archive = TestDirectory::new("archive");
+        let path = archive.0.join("example.fhir.core-1.0.0.tgz");
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            fs::File::create(&path).unwrap(),
+            flate2::Compression::default(),
+        ));
+        for (name, json) in package_files() {
+            let data = json.to_string();
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, format!("package/{}", name), data.as_bytes())
+                .unwrap();
+        }
+        // Examples are not definitions
+        let example = json!({ "resourceType": "StructureDefinition" }).to_string();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(example.len() as u64);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "package/example/bad.json", example.as_bytes())
+            .unwrap();
+        builder.into_inner().unwrap().finish().unwrap();
+        check_package(&SpecificationPackage::load(&path).unwrap());
+
+        fs::write(directory.0.join("package").join("broken.json"), "{").unwrap();
+        assert!(matches!(
+            SpecificationPackage::load(&directory.0),
+            Err(PackageError::InvalidFile(path, ParseError::InvalidJSON(_))) if path.ends_with("broken.json")
+        ));
+    }
+
+    #[test]
+    fn test_load_bundles() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/fhir/specification/R4B");
+        let package = SpecificationPackage::load_bundles(
+            "hl7.fhir.r4b.core",
+            "4.3.0",
+            &[directory.join("profiles-types.json")],
+        )
+        .unwrap();
+        assert_eq!(
+            package.data_types().len(),
+            default_model().unwrap().data_types().len()
+        );
+        assert!(package.resources().is_empty());
+
+        let missing = directory.join("profiles-others.json");
+        let err = SpecificationPackage::load_bundles("hl7.fhir.r4b.core", "4.3.0", &[&missing])
+            .unwrap_err();
+        assert!(
+            matches!(&err, PackageError::Io(path, err) if path == &missing && err.kind() == std::io::ErrorKind::NotFound)
+        );
+        assert!(err.to_string().contains("profiles-others.json"));
+
+        // Evaluation reports that no specification is available rather than panicking
+        let err = crate::evaluation::EvaluationError::from(PackageError::NoSpecification);
+        assert!(err.to_string().contains(PACKAGE_PATH_VARIABLE));
+    }
+}
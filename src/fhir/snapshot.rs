@@ -0,0 +1,857 @@
+use super::*;
+use std::collections::HashMap;
+
+/// How many definitions deep a definition can be derived, beyond which its bases are taken to
+/// lead back to it
+const MAX_DEPTH: usize = 32;
+
+/// Generates the snapshots of StructureDefinitions, such as profiles published with only a
+/// differential, by applying the differential to the snapshot of the base definition.
+///
+/// Elements of the differential constrain the cardinality, types, bindings, constraints and
+/// other properties of the elements of the base, slice them (`Patient.identifier:mrn`) and
+/// constrain the children of their types or content references, which are unfolded into the
+/// snapshot as needed.  Specializations (new resources and data types) add elements as well.
+/// Extension slices take their children from the extension's definition when it is known.
+pub struct SnapshotGenerator<'a> {
+    model: &'a Model,
+    definitions: HashMap<&'a str, &'a StructureDefinition>,
+}
+
+impl<'a> SnapshotGenerator<'a> {
+    /// A generator that finds base definitions and types in a model
+    pub fn new(model: &'a Model) -> Self {
+        SnapshotGenerator {
+            model,
+            definitions: HashMap::new(),
+        }
+    }
+
+    /// Makes a profile or extension definition available as a base definition, or as the
+    /// definition of the extensions in a slice
+    pub fn with_definition(mut self, definition: &'a StructureDefinition) -> Self {
+        self.definitions.insert(&definition.url, definition);
+        self
+    }
+
+    /// Generates the snapshot of a definition, whether or not it already has one
+    pub fn generate(&self, definition: &StructureDefinition) -> Result<Definitions, SnapshotError> {
+        self.generate_at(definition, 0)
+    }
+
+    fn generate_at(
+        &self,
+        definition: &StructureDefinition,
+        depth: usize,
+    ) -> Result<Definitions, SnapshotError> {
+        if depth > MAX_DEPTH {
+            return Err(SnapshotError::CircularDefinition(definition.url.clone()));
+        }
+
+        let mut elements = match &definition.base_definition {
+            Some(url) => {
+                let base = self
+                    .find(url)
+                    .ok_or_else(|| SnapshotError::UnknownBase(url.clone()))?;
+                let elements = self.snapshot_elements(base, depth + 1)?;
+                if base.r#type == definition.r#type {
+                    elements
+                } else {
+                    specialize(elements, &base.r#type, &definition.r#type)
+                }
+            }
+            None => Vec::new(),
+        };
+
+        let mut snapshot = Snapshot {
+            generator: self,
+            elements: &mut elements,
+            specialization: definition.derivation.as_deref() != Some("constraint"),
+            depth,
+        };
+        for element in definition
+            .differential
+            .iter()
+            .flat_map(|differential| differential.element.iter())
+        {
+            snapshot.apply(element)?;
+        }
+        Ok(Definitions { element: elements })
+    }
+
+    /// Looks up a definition by URL, among those given to the generator and then in the model
+    fn find(&self, url: &str) -> Option<&StructureDefinition> {
+        let url = url.split('|').next().unwrap_or(url);
+        self.definitions
+            .get(url)
+            .copied()
+            .or_else(|| self.model.find_structure_definition(url))
+    }
+
+    /// The elements of a definition's snapshot, generated if it has none
+    fn snapshot_elements(
+        &self,
+        definition: &StructureDefinition,
+        depth: usize,
+    ) -> Result<Vec<ElementDefinition>, SnapshotError> {
+        match &definition.snapshot {
+            Some(snapshot) => Ok(snapshot.element.clone()),
+            None => Ok(self.generate_at(definition, depth)?.element),
+        }
+    }
+}
+
+/// A snapshot being generated, which elements of the differential are applied to in turn
+struct Snapshot<'s, 'a> {
+    generator: &'s SnapshotGenerator<'a>,
+    elements: &'s mut Vec<ElementDefinition>,
+    specialization: bool,
+    depth: usize,
+}
+
+impl Snapshot<'_, '_> {
+    fn apply(&mut self, diff: &ElementDefinition) -> Result<(), SnapshotError> {
+        let index = match self.locate(&diff.id)? {
+            Some(index) => index,
+            None => self.add(diff)?,
+        };
+        self.merge(index, diff)?;
+        if diff.slice_name.is_some() && !diff.r#type.is_empty() {
+            self.add_extension_definition(index)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the element with an id, first adding the slice or unfolding the parent it needs
+    fn locate(&mut self, id: &str) -> Result<Option<usize>, SnapshotError> {
+        if let Some(index) = self.position(id) {
+            return Ok(Some(index));
+        }
+
+        if let Some((sliced, name)) = id.rsplit_once(':').filter(|(_, name)| !name.contains('.')) {
+            return match self.locate(sliced)? {
+                Some(index) => Ok(Some(self.add_slice(index, name))),
+                None => Ok(None),
+            };
+        }
+        let Some((parent, _)) = id.rsplit_once('.') else {
+            return Ok(None);
+        };
+        match self.locate(parent)? {
+            Some(index) if !self.has_children(index) && self.can_unfold(index) => {
+                self.unfold(index)?;
+                Ok(self.position(id))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Adds an element that is not in the base, as a specialization can
+    fn add(&mut self, diff: &ElementDefinition) -> Result<usize, SnapshotError> {
+        let unknown = || SnapshotError::UnknownElement(diff.id.clone());
+        if !self.specialization {
+            return Err(unknown());
+        }
+        let index = match diff.id.rsplit_once('.') {
+            Some((parent, _)) => self.subtree_end(self.position(parent).ok_or_else(unknown)?),
+            None if self.elements.is_empty() => 0,
+            None => return Err(unknown()),
+        };
+
+        let mut element = diff.clone();
+        element.base.get_or_insert_with(|| BaseElement {
+            path: diff.path.clone(),
+            min: diff.min.unwrap_or(0),
+            max: diff.max.clone().unwrap_or_else(|| "*".to_string()),
+        });
+        for constraint in self.type_constraints(&diff.r#type) {
+            if !element.constraint.iter().any(|c| c.key == constraint.key) {
+                element.constraint.push(constraint);
+            }
+        }
+        self.elements.insert(index, element);
+
+        // Elements defined inline have the elements of their type, which the differential
+        // leaves out
+        if let [t] = diff.r#type.as_slice() {
+            if t.code == "BackboneElement" || t.code == "Element" {
+                self.unfold(index)?;
+            }
+        }
+        Ok(index)
+    }
+
+    /// Adds a slice of an element after its existing slices, with a copy of its children
+    fn add_slice(&mut self, sliced: usize, name: &str) -> usize {
+        let element = &self.elements[sliced];
+        let slice_id = format!("{}:{}", element.id, name);
+        let mut slice = ElementDefinition {
+            id: slice_id.clone(),
+            slice_name: Some(name.to_string()),
+            slicing: None,
+            // Each slice holds only some of the items of the sliced element
+            min: Some(0),
+            ..element.clone()
+        };
+        slice.properties.remove("sliceIsConstraining");
+
+        let children = rebase(self.children(sliced), &element.id, &slice_id, None);
+        let index = self.subtree_end(sliced);
+        self.elements
+            .splice(index..index, std::iter::once(slice).chain(children));
+        index
+    }
+
+    /// Adds the children of an element, from the definition of its type or the element its
+    /// content reference refers to
+    fn unfold(&mut self, index: usize) -> Result<(), SnapshotError> {
+        let element = &self.elements[index];
+        let unknown_type = || SnapshotError::UnknownType(element.id.clone());
+
+        let children = if let Some(reference) = &element.content_reference {
+            let reference = reference.rsplit('#').next().unwrap_or(reference);
+            let referenced = self.position(reference).ok_or_else(unknown_type)?;
+            rebase(
+                self.children(referenced),
+                reference,
+                &element.id,
+                Some((reference, &element.path)),
+            )
+        } else {
+            let element_type = match element.r#type.as_slice() {
+                [t] => t,
+                // A slice of a choice element, e.g. `value[x]:valueQuantity`, is of one type
+                types => types
+                    .iter()
+                    .find(|t| {
+                        let name = element.name();
+                        element.slice_name.as_deref()
+                            == Some(&format!("{}{}", name, capitalize(&t.code)))
+                    })
+                    .ok_or_else(unknown_type)?,
+            };
+            let definition = match element_type.profile.as_slice() {
+                [profile] => self.generator.find(profile),
+                _ => None,
+            }
+            .or_else(|| {
+                self.generator
+                    .model
+                    .structure_definition(&element_type.code)
+            })
+            .ok_or_else(unknown_type)?;
+            let elements = self
+                .generator
+                .snapshot_elements(definition, self.depth + 1)?;
+            let Some((root, children)) = elements.split_first() else {
+                return Err(unknown_type());
+            };
+            rebase(
+                children,
+                &root.id,
+                &element.id,
+                Some((&root.path, &element.path)),
+            )
+        };
+        self.elements.splice(index + 1..index + 1, children);
+        Ok(())
+    }
+
+    /// Replaces the children of an extension slice with those of the extension's definition
+    fn add_extension_definition(&mut self, index: usize) -> Result<(), SnapshotError> {
+        let element = &self.elements[index];
+        let [t] = element.r#type.as_slice() else {
+            return Ok(());
+        };
+        let known = match t.profile.as_slice() {
+            [profile] if t.code == "Extension" => self.generator.find(profile).is_some(),
+            _ => false,
+        };
+        if !known {
+            return Ok(());
+        }
+
+        let children = self.children(index).len();
+        self.elements.drain(index + 1..index + 1 + children);
+        self.unfold(index)
+    }
+
+    /// Applies the constraints of an element of the differential to the element in the
+    /// snapshot it constrains
+    fn merge(&mut self, index: usize, diff: &ElementDefinition) -> Result<(), SnapshotError> {
+        let model = self.generator.model;
+        let target = &mut self.elements[index];
+        let invalid_cardinality = || SnapshotError::InvalidCardinality(diff.id.clone());
+
+        if let Some(min) = diff.min {
+            if min < target.min.unwrap_or(0) {
+                return Err(invalid_cardinality());
+            }
+            target.min = Some(min);
+        }
+        if let Some(max) = &diff.max {
+            if !allows(target.max.as_deref(), max_items(Some(max))) {
+                return Err(invalid_cardinality());
+            }
+            target.max = Some(max.clone());
+        }
+        if !allows(target.max.as_deref(), target.min.map(|min| min as usize)) {
+            return Err(invalid_cardinality());
+        }
+
+        if !diff.r#type.is_empty() {
+            for t in &diff.r#type {
+                // System types, which only the ids and values of elements have, can be given as
+                // the FHIR types they represent
+                let allowed = target.r#type.is_empty()
+                    || target.r#type.iter().any(|base| {
+                        base.code == t.code
+                            || is_system_type(&base.code)
+                            || model.is_subtype(&t.code, &base.code)
+                    });
+                if !allowed {
+                    return Err(SnapshotError::InvalidType(diff.id.clone(), t.code.clone()));
+                }
+            }
+            target.r#type = diff.r#type.clone();
+        }
+
+        if diff.content_reference.is_some() {
+            target.content_reference = diff.content_reference.clone();
+        }
+        if diff.slicing.is_some() {
+            target.slicing = diff.slicing.clone();
+        }
+        if diff.binding.is_some() {
+            target.binding = diff.binding.clone();
+        }
+        for constraint in &diff.constraint {
+            if !target.constraint.iter().any(|c| c.key == constraint.key) {
+                target.constraint.push(constraint.clone());
+            }
+        }
+        for (key, value) in &diff.properties {
+            target.properties.insert(key.clone(), value.clone());
+        }
+        Ok(())
+    }
+
+    /// The constraints an element of some types is under: those of every element, and of
+    /// every extension.  Elements of System types or without types are under none.
+    fn type_constraints(&self, types: &[ElementType]) -> Vec<Constraint> {
+        if types.is_empty() || types.iter().any(|t| is_system_type(&t.code)) {
+            return Vec::new();
+        }
+        let root_constraints = |name| {
+            self.generator
+                .model
+                .structure_definition(name)
+                .and_then(|definition| definition.snapshot.as_ref())
+                .and_then(|snapshot| snapshot.element.first())
+                .map(|root| root.constraint.clone())
+                .unwrap_or_default()
+        };
+        let mut constraints = root_constraints("Element");
+        if types.iter().all(|t| t.code == "Extension") {
+            for constraint in root_constraints("Extension") {
+                if !constraints.iter().any(|c| c.key == constraint.key) {
+                    constraints.push(constraint);
+                }
+            }
+        }
+        constraints
+    }
+
+    /// Whether an element has a type or content reference to take children from
+    fn can_unfold(&self, index: usize) -> bool {
+        let element = &self.elements[index];
+        !element.r#type.is_empty() || element.content_reference.is_some()
+    }
+
+    fn position(&self, id: &str) -> Option<usize> {
+        self.elements.iter().position(|el| el.id == id)
+    }
+
+    fn has_children(&self, index: usize) -> bool {
+        let id = &self.elements[index].id;
+        self.elements
+            .get(index + 1)
+            .is_some_and(|next| is_child(&next.id, id))
+    }
+
+    /// The descendants of an element, excluding its slices
+    fn children(&self, index: usize) -> &[ElementDefinition] {
+        let id = &self.elements[index].id;
+        let count = self.elements[index + 1..]
+            .iter()
+            .take_while(|el| is_child(&el.id, id))
+            .count();
+        &self.elements[index + 1..index + 1 + count]
+    }
+
+    /// The index after an element's descendants and slices
+    fn subtree_end(&self, index: usize) -> usize {
+        let id = &self.elements[index].id;
+        let count = self.elements[index + 1..]
+            .iter()
+            .take_while(|el| {
+                el.id
+                    .strip_prefix(id.as_str())
+                    .is_some_and(|rest| rest.starts_with('.') || rest.starts_with(':'))
+            })
+            .count();
+        index + 1 + count
+    }
+}
+
+fn is_system_type(code: &str) -> bool {
+    code.starts_with("http://hl7.org/fhirpath/System.")
+}
+
+/// Whether an element id is of a descendant of another element
+fn is_child(id: &str, ancestor: &str) -> bool {
+    id.strip_prefix(ancestor)
+        .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Moves the elements of the base of a specialization to the new type, e.g. `Quantity.value`
+/// to `Age.value`.  The root element is the new type's own.
+fn specialize(
+    mut elements: Vec<ElementDefinition>,
+    base_type: &str,
+    new_type: &str,
+) -> Vec<ElementDefinition> {
+    if let Some(root) = elements.first_mut() {
+        root.base = Some(BaseElement {
+            path: new_type.to_string(),
+            min: root.min.unwrap_or(0),
+            max: root.max.clone().unwrap_or_else(|| "*".to_string()),
+        });
+    }
+    rebase(&elements, base_type, new_type, Some((base_type, new_type)))
+}
+
+/// Copies elements from beneath one element to beneath another, replacing the start of their
+/// ids and, if given, their paths
+fn rebase(
+    elements: &[ElementDefinition],
+    from_id: &str,
+    to_id: &str,
+    paths: Option<(&str, &str)>,
+) -> Vec<ElementDefinition> {
+    let replace = |s: &str, from: &str, to: &str| match s.strip_prefix(from) {
+        Some(rest) => format!("{}{}", to, rest),
+        None => s.to_string(),
+    };
+    elements
+        .iter()
+        .map(|element| {
+            let mut element = element.clone();
+            element.id = replace(&element.id, from_id, to_id);
+            if let Some((from_path, to_path)) = paths {
+                element.path = replace(&element.path, from_path, to_path);
+            }
+            element
+        })
+        .collect()
+}
+
+/// The number of items a `max` allows, with `None` for no limit
+fn max_items(max: Option<&str>) -> Option<usize> {
+    match max {
+        Some("*") | None => None,
+        Some(max) => max.parse().ok(),
+    }
+}
+
+/// Whether a `max` allows a number of items, or any number if `None`
+fn allows(max: Option<&str>, items: Option<usize>) -> bool {
+    match (max_items(max), items) {
+        (None, _) => true,
+        (Some(_), None) => false,
+        (Some(max), Some(items)) => items <= max,
+    }
+}
+
+fn capitalize(s: &str) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::collections::BTreeSet;
+
+    /// The parts of an element that snapshots are compared on
+    fn summary(element: &ElementDefinition, constraints: bool) -> String {
+        let types: Vec<_> = element.r#type.iter().map(|t| t.code.as_str()).collect();
+        let keys: BTreeSet<_> = element.constraint.iter().map(|c| &c.key).collect();
+        format!(
+            "{} {} {}..{} base={:?} ref={:?} slice={:?} types={:?} constraints={:?}",
+            element.id,
+            element.path,
+            element.min.unwrap_or(0),
+            element.max.as_deref().unwrap_or("*"),
+            element.base.as_ref().map(|base| &base.path),
+            element.content_reference,
+            element.slice_name,
+            types,
+            if constraints { keys } else { BTreeSet::new() },
+        )
+    }
+
+    fn definition(json: serde_json::Value) -> StructureDefinition {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn element<'a>(snapshot: &'a Definitions, id: &str) -> &'a ElementDefinition {
+        snapshot
+            .element
+            .iter()
+            .find(|el| el.id == id)
+            .unwrap_or_else(|| panic!("no element {}", id))
+    }
+
+    fn ids(snapshot: &Definitions, prefix: &str) -> Vec<String> {
+        snapshot
+            .element
+            .iter()
+            .filter(|el| el.id.starts_with(prefix))
+            .map(|el| el.id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_base_definitions() {
+        let model = default_model().unwrap();
+        let generator = SnapshotGenerator::new(model);
+
+        // Data types are generated exactly as published
+        for definition in model.data_types().values() {
+            let mut expected = definition.snapshot.clone().unwrap().element;
+            if definition.name == "positiveInt" || definition.name == "unsignedInt" {
+                // The published snapshots give these values as strings, against the differentials
+                expected[3].r#type[0].code = "http://hl7.org/fhirpath/System.Integer".to_string();
+            }
+            let snapshot = generator.generate(definition).unwrap();
+            assert_eq!(
+                snapshot
+                    .element
+                    .iter()
+                    .map(|el| summary(el, true))
+                    .collect::<Vec<_>>(),
+                expected
+                    .iter()
+                    .map(|el| summary(el, true))
+                    .collect::<Vec<_>>(),
+                "{}",
+                definition.name
+            );
+        }
+
+        // Resources have each published element, under at least the published constraints
+        for definition in model.resources().values() {
+            let snapshot = generator.generate(definition).unwrap();
+            for expected in &definition.snapshot.as_ref().unwrap().element {
+                let actual = element(&snapshot, &expected.id);
+                assert_eq!(summary(actual, false), summary(expected, false));
+                for constraint in &expected.constraint {
+                    assert!(
+                        actual.constraint.iter().any(|c| c.key == constraint.key),
+                        "{} is not under {}",
+                        actual.id,
+                        constraint.key
+                    );
+                }
+            }
+        }
+    }
+
+    fn birth_place() -> StructureDefinition {
+        definition(serde_json::json!({
+            "id": "birth-place",
+            "url": "http://example.org/StructureDefinition/birth-place",
+            "name": "BirthPlace",
+            "status": "draft",
+            "kind": "complex-type",
+            "abstract": false,
+            "type": "Extension",
+            "baseDefinition": "http://hl7.org/fhir/StructureDefinition/Extension",
+            "derivation": "constraint",
+            "differential": { "element": [
+                { "id": "Extension", "path": "Extension", "max": "1" },
+                { "id": "Extension.extension", "path": "Extension.extension", "max": "0" },
+                {
+                    "id": "Extension.url",
+                    "path": "Extension.url",
+                    "fixedUri": "http://example.org/StructureDefinition/birth-place"
+                },
+                { "id": "Extension.value[x]", "path": "Extension.value[x]", "type": [{ "code": "Address" }] }
+            ]}
+        }))
+    }
+
+    fn patient_profile(differential: serde_json::Value) -> StructureDefinition {
+        definition(serde_json::json!({
+            "id": "mrn-patient",
+            "url": "http://example.org/StructureDefinition/mrn-patient",
+            "name": "MrnPatient",
+            "status": "draft",
+            "kind": "resource",
+            "abstract": false,
+            "type": "Patient",
+            "baseDefinition": "http://hl7.org/fhir/StructureDefinition/Patient",
+            "derivation": "constraint",
+            "differential": { "element": differential }
+        }))
+    }
+
+    #[test]
+    fn test_constraint_profile() {
+        let birth_place = birth_place();
+        let generator =
+            SnapshotGenerator::new(default_model().unwrap()).with_definition(&birth_place);
+        let profile = patient_profile(serde_json::json!([
+            {
+                "id": "Patient.identifier",
+                "path": "Patient.identifier",
+                "slicing": { "discriminator": [{ "type": "value", "path": "system" }], "rules": "open" },
+                "min": 1
+            },
+            { "id": "Patient.identifier:mrn", "path": "Patient.identifier", "sliceName": "mrn", "max": "1" },
+            {
+                "id": "Patient.identifier:mrn.system",
+                "path": "Patient.identifier.system",
+                "min": 1,
+                "fixedUri": "http://example.org/mrn"
+            },
+            {
+                "id": "Patient.extension:birthPlace",
+                "path": "Patient.extension",
+                "sliceName": "birthPlace",
+                "type": [{
+                    "code": "Extension",
+                    "profile": ["http://example.org/StructureDefinition/birth-place"]
+                }]
+            },
+            { "id": "Patient.name.family", "path": "Patient.name.family", "min": 1 },
+            {
+                "id": "Patient.deceased[x]",
+                "path": "Patient.deceased[x]",
+                "type": [{ "code": "boolean" }]
+            },
+            {
+                "id": "Patient.managingOrganization",
+                "path": "Patient.managingOrganization",
+                "constraint": [{
+                    "key": "mrn-1",
+                    "severity": "error",
+                    "human": "The organization is referred to",
+                    "expression": "reference.exists()"
+                }]
+            }
+        ]));
+        let snapshot = generator.generate(&profile).unwrap();
+
+        let identifier = element(&snapshot, "Patient.identifier");
+        assert_eq!(identifier.min, Some(1));
+        assert!(identifier.slicing.is_some());
+        // Slices follow the sliced element and its children, with children of their own
+        assert_eq!(
+            ids(&snapshot, "Patient.identifier"),
+            [
+                "Patient.identifier",
+                "Patient.identifier:mrn",
+                "Patient.identifier:mrn.id",
+                "Patient.identifier:mrn.extension",
+                "Patient.identifier:mrn.use",
+                "Patient.identifier:mrn.type",
+                "Patient.identifier:mrn.system",
+                "Patient.identifier:mrn.value",
+                "Patient.identifier:mrn.period",
+                "Patient.identifier:mrn.assigner",
+            ]
+        );
+        let mrn = element(&snapshot, "Patient.identifier:mrn");
+        assert_eq!(
+            (
+                mrn.path.as_str(),
+                mrn.slice_name.as_deref(),
+                mrn.min,
+                mrn.max.as_deref()
+            ),
+            ("Patient.identifier", Some("mrn"), Some(0), Some("1"))
+        );
+        let system = element(&snapshot, "Patient.identifier:mrn.system");
+        assert_eq!(system.path, "Patient.identifier.system");
+        assert_eq!(system.min, Some(1));
+        assert_eq!(
+            system.fixed(),
+            Some((
+                "uri".to_string(),
+                &serde_json::json!("http://example.org/mrn")
+            ))
+        );
+        assert_eq!(
+            element(&snapshot, "Patient.identifier:mrn.system")
+                .base
+                .as_ref()
+                .unwrap()
+                .path,
+            "Identifier.system"
+        );
+
+        // Extension slices have the elements of the extension's definition
+        assert_eq!(
+            ids(&snapshot, "Patient.extension:birthPlace"),
+            [
+                "Patient.extension:birthPlace",
+                "Patient.extension:birthPlace.id",
+                "Patient.extension:birthPlace.extension",
+                "Patient.extension:birthPlace.url",
+                "Patient.extension:birthPlace.value[x]",
+            ]
+        );
+        let value = element(&snapshot, "Patient.extension:birthPlace.value[x]");
+        assert_eq!(value.path, "Patient.extension.value[x]");
+        assert_eq!(value.r#type[0].code, "Address");
+        assert_eq!(
+            element(&snapshot, "Patient.extension:birthPlace.extension")
+                .max
+                .as_deref(),
+            Some("0")
+        );
+
+        assert_eq!(element(&snapshot, "Patient.name.family").min, Some(1));
+        assert_eq!(
+            element(&snapshot, "Patient.name.family")
+                .base
+                .as_ref()
+                .unwrap()
+                .path,
+            "HumanName.family"
+        );
+        let deceased = element(&snapshot, "Patient.deceased[x]");
+        assert_eq!(deceased.r#type.len(), 1);
+        let organization = element(&snapshot, "Patient.managingOrganization");
+        let keys: Vec<_> = organization
+            .constraint
+            .iter()
+            .map(|c| c.key.as_str())
+            .collect();
+        assert_eq!(keys.last(), Some(&"mrn-1"));
+        assert!(keys.contains(&"ele-1"));
+
+        // The elements of the base are kept in order
+        let base = &default_model().unwrap().resources()["Patient"]
+            .snapshot
+            .as_ref()
+            .unwrap()
+            .element;
+        let kept: Vec<_> = snapshot
+            .element
+            .iter()
+            .filter(|el| base.iter().any(|b| b.id == el.id))
+            .map(|el| el.id.as_str())
+            .collect();
+        assert_eq!(
+            kept,
+            base.iter().map(|el| el.id.as_str()).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_invalid_profiles() {
+        let generator = SnapshotGenerator::new(default_model().unwrap());
+        let cases = [
+            (
+                serde_json::json!([{ "id": "Patient.birthDate", "path": "Patient.birthDate", "max": "2" }]),
+                SnapshotError::InvalidCardinality("Patient.birthDate".to_string()),
+            ),
+            (
+                serde_json::json!([{ "id": "Patient.name", "path": "Patient.name", "min": 2, "max": "1" }]),
+                SnapshotError::InvalidCardinality("Patient.name".to_string()),
+            ),
+            (
+                serde_json::json!([{
+                    "id": "Patient.deceased[x]",
+                    "path": "Patient.deceased[x]",
+                    "type": [{ "code": "string" }]
+                }]),
+                SnapshotError::InvalidType("Patient.deceased[x]".to_string(), "string".to_string()),
+            ),
+            (
+                serde_json::json!([{ "id": "Patient.colour", "path": "Patient.colour", "min": 1 }]),
+                SnapshotError::UnknownElement("Patient.colour".to_string()),
+            ),
+            (
+                serde_json::json!([{ "id": "Patient.deceased[x].id", "path": "Patient.deceased[x].id", "min": 1 }]),
+                SnapshotError::UnknownType("Patient.deceased[x]".to_string()),
+            ),
+        ];
+        for (differential, expected) in cases {
+            let err = generator
+                .generate(&patient_profile(differential))
+                .unwrap_err();
+            assert_eq!(err.to_string(), expected.to_string());
+        }
+
+        let mut profile = patient_profile(serde_json::json!([]));
+        profile.base_definition =
+            Some("http://example.org/StructureDefinition/unknown".to_string());
+        assert!(matches!(
+            generator.generate(&profile),
+            Err(SnapshotError::UnknownBase(_))
+        ));
+
+        let mut circular = patient_profile(serde_json::json!([]));
+        circular.base_definition = Some(circular.url.clone());
+        let generator = generator.with_definition(&circular);
+        assert!(matches!(
+            generator.generate(&circular),
+            Err(SnapshotError::CircularDefinition(_))
+        ));
+    }
+
+    #[test]
+    fn test_content_references() {
+        let profile = definition(serde_json::json!({
+            "id": "linked-bundle",
+            "url": "http://example.org/StructureDefinition/linked-bundle",
+            "name": "LinkedBundle",
+            "status": "draft",
+            "kind": "resource",
+            "abstract": false,
+            "type": "Bundle",
+            "baseDefinition": "http://hl7.org/fhir/StructureDefinition/Bundle",
+            "derivation": "constraint",
+            "differential": { "element": [
+                { "id": "Bundle.entry.link", "path": "Bundle.entry.link", "min": 1 },
+                { "id": "Bundle.entry.link.url", "path": "Bundle.entry.link.url", "fixedUri": "http://example.org" }
+            ]}
+        }));
+        let snapshot = SnapshotGenerator::new(default_model().unwrap())
+            .generate(&profile)
+            .unwrap();
+
+        // The elements of the referenced element are copied beneath the element
+        let link = ids(&snapshot, "Bundle.link.");
+        assert!(!link.is_empty());
+        assert_eq!(
+            ids(&snapshot, "Bundle.entry.link."),
+            link.iter()
+                .map(|id| id.replacen("Bundle.link", "Bundle.entry.link", 1))
+                .collect::<Vec<_>>()
+        );
+        let url = element(&snapshot, "Bundle.entry.link.url");
+        assert_eq!(url.path, "Bundle.entry.link.url");
+        assert_eq!(url.base.as_ref().unwrap().path, "Bundle.link.url");
+        assert!(url.fixed().is_some());
+        assert_eq!(element(&snapshot, "Bundle.entry.link").min, Some(1));
+    }
+}
@@ -1,40 +1,56 @@
 use super::*;
-use lazy_static::lazy_static;
-use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
 
-const RESOURCE_JSON: &[u8] = include_bytes!("specification/R4B/profiles-resources.json");
-const DATA_TYPE_JSON: &[u8] = include_bytes!("specification/R4B/profiles-types.json");
+static DEFAULT_MODEL: OnceLock<Model> = OnceLock::new();
 
-lazy_static! {
-    pub static ref RESOURCES: HashMap<String, StructureDefinition> =
-        StructureDefinition::load_bundle(RESOURCE_JSON)
-            .unwrap()
-            .iter()
-            .map(|sd| (sd.name.clone(), sd.clone()))
-            .collect();
-}
+/// Models installed with `Model::install()`
+static MODELS: RwLock<Vec<&'static Model>> = RwLock::new(Vec::new());
 
-lazy_static! {
-    pub static ref DATA_TYPES: HashMap<String, StructureDefinition> =
-        StructureDefinition::load_bundle(DATA_TYPE_JSON)
-            .unwrap()
-            .iter()
-            .map(|sd| (sd.name.clone(), sd.clone()))
-            .collect();
+/// The model built from `specification()`, which resources are read and typed against unless
+/// another is chosen, e.g. with `EvaluationContext::with_model()`.  Fails if no specification
+/// is available or it is not of a supported version of FHIR.
+pub fn default_model() -> Result<&'static Model, PackageError> {
+    if let Some(model) = DEFAULT_MODEL.get() {
+        return Ok(model);
+    }
+    let model = Model::new(specification()?)?;
+    Ok(DEFAULT_MODEL.get_or_init(|| model))
 }
 
-lazy_static! {
-    pub static ref FIELDS: HashMap<String, ElementDefinition> = RESOURCES
+/// The model for a version of FHIR: the one installed for it, or the default model if that is
+/// of the version
+pub fn model(version: FhirVersion) -> Option<&'static Model> {
+    let installed = MODELS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    installed
         .iter()
-        .map(|(_, sd)| sd
-            .snapshot
-            .as_ref()
-            .unwrap()
-            .element
-            .iter()
-            .map(|el| (el.path.clone(), el.clone())))
-        .flatten()
-        .collect();
+        .find(|model| model.version() == version)
+        .copied()
+        .or_else(|| {
+            default_model()
+                .ok()
+                .filter(|model| model.version() == version)
+        })
+}
+
+impl Model {
+    /// Builds the model defined by a package and makes it available from `fhir::model()`, for
+    /// reading and evaluating resources of another version of FHIR than the default model.
+    /// Only one model can be installed for each version.
+    pub fn install(package: SpecificationPackage) -> Result<&'static Model, PackageError> {
+        let mut installed = MODELS
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        package.check_core()?;
+        let version = fhir_version(&package)?;
+        if installed.iter().any(|other| other.version() == version) {
+            return Err(PackageError::AlreadyInstalled);
+        }
+        let model: &'static Model = Box::leak(Box::new(Model::new(Box::leak(Box::new(package)))?));
+        installed.push(model);
+        Ok(model)
+    }
 }
 
 #[cfg(test)]
@@ -44,13 +60,14 @@ mod tests {
 
     #[test]
     fn test_resources_loaded() {
-        assert_eq!(RESOURCES.len(), 143);
+        let resources = default_model().unwrap().resources();
+        assert_eq!(resources.len(), 143);
         assert_eq!(
-            RESOURCES["Patient"].url,
+            resources["Patient"].url,
             "http://hl7.org/fhir/StructureDefinition/Patient"
         );
         assert_eq!(
-            RESOURCES["Patient"]
+            resources["Patient"]
                 .snapshot
                 .as_ref()
                 .unwrap()
@@ -62,22 +79,32 @@ mod tests {
 
     #[test]
     fn test_data_types_loaded() {
-        assert_eq!(DATA_TYPES.len(), 64);
+        let data_types = default_model().unwrap().data_types();
+        assert_eq!(data_types.len(), 64);
         assert_eq!(
-            DATA_TYPES["Meta"].url,
+            data_types["Meta"].url,
             "http://hl7.org/fhir/StructureDefinition/Meta"
         );
         assert_eq!(
-            DATA_TYPES["Meta"].snapshot.as_ref().unwrap().element.len(),
+            data_types["Meta"].snapshot.as_ref().unwrap().element.len(),
             9
         );
     }
 
     #[test]
     fn test_fields_loaded() {
-        let birthday = &FIELDS["Patient.birthDate"];
+        let birthday = default_model().unwrap().field("Patient.birthDate").unwrap();
         assert_eq!(birthday.min, Some(0));
         assert_eq!(birthday.max, Some("1".to_string()));
         assert_eq!(birthday.r#type[0].code, "date");
     }
+
+    #[test]
+    fn test_model_registry() {
+        assert!(std::ptr::eq(
+            model(FhirVersion::R4B).unwrap(),
+            default_model().unwrap()
+        ));
+        assert!(model(FhirVersion::R4).is_none());
+    }
 }
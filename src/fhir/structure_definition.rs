@@ -2,6 +2,7 @@ use super::*;
 use serde::{Deserialize, Serialize};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct StructureDefinition {
     pub id: String,
     pub url: String,
@@ -13,16 +14,19 @@ pub struct StructureDefinition {
     pub r#abstract: bool,
     pub r#type: String,
     pub base_definition: Option<String>,
+    pub derivation: Option<String>,
     pub snapshot: Option<Definitions>,
     pub differential: Option<Definitions>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Definitions {
     pub element: Vec<ElementDefinition>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct ElementDefinition {
     pub id: String,
     pub path: String,
@@ -37,15 +41,22 @@ pub struct ElementDefinition {
     #[serde(default)]
     pub constraint: Vec<Constraint>,
     pub binding: Option<TerminologyBinding>,
+    /// Properties not modelled above, such as `fixed[x]` and `pattern[x]`
+    #[serde(flatten)]
+    pub properties: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct SlicingRules {
     #[serde(default)]
     pub discriminator: Vec<Discriminator>,
+    pub ordered: Option<bool>,
+    pub rules: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Discriminator {
     pub r#type: String,
     pub path: String,
@@ -55,6 +66,7 @@ pub struct Discriminator {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct BaseElement {
     pub path: String,
     pub min: u32,
@@ -62,6 +74,7 @@ pub struct BaseElement {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct ElementType {
     pub code: String,
     #[serde(default)]
@@ -74,6 +87,7 @@ pub struct ElementType {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Constraint {
     pub key: String,
     pub severity: String,
@@ -82,6 +96,7 @@ pub struct Constraint {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct TerminologyBinding {
     pub strength: String,
     pub value_set: Option<String>,
@@ -89,21 +104,6 @@ pub struct TerminologyBinding {
 
 impl StructureDefinition {
     pub fn load_bundle(data: &[u8]) -> Result<Vec<StructureDefinition>, ParseError> {
-        let bundle: serde_json::Value =
-            serde_json::from_slice(data).map_err(|e| ParseError::InvalidJSON(e))?;
-        let Some(entries) = bundle["entry"].as_array() else {return Err(ParseError::MalformedBundle)};
-
-        let mut struct_defs = Vec::new();
-        for entry in entries {
-            let Some(resource) = entry["resource"].as_object() else {return Err(ParseError::MalformedBundle)};
-            if resource["resourceType"].as_str().unwrap_or("") == "StructureDefinition" {
-                let struct_def: StructureDefinition =
-                    serde_json::from_value(entry["resource"].clone())
-                        .map_err(|e| ParseError::InvalidJSON(e))?;
-                struct_defs.push(struct_def);
-            }
-        }
-
-        Ok(struct_defs)
+        load_bundle_resources(data, "StructureDefinition")
     }
 }
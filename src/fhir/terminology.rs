@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CodeSystem {
+    pub url: String,
+    pub version: Option<String>,
+    pub name: Option<String>,
+    pub hierarchy_meaning: Option<String>,
+    #[serde(default)]
+    pub concept: Vec<CodeSystemConcept>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CodeSystemConcept {
+    pub code: String,
+    pub display: Option<String>,
+    pub definition: Option<String>,
+    #[serde(default)]
+    pub concept: Vec<CodeSystemConcept>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ValueSet {
+    pub url: String,
+    pub version: Option<String>,
+    pub name: Option<String>,
+    pub compose: Option<ValueSetCompose>,
+    pub expansion: Option<ValueSetExpansion>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ValueSetCompose {
+    #[serde(default)]
+    pub include: Vec<ConceptSet>,
+    #[serde(default)]
+    pub exclude: Vec<ConceptSet>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConceptSet {
+    pub system: Option<String>,
+    pub version: Option<String>,
+    #[serde(default)]
+    pub concept: Vec<ConceptReference>,
+    #[serde(default)]
+    pub filter: Vec<ConceptFilter>,
+    #[serde(default)]
+    pub value_set: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConceptReference {
+    pub code: String,
+    pub display: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConceptFilter {
+    pub property: String,
+    pub op: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ValueSetExpansion {
+    #[serde(default)]
+    pub contains: Vec<ExpansionContains>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpansionContains {
+    pub system: Option<String>,
+    pub version: Option<String>,
+    pub code: Option<String>,
+    pub display: Option<String>,
+    #[serde(default)]
+    pub contains: Vec<ExpansionContains>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConceptMap {
+    pub url: String,
+    pub version: Option<String>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub group: Vec<ConceptMapGroup>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConceptMapGroup {
+    pub source: Option<String>,
+    pub target: Option<String>,
+    #[serde(default)]
+    pub element: Vec<SourceElement>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceElement {
+    pub code: Option<String>,
+    pub display: Option<String>,
+    #[serde(default)]
+    pub target: Vec<TargetElement>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TargetElement {
+    pub code: Option<String>,
+    pub display: Option<String>,
+    pub equivalence: String,
+}
+
+impl CodeSystem {
+    /// Finds a concept anywhere in the hierarchy
+    pub fn concept(&self, code: &str) -> Option<&CodeSystemConcept> {
+        find_concept(&self.concept, code)
+    }
+}
+
+impl CodeSystemConcept {
+    /// Whether `code` is this concept or one of its descendants
+    pub fn subsumes(&self, code: &str) -> bool {
+        self.code == code || find_concept(&self.concept, code).is_some()
+    }
+
+    /// This concept followed by all of its descendants
+    pub fn descendants(&self) -> Vec<&CodeSystemConcept> {
+        let mut concepts = vec![self];
+        for child in &self.concept {
+            concepts.extend(child.descendants());
+        }
+        concepts
+    }
+}
+
+fn find_concept<'a>(
+    concepts: &'a [CodeSystemConcept],
+    code: &str,
+) -> Option<&'a CodeSystemConcept> {
+    concepts.iter().find_map(|concept| {
+        if concept.code == code {
+            Some(concept)
+        } else {
+            find_concept(&concept.concept, code)
+        }
+    })
+}
+
+impl ExpansionContains {
+    /// This entry followed by the entries nested beneath it
+    pub fn flatten(&self) -> Vec<&ExpansionContains> {
+        let mut entries = vec![self];
+        for child in &self.contains {
+            entries.extend(child.flatten());
+        }
+        entries
+    }
+}
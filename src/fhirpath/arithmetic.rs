@@ -0,0 +1,417 @@
+use super::*;
+use crate::evaluation::EvaluationError;
+use rust_decimal::Decimal;
+
+impl Value {
+    /// Evaluates `+` between two values.  Returns `Ok(None)` when the result is empty, e.g. on
+    /// integer overflow, and an error when the operation is not defined for the operand types.
+    pub fn add(&self, other: &Value) -> Result<Option<Value>, ValueError> {
+        match (self, other) {
+            (Value::Any(v1), _) => v1.add(other),
+            (_, Value::Any(v2)) => self.add(v2),
+            (Value::Complex(_), _) | (_, Value::Complex(_))
+                if self.is_fhir_primitive() || other.is_fhir_primitive() =>
+            {
+                self.system_value().add(other.system_value())
+            }
+            (Value::Integer(n1), Value::Integer(n2)) => Ok(n1.checked_add(*n2).map(Value::Integer)),
+            (Value::Integer(_) | Value::Long(_), Value::Integer(_) | Value::Long(_)) => {
+                Ok(to_long(self).checked_add(to_long(other)).map(Value::Long))
+            }
+            (
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+            ) => Ok(to_decimal(self)
+                .checked_add(to_decimal(other))
+                .map(Value::Decimal)),
+            (Value::String(s1), Value::String(s2)) => {
+                Ok(Some(Value::String(format!("{}{}", s1, s2))))
+            }
+            (Value::Date(_) | Value::DateTime(_) | Value::Time(_), Value::Quantity(q)) => {
+                self.add_duration(q.value(), q.unit()).map(Some)
+            }
+            (Value::Quantity(_) | Value::Complex(_), Value::Quantity(_) | Value::Complex(_)) => {
+                quantity_operation("+", self, other, Quantity::add)
+            }
+            _ => Err(ValueError::UnsupportedOperation(
+                "+",
+                self.data_type(),
+                other.data_type(),
+            )),
+        }
+    }
+
+    /// Evaluates `-` between two values, with the same conventions as [`Value::add`]
+    pub fn subtract(&self, other: &Value) -> Result<Option<Value>, ValueError> {
+        match (self, other) {
+            (Value::Any(v1), _) => v1.subtract(other),
+            (_, Value::Any(v2)) => self.subtract(v2),
+            (Value::Complex(_), _) | (_, Value::Complex(_))
+                if self.is_fhir_primitive() || other.is_fhir_primitive() =>
+            {
+                self.system_value().subtract(other.system_value())
+            }
+            (Value::Integer(n1), Value::Integer(n2)) => Ok(n1.checked_sub(*n2).map(Value::Integer)),
+            (Value::Integer(_) | Value::Long(_), Value::Integer(_) | Value::Long(_)) => {
+                Ok(to_long(self).checked_sub(to_long(other)).map(Value::Long))
+            }
+            (
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+            ) => Ok(to_decimal(self)
+                .checked_sub(to_decimal(other))
+                .map(Value::Decimal)),
+            (Value::Date(_) | Value::DateTime(_) | Value::Time(_), Value::Quantity(q)) => {
+                self.add_duration(-q.value(), q.unit()).map(Some)
+            }
+            (Value::Quantity(_) | Value::Complex(_), Value::Quantity(_) | Value::Complex(_)) => {
+                quantity_operation("-", self, other, Quantity::subtract)
+            }
+            _ => Err(ValueError::UnsupportedOperation(
+                "-",
+                self.data_type(),
+                other.data_type(),
+            )),
+        }
+    }
+
+    /// Evaluates `*`.  Multiplying quantities multiplies their units, and numbers act as
+    /// quantities with the unit `'1'`.
+    pub fn multiply(&self, other: &Value) -> Result<Option<Value>, ValueError> {
+        match (self, other) {
+            (Value::Any(v1), _) => v1.multiply(other),
+            (_, Value::Any(v2)) => self.multiply(v2),
+            (Value::Complex(_), _) | (_, Value::Complex(_))
+                if self.is_fhir_primitive() || other.is_fhir_primitive() =>
+            {
+                self.system_value().multiply(other.system_value())
+            }
+            (Value::Integer(n1), Value::Integer(n2)) => Ok(n1.checked_mul(*n2).map(Value::Integer)),
+            (Value::Integer(_) | Value::Long(_), Value::Integer(_) | Value::Long(_)) => {
+                Ok(to_long(self).checked_mul(to_long(other)).map(Value::Long))
+            }
+            (
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+            ) => Ok(to_decimal(self)
+                .checked_mul(to_decimal(other))
+                .map(Value::Decimal)),
+            _ => quantity_operation("*", self, other, Quantity::multiply),
+        }
+    }
+
+    /// Evaluates `/`, which always produces a Decimal (or Quantity) and is empty when dividing
+    /// by zero
+    pub fn divide(&self, other: &Value) -> Result<Option<Value>, ValueError> {
+        match (self, other) {
+            (Value::Any(v1), _) => v1.divide(other),
+            (_, Value::Any(v2)) => self.divide(v2),
+            (Value::Complex(_), _) | (_, Value::Complex(_))
+                if self.is_fhir_primitive() || other.is_fhir_primitive() =>
+            {
+                self.system_value().divide(other.system_value())
+            }
+            (
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+            ) => Ok(to_decimal(self)
+                .checked_div(to_decimal(other))
+                .map(Value::Decimal)),
+            _ => quantity_operation("/", self, other, Quantity::divide),
+        }
+    }
+
+    /// Evaluates `div`, division truncated towards zero
+    pub fn div(&self, other: &Value) -> Result<Option<Value>, ValueError> {
+        match (self, other) {
+            (Value::Any(v1), _) => v1.div(other),
+            (_, Value::Any(v2)) => self.div(v2),
+            (Value::Complex(_), _) | (_, Value::Complex(_))
+                if self.is_fhir_primitive() || other.is_fhir_primitive() =>
+            {
+                self.system_value().div(other.system_value())
+            }
+            (Value::Integer(n1), Value::Integer(n2)) => Ok(n1.checked_div(*n2).map(Value::Integer)),
+            (Value::Integer(_) | Value::Long(_), Value::Integer(_) | Value::Long(_)) => {
+                Ok(to_long(self).checked_div(to_long(other)).map(Value::Long))
+            }
+            (
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+            ) => Ok(to_decimal(self)
+                .checked_div(to_decimal(other))
+                .map(|d| Value::Decimal(d.trunc()))),
+            _ => Err(ValueError::UnsupportedOperation(
+                "div",
+                self.data_type(),
+                other.data_type(),
+            )),
+        }
+    }
+
+    /// Evaluates `mod`, the remainder of truncated division
+    pub fn modulo(&self, other: &Value) -> Result<Option<Value>, ValueError> {
+        match (self, other) {
+            (Value::Any(v1), _) => v1.modulo(other),
+            (_, Value::Any(v2)) => self.modulo(v2),
+            (Value::Complex(_), _) | (_, Value::Complex(_))
+                if self.is_fhir_primitive() || other.is_fhir_primitive() =>
+            {
+                self.system_value().modulo(other.system_value())
+            }
+            (Value::Integer(n1), Value::Integer(n2)) => Ok(n1.checked_rem(*n2).map(Value::Integer)),
+            (Value::Integer(_) | Value::Long(_), Value::Integer(_) | Value::Long(_)) => {
+                Ok(to_long(self).checked_rem(to_long(other)).map(Value::Long))
+            }
+            (
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+            ) => Ok(to_decimal(self)
+                .checked_rem(to_decimal(other))
+                .map(Value::Decimal)),
+            _ => Err(ValueError::UnsupportedOperation(
+                "mod",
+                self.data_type(),
+                other.data_type(),
+            )),
+        }
+    }
+
+    fn add_duration(&self, amount: Decimal, unit: &str) -> Result<Value, ValueError> {
+        let unit = DurationUnit::from_unit(unit)?;
+        match self {
+            Value::Date(date) => Ok(Value::Date(date.add_duration(amount, unit)?)),
+            Value::DateTime(datetime) => Ok(Value::DateTime(datetime.add_duration(amount, unit)?)),
+            Value::Time(time) => Ok(Value::Time(time.add_duration(amount, unit)?)),
+            _ => Err(ValueError::UnsupportedOperation(
+                "+",
+                self.data_type(),
+                QUANTITY,
+            )),
+        }
+    }
+}
+
+/// Applies a quantity operation, implicitly converting numbers and FHIR Quantity elements to
+/// System.Quantity.  The result is empty when the units are incompatible.
+fn quantity_operation(
+    name: &'static str,
+    v1: &Value,
+    v2: &Value,
+    op: fn(&Quantity, &Quantity) -> Option<Quantity>,
+) -> Result<Option<Value>, ValueError> {
+    let to_quantity = |value: &Value| match value {
+        Value::Integer(_) | Value::Long(_) | Value::Decimal(_) => {
+            Some(Quantity::new(to_decimal(value), "1"))
+        }
+        _ => value.to_quantity(),
+    };
+    match (to_quantity(v1), to_quantity(v2)) {
+        (Some(q1), Some(q2)) => Ok(op(&q1, &q2).map(Value::Quantity)),
+        _ => Err(ValueError::UnsupportedOperation(
+            name,
+            v1.data_type(),
+            v2.data_type(),
+        )),
+    }
+}
+
+fn to_decimal(value: &Value) -> Decimal {
+    value.as_decimal().unwrap_or_default()
+}
+
+fn to_long(value: &Value) -> i64 {
+    value.as_long().unwrap_or_default()
+}
+
+impl Collection {
+    /// Applies a binary arithmetic operator to singleton collections; if either is empty, so is
+    /// the result
+    pub fn arithmetic(
+        &self,
+        other: &Collection,
+        op: fn(&Value, &Value) -> Result<Option<Value>, ValueError>,
+    ) -> Result<Collection, EvaluationError> {
+        match (self.as_slice(), other.as_slice()) {
+            ([], _) | (_, []) => Ok(Collection::new()),
+            ([v1], [v2]) => Ok(op(v1, v2)?.map(Collection::from).unwrap_or_default()),
+            _ => Err(EvaluationError::InvalidOperands(
+                self.clone(),
+                other.clone(),
+            )),
+        }
+    }
+
+    /// Evaluates `&`, which concatenates strings treating empty operands as empty strings
+    pub fn concatenate(&self, other: &Collection) -> Result<Collection, EvaluationError> {
+        let to_string = |c: &Collection| match c.as_slice() {
+            [] => Ok(String::new()),
+            [Value::String(s)] => Ok(s.clone()),
+            _ => Err(EvaluationError::InvalidOperands(
+                self.clone(),
+                other.clone(),
+            )),
+        };
+        Ok(Collection::from(Value::String(
+            to_string(self)? + &to_string(other)?,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn duration(amount: i64, unit: &str) -> Value {
+        Value::Quantity(Quantity::new(Decimal::from(amount), unit))
+    }
+
+    #[test]
+    fn test_numeric_arithmetic() {
+        assert_eq!(
+            Value::integer(2).add(&Value::integer(3)),
+            Ok(Some(Value::integer(5)))
+        );
+        assert_eq!(
+            Value::integer(2).subtract(&Value::decimal(5, 1)),
+            Ok(Some(Value::decimal(15, 1)))
+        );
+        assert_eq!(Value::integer(i32::MAX).add(&Value::integer(1)), Ok(None));
+        assert!(Value::integer(1).add(&Value::string("a")).is_err());
+        assert_eq!(
+            Value::integer(7).multiply(&Value::decimal(5, 1)),
+            Ok(Some(Value::decimal(35, 1)))
+        );
+        assert_eq!(
+            Value::integer(7).divide(&Value::integer(2)),
+            Ok(Some(Value::decimal(35, 1)))
+        );
+        assert_eq!(Value::integer(7).divide(&Value::integer(0)), Ok(None));
+        assert_eq!(
+            Value::integer(-7).div(&Value::integer(2)),
+            Ok(Some(Value::integer(-3)))
+        );
+        assert_eq!(
+            Value::decimal(55, 1).div(&Value::decimal(7, 1)),
+            Ok(Some(Value::decimal(7, 0)))
+        );
+        assert_eq!(
+            Value::integer(-7).modulo(&Value::integer(2)),
+            Ok(Some(Value::integer(-1)))
+        );
+        assert_eq!(
+            Value::decimal(55, 1).modulo(&Value::decimal(7, 1)),
+            Ok(Some(Value::decimal(6, 1)))
+        );
+        assert_eq!(Value::integer(7).div(&Value::integer(0)), Ok(None));
+    }
+
+    #[test]
+    fn test_long_arithmetic() {
+        assert_eq!(
+            Value::integer(i32::MAX).add(&Value::long(1)),
+            Ok(Some(Value::long(2_147_483_648)))
+        );
+        assert_eq!(
+            Value::long(3_000_000_000).multiply(&Value::integer(2)),
+            Ok(Some(Value::long(6_000_000_000)))
+        );
+        assert_eq!(
+            Value::long(7).subtract(&Value::decimal(5, 1)),
+            Ok(Some(Value::decimal(65, 1)))
+        );
+        assert_eq!(
+            Value::long(7).divide(&Value::long(2)),
+            Ok(Some(Value::decimal(35, 1)))
+        );
+        assert_eq!(
+            Value::long(-7).div(&Value::integer(2)),
+            Ok(Some(Value::long(-3)))
+        );
+        assert_eq!(
+            Value::long(7).modulo(&Value::long(2)),
+            Ok(Some(Value::long(1)))
+        );
+        assert_eq!(Value::long(i64::MAX).add(&Value::long(1)), Ok(None));
+    }
+
+    #[test]
+    fn test_quantity_arithmetic() {
+        let quantity =
+            |amount: i64, unit: &str| Value::Quantity(Quantity::new(Decimal::from(amount), unit));
+
+        assert_eq!(
+            quantity(1, "g").add(&quantity(500, "mg")),
+            Ok(Some(Value::Quantity(Quantity::new(
+                Decimal::new(15, 1),
+                "g"
+            ))))
+        );
+        assert_eq!(quantity(1, "g").add(&quantity(1, "m")), Ok(None));
+        assert_eq!(
+            quantity(3, "mg").multiply(&Value::integer(2)),
+            Ok(Some(quantity(6, "mg")))
+        );
+        assert_eq!(
+            Value::integer(6).divide(&quantity(2, "h")),
+            Ok(Some(quantity(3, "1/h")))
+        );
+        assert!(quantity(1, "g").add(&Value::integer(1)).is_err());
+        assert!(quantity(1, "g").div(&quantity(1, "g")).is_err());
+    }
+
+    #[test]
+    fn test_date_arithmetic() {
+        let date = |s: &str| Value::Date(s.parse().unwrap());
+        let datetime = |s: &str| Value::DateTime(s.parse().unwrap());
+        let time = |s: &str| Value::Time(s.parse().unwrap());
+
+        let cases = vec![
+            (date("2023-01-31"), duration(1, "month"), date("2023-02-28")),
+            (date("2024-01-31"), duration(1, "month"), date("2024-02-29")),
+            (date("2020-02-29"), duration(1, "year"), date("2021-02-28")),
+            (date("2014"), duration(24, "months"), date("2016")),
+            (date("2014-01"), duration(45, "days"), date("2014-02")),
+            (date("2020-12-25"), duration(2, "weeks"), date("2021-01-08")),
+            (
+                date("2020-12-25"),
+                duration(36, "hours"),
+                date("2020-12-26"),
+            ),
+            (
+                datetime("2020-01-01T23:30:00Z"),
+                duration(90, "minutes"),
+                datetime("2020-01-02T01:00:00Z"),
+            ),
+            (
+                datetime("2020-01-01T10:00"),
+                Value::Quantity(Quantity::new(Decimal::new(15, 1), "s")),
+                datetime("2020-01-01T10:00"),
+            ),
+            (
+                datetime("2020-01-01T10:00:00.000"),
+                Value::Quantity(Quantity::new(Decimal::new(15, 1), "s")),
+                datetime("2020-01-01T10:00:01.500"),
+            ),
+            (time("23:00"), duration(2, "hours"), time("01:00")),
+        ];
+
+        for (value, quantity, expected) in cases {
+            assert_eq!(
+                value.add(&quantity),
+                Ok(Some(expected)),
+                "{} + {:?}",
+                value,
+                quantity
+            );
+        }
+
+        assert_eq!(
+            date("2023-03-31").subtract(&duration(1, "month")),
+            Ok(Some(date("2023-02-28")))
+        );
+        assert!(date("2020-01-01").add(&duration(1, "a")).is_err());
+        assert!(time("10:00").add(&duration(1, "day")).is_err());
+    }
+}
@@ -1,6 +1,7 @@
 use crate::evaluation::EvaluationError;
 
 use super::*;
+use std::cmp::Ordering;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Collection(Vec<Value>);
@@ -10,13 +11,108 @@ impl Collection {
         Collection(Vec::new())
     }
 
+    /// The only item in the collection, which must be of the given System type (FHIR primitive
+    /// elements are unwrapped to their System values)
     pub fn singleton(&self, t: Type) -> Result<&Value, EvaluationError> {
-        if self.0.len() != 1 {
-            return Err(EvaluationError::ExpectedSingleton(t));
-        } else if self.0.get(0).unwrap().data_type() != t {
-            return Err(EvaluationError::ExpectedSingleton(t));
+        match self.0.as_slice() {
+            [value] if value.system_value().data_type() == t => Ok(value.system_value()),
+            [value] => Err(EvaluationError::UnexpectedType {
+                expected: t,
+                actual: value.system_value().data_type(),
+            }),
+            items => Err(EvaluationError::ExpectedSingleton {
+                expected: t,
+                count: items.len(),
+            }),
+        }
+    }
+
+    /// The collection as a boolean, as the operands of `and` and the criteria of `where()` are
+    /// read: empty if it is empty, and true if it holds a single item that is not a Boolean
+    pub fn to_boolean(&self) -> Result<Option<bool>, EvaluationError> {
+        match self.as_slice() {
+            [] => Ok(None),
+            [value] => Ok(Some(match value.system_value() {
+                Value::Boolean(b) => *b,
+                _ => true,
+            })),
+            items => Err(EvaluationError::ExpectedSingleton {
+                expected: BOOLEAN,
+                count: items.len(),
+            }),
+        }
+    }
+
+    /// Collections are equal when they have the same number of items and each item is equal to
+    /// the one at the same position; the result is empty if either collection is
+    pub fn equal(&self, other: &Collection) -> Option<bool> {
+        if self.is_empty() || other.is_empty() {
+            return None;
+        } else if self.len() != other.len() {
+            return Some(false);
+        }
+
+        let mut result = Some(true);
+        for (v1, v2) in self.iter().zip(other.iter()) {
+            match v1.equal(v2) {
+                Some(false) => return Some(false),
+                None => result = None,
+                Some(true) => {}
+            }
+        }
+        result
+    }
+
+    /// Collections are equivalent when every item has an equivalent item in the other
+    /// collection, regardless of order
+    pub fn equivalent(&self, other: &Collection) -> bool {
+        if self.len() != other.len() {
+            return false;
+        }
+
+        let mut matched = vec![false; other.len()];
+        self.iter().all(|v1| {
+            let found = other
+                .iter()
+                .enumerate()
+                .position(|(i, v2)| !matched[i] && v1.equivalent(v2));
+            if let Some(i) = found {
+                matched[i] = true;
+            }
+            found.is_some()
+        })
+    }
+}
+
+impl Compare for Collection {
+    /// Singleton collections compare as their values do; an empty operand makes the comparison
+    /// indeterminate
+    fn compare(&self, other: &Collection) -> Option<Ordering> {
+        match (self.as_slice(), other.as_slice()) {
+            ([v1], [v2]) => v1.compare(v2),
+            _ => None,
+        }
+    }
+}
+
+impl Collection {
+    /// Checks that both operands of a comparison operator are singletons of comparable types,
+    /// or that at least one of them is empty
+    pub fn check_comparable(&self, other: &Collection) -> Result<(), EvaluationError> {
+        match (self.as_slice(), other.as_slice()) {
+            ([], _) | (_, []) => Ok(()),
+            ([v1], [v2]) if v1.is_comparable_to(v2) => Ok(()),
+            _ => Err(EvaluationError::InvalidOperands(
+                self.clone(),
+                other.clone(),
+            )),
         }
-        Ok(self.0.get(0).unwrap())
+    }
+}
+
+impl Default for Collection {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -40,6 +136,19 @@ impl FromIterator<Value> for Collection {
     }
 }
 
+impl std::fmt::Display for Collection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[")?;
+        for (i, value) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", value)?;
+        }
+        write!(f, "]")
+    }
+}
+
 impl From<Value> for Collection {
     fn from(value: Value) -> Self {
         Collection(vec![value])
@@ -0,0 +1,125 @@
+use super::*;
+use std::cmp::Ordering;
+
+/// Ordering between values as defined by the FHIRPath comparison operators (`<`, `<=`, `>`,
+/// `>=`).  Unlike `PartialOrd`, a result of `None` means the comparison is indeterminate (e.g.
+/// dates of differing precision, or quantities with incommensurable units) and the operator
+/// evaluates to empty.
+pub trait Compare<Rhs: ?Sized = Self> {
+    fn compare(&self, other: &Rhs) -> Option<Ordering>;
+}
+
+impl Compare for Value {
+    fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Any(v1), _) => v1.compare(other),
+            (_, Value::Any(v2)) => self.compare(v2),
+            (Value::Complex(_), _) | (_, Value::Complex(_))
+                if self.is_fhir_primitive() || other.is_fhir_primitive() =>
+            {
+                self.system_value().compare(other.system_value())
+            }
+            (Value::Integer(_) | Value::Long(_), Value::Integer(_) | Value::Long(_)) => {
+                Some(self.as_long()?.cmp(&other.as_long()?))
+            }
+            (
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+            ) => Some(self.as_decimal()?.cmp(&other.as_decimal()?)),
+            (Value::String(s1), Value::String(s2)) => Some(s1.cmp(s2)),
+            (Value::Date(d1), Value::Date(d2)) => d1.compare(d2),
+            (Value::Time(t1), Value::Time(t2)) => t1.compare(t2),
+            (Value::DateTime(dt1), Value::DateTime(dt2)) => dt1.compare(dt2),
+            (Value::Date(d), Value::DateTime(dt)) => FhirDateTime::from(*d).compare(dt),
+            (Value::DateTime(dt), Value::Date(d)) => dt.compare(&FhirDateTime::from(*d)),
+            (Value::Quantity(q1), Value::Quantity(q2)) => q1.compare(q2),
+            (Value::Quantity(_), Value::Complex(_)) | (Value::Complex(_), Value::Quantity(_)) => {
+                self.to_quantity()?.compare(&other.to_quantity()?)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Value {
+    /// Returns whether the comparison operators are defined between the types of the two
+    /// values, after implicit conversion
+    pub fn is_comparable_to(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Any(v1), _) => v1.is_comparable_to(other),
+            (_, Value::Any(v2)) => self.is_comparable_to(v2),
+            (Value::Complex(_), _) | (_, Value::Complex(_))
+                if self.is_fhir_primitive() || other.is_fhir_primitive() =>
+            {
+                self.system_value().is_comparable_to(other.system_value())
+            }
+            (
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+            )
+            | (Value::String(_), Value::String(_))
+            | (Value::Date(_) | Value::DateTime(_), Value::Date(_) | Value::DateTime(_))
+            | (Value::Time(_), Value::Time(_))
+            | (Value::Quantity(_), Value::Quantity(_)) => true,
+            (Value::Quantity(_) | Value::Complex(_), Value::Quantity(_) | Value::Complex(_)) => {
+                self.to_quantity().is_some() && other.to_quantity().is_some()
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_numbers() {
+        assert_eq!(
+            Value::integer(1).compare(&Value::decimal(15, 1)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            Value::decimal(20, 1).compare(&Value::integer(2)),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(
+            Value::string("abc").compare(&Value::string("abd")),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            Value::long(3_000_000_000).compare(&Value::integer(i32::MAX)),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            Value::decimal(25, 1).compare(&Value::long(3)),
+            Some(Ordering::Less)
+        );
+        assert!(!Value::boolean(true).is_comparable_to(&Value::boolean(false)));
+        assert!(!Value::integer(1).is_comparable_to(&Value::string("1")));
+    }
+
+    #[test]
+    fn test_compare_temporal() {
+        let date = |s: &str| Value::Date(s.parse().unwrap());
+        let datetime = |s: &str| Value::DateTime(s.parse().unwrap());
+
+        assert_eq!(date("2020").compare(&date("2020-06-01")), None);
+        assert_eq!(
+            date("2020-06-01").compare(&date("2020-06-02")),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            date("2020-06-01").compare(&datetime("2020-06-01T12:00")),
+            None
+        );
+        assert_eq!(
+            date("2020-06-02").compare(&datetime("2020-06-01T12:00")),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            datetime("2020-06-01T10:00:00+02:00").compare(&datetime("2020-06-01T09:00:00Z")),
+            Some(Ordering::Less)
+        );
+    }
+}
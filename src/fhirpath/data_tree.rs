@@ -1,8 +1,12 @@
 use super::*;
+use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub enum DataNode {
-    Object(Type, Vec<Box<DataNode>>),
+    /// An element with its named members in document order; repeating elements have one member
+    /// per item, all with the same name.  Members are shared, so that navigating to them, or
+    /// cloning the element, does not copy their subtrees.
+    Object(Type, Vec<(String, Arc<DataNode>)>),
     Value(Value),
 }
 
@@ -13,6 +17,149 @@ impl DataNode {
             Self::Value(value) => value.data_type(),
         }
     }
+
+    /// All members with the given name
+    pub fn members<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a DataNode> + 'a {
+        self.shared_members(name).map(|node| node.as_ref())
+    }
+
+    /// All members with the given name, as the shared nodes they are held in
+    pub fn shared_members<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a Arc<DataNode>> + 'a {
+        let members = match self {
+            Self::Object(_, members) => members.as_slice(),
+            Self::Value(_) => &[],
+        };
+        members
+            .iter()
+            .filter(move |(n, _)| n == name)
+            .map(|(_, node)| node)
+    }
+
+    /// The first member with the given name
+    pub fn member(&self, name: &str) -> Option<&DataNode> {
+        match self {
+            Self::Object(_, members) => members
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, node)| node.as_ref()),
+            Self::Value(_) => None,
+        }
+    }
+
+    /// The System value of a FHIR primitive element, which is held in its `value` member.  In
+    /// every version of FHIR the names of primitive types, and only those, start in lowercase.
+    pub fn primitive_value(&self) -> Option<&Value> {
+        let Self::Object(data_type, _) = self else {
+            return None;
+        };
+        let is_primitive = data_type
+            .strip_prefix("FHIR.")
+            .is_some_and(|name| name.starts_with(char::is_lowercase));
+        match self.member("value") {
+            Some(DataNode::Value(value)) if is_primitive => Some(value),
+            _ => None,
+        }
+    }
+
+    /// The System value of a member, whether it holds the value directly or is a FHIR primitive
+    pub fn member_value(&self, name: &str) -> Option<&Value> {
+        match self.member(name)? {
+            DataNode::Value(value) => Some(value),
+            node => node.primitive_value(),
+        }
+    }
+
+    /// The members with the given name as items of a collection, sharing rather than copying
+    /// them
+    pub fn member_values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = Value> + 'a {
+        self.shared_members(name).map(DataNode::to_value)
+    }
+
+    /// All members as items of a collection, in document order, except the System value of a
+    /// FHIR primitive, which is not one of its child elements
+    pub fn child_values(&self) -> impl Iterator<Item = Value> + '_ {
+        let members = match self {
+            Self::Object(_, members) => members.as_slice(),
+            Self::Value(_) => &[],
+        };
+        let is_primitive = self.primitive_value().is_some();
+        members
+            .iter()
+            .filter(move |(name, node)| {
+                !(is_primitive && name == "value" && matches!(node.as_ref(), Self::Value(_)))
+            })
+            .map(|(_, node)| node.to_value())
+    }
+
+    /// The node as an item of a collection, sharing rather than copying it
+    pub fn to_value(self: &Arc<Self>) -> Value {
+        match self.as_ref() {
+            Self::Object(..) => Value::Complex(Arc::clone(self)),
+            Self::Value(value) => value.clone(),
+        }
+    }
+
+    /// Deep equality: objects must have the same type and members equal in the same order
+    pub fn equal(&self, other: &DataNode) -> Option<bool> {
+        match (self, other) {
+            (DataNode::Object(t1, c1), DataNode::Object(t2, c2)) => {
+                if t1 != t2 || c1.len() != c2.len() {
+                    return Some(false);
+                }
+
+                let mut result = Some(true);
+                for ((name1, n1), (name2, n2)) in c1.iter().zip(c2.iter()) {
+                    if name1 != name2 {
+                        return Some(false);
+                    }
+                    match n1.equal(n2) {
+                        Some(false) => return Some(false),
+                        None => result = None,
+                        Some(true) => {}
+                    }
+                }
+                result
+            }
+            (DataNode::Value(v1), DataNode::Value(v2)) => v1.equal(v2),
+            _ => Some(false),
+        }
+    }
+
+    /// Deep equivalence: objects must have the same type and all members equivalent
+    pub fn equivalent(&self, other: &DataNode) -> bool {
+        match (self, other) {
+            (DataNode::Object(t1, c1), DataNode::Object(t2, c2)) => {
+                t1 == t2
+                    && c1.len() == c2.len()
+                    && c1
+                        .iter()
+                        .zip(c2.iter())
+                        .all(|((name1, n1), (name2, n2))| name1 == name2 && n1.equivalent(n2))
+            }
+            (DataNode::Value(v1), DataNode::Value(v2)) => v1.equivalent(v2),
+            _ => false,
+        }
+    }
+
+    /// Whether the node matches a profile's `pattern[x]`: values must be equal, and each member
+    /// of a pattern object must match some member of the same name, while other members are
+    /// unconstrained
+    pub fn matches_pattern(&self, pattern: &DataNode) -> bool {
+        match (self, pattern) {
+            (DataNode::Object(_, members), DataNode::Object(_, pattern_members)) => {
+                pattern_members.iter().all(|(name, p)| {
+                    members
+                        .iter()
+                        .any(|(n, node)| n == name && node.matches_pattern(p))
+                })
+            }
+            (DataNode::Value(v1), DataNode::Value(v2)) => v1.equal(v2) == Some(true),
+            _ => false,
+        }
+    }
 }
 
 impl PartialEq for DataNode {
@@ -23,8 +170,39 @@ impl PartialEq for DataNode {
             _ => false,
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_member_values_are_shared() {
+        let name = Arc::new(DataNode::Object(
+            "FHIR.HumanName",
+            vec![(
+                "family".to_string(),
+                Arc::new(DataNode::Value(Value::string("Chalmers"))),
+            )],
+        ));
+        let patient = Arc::new(DataNode::Object(
+            "FHIR.Patient",
+            vec![
+                ("name".to_string(), Arc::clone(&name)),
+                (
+                    "active".to_string(),
+                    Arc::new(DataNode::Value(Value::boolean(true))),
+                ),
+            ],
+        ));
 
-    fn ne(&self, other: &Self) -> bool {
-        !self.eq(other)
+        let names: Vec<_> = patient.member_values("name").collect();
+        assert!(matches!(names.as_slice(), [Value::Complex(node)] if Arc::ptr_eq(node, &name)));
+        // The element itself is shared as an item of a collection too
+        assert!(matches!(patient.to_value(), Value::Complex(node) if Arc::ptr_eq(&node, &patient)));
+        assert_eq!(
+            patient.member_values("active").collect::<Vec<_>>(),
+            vec![Value::boolean(true)]
+        );
     }
 }
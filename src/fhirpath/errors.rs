@@ -1 +1,34 @@
+use super::*;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValueError {
+    InvalidDate(String),
+    InvalidDateTime(String),
+    InvalidTime(String),
+    InvalidDurationUnit(String),
+    InvalidUnit(String),
+    OutOfRange,
+    UnsupportedOperation(&'static str, Type, Type),
+}
+
+impl std::fmt::Display for ValueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueError::InvalidDate(s) => write!(f, "invalid date {}", s),
+            ValueError::InvalidDateTime(s) => write!(f, "invalid date time {}", s),
+            ValueError::InvalidTime(s) => write!(f, "invalid time {}", s),
+            ValueError::InvalidDurationUnit(unit) => write!(f, "invalid duration unit {}", unit),
+            ValueError::InvalidUnit(unit) => write!(f, "invalid unit {}", unit),
+            ValueError::OutOfRange => write!(f, "value out of range"),
+            ValueError::UnsupportedOperation(operation, t1, t2) => {
+                write!(
+                    f,
+                    "unsupported operation {} on {} and {}",
+                    operation, t1, t2
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValueError {}
@@ -1,9 +1,20 @@
+mod arithmetic;
 mod collection;
+mod comparison;
 mod data_tree;
 mod errors;
+mod quantity;
+mod temporal;
+mod types;
+mod ucum;
 mod value;
 
 pub use collection::*;
+pub use comparison::*;
 pub use data_tree::*;
 pub use errors::*;
+pub use quantity::*;
+pub use temporal::*;
+pub use types::*;
+pub use ucum::*;
 pub use value::*;
@@ -0,0 +1,343 @@
+use super::*;
+use rust_decimal::Decimal;
+use std::cmp::Ordering;
+use std::fmt::Display;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Quantity {
+    value: Decimal,
+    unit: String,
+}
+
+/// FHIR data types which are profiles of Quantity, and so map onto System.Quantity
+pub const FHIR_QUANTITY_TYPES: [Type; 7] = [
+    "FHIR.Quantity",
+    "FHIR.Age",
+    "FHIR.Count",
+    "FHIR.Distance",
+    "FHIR.Duration",
+    "FHIR.MoneyQuantity",
+    "FHIR.SimpleQuantity",
+];
+
+const UCUM_SYSTEM: &str = "http://unitsofmeasure.org";
+
+impl Quantity {
+    pub fn new(value: Decimal, unit: impl ToString) -> Self {
+        Quantity {
+            value,
+            unit: unit.to_string(),
+        }
+    }
+
+    /// Maps a FHIR Quantity element onto a System.Quantity, using the UCUM code as the unit.
+    /// Returns `None` for elements which cannot be mapped, such as those with a comparator or a
+    /// coded unit from another system.
+    pub fn from_fhir(node: &DataNode) -> Option<Quantity> {
+        if !FHIR_QUANTITY_TYPES.contains(&node.data_type()) || node.member("comparator").is_some() {
+            return None;
+        }
+
+        let string = |name: &str| match node.member_value(name) {
+            Some(Value::String(s)) => Some(s.as_str()),
+            _ => None,
+        };
+        let value = match node.member_value("value")? {
+            Value::Decimal(d) => *d,
+            Value::Integer(n) => Decimal::from(*n),
+            _ => return None,
+        };
+        let unit = match (string("system"), string("code")) {
+            (Some(UCUM_SYSTEM), Some(code)) => code,
+            (None, None) => string("unit").unwrap_or("1"),
+            _ => return None,
+        };
+        Some(Quantity::new(value, unit))
+    }
+
+    pub fn value(&self) -> Decimal {
+        self.value
+    }
+
+    pub fn unit(&self) -> &str {
+        &self.unit
+    }
+
+    /// The parsed unit, where calendar duration keywords are distinct from the UCUM definite
+    /// durations for years and months
+    pub fn ucum_unit(&self) -> Result<Unit, ValueError> {
+        match Unit::calendar_duration(&self.unit) {
+            Some(unit) => Ok(unit),
+            None => Unit::parse(&self.unit),
+        }
+    }
+
+    /// The UCUM code for the unit, replacing any calendar duration keyword
+    pub fn ucum_code(&self) -> &str {
+        match self.unit.as_str() {
+            "year" | "years" => "a",
+            "month" | "months" => "mo",
+            "week" | "weeks" => "wk",
+            "day" | "days" => "d",
+            "hour" | "hours" => "h",
+            "minute" | "minutes" => "min",
+            "second" | "seconds" => "s",
+            "millisecond" | "milliseconds" => "ms",
+            unit => unit,
+        }
+    }
+
+    /// Converts the quantity to a commensurable unit, returning `None` if it is not
+    pub fn convert_to(&self, unit: &str) -> Option<Quantity> {
+        if unit == self.unit {
+            return Some(self.clone());
+        }
+
+        let target = Quantity::new(Decimal::ONE, unit).ucum_unit().ok()?;
+        let value = self.ucum_unit().ok()?.convert(self.value, &target)?;
+        Some(Quantity::new(value, unit))
+    }
+
+    /// Both values expressed in a common unit, if the units are commensurable.  Quantities with
+    /// units that are not valid UCUM can still be compared when their units are identical.
+    fn common_values(
+        &self,
+        other: &Quantity,
+        unit: fn(&Quantity) -> Result<Unit, ValueError>,
+    ) -> Option<(Decimal, Decimal)> {
+        if self.unit == other.unit {
+            return Some((self.value, other.value));
+        }
+
+        let (u1, u2) = (unit(self).ok()?, unit(other).ok()?);
+        if !u1.is_commensurable(&u2) {
+            return None;
+        }
+        Some((u1.to_base(self.value)?, u2.to_base(other.value)?))
+    }
+
+    pub fn equal(&self, other: &Quantity) -> Option<bool> {
+        let (v1, v2) = self.common_values(other, Quantity::ucum_unit)?;
+        Some(v1 == v2)
+    }
+
+    /// Equivalence additionally treats the calendar durations `year` and `month` as the UCUM
+    /// `'a'` and `'mo'`, and compares values at the precision of the less precise quantity
+    pub fn equivalent(&self, other: &Quantity) -> bool {
+        self.common_values(other, |q| Unit::parse(q.ucum_code()))
+            .is_some_and(|(v1, v2)| decimal_equivalent(v1, v2))
+    }
+
+    /// Adds a commensurable quantity, giving the result in the units of `self`
+    pub fn add(&self, other: &Quantity) -> Option<Quantity> {
+        let other = other.convert_to(&self.unit)?;
+        Some(Quantity::new(
+            self.value.checked_add(other.value)?,
+            &self.unit,
+        ))
+    }
+
+    pub fn subtract(&self, other: &Quantity) -> Option<Quantity> {
+        self.add(&Quantity::new(-other.value, &other.unit))
+    }
+
+    pub fn multiply(&self, other: &Quantity) -> Option<Quantity> {
+        Some(Quantity::new(
+            self.value.checked_mul(other.value)?,
+            combine_units(self.ucum_code(), '.', other.ucum_code()),
+        ))
+    }
+
+    /// Divides by another quantity, returning `None` on division by zero
+    pub fn divide(&self, other: &Quantity) -> Option<Quantity> {
+        Some(Quantity::new(
+            self.value.checked_div(other.value)?,
+            combine_units(self.ucum_code(), '/', other.ucum_code()),
+        ))
+    }
+}
+
+/// Builds the UCUM expression for the product or quotient of two units
+fn combine_units(u1: &str, op: char, u2: &str) -> String {
+    match (u1, op, u2) {
+        (_, _, "1") => u1.to_string(),
+        ("1", '.', _) => u2.to_string(),
+        _ if op == '/' && u1 == u2 => "1".to_string(),
+        _ if u2.contains(['.', '/']) => format!("{}{}({})", u1, op, u2),
+        _ => format!("{}{}{}", u1, op, u2),
+    }
+}
+
+impl Compare for Quantity {
+    fn compare(&self, other: &Quantity) -> Option<Ordering> {
+        let (v1, v2) = self.common_values(other, Quantity::ucum_unit)?;
+        Some(v1.cmp(&v2))
+    }
+}
+
+impl Display for Quantity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if Unit::calendar_duration(&self.unit).is_some() {
+            write!(f, "{} {}", self.value, self.unit)
+        } else {
+            write!(f, "{} '{}'", self.value, self.unit)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use std::sync::Arc;
+
+    fn quantity(value: i64, exp: u32, unit: &str) -> Value {
+        Value::Quantity(Quantity::new(Decimal::new(value, exp), unit))
+    }
+
+    #[test]
+    fn test_quantity_equality() {
+        assert_eq!(
+            quantity(5, 0, "mg").equal(&quantity(50, 1, "mg")),
+            Some(true)
+        );
+        assert_eq!(
+            quantity(5, 0, "mg").equal(&quantity(6, 0, "mg")),
+            Some(false)
+        );
+        assert_eq!(quantity(5, 0, "mg").equal(&quantity(5, 0, "cm")), None);
+        assert_eq!(
+            quantity(1, 0, "day").equal(&quantity(1, 0, "d")),
+            Some(true)
+        );
+        assert_eq!(quantity(1, 0, "year").equal(&quantity(1, 0, "a")), None);
+        assert!(quantity(1, 0, "years").equivalent(&quantity(1, 0, "a")));
+        assert!(quantity(12, 1, "mg").equivalent(&quantity(124, 2, "mg")));
+        assert!(!quantity(1, 0, "mg").equivalent(&quantity(1, 0, "cm")));
+    }
+
+    #[test]
+    fn test_unit_conversion() {
+        assert_eq!(
+            quantity(1, 0, "g").equal(&quantity(1000, 0, "mg")),
+            Some(true)
+        );
+        assert_eq!(
+            quantity(1, 0, "[lb_av]").equal(&quantity(45359237, 8, "kg")),
+            Some(true)
+        );
+        assert_eq!(
+            quantity(100, 0, "mg/dL").equal(&quantity(1, 0, "g/L")),
+            Some(true)
+        );
+        assert_eq!(
+            quantity(1, 0, "year").equal(&quantity(12, 0, "months")),
+            Some(true)
+        );
+        assert_eq!(
+            quantity(1, 0, "mg/dL").equal(&quantity(1, 0, "mmol/L")),
+            None
+        );
+        assert!(quantity(12, 1, "g").equivalent(&quantity(1240, 0, "mg")));
+        assert!(quantity(1, 0, "month").equivalent(&quantity(1, 0, "mo")));
+        assert_eq!(
+            quantity(1, 0, "{score}").equal(&quantity(1, 0, "{score}")),
+            Some(true)
+        );
+        assert_eq!(
+            quantity(1, 0, "widget").equal(&quantity(1, 0, "widget")),
+            Some(true)
+        );
+        assert_eq!(quantity(1, 0, "widget").equal(&quantity(1, 0, "g")), None);
+    }
+
+    #[test]
+    fn test_quantity_comparison() {
+        assert_eq!(
+            quantity(5, 0, "mg").compare(&quantity(6, 0, "mg")),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            quantity(2, 0, "days").compare(&quantity(1, 0, "d")),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(quantity(5, 0, "mg").compare(&quantity(5, 0, "cm")), None);
+        assert_eq!(
+            quantity(1, 0, "[ft_i]").compare(&quantity(30, 0, "cm")),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            quantity(37, 0, "Cel").compare(&quantity(98, 0, "[degF]")),
+            Some(Ordering::Greater)
+        );
+    }
+
+    #[test]
+    fn test_quantity_arithmetic() {
+        let q = |value: i64, exp: u32, unit: &str| Quantity::new(Decimal::new(value, exp), unit);
+
+        assert_eq!(q(1, 0, "g").add(&q(500, 0, "mg")), Some(q(15, 1, "g")));
+        assert_eq!(q(1, 0, "g").subtract(&q(1, 0, "kg")), Some(q(-999, 0, "g")));
+        assert_eq!(q(1, 0, "g").add(&q(1, 0, "m")), None);
+        assert_eq!(q(2, 0, "m").multiply(&q(3, 0, "m")), Some(q(6, 0, "m.m")));
+        assert_eq!(q(6, 0, "m.m").convert_to("m2"), Some(q(6, 0, "m2")));
+        assert_eq!(q(10, 0, "km").divide(&q(2, 0, "h")), Some(q(5, 0, "km/h")));
+        assert_eq!(
+            q(1, 0, "g").divide(&q(2, 0, "m/s")),
+            Some(q(5, 1, "g/(m/s)"))
+        );
+        assert_eq!(q(4, 0, "g").divide(&q(2, 0, "g")), Some(q(2, 0, "1")));
+        assert_eq!(q(4, 0, "g").divide(&q(0, 0, "g")), None);
+        assert_eq!(q(2, 0, "days").multiply(&q(3, 0, "1")), Some(q(6, 0, "d")));
+    }
+
+    #[test]
+    fn test_fhir_quantity() {
+        let node = |members: Vec<(&str, Value)>| {
+            DataNode::Object(
+                "FHIR.Quantity",
+                members
+                    .into_iter()
+                    .map(|(name, value)| (name.to_string(), Arc::new(DataNode::Value(value))))
+                    .collect(),
+            )
+        };
+
+        assert_eq!(
+            Quantity::from_fhir(&node(vec![
+                ("value", Value::decimal(54, 1)),
+                ("unit", Value::string("mg/dL")),
+                ("system", Value::string(UCUM_SYSTEM)),
+                ("code", Value::string("mg/dL")),
+            ])),
+            Some(Quantity::new(Decimal::new(54, 1), "mg/dL"))
+        );
+        assert_eq!(
+            Quantity::from_fhir(&node(vec![
+                ("value", Value::integer(3)),
+                ("unit", Value::string("tablets")),
+            ])),
+            Some(Quantity::new(Decimal::from(3), "tablets"))
+        );
+        assert_eq!(
+            Quantity::from_fhir(&node(vec![
+                ("value", Value::integer(3)),
+                ("comparator", Value::string("<")),
+                ("system", Value::string(UCUM_SYSTEM)),
+                ("code", Value::string("mg")),
+            ])),
+            None
+        );
+
+        let element = Value::Complex(Arc::new(node(vec![
+            ("value", Value::decimal(54, 1)),
+            ("system", Value::string(UCUM_SYSTEM)),
+            ("code", Value::string("mg/dL")),
+        ])));
+        assert_eq!(element.equal(&quantity(54, 0, "mg/L")), Some(true));
+        assert_eq!(
+            quantity(5, 0, "mg/dL").compare(&element),
+            Some(Ordering::Less)
+        );
+    }
+}
@@ -0,0 +1,871 @@
+use super::*;
+use chrono::{
+    Datelike, Duration, FixedOffset, Months, NaiveDate, NaiveDateTime, NaiveTime, Timelike,
+};
+use rust_decimal::prelude::*;
+use std::cmp::Ordering;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// The finest component specified in a temporal value.  Seconds may carry a fractional part,
+/// which is tracked by the number of digits so that values round-trip exactly; for comparison
+/// purposes seconds and fractional seconds are a single precision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Precision {
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Fraction(u8),
+}
+
+impl Precision {
+    fn level(&self) -> usize {
+        match self {
+            Precision::Year => 0,
+            Precision::Month => 1,
+            Precision::Day => 2,
+            Precision::Hour => 3,
+            Precision::Minute => 4,
+            Precision::Second | Precision::Fraction(_) => 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimeZoneOffset {
+    /// Written as `Z`
+    Utc,
+    Fixed(FixedOffset),
+}
+
+impl TimeZoneOffset {
+    pub fn fixed_offset(&self) -> FixedOffset {
+        match self {
+            TimeZoneOffset::Utc => FixedOffset::east_opt(0).unwrap(),
+            TimeZoneOffset::Fixed(offset) => *offset,
+        }
+    }
+}
+
+/// A FHIR `date`, or a FHIRPath `System.Date`, with year, month or day precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FhirDate {
+    date: NaiveDate,
+    precision: Precision,
+}
+
+/// A FHIR `time`, or a FHIRPath `System.Time`, with hour to fractional second precision
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FhirTime {
+    time: NaiveTime,
+    precision: Precision,
+}
+
+/// A FHIR `dateTime` or `instant`, or a FHIRPath `System.DateTime`, with any precision and an
+/// optional time zone offset
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FhirDateTime {
+    datetime: NaiveDateTime,
+    precision: Precision,
+    offset: Option<TimeZoneOffset>,
+}
+
+impl FhirDate {
+    pub fn new(date: NaiveDate, precision: Precision) -> Self {
+        FhirDate {
+            date,
+            precision: precision.min(Precision::Day),
+        }
+    }
+
+    /// The date, with any unspecified components set to their first value
+    pub fn naive_date(&self) -> NaiveDate {
+        self.date
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    fn components(&self) -> [i64; 3] {
+        [
+            self.date.year() as i64,
+            self.date.month() as i64,
+            self.date.day() as i64,
+        ]
+    }
+}
+
+impl FhirTime {
+    pub fn new(time: NaiveTime, precision: Precision) -> Self {
+        FhirTime {
+            time,
+            precision: precision.max(Precision::Hour),
+        }
+    }
+
+    /// The time, with any unspecified components set to zero
+    pub fn naive_time(&self) -> NaiveTime {
+        self.time
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+}
+
+impl FhirDateTime {
+    pub fn new(
+        datetime: NaiveDateTime,
+        precision: Precision,
+        offset: Option<TimeZoneOffset>,
+    ) -> Self {
+        FhirDateTime {
+            datetime,
+            precision,
+            offset: offset.filter(|_| precision >= Precision::Hour),
+        }
+    }
+
+    /// The date and time as written, with any unspecified components set to their first value
+    pub fn naive_datetime(&self) -> NaiveDateTime {
+        self.datetime
+    }
+
+    pub fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    pub fn offset(&self) -> Option<TimeZoneOffset> {
+        self.offset
+    }
+
+    pub fn date(&self) -> FhirDate {
+        FhirDate::new(self.datetime.date(), self.precision)
+    }
+
+    /// Components from year to (fractional) seconds.  When `utc` is set and the value has both a
+    /// time and an offset, it is first normalized to UTC.
+    fn components(&self, utc: bool) -> [i64; 6] {
+        let datetime = match self.offset {
+            Some(offset) if utc => self.datetime - offset.fixed_offset(),
+            _ => self.datetime,
+        };
+        let time = time_components(&datetime.time());
+        [
+            datetime.year() as i64,
+            datetime.month() as i64,
+            datetime.day() as i64,
+            time[0],
+            time[1],
+            time[2],
+        ]
+    }
+}
+
+impl From<FhirDate> for FhirDateTime {
+    fn from(date: FhirDate) -> Self {
+        FhirDateTime::new(date.date.and_time(NaiveTime::MIN), date.precision, None)
+    }
+}
+
+impl From<NaiveDate> for FhirDate {
+    fn from(date: NaiveDate) -> Self {
+        FhirDate::new(date, Precision::Day)
+    }
+}
+
+impl From<NaiveTime> for FhirTime {
+    fn from(time: NaiveTime) -> Self {
+        FhirTime::new(time, Precision::Fraction(3))
+    }
+}
+
+impl From<chrono::DateTime<FixedOffset>> for FhirDateTime {
+    fn from(datetime: chrono::DateTime<FixedOffset>) -> Self {
+        FhirDateTime::new(
+            datetime.naive_local(),
+            Precision::Fraction(3),
+            Some(TimeZoneOffset::Fixed(*datetime.offset())),
+        )
+    }
+}
+
+fn time_components(time: &NaiveTime) -> [i64; 3] {
+    [
+        time.hour() as i64,
+        time.minute() as i64,
+        time.second() as i64 * 1_000_000_000 + time.nanosecond() as i64,
+    ]
+}
+
+/// Compares values component by component down to the coarser of the two precisions.  If the
+/// values agree that far but the precisions differ, the result is indeterminate.
+fn compare_components(c1: &[i64], p1: usize, c2: &[i64], p2: usize) -> Option<Ordering> {
+    for (v1, v2) in c1.iter().zip(c2.iter()).take(p1.min(p2) + 1) {
+        match v1.cmp(v2) {
+            Ordering::Equal => continue,
+            ordering => return Some(ordering),
+        }
+    }
+
+    if p1 == p2 {
+        Some(Ordering::Equal)
+    } else {
+        None
+    }
+}
+
+impl Compare for FhirDate {
+    fn compare(&self, other: &FhirDate) -> Option<Ordering> {
+        compare_components(
+            &self.components(),
+            self.precision.level(),
+            &other.components(),
+            other.precision.level(),
+        )
+    }
+}
+
+impl Compare for FhirTime {
+    fn compare(&self, other: &FhirTime) -> Option<Ordering> {
+        compare_components(
+            &time_components(&self.time),
+            self.precision.level() - Precision::Hour.level(),
+            &time_components(&other.time),
+            other.precision.level() - Precision::Hour.level(),
+        )
+    }
+}
+
+impl Compare for FhirDateTime {
+    /// Values that both carry a time zone offset are normalized to UTC before comparison;
+    /// otherwise they are compared as written
+    fn compare(&self, other: &FhirDateTime) -> Option<Ordering> {
+        let utc = self.offset.is_some() && other.offset.is_some();
+        compare_components(
+            &self.components(utc),
+            self.precision.level(),
+            &other.components(utc),
+            other.precision.level(),
+        )
+    }
+}
+
+/// Units of calendar duration, as used in date/time arithmetic and the `duration()` and
+/// `difference()` functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DurationUnit {
+    Year,
+    Month,
+    Week,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Millisecond,
+}
+
+impl DurationUnit {
+    /// Accepts the calendar duration keywords, and the UCUM units with a definite duration.
+    /// UCUM `'a'` and `'mo'` are mean lengths rather than calendar durations, so they are not
+    /// accepted.
+    pub fn from_unit(unit: &str) -> Result<Self, ValueError> {
+        match unit {
+            "year" | "years" => Ok(DurationUnit::Year),
+            "month" | "months" => Ok(DurationUnit::Month),
+            "week" | "weeks" | "wk" => Ok(DurationUnit::Week),
+            "day" | "days" | "d" => Ok(DurationUnit::Day),
+            "hour" | "hours" | "h" => Ok(DurationUnit::Hour),
+            "minute" | "minutes" | "min" => Ok(DurationUnit::Minute),
+            "second" | "seconds" | "s" => Ok(DurationUnit::Second),
+            "millisecond" | "milliseconds" | "ms" => Ok(DurationUnit::Millisecond),
+            _ => Err(ValueError::InvalidDurationUnit(unit.to_string())),
+        }
+    }
+
+    /// The coarsest precision a value needs for this unit to be meaningful
+    fn precision(&self) -> Precision {
+        match self {
+            DurationUnit::Year => Precision::Year,
+            DurationUnit::Month => Precision::Month,
+            DurationUnit::Week | DurationUnit::Day => Precision::Day,
+            DurationUnit::Hour => Precision::Hour,
+            DurationUnit::Minute => Precision::Minute,
+            DurationUnit::Second => Precision::Second,
+            DurationUnit::Millisecond => Precision::Fraction(3),
+        }
+    }
+
+    /// The unit used to add durations to a value with the given precision
+    fn for_precision(precision: Precision) -> Self {
+        match precision {
+            Precision::Year => DurationUnit::Year,
+            Precision::Month => DurationUnit::Month,
+            Precision::Day => DurationUnit::Day,
+            Precision::Hour => DurationUnit::Hour,
+            Precision::Minute => DurationUnit::Minute,
+            Precision::Second => DurationUnit::Second,
+            Precision::Fraction(_) => DurationUnit::Millisecond,
+        }
+    }
+
+    /// Nominal length in milliseconds, used when a duration has to be expressed in a coarser
+    /// unit
+    fn nominal_millis(&self) -> i64 {
+        const DAY: i64 = 86_400_000;
+        match self {
+            DurationUnit::Year => 365 * DAY,
+            DurationUnit::Month => 30 * DAY,
+            DurationUnit::Week => 7 * DAY,
+            DurationUnit::Day => DAY,
+            DurationUnit::Hour => 3_600_000,
+            DurationUnit::Minute => 60_000,
+            DurationUnit::Second => 1_000,
+            DurationUnit::Millisecond => 1,
+        }
+    }
+
+    /// Converts an amount of this unit to a whole number of `target` units, truncating any
+    /// remainder.  Above seconds, calendar durations only use the integer part of the amount.
+    fn convert(&self, amount: Decimal, target: DurationUnit) -> Result<i64, ValueError> {
+        let amount = if *self < DurationUnit::Second {
+            amount.trunc()
+        } else {
+            amount
+        };
+        let converted = match (self, target) {
+            (unit, target) if *unit == target => Some(amount),
+            (DurationUnit::Year, DurationUnit::Month) => amount.checked_mul(Decimal::from(12)),
+            (DurationUnit::Month, DurationUnit::Year) => amount.checked_div(Decimal::from(12)),
+            (unit, target) => amount
+                .checked_mul(Decimal::from(unit.nominal_millis()))
+                .and_then(|millis| millis.checked_div(Decimal::from(target.nominal_millis()))),
+        };
+        converted
+            .and_then(|n| n.trunc().to_i64())
+            .ok_or(ValueError::OutOfRange)
+    }
+}
+
+impl FromStr for DurationUnit {
+    type Err = ValueError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DurationUnit::from_unit(s)
+    }
+}
+
+/// Adds a whole number of `unit`s to a date and time, clamping to the end of the month when
+/// adding months or years lands on a day that does not exist
+fn add_to_datetime(
+    datetime: NaiveDateTime,
+    amount: i64,
+    unit: DurationUnit,
+) -> Result<NaiveDateTime, ValueError> {
+    let add_months = |months: i64| {
+        let magnitude = Months::new(u32::try_from(months.unsigned_abs()).ok()?);
+        if months < 0 {
+            datetime.checked_sub_months(magnitude)
+        } else {
+            datetime.checked_add_months(magnitude)
+        }
+    };
+    let result = match unit {
+        DurationUnit::Year => amount.checked_mul(12).and_then(add_months),
+        DurationUnit::Month => add_months(amount),
+        unit => amount
+            .checked_mul(unit.nominal_millis())
+            .and_then(|millis| datetime.checked_add_signed(Duration::milliseconds(millis))),
+    };
+    result.ok_or(ValueError::OutOfRange)
+}
+
+impl FhirDateTime {
+    /// Adds a calendar duration.  If the duration is more precise than the value, it is first
+    /// converted to the value's precision, e.g. `@2014 + 24 months` is `@2016`.
+    pub fn add_duration(&self, amount: Decimal, unit: DurationUnit) -> Result<Self, ValueError> {
+        let finest = DurationUnit::for_precision(self.precision);
+        let unit_used = if finest == DurationUnit::Millisecond && unit >= DurationUnit::Second {
+            DurationUnit::Millisecond
+        } else {
+            unit.min(finest)
+        };
+        let amount = unit.convert(amount, unit_used)?;
+        let datetime = add_to_datetime(self.datetime, amount, unit_used)?;
+        Ok(FhirDateTime { datetime, ..*self })
+    }
+
+    /// Counts the `unit` boundaries crossed between this value and `other`, e.g. there is one
+    /// month boundary between 2020-01-31 and 2020-02-01.  Empty if either value is less precise
+    /// than `unit`.
+    pub fn difference(&self, other: &FhirDateTime, unit: DurationUnit) -> Option<i64> {
+        let (start, end) = self.normalized_pair(other, unit)?;
+        let months = |dt: &NaiveDateTime| dt.year() as i64 * 12 + dt.month0() as i64;
+        let truncated_millis = |dt: &NaiveDateTime, unit: DurationUnit| {
+            dt.and_utc()
+                .timestamp_millis()
+                .div_euclid(unit.nominal_millis())
+        };
+        Some(match unit {
+            DurationUnit::Year => end.year() as i64 - start.year() as i64,
+            DurationUnit::Month => months(&end) - months(&start),
+            DurationUnit::Week => (end.date() - start.date()).num_days() / 7,
+            DurationUnit::Day => (end.date() - start.date()).num_days(),
+            unit => truncated_millis(&end, unit) - truncated_millis(&start, unit),
+        })
+    }
+
+    /// Counts the whole `unit`s elapsed between this value and `other`, e.g. the duration between
+    /// 2020-01-31 and 2020-02-01 is zero months.  Empty if either value is less precise than
+    /// `unit`.
+    pub fn duration(&self, other: &FhirDateTime, unit: DurationUnit) -> Option<i64> {
+        let (start, end) = self.normalized_pair(other, unit)?;
+        match unit {
+            // Values without months can only count the years between them
+            DurationUnit::Year
+                if self.precision < Precision::Month || other.precision < Precision::Month =>
+            {
+                self.difference(other, DurationUnit::Year)
+            }
+            DurationUnit::Year | DurationUnit::Month => {
+                let mut months = self.difference(other, DurationUnit::Month)?;
+                // A final month only counts once the day and time have been reached
+                let rest = |dt: &NaiveDateTime| (dt.day(), dt.time());
+                if months > 0 && rest(&end) < rest(&start) {
+                    months -= 1;
+                } else if months < 0 && rest(&end) > rest(&start) {
+                    months += 1;
+                }
+                Some(if unit == DurationUnit::Year {
+                    months / 12
+                } else {
+                    months
+                })
+            }
+            unit => Some((end - start).num_milliseconds() / unit.nominal_millis()),
+        }
+    }
+
+    /// Both values, normalized to UTC if they both have offsets, provided they are at least as
+    /// precise as `unit`
+    fn normalized_pair(
+        &self,
+        other: &FhirDateTime,
+        unit: DurationUnit,
+    ) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        let precision = unit.precision().min(Precision::Second);
+        if self.precision < precision || other.precision < precision {
+            return None;
+        }
+
+        let utc = self.offset.is_some() && other.offset.is_some();
+        let normalize = |dt: &FhirDateTime| match dt.offset {
+            Some(offset) if utc => dt.datetime - offset.fixed_offset(),
+            _ => dt.datetime,
+        };
+        Some((normalize(self), normalize(other)))
+    }
+}
+
+impl FhirDate {
+    pub fn add_duration(&self, amount: Decimal, unit: DurationUnit) -> Result<Self, ValueError> {
+        Ok(FhirDateTime::from(*self).add_duration(amount, unit)?.date())
+    }
+
+    pub fn difference(&self, other: &FhirDate, unit: DurationUnit) -> Option<i64> {
+        FhirDateTime::from(*self).difference(&FhirDateTime::from(*other), unit)
+    }
+
+    pub fn duration(&self, other: &FhirDate, unit: DurationUnit) -> Option<i64> {
+        FhirDateTime::from(*self).duration(&FhirDateTime::from(*other), unit)
+    }
+}
+
+impl FhirTime {
+    /// Adds a duration of hours or less, wrapping around midnight
+    pub fn add_duration(&self, amount: Decimal, unit: DurationUnit) -> Result<Self, ValueError> {
+        if unit < DurationUnit::Hour {
+            return Err(ValueError::InvalidDurationUnit(format!("{:?}", unit)));
+        }
+
+        let datetime = self.on_day().add_duration(amount, unit)?;
+        Ok(FhirTime {
+            time: datetime.datetime.time(),
+            ..*self
+        })
+    }
+
+    pub fn difference(&self, other: &FhirTime, unit: DurationUnit) -> Option<i64> {
+        self.on_same_day(other, unit)
+            .and_then(|(t1, t2)| t1.difference(&t2, unit))
+    }
+
+    pub fn duration(&self, other: &FhirTime, unit: DurationUnit) -> Option<i64> {
+        self.on_same_day(other, unit)
+            .and_then(|(t1, t2)| t1.duration(&t2, unit))
+    }
+
+    fn on_same_day(
+        &self,
+        other: &FhirTime,
+        unit: DurationUnit,
+    ) -> Option<(FhirDateTime, FhirDateTime)> {
+        if unit < DurationUnit::Hour {
+            return None;
+        }
+        Some((self.on_day(), other.on_day()))
+    }
+
+    /// The time on an ordinary day, with days either side of it to wrap around into
+    fn on_day(&self) -> FhirDateTime {
+        let day = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap_or_default();
+        FhirDateTime::new(day.and_time(self.time), self.precision, None)
+    }
+}
+
+impl Display for FhirDate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}", self.date.year())?;
+        if self.precision >= Precision::Month {
+            write!(f, "-{:02}", self.date.month())?;
+        }
+        if self.precision >= Precision::Day {
+            write!(f, "-{:02}", self.date.day())?;
+        }
+        Ok(())
+    }
+}
+
+fn fmt_time(
+    f: &mut std::fmt::Formatter<'_>,
+    time: &NaiveTime,
+    precision: Precision,
+) -> std::fmt::Result {
+    write!(f, "{:02}", time.hour())?;
+    if precision >= Precision::Minute {
+        write!(f, ":{:02}", time.minute())?;
+    }
+    if precision >= Precision::Second {
+        write!(f, ":{:02}", time.second())?;
+    }
+    if let Precision::Fraction(digits) = precision {
+        let fraction = time.nanosecond() / 10u32.pow(9 - digits as u32);
+        write!(f, ".{:0width$}", fraction, width = digits as usize)?;
+    }
+    Ok(())
+}
+
+impl Display for FhirTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_time(f, &self.time, self.precision)
+    }
+}
+
+impl Display for FhirDateTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.date().fmt(f)?;
+        if self.precision < Precision::Hour {
+            return Ok(());
+        }
+
+        f.write_str("T")?;
+        fmt_time(f, &self.datetime.time(), self.precision)?;
+        match self.offset {
+            Some(TimeZoneOffset::Utc) => f.write_str("Z"),
+            Some(TimeZoneOffset::Fixed(offset)) => {
+                let seconds = offset.local_minus_utc();
+                let sign = if seconds < 0 { '-' } else { '+' };
+                let minutes = seconds.abs() / 60;
+                write!(f, "{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads a fixed-width run of digits from the start of the input
+fn take_number(input: &mut &str, width: usize) -> Option<u32> {
+    let digits = input.get(..width)?;
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    *input = &input[width..];
+    digits.parse().ok()
+}
+
+/// Consumes `prefix` from the start of the input, returning whether it was present
+fn take_prefix(input: &mut &str, prefix: char) -> bool {
+    if let Some(rest) = input.strip_prefix(prefix) {
+        *input = rest;
+        true
+    } else {
+        false
+    }
+}
+
+/// Parses `YYYY(-MM(-DD)?)?`
+fn parse_date(input: &mut &str) -> Option<FhirDate> {
+    let year = take_number(input, 4)? as i32;
+    let (mut month, mut day, mut precision) = (1, 1, Precision::Year);
+    if take_prefix(input, '-') {
+        month = take_number(input, 2)?;
+        precision = Precision::Month;
+        if take_prefix(input, '-') {
+            day = take_number(input, 2)?;
+            precision = Precision::Day;
+        }
+    }
+
+    let date = NaiveDate::from_ymd_opt(year, month, day)?;
+    Some(FhirDate::new(date, precision))
+}
+
+/// Parses `hh(:mm(:ss(.f+)?)?)?`
+fn parse_time(input: &mut &str) -> Option<FhirTime> {
+    let hour = take_number(input, 2)?;
+    let (mut minute, mut second, mut nanosecond) = (0, 0, 0);
+    let mut precision = Precision::Hour;
+    if take_prefix(input, ':') {
+        minute = take_number(input, 2)?;
+        precision = Precision::Minute;
+        if take_prefix(input, ':') {
+            second = take_number(input, 2)?;
+            precision = Precision::Second;
+            if take_prefix(input, '.') {
+                let digits = input.chars().take_while(|c| c.is_ascii_digit()).count();
+                if digits == 0 || digits > 9 {
+                    return None;
+                }
+                nanosecond = take_number(input, digits)? * 10u32.pow(9 - digits as u32);
+                precision = Precision::Fraction(digits as u8);
+            }
+        }
+    }
+
+    let time = NaiveTime::from_hms_nano_opt(hour, minute, second, nanosecond)?;
+    Some(FhirTime::new(time, precision))
+}
+
+/// Parses `Z` or `(+|-)hh:mm`
+fn parse_offset(input: &mut &str) -> Option<TimeZoneOffset> {
+    if take_prefix(input, 'Z') {
+        return Some(TimeZoneOffset::Utc);
+    }
+
+    let sign = if take_prefix(input, '+') {
+        1
+    } else if take_prefix(input, '-') {
+        -1
+    } else {
+        return None;
+    };
+    let hours = take_number(input, 2)? as i32;
+    if !take_prefix(input, ':') {
+        return None;
+    }
+    let minutes = take_number(input, 2)? as i32;
+    let offset = FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))?;
+    Some(TimeZoneOffset::Fixed(offset))
+}
+
+impl FromStr for FhirDate {
+    type Err = ValueError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut input = s;
+        match parse_date(&mut input) {
+            Some(date) if input.is_empty() => Ok(date),
+            _ => Err(ValueError::InvalidDate(s.to_string())),
+        }
+    }
+}
+
+impl FromStr for FhirTime {
+    type Err = ValueError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut input = s;
+        match parse_time(&mut input) {
+            Some(time) if input.is_empty() => Ok(time),
+            _ => Err(ValueError::InvalidTime(s.to_string())),
+        }
+    }
+}
+
+impl FromStr for FhirDateTime {
+    type Err = ValueError;
+
+    /// Accepts FHIR `dateTime` and `instant` strings, as well as the body of FHIRPath DateTime
+    /// literals, which may end in a bare `T` or omit the time zone offset
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ValueError::InvalidDateTime(s.to_string());
+        let mut input = s;
+        let date = parse_date(&mut input).ok_or_else(invalid)?;
+        if !take_prefix(&mut input, 'T') || input.is_empty() {
+            return if input.is_empty() {
+                Ok(date.into())
+            } else {
+                Err(invalid())
+            };
+        }
+
+        let time = parse_time(&mut input).ok_or_else(invalid)?;
+        let offset = if input.is_empty() {
+            None
+        } else {
+            Some(parse_offset(&mut input).ok_or_else(invalid)?)
+        };
+        if !input.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(FhirDateTime::new(
+            date.date.and_time(time.time),
+            time.precision,
+            offset,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_round_trip() {
+        for s in ["2020", "2020-05", "2020-05-31"] {
+            assert_eq!(FhirDate::from_str(s).unwrap().to_string(), s);
+        }
+        for s in ["14", "14:30", "14:30:15", "14:30:15.1", "14:30:15.000250"] {
+            assert_eq!(FhirTime::from_str(s).unwrap().to_string(), s);
+        }
+        for s in [
+            "2020",
+            "2020-05-31",
+            "2020-05-31T14",
+            "2020-05-31T14:30:15",
+            "2020-05-31T14:30:15.123456789Z",
+            "2020-05-31T14:30:15+00:00",
+            "2020-05-31T14:30-05:30",
+        ] {
+            assert_eq!(FhirDateTime::from_str(s).unwrap().to_string(), s);
+        }
+        assert_eq!(FhirDateTime::from_str("2020T").unwrap().to_string(), "2020");
+    }
+
+    #[test]
+    fn test_invalid() {
+        for s in ["20", "2020-13", "2020-02-30", "2020-5", "2020-05-01T"] {
+            assert!(FhirDate::from_str(s).is_err(), "{}", s);
+        }
+        for s in ["24", "14:60", "14:30:15.", "14:30:15.1234567890", "2PM"] {
+            assert!(FhirTime::from_str(s).is_err(), "{}", s);
+        }
+        for s in [
+            "2020-05-01T25",
+            "2020-05-01T10:00+5",
+            "2020-05-01Z",
+            "2020-05-01T10Zz",
+        ] {
+            assert!(FhirDateTime::from_str(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let date = |s: &str| FhirDate::from_str(s).unwrap();
+        let datetime = |s: &str| FhirDateTime::from_str(s).unwrap();
+        let time = |s: &str| FhirTime::from_str(s).unwrap();
+
+        assert_eq!(date("2020").compare(&date("2020-06-01")), None);
+        assert_eq!(
+            date("2019").compare(&date("2020-06-01")),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            date("2020-06").compare(&date("2020-05")),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            datetime("2020-06-01T10:00:00+02:00").compare(&datetime("2020-06-01T08:00:00Z")),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(
+            datetime("2020-06-01T23:00:00-05:00").compare(&datetime("2020-06-02T01:00:00Z")),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(
+            datetime("2020-06-01T10:00:00").compare(&datetime("2020-06-01T10:00:00.000")),
+            Some(Ordering::Equal)
+        );
+        assert_eq!(
+            datetime("2020-06-01T10:00").compare(&datetime("2020-06-01T10:00:30")),
+            None
+        );
+        assert_eq!(time("10:00").compare(&time("10:00:00")), None);
+        assert_eq!(
+            time("10:01").compare(&time("10:00:59")),
+            Some(Ordering::Greater)
+        );
+    }
+
+    #[test]
+    fn test_duration_and_difference() {
+        let date = |s: &str| FhirDate::from_str(s).unwrap();
+        let datetime = |s: &str| FhirDateTime::from_str(s).unwrap();
+        let time = |s: &str| FhirTime::from_str(s).unwrap();
+
+        let (d1, d2) = (date("2020-01-31"), date("2020-03-01"));
+        assert_eq!(d1.difference(&d2, DurationUnit::Month), Some(2));
+        assert_eq!(d1.duration(&d2, DurationUnit::Month), Some(1));
+        assert_eq!(d1.duration(&d2, DurationUnit::Day), Some(30));
+        assert_eq!(d1.duration(&d2, DurationUnit::Week), Some(4));
+        assert_eq!(d2.duration(&d1, DurationUnit::Month), Some(-1));
+        assert_eq!(d1.duration(&d2, DurationUnit::Hour), None);
+        assert_eq!(date("2020").difference(&d2, DurationUnit::Month), None);
+        assert_eq!(date("2020").difference(&d2, DurationUnit::Year), Some(0));
+        assert_eq!(
+            date("2020").duration(&date("2022"), DurationUnit::Year),
+            Some(2)
+        );
+        assert_eq!(date("2020").duration(&d2, DurationUnit::Year), Some(0));
+        assert_eq!(date("2020").duration(&d2, DurationUnit::Month), None);
+
+        let (dt1, dt2) = (
+            datetime("2020-01-01T23:59:00+01:00"),
+            datetime("2020-01-01T23:01:00Z"),
+        );
+        assert_eq!(dt1.duration(&dt2, DurationUnit::Minute), Some(2));
+        assert_eq!(dt1.difference(&dt2, DurationUnit::Hour), Some(1));
+        assert_eq!(dt1.duration(&dt2, DurationUnit::Hour), Some(0));
+        assert_eq!(dt1.difference(&dt2, DurationUnit::Day), Some(0));
+
+        assert_eq!(
+            time("10:59:59").difference(&time("11:00:00"), DurationUnit::Hour),
+            Some(1)
+        );
+        assert_eq!(
+            time("10:59:59").duration(&time("11:00:00"), DurationUnit::Second),
+            Some(1)
+        );
+        assert_eq!(
+            time("10:00").duration(&time("11:00"), DurationUnit::Day),
+            None
+        );
+
+        // Times wrap around midnight in either direction
+        assert_eq!(
+            time("01:00").add_duration(Decimal::from(-2), DurationUnit::Hour),
+            Ok(time("23:00"))
+        );
+        assert_eq!(
+            time("00:00:30").add_duration(Decimal::from(-1), DurationUnit::Minute),
+            Ok(time("23:59:30"))
+        );
+    }
+}
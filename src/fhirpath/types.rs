@@ -0,0 +1,282 @@
+use super::*;
+use crate::evaluation::EvaluationError;
+use crate::fhir::{self, Model, SYSTEM_TYPE_PREFIX};
+use std::sync::Arc;
+
+pub const SIMPLE_TYPE_INFO: Type = "System.SimpleTypeInfo";
+pub const CLASS_INFO: Type = "System.ClassInfo";
+pub const CLASS_INFO_ELEMENT: Type = "System.ClassInfoElement";
+pub const LIST_TYPE_INFO: Type = "System.ListTypeInfo";
+
+/// Reflection information about a type, as returned by `type()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TypeInfo {
+    Simple {
+        namespace: String,
+        name: String,
+        base_type: String,
+    },
+    Class {
+        namespace: String,
+        name: String,
+        base_type: String,
+        elements: Vec<ClassInfoElement>,
+    },
+    List {
+        element_type: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClassInfoElement {
+    pub name: String,
+    pub r#type: String,
+    pub is_one_based: bool,
+}
+
+impl TypeInfo {
+    /// Describes the type of a value.  System types and FHIR primitives are simple types, while
+    /// FHIR resources and complex data types are classes whose elements come from the snapshot.
+    pub fn of(value: &Value, model: &Model) -> TypeInfo {
+        if let Value::Any(inner) = value {
+            return TypeInfo::of(inner, model);
+        }
+        let (namespace, name) = split_type(value.data_type());
+        if namespace != "FHIR" {
+            return TypeInfo::Simple {
+                namespace: namespace.to_string(),
+                name: name.to_string(),
+                base_type: ANY.to_string(),
+            };
+        }
+
+        let base_type = model
+            .base_type(name)
+            .map_or(ANY.to_string(), |base| format!("FHIR.{}", base));
+        match model.structure_definition(name) {
+            Some(definition) if !model.is_primitive_type(name) => TypeInfo::Class {
+                namespace: namespace.to_string(),
+                name: name.to_string(),
+                base_type,
+                elements: definition
+                    .child_elements(&definition.r#type)
+                    .map(|element| ClassInfoElement {
+                        name: element.name().to_string(),
+                        r#type: element_type(element),
+                        is_one_based: false,
+                    })
+                    .collect(),
+            },
+            _ => TypeInfo::Simple {
+                namespace: namespace.to_string(),
+                name: name.to_string(),
+                base_type,
+            },
+        }
+    }
+
+    /// The type information as a FHIRPath value, so that expressions can navigate it
+    pub fn to_value(&self) -> Value {
+        let string = |name: &str, value: &str| {
+            (
+                name.to_string(),
+                Arc::new(DataNode::Value(Value::string(value))),
+            )
+        };
+        let node = match self {
+            TypeInfo::Simple {
+                namespace,
+                name,
+                base_type,
+            } => DataNode::Object(
+                SIMPLE_TYPE_INFO,
+                vec![
+                    string("namespace", namespace),
+                    string("name", name),
+                    string("baseType", base_type),
+                ],
+            ),
+            TypeInfo::Class {
+                namespace,
+                name,
+                base_type,
+                elements,
+            } => {
+                let mut members = vec![
+                    string("namespace", namespace),
+                    string("name", name),
+                    string("baseType", base_type),
+                ];
+                members.extend(elements.iter().map(|element| {
+                    let node = DataNode::Object(
+                        CLASS_INFO_ELEMENT,
+                        vec![
+                            string("name", &element.name),
+                            string("type", &element.r#type),
+                            (
+                                "isOneBased".to_string(),
+                                Arc::new(DataNode::Value(Value::boolean(element.is_one_based))),
+                            ),
+                        ],
+                    );
+                    ("element".to_string(), Arc::new(node))
+                }));
+                DataNode::Object(CLASS_INFO, members)
+            }
+            TypeInfo::List { element_type } => {
+                DataNode::Object(LIST_TYPE_INFO, vec![string("elementType", element_type)])
+            }
+        };
+        Value::Complex(Arc::new(node))
+    }
+}
+
+/// The type specifier for an element, as a `List<>` if it repeats
+fn element_type(element: &fhir::ElementDefinition) -> String {
+    let item_type = match element.r#type.as_slice() {
+        _ if element.content_reference.is_some() => "FHIR.BackboneElement".to_string(),
+        [t] => match t.code.strip_prefix(SYSTEM_TYPE_PREFIX) {
+            Some(system_type) => system_type.to_string(),
+            None => format!("FHIR.{}", t.code),
+        },
+        _ => ANY.to_string(),
+    };
+    if element.is_repeating() {
+        format!("List<{}>", item_type)
+    } else {
+        item_type
+    }
+}
+
+fn split_type(data_type: &str) -> (&str, &str) {
+    data_type.split_once('.').unwrap_or(("System", data_type))
+}
+
+impl Value {
+    /// Whether the value is of the given type or one derived from it.  Unqualified type names
+    /// match in either the FHIR or the System namespace, so `Quantity` matches both.
+    pub fn is_type(&self, specifier: &str, model: &Model) -> bool {
+        if let Value::Any(inner) = self {
+            return inner.is_type(specifier, model);
+        }
+        let (namespace, name) = match specifier.split_once('.') {
+            Some((namespace, name)) => (Some(namespace), name),
+            None => (None, specifier),
+        };
+        let (actual_namespace, actual_name) = split_type(self.data_type());
+        if namespace.is_some_and(|namespace| namespace != actual_namespace) {
+            return false;
+        }
+
+        match actual_namespace {
+            "FHIR" => model.is_subtype(actual_name, name),
+            _ => actual_name == name || name == "Any",
+        }
+    }
+}
+
+impl Collection {
+    /// Evaluates `ofType()`, keeping the items of the given type
+    pub fn of_type(&self, specifier: &str, model: &Model) -> Collection {
+        self.iter()
+            .filter(|value| value.is_type(specifier, model))
+            .cloned()
+            .collect()
+    }
+
+    /// Evaluates `is`, which requires a single item
+    pub fn is_type(&self, specifier: &str, model: &Model) -> Result<Collection, EvaluationError> {
+        match self.as_slice() {
+            [] => Ok(Collection::new()),
+            [value] => Ok(Collection::from(Value::Boolean(
+                value.is_type(specifier, model),
+            ))),
+            items => Err(EvaluationError::ExpectedSingleton {
+                expected: ANY,
+                count: items.len(),
+            }),
+        }
+    }
+
+    /// Evaluates `as`, which gives the item if it is of the type and is otherwise empty
+    pub fn as_type(&self, specifier: &str, model: &Model) -> Result<Collection, EvaluationError> {
+        match self.as_slice() {
+            [] => Ok(Collection::new()),
+            [_] => Ok(self.of_type(specifier, model)),
+            items => Err(EvaluationError::ExpectedSingleton {
+                expected: ANY,
+                count: items.len(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fhir::default_model;
+    use pretty_assertions::assert_eq;
+
+    fn node(data_type: Type) -> Value {
+        Value::Complex(Arc::new(DataNode::Object(data_type, vec![])))
+    }
+
+    #[test]
+    fn test_is_type() {
+        let model = default_model().unwrap();
+        assert!(Value::integer(1).is_type("Integer", model));
+        assert!(Value::integer(1).is_type("System.Integer", model));
+        assert!(!Value::integer(1).is_type("FHIR.Integer", model));
+        assert!(!Value::integer(1).is_type("Decimal", model));
+        assert!(Value::string("a").is_type("Any", model));
+        assert!(node("FHIR.Patient").is_type("Patient", model));
+        assert!(node("FHIR.Patient").is_type("FHIR.DomainResource", model));
+        assert!(node("FHIR.Patient").is_type("Resource", model));
+        assert!(!node("FHIR.Patient").is_type("System.Resource", model));
+        assert!(!node("FHIR.Patient").is_type("Observation", model));
+        assert!(node("FHIR.code").is_type("string", model));
+    }
+
+    #[test]
+    fn test_type_info() {
+        let model = default_model().unwrap();
+        assert_eq!(
+            TypeInfo::of(&Value::integer(1), model),
+            TypeInfo::Simple {
+                namespace: "System".to_string(),
+                name: "Integer".to_string(),
+                base_type: "System.Any".to_string(),
+            }
+        );
+        assert_eq!(
+            TypeInfo::of(&node("FHIR.code"), model),
+            TypeInfo::Simple {
+                namespace: "FHIR".to_string(),
+                name: "code".to_string(),
+                base_type: "FHIR.string".to_string(),
+            }
+        );
+
+        let TypeInfo::Class {
+            name,
+            base_type,
+            elements,
+            ..
+        } = TypeInfo::of(&node("FHIR.HumanName"), model)
+        else {
+            panic!("expected a ClassInfo");
+        };
+        assert_eq!(name, "HumanName");
+        assert_eq!(base_type, "FHIR.Element");
+        assert!(elements.contains(&ClassInfoElement {
+            name: "given".to_string(),
+            r#type: "List<FHIR.string>".to_string(),
+            is_one_based: false,
+        }));
+        assert!(elements.contains(&ClassInfoElement {
+            name: "id".to_string(),
+            r#type: "System.String".to_string(),
+            is_one_based: false,
+        }));
+    }
+}
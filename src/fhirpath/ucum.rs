@@ -0,0 +1,474 @@
+use super::*;
+use lazy_static::lazy_static;
+use rust_decimal::prelude::*;
+use std::collections::HashMap;
+
+/// Number of independent dimensions: the seven UCUM base units, international units (which
+/// have no defined relationship to anything else) and calendar months, which let the FHIRPath
+/// calendar duration keywords `year` and `month` convert between each other but not to the
+/// UCUM `'a'` and `'mo'`
+const DIMENSIONS: usize = 9;
+const LENGTH: usize = 0;
+const TIME: usize = 1;
+const MASS: usize = 2;
+const ANGLE: usize = 3;
+const TEMPERATURE: usize = 4;
+const CHARGE: usize = 5;
+const LUMINOSITY: usize = 6;
+const INTERNATIONAL_UNIT: usize = 7;
+const CALENDAR_MONTH: usize = 8;
+
+/// Conversions are rounded to fewer digits than a Decimal holds, so that factors which cannot be
+/// represented exactly (like the 5/9 between kelvin and degrees Fahrenheit) round trip
+const SIGNIFICANT_DIGITS: u32 = 24;
+
+/// A parsed UCUM unit, expressed as a multiple of a product of powers of the base units
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unit {
+    factor: Decimal,
+    dimensions: [i8; DIMENSIONS],
+    /// Added before scaling to the base unit, for units like `Cel` whose zero point differs
+    offset: Decimal,
+}
+
+impl Unit {
+    fn dimensionless(factor: Decimal) -> Self {
+        Unit {
+            factor,
+            dimensions: [0; DIMENSIONS],
+            offset: Decimal::ZERO,
+        }
+    }
+
+    fn base(dimension: usize) -> Self {
+        let mut unit = Unit::dimensionless(Decimal::ONE);
+        unit.dimensions[dimension] = 1;
+        unit
+    }
+
+    /// Parses a UCUM case-sensitive unit expression, such as `mg/dL`, `kg.m/s2` or `[lb_av]`
+    pub fn parse(code: &str) -> Result<Unit, ValueError> {
+        if code.is_empty() {
+            return Err(ValueError::InvalidUnit(code.to_string()));
+        }
+        parse_term(code).ok_or_else(|| ValueError::InvalidUnit(code.to_string()))
+    }
+
+    /// The unit of a calendar duration keyword, such as `year` or `days`
+    pub fn calendar_duration(keyword: &str) -> Option<Unit> {
+        let months = |n: i64| {
+            let mut unit = Unit::base(CALENDAR_MONTH);
+            unit.factor = Decimal::from(n);
+            unit
+        };
+        let ucum = match keyword {
+            "year" | "years" => return Some(months(12)),
+            "month" | "months" => return Some(months(1)),
+            "week" | "weeks" => "wk",
+            "day" | "days" => "d",
+            "hour" | "hours" => "h",
+            "minute" | "minutes" => "min",
+            "second" | "seconds" => "s",
+            "millisecond" | "milliseconds" => "ms",
+            _ => return None,
+        };
+        Unit::parse(ucum).ok()
+    }
+
+    /// Whether values in the two units can be converted between each other
+    pub fn is_commensurable(&self, other: &Unit) -> bool {
+        self.dimensions == other.dimensions
+    }
+
+    /// Converts a value in this unit to the equivalent value in the base units
+    pub fn to_base(&self, value: Decimal) -> Option<Decimal> {
+        value
+            .checked_add(self.offset)?
+            .checked_mul(self.factor)?
+            .round_sf(SIGNIFICANT_DIGITS)
+    }
+
+    /// Converts a value in the base units to the equivalent value in this unit
+    pub fn from_base(&self, value: Decimal) -> Option<Decimal> {
+        value
+            .checked_div(self.factor)?
+            .checked_sub(self.offset)?
+            .round_sf(SIGNIFICANT_DIGITS)
+    }
+
+    /// Converts a value in this unit to a commensurable unit
+    pub fn convert(&self, value: Decimal, to: &Unit) -> Option<Decimal> {
+        if !self.is_commensurable(to) {
+            return None;
+        }
+        to.from_base(self.to_base(value)?)
+    }
+
+    fn is_special(&self) -> bool {
+        !self.offset.is_zero()
+    }
+
+    fn multiply(&self, other: &Unit) -> Option<Unit> {
+        if self.is_special() || other.is_special() {
+            return None;
+        }
+
+        let mut dimensions = self.dimensions;
+        for (d, e) in dimensions.iter_mut().zip(other.dimensions.iter()) {
+            *d = d.checked_add(*e)?;
+        }
+        Some(Unit {
+            factor: self.factor.checked_mul(other.factor)?,
+            dimensions,
+            offset: Decimal::ZERO,
+        })
+    }
+
+    fn pow(&self, exponent: i32) -> Option<Unit> {
+        if exponent == 1 {
+            return Some(self.clone());
+        } else if self.is_special() {
+            return None;
+        }
+
+        // Dimensions are i8, so larger exponents cannot give a valid unit
+        let exponent = i8::try_from(exponent).ok()?;
+        let mut dimensions = self.dimensions;
+        for d in dimensions.iter_mut() {
+            *d = d.checked_mul(exponent)?;
+        }
+        Some(Unit {
+            factor: self.factor.checked_powi(exponent.into())?,
+            dimensions,
+            offset: Decimal::ZERO,
+        })
+    }
+}
+
+/// An entry in the unit table: the code, whether it accepts metric prefixes, and its definition
+/// as a multiple of other units
+type AtomDefinition = (&'static str, bool, &'static str, &'static str);
+
+const ATOMS: &[AtomDefinition] = &[
+    // Dimensionless
+    ("[pi]", false, "3.141592653589793238462643383", "1"),
+    ("%", false, "0.01", "1"),
+    ("[ppth]", false, "1e-3", "1"),
+    ("[ppm]", false, "1e-6", "1"),
+    ("[ppb]", false, "1e-9", "1"),
+    ("mol", true, "6.0221367e23", "1"),
+    // SI derived units
+    ("sr", true, "1", "rad2"),
+    ("Hz", true, "1", "s-1"),
+    ("N", true, "1", "kg.m/s2"),
+    ("Pa", true, "1", "N/m2"),
+    ("J", true, "1", "N.m"),
+    ("W", true, "1", "J/s"),
+    ("A", true, "1", "C/s"),
+    ("V", true, "1", "J/C"),
+    ("F", true, "1", "C/V"),
+    ("Ohm", true, "1", "V/A"),
+    ("S", true, "1", "Ohm-1"),
+    ("Wb", true, "1", "V.s"),
+    ("T", true, "1", "Wb/m2"),
+    ("H", true, "1", "Wb/A"),
+    ("lm", true, "1", "cd.sr"),
+    ("lx", true, "1", "lm/m2"),
+    ("Bq", true, "1", "s-1"),
+    ("Gy", true, "1", "J/kg"),
+    ("Sv", true, "1", "J/kg"),
+    // Other metric units
+    ("deg", false, "2", "[pi].rad/360"),
+    ("l", true, "1", "dm3"),
+    ("L", true, "1", "l"),
+    ("ar", true, "100", "m2"),
+    ("t", true, "1e3", "kg"),
+    ("bar", true, "1e5", "Pa"),
+    ("eV", true, "1.60217733e-19", "J"),
+    ("[g]", false, "9.80665", "m/s2"),
+    // Time
+    ("min", false, "60", "s"),
+    ("h", false, "60", "min"),
+    ("d", false, "24", "h"),
+    ("wk", false, "7", "d"),
+    ("a_t", false, "365.24219", "d"),
+    ("a_j", false, "365.25", "d"),
+    ("a_g", false, "365.2425", "d"),
+    ("a", false, "1", "a_j"),
+    ("mo_j", false, "1", "a_j/12"),
+    ("mo_g", false, "1", "a_g/12"),
+    ("mo", false, "1", "mo_j"),
+    // Chemistry and clinical units
+    ("eq", true, "1", "mol"),
+    ("osm", true, "1", "mol"),
+    ("g%", true, "1", "g/dL"),
+    ("kat", true, "1", "mol/s"),
+    ("U", true, "1", "umol/min"),
+    ("[IU]", true, "1", "[iU]"),
+    ("m[Hg]", true, "133.3220", "kPa"),
+    ("m[H2O]", true, "9.80665", "kPa"),
+    ("cal", true, "4.184", "J"),
+    ("[Cal]", false, "1", "kcal"),
+    ("[drp]", false, "1", "mL/20"),
+    // US customary and international units
+    ("[in_i]", false, "2.54", "cm"),
+    ("[ft_i]", false, "12", "[in_i]"),
+    ("[yd_i]", false, "3", "[ft_i]"),
+    ("[mi_i]", false, "5280", "[ft_i]"),
+    ("[gr]", false, "64.79891", "mg"),
+    ("[lb_av]", false, "7000", "[gr]"),
+    ("[oz_av]", false, "1", "[lb_av]/16"),
+    ("[stone_av]", false, "14", "[lb_av]"),
+    ("[lbf_av]", false, "1", "[lb_av].[g]"),
+    ("[psi]", false, "1", "[lbf_av]/[in_i]2"),
+    ("[gal_us]", false, "231", "[in_i]3"),
+    ("[qt_us]", false, "1", "[gal_us]/4"),
+    ("[pt_us]", false, "1", "[qt_us]/2"),
+    ("[foz_us]", false, "1", "[pt_us]/16"),
+    ("[cup_us]", false, "8", "[foz_us]"),
+    ("[tbs_us]", false, "1", "[foz_us]/2"),
+    ("[tsp_us]", false, "1", "[tbs_us]/3"),
+];
+
+const PREFIXES: &[(&str, &str)] = &[
+    ("Y", "1e24"),
+    ("Z", "1e21"),
+    ("E", "1e18"),
+    ("P", "1e15"),
+    ("T", "1e12"),
+    ("G", "1e9"),
+    ("M", "1e6"),
+    ("k", "1e3"),
+    ("h", "1e2"),
+    ("da", "1e1"),
+    ("d", "1e-1"),
+    ("c", "1e-2"),
+    ("m", "1e-3"),
+    ("u", "1e-6"),
+    ("n", "1e-9"),
+    ("p", "1e-12"),
+    ("f", "1e-15"),
+    ("a", "1e-18"),
+    ("z", "1e-21"),
+    ("y", "1e-24"),
+    ("Ki", "1024"),
+    ("Mi", "1048576"),
+    ("Gi", "1073741824"),
+    ("Ti", "1099511627776"),
+];
+
+fn decimal(s: &str) -> Decimal {
+    Decimal::from_str(s)
+        .or_else(|_| Decimal::from_scientific(s))
+        .unwrap()
+}
+
+lazy_static! {
+    static ref UNITS: HashMap<&'static str, (Unit, bool)> = {
+        let mut units = HashMap::from([
+            ("m", (Unit::base(LENGTH), true)),
+            ("s", (Unit::base(TIME), true)),
+            ("g", (Unit::base(MASS), true)),
+            ("rad", (Unit::base(ANGLE), true)),
+            ("K", (Unit::base(TEMPERATURE), true)),
+            ("C", (Unit::base(CHARGE), true)),
+            ("cd", (Unit::base(LUMINOSITY), true)),
+            ("[iU]", (Unit::base(INTERNATIONAL_UNIT), true)),
+            (
+                "Cel",
+                (
+                    Unit {
+                        offset: decimal("273.15"),
+                        ..Unit::base(TEMPERATURE)
+                    },
+                    true,
+                ),
+            ),
+            (
+                "[degF]",
+                (
+                    Unit {
+                        factor: Decimal::from(5) / Decimal::from(9),
+                        offset: decimal("459.67"),
+                        ..Unit::base(TEMPERATURE)
+                    },
+                    false,
+                ),
+            ),
+        ]);
+
+        // Each definition only refers to units defined before it
+        for (code, metric, magnitude, definition) in ATOMS {
+            let unit = parse_term_with(definition, &units)
+                .and_then(|unit| unit.multiply(&Unit::dimensionless(decimal(magnitude))))
+                .unwrap_or_else(|| panic!("invalid definition for unit {}", code));
+            units.insert(code, (unit, *metric));
+        }
+        units
+    };
+    static ref PREFIX_FACTORS: Vec<(&'static str, Decimal)> = PREFIXES
+        .iter()
+        .map(|(prefix, factor)| (*prefix, decimal(factor)))
+        .collect();
+}
+
+fn parse_term(code: &str) -> Option<Unit> {
+    parse_term_with(code, &UNITS)
+}
+
+/// Parses a sequence of components joined by `.` (multiplication) or `/` (division), which may
+/// start with a `/`
+fn parse_term_with(code: &str, units: &HashMap<&str, (Unit, bool)>) -> Option<Unit> {
+    let mut unit = Unit::dimensionless(Decimal::ONE);
+    let (mut divide, mut rest) = match code.strip_prefix('/') {
+        Some(rest) => (true, rest),
+        None => (false, code),
+    };
+
+    let mut components = 0;
+    loop {
+        let (component, separator) = split_component(rest)?;
+        let mut component_unit = parse_component(component, units)?;
+        if divide {
+            component_unit = component_unit.pow(-1)?;
+        }
+        unit = if components == 0 && !divide {
+            component_unit
+        } else {
+            unit.multiply(&component_unit)?
+        };
+        components += 1;
+
+        match separator {
+            Some(('.', remainder)) => (divide, rest) = (false, remainder),
+            Some((_, remainder)) => (divide, rest) = (true, remainder),
+            None => return Some(unit),
+        }
+    }
+}
+
+/// Splits off the first component, returning it with the following separator and remainder
+fn split_component(code: &str) -> Option<(&str, Option<(char, &str)>)> {
+    let mut depth = 0;
+    for (i, c) in code.char_indices() {
+        match c {
+            '(' | '[' | '{' => depth += 1,
+            ')' | ']' | '}' => depth -= 1,
+            '.' | '/' if depth == 0 => {
+                return Some((&code[..i], Some((c, &code[i + 1..]))));
+            }
+            _ => {}
+        }
+    }
+    (depth == 0).then_some((code, None))
+}
+
+/// Parses a single component: a parenthesized term, an integer factor, a power of ten like
+/// `10*3`, or a possibly prefixed unit atom with an optional exponent
+fn parse_component(component: &str, units: &HashMap<&str, (Unit, bool)>) -> Option<Unit> {
+    if let Some(inner) = component
+        .strip_prefix('(')
+        .and_then(|c| c.strip_suffix(')'))
+    {
+        return parse_term_with(inner, units);
+    }
+
+    // Annotations like `{cells}` carry no meaning
+    let component = match component.find('{') {
+        Some(start) if component.ends_with('}') => &component[..start],
+        Some(_) => return None,
+        None => component,
+    };
+    if component.is_empty() {
+        return Some(Unit::dimensionless(Decimal::ONE));
+    } else if component.chars().all(|c| c.is_ascii_digit()) {
+        return Some(Unit::dimensionless(Decimal::from_str(component).ok()?));
+    } else if let Some(exponent) = component
+        .strip_prefix("10*")
+        .or_else(|| component.strip_prefix("10^"))
+    {
+        return Unit::dimensionless(Decimal::TEN).pow(exponent.parse().ok()?);
+    }
+
+    let (atom, exponent) = split_exponent(component)?;
+    let unit = if let Some((unit, _)) = units.get(atom) {
+        unit.clone()
+    } else {
+        PREFIX_FACTORS.iter().find_map(|(prefix, factor)| {
+            let (unit, metric) = units.get(atom.strip_prefix(prefix)?)?;
+            if !metric || unit.is_special() {
+                return None;
+            }
+            unit.multiply(&Unit::dimensionless(*factor))
+        })?
+    };
+    unit.pow(exponent)
+}
+
+/// Separates a trailing signed integer exponent from a unit atom
+fn split_exponent(component: &str) -> Option<(&str, i32)> {
+    let atom_end = component.rfind(']').map_or(0, |i| i + 1);
+    let digits_start = component[atom_end..]
+        .find(|c: char| c.is_ascii_digit() || c == '-' || c == '+')
+        .map_or(component.len(), |i| atom_end + i);
+    if digits_start == 0 {
+        return None;
+    } else if digits_start == component.len() {
+        return Some((component, 1));
+    }
+
+    let exponent = component[digits_start..].parse().ok()?;
+    Some((&component[..digits_start], exponent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn convert(value: &str, from: &str, to: &str) -> Option<Decimal> {
+        Unit::parse(from)
+            .unwrap()
+            .convert(decimal(value), &Unit::parse(to).unwrap())
+            .map(|d| d.normalize())
+    }
+
+    #[test]
+    fn test_conversion() {
+        assert_eq!(convert("1", "g", "mg"), Some(decimal("1000")));
+        assert_eq!(convert("1", "[lb_av]", "kg"), Some(decimal("0.45359237")));
+        assert_eq!(convert("12", "[in_i]", "[ft_i]"), Some(decimal("1")));
+        assert_eq!(convert("100", "mg/dL", "g/L"), Some(decimal("1")));
+        assert_eq!(convert("1", "mmol/L", "umol/mL"), Some(decimal("1")));
+        assert_eq!(convert("120", "mm[Hg]", "kPa"), Some(decimal("15.998640")));
+        assert_eq!(convert("1", "kg.m/s2", "N"), Some(decimal("1")));
+        assert_eq!(convert("2", "h", "min"), Some(decimal("120")));
+        assert_eq!(convert("1", "m2", "cm2"), Some(decimal("10000")));
+        assert_eq!(convert("1", "10*3/uL", "/mL"), Some(decimal("1000000")));
+        assert_eq!(convert("37", "Cel", "K"), Some(decimal("310.15")));
+        assert_eq!(convert("212", "[degF]", "Cel"), Some(decimal("100")));
+        assert_eq!(convert("5", "{cells}/uL", "/uL"), Some(decimal("5")));
+        assert_eq!(convert("1", "mg/dL", "mmol/L"), None);
+        assert_eq!(convert("1", "[iU]/L", "[IU]/mL"), Some(decimal("0.001")));
+    }
+
+    #[test]
+    fn test_invalid_units() {
+        for code in [
+            "",
+            "foo",
+            "m^",
+            "kg/(m",
+            "[lb_av",
+            "k[lb_av]",
+            "mCel",
+            "Cel2",
+            "mg{",
+            "m2000000000",
+            "m-2000000000",
+            "10*2000000000",
+        ] {
+            assert!(Unit::parse(code).is_err(), "{}", code);
+        }
+    }
+}
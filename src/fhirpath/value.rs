@@ -1,4 +1,6 @@
+use std::cmp::Ordering;
 use std::fmt::Display;
+use std::sync::Arc;
 
 use super::*;
 use rust_decimal::prelude::*;
@@ -10,13 +12,14 @@ pub enum Value {
     Boolean(bool),
     String(String),
     Integer(i32),
+    Long(i64),
     Decimal(Decimal),
-    Date(chrono::NaiveDate),
-    Time(chrono::NaiveTime),
-    DateTime(chrono::DateTime<chrono::FixedOffset>),
+    Date(FhirDate),
+    Time(FhirTime),
+    DateTime(FhirDateTime),
     Quantity(Quantity),
 
-    Complex(Box<DataNode>),
+    Complex(Arc<DataNode>),
 
     Any(Box<Value>),
 }
@@ -34,6 +37,10 @@ impl Value {
         Value::Integer(value)
     }
 
+    pub fn long(value: i64) -> Self {
+        Value::Long(value)
+    }
+
     /// Returns the Decimal n * 10^-exp
     pub fn decimal(n: i64, exp: u32) -> Self {
         let d = Decimal::new(n, exp);
@@ -44,6 +51,7 @@ impl Value {
 pub const BOOLEAN: Type = "System.Boolean";
 pub const STRING: Type = "System.String";
 pub const INTEGER: Type = "System.Integer";
+pub const LONG: Type = "System.Long";
 pub const DECIMAL: Type = "System.Decimal";
 pub const DATE: Type = "System.Date";
 pub const TIME: Type = "System.Time";
@@ -51,17 +59,26 @@ pub const DATETIME: Type = "System.DateTime";
 pub const QUANTITY: Type = "System.Quantity";
 pub const ANY: Type = "System.Any";
 
-#[derive(PartialEq, Debug, Clone)]
-pub struct Quantity {
-    value: Decimal,
-    unit: String,
-}
-
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            Value::Boolean(b) => write!(f, "{}", b),
             Value::String(str) => f.write_fmt(format_args!("'{}'", str)),
-            _ => todo!(),
+            Value::Integer(n) => write!(f, "{}", n),
+            Value::Long(n) => write!(f, "{}L", n),
+            Value::Decimal(d) => write!(f, "{}", d),
+            Value::Date(date) => write!(f, "@{}", date),
+            Value::Time(time) => write!(f, "@T{}", time),
+            Value::DateTime(datetime) if datetime.precision() < Precision::Hour => {
+                write!(f, "@{}T", datetime)
+            }
+            Value::DateTime(datetime) => write!(f, "@{}", datetime),
+            Value::Quantity(quantity) => write!(f, "{}", quantity),
+            Value::Complex(node) => match node.primitive_value() {
+                Some(value) => write!(f, "{}", value),
+                None => write!(f, "{}", node.data_type()),
+            },
+            Value::Any(value) => write!(f, "{}", value),
         }
     }
 }
@@ -72,6 +89,7 @@ impl Value {
             Self::Boolean(_) => BOOLEAN,
             Self::String(_) => STRING,
             Self::Integer(_) => INTEGER,
+            Self::Long(_) => LONG,
             Self::Decimal(_) => DECIMAL,
             Self::Date(_) => DATE,
             Self::Time(_) => TIME,
@@ -84,19 +102,246 @@ impl Value {
         }
     }
 
+    /// Compares two values according to the FHIRPath equality (`=`) rules, returning `None`
+    /// when the result is empty (e.g. dates of differing precision)
     pub fn equal(&self, other: &Value) -> Option<bool> {
-        if self.data_type() != other.data_type() {
-            return Some(false);
+        match (self, other) {
+            (Value::Any(v1), _) => v1.equal(other),
+            (_, Value::Any(v2)) => self.equal(v2),
+            (Value::Complex(_), _) | (_, Value::Complex(_))
+                if self.is_fhir_primitive() || other.is_fhir_primitive() =>
+            {
+                self.system_value().equal(other.system_value())
+            }
+            (Value::Boolean(b1), Value::Boolean(b2)) => Some(b1 == b2),
+            (Value::String(s1), Value::String(s2)) => Some(s1 == s2),
+            (Value::Integer(_) | Value::Long(_), Value::Integer(_) | Value::Long(_)) => {
+                Some(self.as_long() == other.as_long())
+            }
+            (
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+                Value::Integer(_) | Value::Long(_) | Value::Decimal(_),
+            ) => Some(self.as_decimal() == other.as_decimal()),
+            (
+                Value::Date(_) | Value::DateTime(_) | Value::Time(_),
+                Value::Date(_) | Value::DateTime(_) | Value::Time(_),
+            ) => {
+                if !self.is_comparable_to(other) {
+                    return Some(false);
+                }
+                self.compare(other)
+                    .map(|ordering| ordering == Ordering::Equal)
+            }
+            (Value::Quantity(q1), Value::Quantity(q2)) => q1.equal(q2),
+            (Value::Quantity(_), Value::Complex(_)) | (Value::Complex(_), Value::Quantity(_)) => {
+                match (self.to_quantity(), other.to_quantity()) {
+                    (Some(q1), Some(q2)) => q1.equal(&q2),
+                    _ => Some(false),
+                }
+            }
+            (Value::Complex(n1), Value::Complex(n2)) => n1.equal(n2),
+            _ => Some(false),
         }
+    }
 
-        match self {
-            Value::String(str) => {
-                if let Value::String(str2) = other {
-                    return Some(str == str2);
+    /// Compares two values according to the FHIRPath equivalence (`~`) rules
+    pub fn equivalent(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Any(v1), _) => v1.equivalent(other),
+            (_, Value::Any(v2)) => self.equivalent(v2),
+            (Value::Complex(_), _) | (_, Value::Complex(_))
+                if self.is_fhir_primitive() || other.is_fhir_primitive() =>
+            {
+                self.system_value().equivalent(other.system_value())
+            }
+            (Value::String(s1), Value::String(s2)) => {
+                normalize_whitespace(s1).to_lowercase() == normalize_whitespace(s2).to_lowercase()
+            }
+            (Value::Integer(_) | Value::Long(_) | Value::Decimal(_), Value::Decimal(_))
+            | (Value::Decimal(_), Value::Integer(_) | Value::Long(_)) => {
+                match (self.as_decimal(), other.as_decimal()) {
+                    (Some(d1), Some(d2)) => decimal_equivalent(d1, d2),
+                    _ => false,
+                }
+            }
+            (
+                Value::Date(_) | Value::DateTime(_) | Value::Time(_),
+                Value::Date(_) | Value::DateTime(_) | Value::Time(_),
+            ) => self.compare(other) == Some(Ordering::Equal),
+            (Value::Quantity(q1), Value::Quantity(q2)) => q1.equivalent(q2),
+            (Value::Quantity(_), Value::Complex(_)) | (Value::Complex(_), Value::Quantity(_)) => {
+                match (self.to_quantity(), other.to_quantity()) {
+                    (Some(q1), Some(q2)) => q1.equivalent(&q2),
+                    _ => false,
                 }
-                return Some(false);
             }
-            _ => todo!(),
+            (Value::Complex(n1), Value::Complex(n2)) => n1.equivalent(n2),
+            _ => self.equal(other) == Some(true),
+        }
+    }
+
+    /// Whether the value is a FHIR primitive element with a value, which operators treat as that
+    /// System value
+    pub fn is_fhir_primitive(&self) -> bool {
+        match self {
+            Value::Complex(node) => node.primitive_value().is_some(),
+            _ => false,
         }
     }
+
+    /// The value to use in operators: the System value of FHIR primitive elements, and the
+    /// value itself otherwise
+    pub fn system_value(&self) -> &Value {
+        match self {
+            Value::Complex(node) => node.primitive_value().unwrap_or(self),
+            Value::Any(value) => value.system_value(),
+            _ => self,
+        }
+    }
+
+    /// Integers and Longs as an i64, the type both are promoted to when mixed
+    pub fn as_long(&self) -> Option<i64> {
+        match self {
+            Value::Integer(n) => Some(i64::from(*n)),
+            Value::Long(n) => Some(*n),
+            Value::Any(_) | Value::Complex(_) if !std::ptr::eq(self.system_value(), self) => {
+                self.system_value().as_long()
+            }
+            _ => None,
+        }
+    }
+
+    /// Any numeric value as a Decimal, the type all numbers are promoted to when mixed
+    pub fn as_decimal(&self) -> Option<Decimal> {
+        match self {
+            Value::Integer(n) => Some(Decimal::from(*n)),
+            Value::Long(n) => Some(Decimal::from(*n)),
+            Value::Decimal(d) => Some(*d),
+            Value::Any(_) | Value::Complex(_) if !std::ptr::eq(self.system_value(), self) => {
+                self.system_value().as_decimal()
+            }
+            _ => None,
+        }
+    }
+
+    /// The value as a System.Quantity, implicitly converting FHIR Quantity elements
+    pub fn to_quantity(&self) -> Option<Quantity> {
+        match self {
+            Value::Quantity(quantity) => Some(quantity.clone()),
+            Value::Complex(node) => Quantity::from_fhir(node),
+            Value::Any(value) => value.to_quantity(),
+            _ => None,
+        }
+    }
+}
+
+/// Collapses all runs of whitespace into a single space, trimming both ends
+fn normalize_whitespace(s: &str) -> String {
+    s.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Decimals are equivalent when they are equal after rounding to the precision of the less
+/// precise value; trailing zeroes do not count towards precision
+pub(super) fn decimal_equivalent(d1: Decimal, d2: Decimal) -> bool {
+    let (d1, d2) = (d1.normalize(), d2.normalize());
+    let scale = d1.scale().min(d2.scale());
+    let round =
+        |d: Decimal| d.round_dp_with_strategy(scale, RoundingStrategy::MidpointAwayFromZero);
+    round(d1) == round(d2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_equality() {
+        assert_eq!(Value::long(5).equal(&Value::integer(5)), Some(true));
+        assert_eq!(
+            Value::long(5_000_000_000).equal(&Value::integer(705_032_704)),
+            Some(false)
+        );
+        assert_eq!(Value::long(5).equal(&Value::decimal(50, 1)), Some(true));
+        assert!(Value::decimal(50, 1).equivalent(&Value::long(5)));
+        assert_eq!(Value::long(5).to_string(), "5L");
+    }
+
+    #[test]
+    fn test_temporal_equality() {
+        let date = |s: &str| Value::Date(s.parse().unwrap());
+        let datetime = |s: &str| Value::DateTime(s.parse().unwrap());
+        let time = |s: &str| Value::Time(s.parse().unwrap());
+
+        assert_eq!(date("2020-06-01").equal(&date("2020-06-01")), Some(true));
+        assert_eq!(date("2020").equal(&date("2020-06-01")), None);
+        assert_eq!(date("2019").equal(&date("2020-06-01")), Some(false));
+        assert!(!date("2020").equivalent(&date("2020-06-01")));
+        assert_eq!(
+            date("2020-06-01").equal(&datetime("2020-06-01T12:00")),
+            None
+        );
+        assert_eq!(
+            date("2020-06-02").equal(&datetime("2020-06-01T12:00")),
+            Some(false)
+        );
+        assert!(!date("2020-06-01").equivalent(&datetime("2020-06-01T12:00")));
+        assert!(date("2020-06-01").equivalent(&datetime("2020-06-01T")));
+        assert_eq!(
+            datetime("2020-06-01T12:00:00-05:00").equal(&datetime("2020-06-01T17:00:00Z")),
+            Some(true)
+        );
+        assert_eq!(time("10:30").equal(&time("10:30")), Some(true));
+        assert_eq!(time("10:30").equal(&date("2020")), Some(false));
+        assert!(time("10:30:00").equivalent(&time("10:30:00.000")));
+    }
+
+    #[test]
+    fn test_temporal_display() {
+        for literal in [
+            "@2020",
+            "@2020-05-01T",
+            "@2020-05-01T10:30:00.125Z",
+            "@T10:30",
+        ] {
+            let value = crate::Expression::new(literal)
+                .unwrap()
+                .evaluate()
+                .unwrap()
+                .remove(0);
+            assert_eq!(value.to_string(), literal);
+        }
+    }
+
+    #[test]
+    fn test_complex_equality() {
+        let node = |given: &str, family: &str| {
+            Value::Complex(Arc::new(DataNode::Object(
+                "FHIR.HumanName",
+                vec![
+                    (
+                        "given".to_string(),
+                        Arc::new(DataNode::Value(Value::string(given))),
+                    ),
+                    (
+                        "family".to_string(),
+                        Arc::new(DataNode::Value(Value::string(family))),
+                    ),
+                ],
+            )))
+        };
+
+        assert_eq!(
+            node("Jim", "Smith").equal(&node("Jim", "Smith")),
+            Some(true)
+        );
+        assert_eq!(
+            node("Jim", "Smith").equal(&node("Smith", "Jim")),
+            Some(false)
+        );
+        assert_eq!(
+            node("jim", "smith").equal(&node("Jim", "Smith")),
+            Some(false)
+        );
+        assert!(node("jim ", "smith").equivalent(&node("Jim", "SMITH")));
+    }
 }
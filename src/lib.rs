@@ -1,6 +1,14 @@
-use evaluation::{EvaluationError, Visitor};
-use fhirpath::Collection;
+use evaluation::{
+    optimize, EvaluationContext, EvaluationError, Plan, StaticType, TypeCheckError, TypeChecker,
+    Visitor,
+};
+use fhir::Model;
+use fhirpath::{Collection, DataNode};
 use parser::{ASTNode, Lexer, Parser, ParserError};
+use std::sync::Arc;
+
+mod batch;
+mod cache;
 
 pub mod evaluation;
 pub mod fhir;
@@ -8,8 +16,13 @@ pub mod fhirpath;
 pub mod parser;
 
 mod node_addon;
+// Re-exports whatever the addon makes public, which is nothing beyond its Node.js module
+#[allow(unused_imports)]
 pub use node_addon::*;
 
+pub use batch::*;
+pub use cache::*;
+
 pub struct Expression {
     _raw: String,
     ast: ASTNode,
@@ -17,8 +30,8 @@ pub struct Expression {
 
 impl Expression {
     pub fn new(str: &str) -> Result<Expression, ParserError> {
-        let tokens = Lexer::new(str).tokenize()?;
-        let ast = Parser::new(tokens).parse()?;
+        let tokens = Lexer::new(str).tokenize_with_spans()?;
+        let ast = Parser::with_spans(tokens).parse()?;
 
         Ok(Expression {
             _raw: str.to_string(),
@@ -27,14 +40,113 @@ impl Expression {
     }
 
     pub fn evaluate(&self) -> Result<Collection, EvaluationError> {
-        let mut visitor = Visitor::new();
-        Ok(visitor.visit_node(&self.ast)?)
+        self.evaluate_with(&EvaluationContext::default())
+    }
+
+    pub fn evaluate_with(
+        &self,
+        context: &EvaluationContext,
+    ) -> Result<Collection, EvaluationError> {
+        let mut visitor = Visitor::new(context);
+        visitor.visit_node(&self.ast)
+    }
+
+    /// Evaluates the expression against a resource, e.g. one read by `fhir::load_resource`.  The
+    /// resource is shared with the results rather than copied.
+    pub fn evaluate_resource(
+        &self,
+        resource: &Arc<DataNode>,
+    ) -> Result<Collection, EvaluationError> {
+        self.evaluate_resource_with(resource, &EvaluationContext::default())
+    }
+
+    pub fn evaluate_resource_with(
+        &self,
+        resource: &Arc<DataNode>,
+        context: &EvaluationContext,
+    ) -> Result<Collection, EvaluationError> {
+        let input = Collection::from(resource.to_value());
+        let mut visitor = Visitor::new(context).with_input(input);
+        visitor.visit_node(&self.ast)
+    }
+
+    /// Infers the type of the expression when evaluated against a resource or data type, or an
+    /// element given by its path (e.g. `Patient.contact`), without evaluating it
+    pub fn check(&self, root: &str) -> Result<StaticType, Vec<TypeCheckError>> {
+        let model =
+            fhir::default_model().map_err(|err| vec![TypeCheckError::NoModel(err.to_string())])?;
+        self.check_with(root, model)
+    }
+
+    pub fn check_with(
+        &self,
+        root: &str,
+        model: &'static Model,
+    ) -> Result<StaticType, Vec<TypeCheckError>> {
+        TypeChecker::for_model(model, root)
+            .map_err(|err| vec![err])?
+            .check(&self.ast)
+    }
+
+    /// Rewrites the expression into one that gives the same result with less work, e.g. by
+    /// evaluating operators on literals ahead of time; see `evaluation::optimize`
+    pub fn optimize(self) -> Expression {
+        Expression {
+            ast: optimize(&self.ast),
+            _raw: self._raw,
+        }
+    }
+
+    /// Compiles the expression into a plan that can be evaluated repeatedly, and shared between
+    /// threads, without walking the AST each time.  Functions are looked up and literals parsed
+    /// here, so errors in either are reported by `compile()` rather than on evaluation.
+    pub fn compile(&self) -> Result<CompiledExpression, EvaluationError> {
+        Ok(CompiledExpression {
+            plan: Plan::compile(&self.ast)?,
+        })
+    }
+}
+
+/// An expression compiled by `Expression::compile()`
+pub struct CompiledExpression {
+    plan: Plan,
+}
+
+impl CompiledExpression {
+    pub fn evaluate(&self) -> Result<Collection, EvaluationError> {
+        self.evaluate_with(&EvaluationContext::default())
+    }
+
+    pub fn evaluate_with(
+        &self,
+        context: &EvaluationContext,
+    ) -> Result<Collection, EvaluationError> {
+        let mut visitor = Visitor::new(context);
+        self.plan.evaluate(&mut visitor)
+    }
+
+    pub fn evaluate_resource(
+        &self,
+        resource: &Arc<DataNode>,
+    ) -> Result<Collection, EvaluationError> {
+        self.evaluate_resource_with(resource, &EvaluationContext::default())
+    }
+
+    pub fn evaluate_resource_with(
+        &self,
+        resource: &Arc<DataNode>,
+        context: &EvaluationContext,
+    ) -> Result<Collection, EvaluationError> {
+        let input = Collection::from(resource.to_value());
+        let mut visitor = Visitor::new(context).with_input(input);
+        self.plan.evaluate(&mut visitor)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::evaluation::FixedClock;
     use crate::fhirpath::Value;
     use itertools::*;
     use pretty_assertions::assert_eq;
@@ -70,6 +182,21 @@ mod tests {
                 expr: "0.00729735257",
                 expected: Collection::from(Value::decimal(729735257, 11)),
             },
+            // Date, DateTime and Time
+            TestCase {
+                expr: "@2020-05",
+                expected: Collection::from(Value::Date("2020-05".parse().unwrap())),
+            },
+            TestCase {
+                expr: "@2020-05-01T10:30:00.125-05:00",
+                expected: Collection::from(Value::DateTime(
+                    "2020-05-01T10:30:00.125-05:00".parse().unwrap(),
+                )),
+            },
+            TestCase {
+                expr: "@T10:30",
+                expected: Collection::from(Value::Time("10:30".parse().unwrap())),
+            },
         ];
 
         for case in cases {
@@ -98,4 +225,1331 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_collection_functions() -> Result<(), EvaluationError> {
+        let strings = |items: &[&str]| items.iter().map(|s| Value::string(*s)).collect();
+        let integers = |items: &[i32]| items.iter().map(|n| Value::integer(*n)).collect();
+        let boolean = |b: bool| Collection::from(Value::boolean(b));
+        let cases: Vec<(&str, Collection)> = vec![
+            ("(1 | 2).where($this > 2).empty()", boolean(true)),
+            ("(1 | 2).exists()", boolean(true)),
+            ("(1 | 2 | 3).exists($this > 2)", boolean(true)),
+            (
+                "(1 | 2 | 2 | 3).count()",
+                Collection::from(Value::integer(3)),
+            ),
+            ("(1 | 2) | (2 | 3)", integers(&[1, 2, 3])),
+            ("(1 | 2 | 3).isDistinct()", boolean(true)),
+            ("(1 | 2 | 3).intersect(2 | 3 | 4)", integers(&[2, 3])),
+            // Items already found are not projected again, so the cycle ends
+            ("(1 | 2).repeat($this)", integers(&[1, 2])),
+            ("true.not()", boolean(false)),
+            ("2 in (1 | 2)", boolean(true)),
+            ("(1 | 2) contains 3", boolean(false)),
+            ("(1 | 2).where($this > 2) in (1 | 2)", Collection::new()),
+            ("'abc'.startsWith('ab')", boolean(true)),
+            ("'abc'.contains('d')", boolean(false)),
+            ("'abc'.matches('^[a-c]+$')", boolean(true)),
+            ("'abcdef'.substring(2, 3)", strings(&["cde"])),
+            (
+                "12.toString() + '3'.toInteger().toString()",
+                strings(&["123"]),
+            ),
+        ];
+
+        for (expr, expected) in cases {
+            let result = Expression::new(expr).unwrap().evaluate()?;
+            assert_eq!(result, expected, "{}", expr);
+        }
+
+        let patient = crate::fhir::load_resource(&serde_json::json!({
+            "resourceType": "Patient",
+            "name": [{ "family": "Chalmers", "given": ["Peter", "James"] }]
+        }))
+        .unwrap();
+        let cases = vec![
+            (
+                "(name.given | name.given | name.family).count()",
+                Collection::from(Value::integer(3)),
+            ),
+            (
+                "name.children().count()",
+                Collection::from(Value::integer(3)),
+            ),
+            (
+                "repeat(name | given).count()",
+                Collection::from(Value::integer(3)),
+            ),
+            (
+                "descendants().ofType(string).count()",
+                Collection::from(Value::integer(3)),
+            ),
+            ("'James' in name.given", boolean(true)),
+            // Unlike the operator, the function filters collections of more than one item
+            (
+                "descendants().as(string).count()",
+                Collection::from(Value::integer(3)),
+            ),
+        ];
+        for (expr, expected) in cases {
+            let result = Expression::new(expr).unwrap().evaluate_resource(&patient)?;
+            assert_eq!(result, expected, "{}", expr);
+        }
+        assert!(Expression::new("name.`given`.toString()")
+            .unwrap()
+            .evaluate_resource(&patient)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_equality() -> Result<(), EvaluationError> {
+        let cases = vec![
+            ("'abc' = 'abc'", Some(true)),
+            ("'abc' = 'ABC'", Some(false)),
+            ("'abc' != 'abd'", Some(true)),
+            ("1 = 1.0", Some(true)),
+            ("1.50 = 1.5", Some(true)),
+            ("true = false", Some(false)),
+            ("true != false", Some(true)),
+            ("1 = 'a'", Some(false)),
+            ("'  Hello   World ' ~ 'hello world'", Some(true)),
+            ("'hello' !~ 'HELLO'", Some(false)),
+            ("1.2 ~ 1.24", Some(true)),
+            ("1.2 ~ 1.26", Some(false)),
+            ("1 ~ 1.0", Some(true)),
+        ];
+
+        for (expr, expected) in cases {
+            let result = Expression::new(expr).unwrap().evaluate()?;
+            let expected = expected
+                .map(|b| Collection::from(Value::boolean(b)))
+                .unwrap_or_default();
+            assert_eq!(result, expected, "{}", expr);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_comparison() -> Result<(), EvaluationError> {
+        let cases = vec![
+            ("1 < 2", true),
+            ("2 <= 2", true),
+            ("2.5 > 2", true),
+            ("1 >= 1.0", true),
+            ("1.99 >= 2", false),
+            ("'abc' < 'abd'", true),
+            ("'b' > 'abc'", true),
+            ("'a' <= 'A'", false),
+            ("1 < 2 = true", true),
+            ("@2020-01-01 < @2020-01-02", true),
+            ("@2020-01 >= @2019-12-31", true),
+            ("@2020-06-01T10:00:00+02:00 < @2020-06-01T09:00:00Z", true),
+            ("@2020-06-01T10:00:00+02:00 = @2020-06-01T08:00:00Z", true),
+            ("@T10:30 > @T09:45", true),
+            ("@2020-01-01 = @2020-01-01T", true),
+        ];
+
+        for (expr, expected) in cases {
+            let result = Expression::new(expr).unwrap().evaluate()?;
+            assert_eq!(
+                result,
+                Collection::from(Value::boolean(expected)),
+                "{}",
+                expr
+            );
+        }
+
+        for expr in [
+            "@2020 < @2020-06-01",
+            "@2020 = @2020-06-01",
+            "@2020-06-01T10:00 >= @2020-06-01T10:00:30",
+        ] {
+            assert_eq!(
+                Expression::new(expr).unwrap().evaluate()?,
+                Collection::new()
+            );
+        }
+
+        assert!(matches!(
+            Expression::new("true < false")
+                .unwrap()
+                .evaluate()
+                .as_ref()
+                .map_err(EvaluationError::kind),
+            Err(EvaluationError::InvalidOperands(_, _))
+        ));
+        assert!(matches!(
+            Expression::new("1 < 'a'")
+                .unwrap()
+                .evaluate()
+                .as_ref()
+                .map_err(EvaluationError::kind),
+            Err(EvaluationError::InvalidOperands(_, _))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_arithmetic() -> Result<(), EvaluationError> {
+        let cases = vec![
+            ("1 + 2 = 3", true),
+            ("1.5 + 2 - 0.5 = 3.0", true),
+            ("10 - 2 - 3 = 5", true),
+            ("'foo' + 'bar' = 'foobar'", true),
+            ("'foo' & 'bar' = 'foobar'", true),
+            ("@2023-01-31 + 1 month = @2023-02-28", true),
+            ("@2023-03-31 - 1 month = @2023-02-28", true),
+            ("@2014 + 24 months = @2016", true),
+            (
+                "@2020-01-01T10:00:00Z + 90 minutes = @2020-01-01T11:30:00Z",
+                true,
+            ),
+            ("@2020-01-01 + 7 'd' = @2020-01-08", true),
+            ("@T23:30 + 1 hour = @T00:30", true),
+            ("@T01:00 - 2 hours = @T23:00", true),
+            ("@2020-01-31.difference(@2020-02-01, 'months') = 1", true),
+            ("@2020-01-31.duration(@2020-02-01, 'months') = 0", true),
+            ("@2000-06-15.duration(@2018-06-14, 'years') = 17", true),
+            ("@2000-06-15.duration(@2018-06-15, 'years') = 18", true),
+            ("@2020.duration(@2022, 'years') = 2", true),
+            ("2 + 3 * 4 = 14", true),
+            ("7 / 2 = 3.5", true),
+            ("7 div 2 = 3", true),
+            ("7 mod 2 = 1", true),
+            ("1 'g' = 1000 'mg'", true),
+            ("1 'g' != 1 'mg'", true),
+            ("5 '[lb_av]' > 2 'kg'", true),
+            ("1 'g' + 500 'mg' = 1.5 'g'", true),
+            ("2 'm' * 3 'm' = 6 'm2'", true),
+            ("10 'km' / 2 'h' = 5000 'm/h'", true),
+            ("100 'mg/dL' ~ 1 'g/L'", true),
+            ("1 year = 12 months", true),
+            ("2147483647 + 1L = 2147483648L", true),
+            ("5000000000L * 2 > 2147483647", true),
+            ("10L / 4 = 2.5", true),
+            ("1L = 1.0", true),
+            ("'9000000000'.toLong() = 9000000000L", true),
+            ("true.toLong() = 1L", true),
+            ("'12a'.convertsToLong()", false),
+            ("'-12'.convertsToLong()", true),
+            ("1.5.convertsToLong()", false),
+        ];
+
+        for (expr, expected) in cases {
+            let result = Expression::new(expr).unwrap().evaluate()?;
+            assert_eq!(
+                result,
+                Collection::from(Value::boolean(expected)),
+                "{}",
+                expr
+            );
+        }
+
+        assert!(Expression::new("@2020-01-01 + 1 'a'")
+            .unwrap()
+            .evaluate()
+            .is_err());
+        let err = Expression::new("(@2020-01-01 | @2021-01-01).duration(@2022-01-01, 'years')")
+            .unwrap()
+            .evaluate()
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            EvaluationError::ExpectedSingleton { count: 2, .. }
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_navigation() -> Result<(), EvaluationError> {
+        let patient = crate::fhir::load_resource(&serde_json::json!({
+            "resourceType": "Patient",
+            "id": "example",
+            "active": true,
+            "birthDate": "1974-12-25",
+            "name": [
+                { "use": "official", "family": "Chalmers", "given": ["Peter", "James"] },
+                { "use": "usual", "given": ["Jim"] }
+            ]
+        }))
+        .unwrap();
+        let observation = crate::fhir::load_resource(&serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "text": "Glucose" },
+            "valueQuantity": {
+                "value": 6.3,
+                "unit": "mmol/l",
+                "system": "http://unitsofmeasure.org",
+                "code": "mmol/L"
+            }
+        }))
+        .unwrap();
+
+        let cases = vec![
+            (&patient, "name.family = 'Chalmers'", true),
+            (&patient, "Patient.active = true", true),
+            (&patient, "birthDate < @2000-01-01", true),
+            (&patient, "Patient is DomainResource", true),
+            (&patient, "Patient is FHIR.Resource", true),
+            (&patient, "Patient is Observation", false),
+            (&patient, "Patient.active is FHIR.boolean", true),
+            (&patient, "Patient.active is System.Boolean", false),
+            (&observation, "Observation.code.is(CodeableConcept)", true),
+            (&patient, "Patient.type().name = 'Patient'", true),
+            (
+                &patient,
+                "Patient.type().baseType = 'FHIR.DomainResource'",
+                true,
+            ),
+            (
+                &patient,
+                "Patient.birthDate.type().namespace = 'FHIR'",
+                true,
+            ),
+            (&patient, "1.type().namespace = 'System'", true),
+            (&observation, "Observation.value > 5 'mmol/L'", true),
+            (&observation, "Observation.value is Quantity", true),
+            (
+                &observation,
+                "Observation.value.as(Quantity).unit = 'mmol/l'",
+                true,
+            ),
+            (
+                &observation,
+                "Observation.value.ofType(FHIR.Quantity).code = 'mmol/L'",
+                true,
+            ),
+            (
+                &observation,
+                "Observation.code.text.ofType(string) = 'Glucose'",
+                true,
+            ),
+            (&observation, "Observation.status as code = 'final'", true),
+        ];
+
+        for (resource, expr, expected) in cases {
+            let result = Expression::new(expr).unwrap().evaluate_resource(resource)?;
+            assert_eq!(
+                result,
+                Collection::from(Value::boolean(expected)),
+                "{}",
+                expr
+            );
+        }
+
+        let given = Expression::new("Patient.name.given")
+            .unwrap()
+            .evaluate_resource(&patient)?;
+        assert_eq!(given.len(), 3);
+
+        // The resource is shared with the results, not copied
+        let this = Expression::new("$this")
+            .unwrap()
+            .evaluate_resource(&patient)?;
+        assert!(matches!(this.as_slice(), [Value::Complex(node)] if Arc::ptr_eq(node, &patient)));
+
+        assert!(Expression::new("Patient.name is HumanName")
+            .unwrap()
+            .evaluate_resource(&patient)
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve() -> Result<(), EvaluationError> {
+        let bundle = crate::fhir::load_resource(&serde_json::json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": [
+                {
+                    "fullUrl": "urn:uuid:61ebe359-bfdc-4613-8bf2-c5e300945f0a",
+                    "resource": {
+                        "resourceType": "Observation",
+                        "status": "final",
+                        "code": { "text": "Glucose" },
+                        "subject": { "reference": "urn:uuid:e1b5a0b4-9c07-4d6e-a8c3-41f2e2f4b3b0" },
+                        "performer": [{ "reference": "Patient/2" }]
+                    }
+                },
+                {
+                    "fullUrl": "urn:uuid:e1b5a0b4-9c07-4d6e-a8c3-41f2e2f4b3b0",
+                    "resource": { "resourceType": "Patient", "id": "p", "birthDate": "1974-12-25" }
+                }
+            ]
+        }))
+        .unwrap();
+
+        let mut store = crate::evaluation::MapResolver::default();
+        store.insert(
+            crate::fhir::load_resource(&serde_json::json!({
+                "resourceType": "Patient",
+                "id": "2",
+                "active": true
+            }))
+            .unwrap(),
+        );
+        let context = EvaluationContext::new().with_resolver(store);
+
+        let expr =
+            Expression::new("Bundle.entry.resource.subject.resolve().birthDate = @1974-12-25")
+                .unwrap();
+        assert_eq!(
+            expr.evaluate_resource(&bundle)?,
+            Collection::from(Value::boolean(true))
+        );
+
+        let expr =
+            Expression::new("Bundle.entry.resource.performer.resolve().active = true").unwrap();
+        assert_eq!(expr.evaluate_resource(&bundle)?, Collection::new());
+        assert_eq!(
+            expr.evaluate_resource_with(&bundle, &context)?,
+            Collection::from(Value::boolean(true))
+        );
+
+        let strict = EvaluationContext::new().with_strict_references(true);
+        assert!(matches!(
+            expr.evaluate_resource_with(&bundle, &strict).as_ref().map_err(EvaluationError::kind),
+            Err(EvaluationError::UnresolvedReference(r)) if r == "Patient/2"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fhir_functions() -> Result<(), EvaluationError> {
+        let patient = crate::fhir::load_resource(&serde_json::json!({
+            "resourceType": "Patient",
+            "text": {
+                "status": "generated",
+                "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\"><p>Peter Chalmers</p></div>"
+            },
+            "extension": [
+                {
+                    "url": "http://hl7.org/fhir/StructureDefinition/patient-birthPlace",
+                    "valueAddress": { "city": "Brisbane" }
+                },
+                {
+                    "url": "http://example.org/eye-colour",
+                    "valueString": "blue"
+                }
+            ],
+            "modifierExtension": [
+                { "url": "http://example.org/test-patient", "valueBoolean": true }
+            ],
+            "birthDate": "1974-12-25",
+            "_birthDate": {
+                "extension": [{
+                    "url": "http://hl7.org/fhir/StructureDefinition/patient-birthTime",
+                    "valueDateTime": "1974-12-25T14:35:45-05:00"
+                }]
+            },
+            "name": [{ "given": ["Peter"] }],
+            "_gender": {
+                "extension": [{
+                    "url": "http://hl7.org/fhir/StructureDefinition/data-absent-reason",
+                    "valueCode": "unknown"
+                }]
+            }
+        }))
+        .unwrap();
+
+        let profile: crate::fhir::StructureDefinition = serde_json::from_value(serde_json::json!({
+            "id": "active-patient",
+            "url": "http://example.org/StructureDefinition/active-patient",
+            "name": "ActivePatient",
+            "status": "draft",
+            "kind": "resource",
+            "abstract": false,
+            "type": "Patient",
+            "baseDefinition": "http://hl7.org/fhir/StructureDefinition/Patient",
+            "snapshot": { "element": [
+                { "id": "Patient", "path": "Patient", "min": 0, "max": "*" },
+                {
+                    "id": "Patient.extension",
+                    "path": "Patient.extension",
+                    "slicing": { "discriminator": [{ "type": "value", "path": "url" }], "rules": "open" },
+                    "min": 0,
+                    "max": "*"
+                },
+                {
+                    "id": "Patient.extension:birthPlace",
+                    "path": "Patient.extension",
+                    "sliceName": "birthPlace",
+                    "min": 0,
+                    "max": "1"
+                },
+                {
+                    "id": "Patient.extension:birthPlace.url",
+                    "path": "Patient.extension.url",
+                    "min": 1,
+                    "max": "1",
+                    "fixedUri": "http://hl7.org/fhir/StructureDefinition/patient-birthPlace"
+                },
+                { "id": "Patient.name", "path": "Patient.name", "min": 1, "max": "*" },
+                { "id": "Patient.active", "path": "Patient.active", "min": 1, "max": "1" }
+            ]}
+        }))
+        .unwrap();
+        let context = EvaluationContext::new().with_profile(profile);
+
+        let cases = vec![
+            ("Patient.extension('http://example.org/eye-colour').value = 'blue'", true),
+            (
+                "Patient.birthDate.extension('http://hl7.org/fhir/StructureDefinition/patient-birthTime').value = @1974-12-25T14:35:45-05:00",
+                true,
+            ),
+            ("Patient.birthDate.hasValue()", true),
+            ("Patient.gender.hasValue()", false),
+            ("Patient.name.hasValue()", false),
+            ("Patient.birthDate.getValue() is System.Date", true),
+            ("Patient.text.div.htmlChecks()", true),
+            ("Patient.birthDate.elementDefinition().path = 'Patient.birthDate'", true),
+            ("Patient.name.given.elementDefinition().path = 'HumanName.given'", true),
+            ("Patient.name.given.elementDefinition().max = '*'", true),
+            ("Patient.birthDate.elementDefinition().min = 0", true),
+            (
+                "Patient.extension.slice('http://example.org/StructureDefinition/active-patient', 'birthPlace').value.city = 'Brisbane'",
+                true,
+            ),
+            (
+                "Patient.checkModifiers('http://example.org/test-patient').birthDate = @1974-12-25",
+                true,
+            ),
+            ("Patient.conformsTo('http://hl7.org/fhir/StructureDefinition/Patient')", true),
+            ("Patient.conformsTo('http://example.org/StructureDefinition/active-patient')", false),
+            ("Patient.name.conformsTo('HumanName')", true),
+            ("1 'kg'.comparable(1 '[lb_av]')", true),
+            ("1 'kg'.comparable(1 'm')", false),
+        ];
+
+        for (expr, expected) in cases {
+            let result = Expression::new(expr)
+                .unwrap()
+                .evaluate_resource_with(&patient, &context)?;
+            assert_eq!(
+                result,
+                Collection::from(Value::boolean(expected)),
+                "{}",
+                expr
+            );
+        }
+
+        let errors = vec![
+            "Patient.checkModifiers('http://example.org/other')",
+            "Patient.conformsTo('http://example.org/StructureDefinition/unknown')",
+        ];
+        for expr in errors {
+            assert!(
+                Expression::new(expr)
+                    .unwrap()
+                    .evaluate_resource_with(&patient, &context)
+                    .is_err(),
+                "{}",
+                expr
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_trace() -> Result<(), EvaluationError> {
+        let patient = crate::fhir::load_resource(&serde_json::json!({
+            "resourceType": "Patient",
+            "name": [
+                { "family": "Chalmers", "given": ["Peter", "James"] },
+                { "given": ["Jim"] }
+            ]
+        }))
+        .unwrap();
+        let sink = std::sync::Arc::new(crate::evaluation::VecTraceSink::new());
+        let context = EvaluationContext::new().with_trace_sink(sink.clone());
+
+        let result = Expression::new("Patient.name.trace('names', given).family.trace('family')")
+            .unwrap()
+            .evaluate_resource_with(&patient, &context)?;
+        assert_eq!(result.len(), 1);
+
+        let traces: Vec<_> = sink
+            .traces()
+            .into_iter()
+            .map(|(name, collection)| (name, collection.to_string()))
+            .collect();
+        assert_eq!(
+            traces,
+            vec![
+                ("names".to_string(), "['Peter', 'James', 'Jim']".to_string()),
+                ("family".to_string(), "['Chalmers']".to_string()),
+            ]
+        );
+
+        let result = Expression::new("1.trace('one') = 1")
+            .unwrap()
+            .evaluate_with(
+                &EvaluationContext::new().with_trace_sink(crate::evaluation::NoopTraceSink),
+            )?;
+        assert_eq!(result, Collection::from(Value::boolean(true)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_terminology() -> Result<(), EvaluationError> {
+        use crate::evaluation::InMemoryTerminology;
+        use serde_json::json;
+
+        let patient = crate::fhir::load_resource(&json!({
+            "resourceType": "Patient",
+            "gender": "male",
+            "maritalStatus": {
+                "coding": [{ "system": "http://example.org/status", "code": "widowed" }]
+            }
+        }))
+        .unwrap();
+        let bundle = json!({
+            "resourceType": "Bundle",
+            "type": "collection",
+            "entry": [
+                {
+                    "resource": {
+                        "resourceType": "CodeSystem",
+                        "url": "http://example.org/status",
+                        "name": "Status",
+                        "concept": [{
+                            "code": "unmarried",
+                            "display": "Unmarried",
+                            "concept": [{ "code": "widowed", "display": "Widowed" }]
+                        }]
+                    }
+                },
+                {
+                    "resource": {
+                        "resourceType": "ValueSet",
+                        "url": "http://hl7.org/fhir/ValueSet/administrative-gender",
+                        "compose": {
+                            "include": [{
+                                "system": "http://hl7.org/fhir/administrative-gender",
+                                "concept": [{ "code": "male" }, { "code": "female" }]
+                            }]
+                        }
+                    }
+                }
+            ]
+        });
+        let mut terminology = InMemoryTerminology::new();
+        terminology
+            .load_bundle(&serde_json::to_vec(&bundle).unwrap())
+            .unwrap();
+        terminology
+            .load_bundle(include_bytes!("fhir/specification/R4B/conceptmaps.json"))
+            .unwrap();
+        let unmarried = crate::fhir::load_data_type(
+            &json!({ "system": "http://example.org/status", "code": "unmarried" }),
+            "Coding",
+        )
+        .unwrap();
+        let uncoded = crate::fhir::load_data_type(
+            &json!({ "system": "http://hl7.org/fhir/administrative-gender" }),
+            "Coding",
+        )
+        .unwrap();
+        let context = EvaluationContext::new()
+            .with_terminology_service(terminology)
+            .with_variable("unmarried", Collection::from(unmarried.to_value()))
+            .with_variable("uncoded", Collection::from(uncoded.to_value()));
+
+        let cases = vec![
+            ("Patient.gender.memberOf(%`vs-administrative-gender`)", true),
+            ("Patient.maritalStatus.memberOf(%'vs-administrative-gender')", false),
+            ("Patient.maritalStatus.subsumedBy(%unmarried)", true),
+            ("Patient.maritalStatus.subsumes(%unmarried)", false),
+            ("%unmarried.subsumes(Patient.maritalStatus)", true),
+            ("%resource.gender = 'male'", true),
+            ("%ucum = 'http://unitsofmeasure.org'", true),
+            (
+                "%terminologies.expand(%'vs-administrative-gender').expansion.total = 2",
+                true,
+            ),
+            (
+                "%terminologies.subsumes('http://example.org/status', 'unmarried', 'widowed') = 'subsumes'",
+                true,
+            ),
+            (
+                "%terminologies.validateVS(%`vs-administrative-gender`, 'female').parameter.value = true",
+                true,
+            ),
+            (
+                "%terminologies.translate('http://hl7.org/fhir/ConceptMap/cm-administrative-gender-v2', Patient.gender).parameter.part.value.code = 'M'",
+                true,
+            ),
+        ];
+
+        for (expr, expected) in cases {
+            let result = Expression::new(expr)
+                .unwrap()
+                .evaluate_resource_with(&patient, &context)?;
+            assert_eq!(
+                result,
+                Collection::from(Value::boolean(expected)),
+                "{}",
+                expr
+            );
+        }
+
+        let lookup =
+            Expression::new("%terminologies.lookup(Patient.maritalStatus).parameter.value")
+                .unwrap()
+                .evaluate_resource_with(&patient, &context)?;
+        assert_eq!(lookup.to_string(), "['Status', 'Widowed']");
+
+        let uncoded = Expression::new("%uncoded.memberOf(%`vs-administrative-gender`)")
+            .unwrap()
+            .evaluate_resource_with(&patient, &context)?;
+        assert!(uncoded.is_empty());
+
+        let expression = Expression::new("Patient.gender.memberOf(%`vs-administrative-gender`)");
+        assert!(matches!(
+            expression
+                .unwrap()
+                .evaluate_resource(&patient)
+                .as_ref()
+                .map_err(EvaluationError::kind),
+            Err(EvaluationError::TerminologyUnavailable)
+        ));
+        assert!(matches!(
+            Expression::new("%unknown")
+                .unwrap()
+                .evaluate_with(&context)
+                .as_ref()
+                .map_err(EvaluationError::kind),
+            Err(EvaluationError::UnknownVariable(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() {
+        use crate::fhirpath::{INTEGER, STRING};
+        use crate::parser::Span;
+
+        let patient = crate::fhir::load_resource(&serde_json::json!({
+            "resourceType": "Patient",
+            "name": [{ "given": ["Peter", "James"] }]
+        }))
+        .unwrap();
+        let error = |expr| {
+            Expression::new(expr)
+                .unwrap()
+                .evaluate_resource(&patient)
+                .unwrap_err()
+        };
+
+        let err = error("'abc'.replace('a')");
+        assert!(matches!(
+            err.kind(),
+            EvaluationError::InvalidFunctionArguments(_)
+        ));
+        assert_eq!(err.function(), Some("replace"));
+        assert_eq!(err.span(), Some(Span { start: 6, end: 18 }));
+
+        let err = error("1 + 1.replace('a', 'b')");
+        assert!(matches!(
+            err.kind(),
+            EvaluationError::UnexpectedType { expected, actual } if *expected == STRING && *actual == INTEGER
+        ));
+        assert_eq!(err.span(), Some(Span { start: 6, end: 23 }));
+        assert_eq!(
+            err.to_string(),
+            "expected a System.String, but found a System.Integer in replace() at 6..23"
+        );
+
+        let err = error("Patient.name.given.replace('e', 'o')");
+        assert!(matches!(
+            err.kind(),
+            EvaluationError::ExpectedSingleton { expected, count: 2 } if *expected == STRING
+        ));
+
+        let err = error("true < false");
+        assert!(matches!(err.kind(), EvaluationError::InvalidOperands(_, _)));
+        assert_eq!(err.function(), None);
+        assert_eq!(err.span(), Some(Span { start: 0, end: 12 }));
+        assert!(std::error::Error::source(&err).is_some());
+
+        // Literals that are not valid values are reported where they are, whether the
+        // expression is evaluated or compiled
+        let expression = Expression::new("1 + @2020-13-01").unwrap();
+        let err = expression.evaluate().unwrap_err();
+        assert_eq!(err.span(), Some(Span { start: 4, end: 15 }));
+        let Err(err) = expression.compile() else {
+            panic!("@2020-13-01 compiled");
+        };
+        assert_eq!(err.span(), Some(Span { start: 4, end: 15 }));
+    }
+
+    #[test]
+    fn test_no_panics() {
+        let expressions = [
+            "",
+            "(",
+            ")",
+            "'",
+            "'abc",
+            "%",
+            "%'abc",
+            "1 +",
+            ".",
+            "a..b",
+            "@",
+            "@T",
+            "1.5.5",
+            "2147483648",
+            "99999999999999999999L",
+            "1 / 0",
+            "5 div 0",
+            "5 mod 0",
+            "@2020-13-45",
+            "'a' + 1",
+            "1 'xyz' + 1 'abc'",
+            "9999999999999999999999999999.0 * 10",
+            "@2020-01-01 + 9999999999 years",
+            "@2020-01-01 + 2000000000 years",
+            "@2020-01-01T10:00:00Z - 2000000000 days",
+            "@T10:00 + 2000000000 hours",
+            "@2020-01-01.duration(@9999-12-31, 'milliseconds')",
+            "1 'mg' * 1 'kg' / 0 'g'",
+            "now().duration(1, 'x')",
+            "'\u{e9}' = '\u{e9}'",
+            "\u{e9}",
+            "1 is",
+            "value.is(",
+            "value.ofType()",
+            "unknown()",
+            "%terminologies",
+            "%terminologies.expand('x')",
+            "'a'.replace()",
+            "type().name",
+            "resolve()",
+            "1.trace()",
+        ];
+
+        for expr in expressions {
+            if let Ok(expression) = Expression::new(expr) {
+                let _ = expression.evaluate();
+            }
+        }
+    }
+
+    #[test]
+    fn test_limits() -> Result<(), EvaluationError> {
+        use crate::evaluation::{CancellationToken, Limit, Limits, TraceSink};
+        use std::time::Duration;
+
+        /// Does something slow, or cancels the evaluation, when `trace()` is called
+        struct SlowSink(Option<CancellationToken>);
+
+        impl TraceSink for SlowSink {
+            fn trace(&self, _: &str, _: &Collection) {
+                match &self.0 {
+                    Some(token) => token.cancel(),
+                    None => std::thread::sleep(Duration::from_millis(20)),
+                }
+            }
+        }
+
+        let patient = crate::fhir::load_resource(&serde_json::json!({
+            "resourceType": "Patient",
+            "name": [{ "given": ["Peter", "James"] }]
+        }))
+        .unwrap();
+        let token = CancellationToken::new();
+        let cases = vec![
+            (
+                Limits::new().with_max_steps(5),
+                "1 + 2 + 3 + 4",
+                Limit::Steps(5),
+            ),
+            (
+                Limits::new().with_max_depth(3),
+                "1 + 2 + 3 + 4",
+                Limit::Depth(3),
+            ),
+            (
+                Limits::new().with_max_collection_size(1),
+                "Patient.name.given",
+                Limit::CollectionSize(1),
+            ),
+            (
+                Limits::new().with_max_string_length(5),
+                "'abc' & 'def'",
+                Limit::StringLength(5),
+            ),
+            (
+                Limits::new().with_max_collection_size(2),
+                "descendants().exists()",
+                Limit::CollectionSize(2),
+            ),
+            (
+                Limits::new().with_max_collection_size(2),
+                "repeat(name | given).exists()",
+                Limit::CollectionSize(2),
+            ),
+            (
+                Limits::new().with_max_string_length(6),
+                "'aaa'.replace('a', 'bbb') = ''",
+                Limit::StringLength(6),
+            ),
+            (
+                Limits::new().with_timeout(Duration::from_millis(10)),
+                "1.trace('slow') + 1",
+                Limit::Timeout(Duration::from_millis(10)),
+            ),
+        ];
+
+        for (limits, expr, expected) in cases {
+            let context = EvaluationContext::new()
+                .with_limits(limits)
+                .with_trace_sink(SlowSink(None));
+            let result = Expression::new(expr)
+                .unwrap()
+                .evaluate_resource_with(&patient, &context);
+            assert!(
+                matches!(
+                    result.as_ref().map_err(EvaluationError::kind),
+                    Err(EvaluationError::LimitExceeded(limit)) if *limit == expected
+                ),
+                "{}: {:?}",
+                expr,
+                result
+            );
+
+            let unlimited = EvaluationContext::new().with_trace_sink(SlowSink(None));
+            Expression::new(expr)
+                .unwrap()
+                .evaluate_resource_with(&patient, &unlimited)?;
+        }
+
+        // Regular expressions that would compile to too large a program are refused
+        assert!(Expression::new("'a'.matches('a{50000}')")
+            .unwrap()
+            .evaluate()
+            .is_err());
+
+        let context = EvaluationContext::new()
+            .with_trace_sink(SlowSink(Some(token.clone())))
+            .with_cancellation_token(token);
+        let result = Expression::new("1.trace('cancel') + 1")
+            .unwrap()
+            .evaluate_with(&context);
+        assert!(matches!(
+            result.as_ref().map_err(EvaluationError::kind),
+            Err(EvaluationError::LimitExceeded(Limit::Cancelled))
+        ));
+
+        let context = EvaluationContext::new().with_limits(
+            Limits::new()
+                .with_max_steps(100)
+                .with_max_depth(10)
+                .with_max_collection_size(2)
+                .with_max_string_length(6),
+        );
+        let result = Expression::new("Patient.name.given")
+            .unwrap()
+            .evaluate_resource_with(&patient, &context)?;
+        assert_eq!(result.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile() -> Result<(), EvaluationError> {
+        use crate::evaluation::{Limit, Limits};
+
+        let patient = crate::fhir::load_resource(&serde_json::json!({
+            "resourceType": "Patient",
+            "id": "example",
+            "active": true,
+            "birthDate": "1974-12-25",
+            "name": [
+                { "use": "official", "family": "Chalmers", "given": ["Peter", "James"] },
+                { "use": "usual", "given": ["Jim"] }
+            ]
+        }))
+        .unwrap();
+
+        let expressions = [
+            "1 + 2 * 3",
+            "'abc' & 'def'",
+            "@2020-01-01 + 7 'd'",
+            "Patient.name.given",
+            "name.given",
+            "Patient.name.family.replace('C', 'K') = 'Khalmers'",
+            "Patient.birthDate < @2000-01-01",
+            "Patient.birthDate.duration(@2020-01-01, 'years')",
+            "Patient.active is Boolean",
+            "Patient.name.given.ofType(FHIR.string).trace('given')",
+            "Patient.name.given.trace('given', replace('e', 'o'))",
+            "Patient.name.given.elementDefinition().path",
+            "%resource.id",
+            "Patient.name.given.replace('e', 'o')",
+            "true < false",
+            "name.defineVariable('first', given.where($this = 'Peter')).select(%first & ' ' & family)",
+            "(1 | 2).select(defineVariable('x', $this).select(%x * 10))",
+            "defineVariable('v', 1).select(%v) | %v",
+            "defineVariable('x', 1).defineVariable('x', 2)",
+        ];
+        let context = EvaluationContext::new();
+        for expr in expressions {
+            let expression = Expression::new(expr).unwrap();
+            let compiled = expression.compile()?;
+            match (
+                expression.evaluate_resource_with(&patient, &context),
+                compiled.evaluate_resource_with(&patient, &context),
+            ) {
+                (Ok(expected), Ok(actual)) => assert_eq!(actual, expected, "{}", expr),
+                (Err(expected), Err(actual)) => {
+                    assert_eq!(actual.to_string(), expected.to_string(), "{}", expr)
+                }
+                (expected, actual) => panic!("{}: {:?} != {:?}", expr, actual, expected),
+            }
+        }
+
+        // Defined variables are scoped to their invocation chain and cannot be redefined
+        for expr in [
+            "defineVariable('v', 1).select(%v) | %v",
+            "(defineVariable('v', 1) | 2).where(%v = 1)",
+            "defineVariable('x', 1).defineVariable('x', 2)",
+            "defineVariable('x', 1).select(defineVariable('x', 2))",
+            "defineVariable('resource', 1)",
+        ] {
+            let expression = Expression::new(expr).unwrap();
+            for result in [
+                expression.evaluate_resource_with(&patient, &context),
+                expression
+                    .compile()?
+                    .evaluate_resource_with(&patient, &context),
+            ] {
+                assert!(result.is_err(), "{}", expr);
+            }
+        }
+        assert_eq!(
+            Expression::new("(1 | 2).select(defineVariable('x', $this).select(%x * 10))")
+                .unwrap()
+                .compile()?
+                .evaluate_resource_with(&patient, &context)?,
+            [Value::Integer(10), Value::Integer(20)]
+                .into_iter()
+                .collect::<Collection>()
+        );
+
+        // Functions and literals are checked when the expression is compiled
+        let err = Expression::new("1 + 1.frobnicate()")
+            .unwrap()
+            .compile()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.kind(),
+            EvaluationError::FunctionUnavailable(name) if name == "frobnicate"
+        ));
+        assert_eq!(
+            err.to_string(),
+            "function frobnicate() is not available in frobnicate() at 6..18"
+        );
+        assert!(Expression::new("2147483648 + 1")
+            .unwrap()
+            .compile()
+            .is_err());
+
+        // Limits apply to compiled expressions as they do to the tree walker
+        let context = EvaluationContext::new().with_limits(Limits::new().with_max_steps(5));
+        let err = Expression::new("Patient.name.family.replace('C', 'K') = 'Khalmers'")
+            .unwrap()
+            .compile()?
+            .evaluate_resource_with(&patient, &context)
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            EvaluationError::LimitExceeded(Limit::Steps(5))
+        ));
+
+        // A compiled expression can be shared between threads
+        let compiled =
+            std::sync::Arc::new(Expression::new("Patient.name.given").unwrap().compile()?);
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let compiled = compiled.clone();
+                let patient = patient.clone();
+                std::thread::spawn(move || compiled.evaluate_resource(&patient).unwrap())
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.join().unwrap().len(), 3);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() {
+        let check = |root, expr| Expression::new(expr).unwrap().check(root);
+
+        // Search parameter expressions and parts of invariants from the specification
+        for (root, expr) in [
+            ("Patient.contact", "name.exists()"),
+            ("Patient", "Patient.name.family"),
+            (
+                "Patient",
+                "Patient.deceased.exists() and Patient.deceased != false",
+            ),
+            ("Observation", "value.ofType(Quantity).value"),
+        ] {
+            assert!(check(root, expr).is_ok(), "{}", expr);
+        }
+        assert_eq!(
+            check("Patient", "Patient.name.family").unwrap().to_string(),
+            "FHIR.string[0..*]"
+        );
+
+        let errors = check("Patient", "Patient.name.family.single() = 'Smith'").unwrap_err();
+        assert_eq!(
+            errors[0].to_string(),
+            "single() expects a single item, but its input is FHIR.string[0..*] at 20..28"
+        );
+        assert!(matches!(
+            check("Nonsense", "name").unwrap_err().as_slice(),
+            [TypeCheckError::UnknownType(_)]
+        ));
+    }
+
+    /// A cut-down R5 model, made from the R4B one with some of the changes R5 made to it.  It is
+    /// installed once, for all the tests that use it.
+    pub(crate) fn r5_model() -> &'static Model {
+        static R5: std::sync::OnceLock<&'static Model> = std::sync::OnceLock::new();
+        R5.get_or_init(install_r5_model)
+    }
+
+    fn install_r5_model() -> &'static Model {
+        use crate::fhir::{ElementDefinition, SpecificationPackage, StructureDefinition};
+
+        let base = fhir::default_model().unwrap();
+        let mut definitions: Vec<StructureDefinition> = base
+            .resources()
+            .values()
+            .chain(base.data_types().values())
+            .cloned()
+            .collect();
+
+        let mut integer64 = base.data_types()["integer"].clone();
+        integer64.url = "http://hl7.org/fhir/StructureDefinition/integer64".to_string();
+        integer64.id = "integer64".to_string();
+        integer64.name = "integer64".to_string();
+        integer64.r#type = "integer64".to_string();
+        for element in &mut integer64.snapshot.as_mut().unwrap().element {
+            element.id = element.id.replacen("integer", "integer64", 1);
+            element.path = element.path.replacen("integer", "integer64", 1);
+            if element.path == "integer64.value" {
+                element.r#type[0].code = "http://hl7.org/fhirpath/System.String".to_string();
+            }
+        }
+        definitions.push(integer64);
+
+        let element = |json| serde_json::from_value::<ElementDefinition>(json).unwrap();
+        for definition in &mut definitions {
+            let elements = &mut definition.snapshot.as_mut().unwrap().element;
+            if definition.name == "Extension" {
+                let value = elements
+                    .iter_mut()
+                    .find(|el| el.path == "Extension.value[x]")
+                    .unwrap();
+                value.r#type.push(
+                    serde_json::from_value(serde_json::json!({ "code": "integer64" })).unwrap(),
+                );
+            } else if definition.name == "Observation" {
+                elements.push(element(serde_json::json!({
+                    "id": "Observation.instantiates[x]",
+                    "path": "Observation.instantiates[x]",
+                    "min": 0,
+                    "max": "1",
+                    "type": [{ "code": "canonical" }, { "code": "Reference" }]
+                })));
+            }
+        }
+
+        let package =
+            SpecificationPackage::from_definitions("hl7.fhir.r5.core", "5.0.0", definitions);
+        Model::install(package).unwrap()
+    }
+
+    #[test]
+    fn test_fhir_versions() -> Result<(), EvaluationError> {
+        use crate::fhir::{FhirVersion, PackageError, SpecificationPackage};
+
+        let r5 = r5_model();
+        assert_eq!(r5.version(), FhirVersion::R5);
+        assert!(std::ptr::eq(fhir::model(FhirVersion::R5).unwrap(), r5));
+        assert!(matches!(
+            Model::install(SpecificationPackage::clone(r5.package())),
+            Err(PackageError::AlreadyInstalled)
+        ));
+
+        let json = serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "text": "Steps" },
+            "instantiatesCanonical": "http://example.org/ObservationDefinition/steps",
+            "extension": [{ "url": "http://example.org/total", "valueInteger64": "9000000000" }]
+        });
+        assert!(matches!(
+            crate::fhir::load_resource(&json),
+            Err(crate::fhir::ParseError::UnknownElement(_))
+        ));
+        let observation = r5.load_resource(&json).unwrap();
+
+        let context = EvaluationContext::new().with_model(r5);
+        for expr in [
+            "Observation.instantiates = 'http://example.org/ObservationDefinition/steps'",
+            "Observation.extension.value is integer64",
+            "Observation.extension.value = 9000000000L",
+            "Observation.extension.value.type().name = 'integer64'",
+        ] {
+            let result = Expression::new(expr)
+                .unwrap()
+                .evaluate_resource_with(&observation, &context)?;
+            assert_eq!(result, Collection::from(Value::boolean(true)), "{}", expr);
+        }
+
+        let check = |expr, model| {
+            Expression::new(expr)
+                .unwrap()
+                .check_with("Observation", model)
+        };
+        assert_eq!(
+            check("instantiates", r5).unwrap().to_string(),
+            "(FHIR.canonical | FHIR.Reference)[0..1]"
+        );
+        assert_eq!(
+            check("extension.value.ofType(integer64).first() + 1", r5)
+                .unwrap()
+                .to_string(),
+            "System.Long[0..1]"
+        );
+        let r4b = fhir::default_model().unwrap();
+        assert!(check("instantiates", r4b).is_err());
+        assert!(check("extension.value.ofType(integer64)", r4b).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_generated_snapshot() -> Result<(), EvaluationError> {
+        let mut profile: fhir::StructureDefinition = serde_json::from_value(serde_json::json!({
+            "id": "mrn-patient",
+            "url": "http://example.org/StructureDefinition/mrn-patient",
+            "name": "MrnPatient",
+            "status": "draft",
+            "kind": "resource",
+            "abstract": false,
+            "type": "Patient",
+            "baseDefinition": "http://hl7.org/fhir/StructureDefinition/Patient",
+            "derivation": "constraint",
+            "differential": { "element": [
+                {
+                    "id": "Patient.identifier",
+                    "path": "Patient.identifier",
+                    "slicing": { "discriminator": [{ "type": "value", "path": "system" }], "rules": "open" },
+                    "min": 1
+                },
+                { "id": "Patient.identifier:mrn", "path": "Patient.identifier", "sliceName": "mrn", "min": 1 },
+                {
+                    "id": "Patient.identifier:mrn.system",
+                    "path": "Patient.identifier.system",
+                    "fixedUri": "http://example.org/mrn"
+                }
+            ]}
+        }))
+        .unwrap();
+        let snapshot = fhir::SnapshotGenerator::new(fhir::default_model().unwrap())
+            .generate(&profile)
+            .unwrap();
+        profile.snapshot = Some(snapshot);
+        let context = EvaluationContext::new().with_profile(profile);
+
+        let patient = |identifier: serde_json::Value| {
+            fhir::load_resource(&serde_json::json!({
+                "resourceType": "Patient",
+                "identifier": identifier
+            }))
+            .unwrap()
+        };
+        let mrn =
+            patient(serde_json::json!([{ "system": "http://example.org/mrn", "value": "12345" }]));
+        let unidentified = patient(serde_json::json!([]));
+        let profile = "'http://example.org/StructureDefinition/mrn-patient'";
+        let cases = vec![
+            (format!("Patient.conformsTo({})", profile), &mrn, true),
+            (
+                format!("Patient.conformsTo({})", profile),
+                &unidentified,
+                false,
+            ),
+            (
+                format!(
+                    "Patient.identifier.slice({}, 'mrn').value = '12345'",
+                    profile
+                ),
+                &mrn,
+                true,
+            ),
+        ];
+        for (expr, patient, expected) in cases {
+            let result = Expression::new(&expr)
+                .unwrap()
+                .evaluate_resource_with(patient, &context)?;
+            assert_eq!(
+                result,
+                Collection::from(Value::boolean(expected)),
+                "{}",
+                expr
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_clock() -> Result<(), EvaluationError> {
+        let now = chrono::DateTime::parse_from_rfc3339("2023-05-01T13:45:30.250-04:00").unwrap();
+        let context = EvaluationContext::new().with_clock(FixedClock(now));
+
+        let cases = vec![
+            ("now() = @2023-05-01T13:45:30.250-04:00", true),
+            ("today() = @2023-05-01", true),
+            ("timeOfDay() = @T13:45:30.250", true),
+            ("now() = now()", true),
+            ("@2005-05-01 <= today() - 18 years", true),
+            ("@2005-05-02 <= today() - 18 years", false),
+            ("@2005-05-02.duration(today(), 'years') = 17", true),
+        ];
+
+        for (expr, expected) in cases {
+            let result = Expression::new(expr).unwrap().evaluate_with(&context)?;
+            assert_eq!(
+                result,
+                Collection::from(Value::boolean(expected)),
+                "{}",
+                expr
+            );
+        }
+
+        Ok(())
+    }
 }
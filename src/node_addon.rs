@@ -10,15 +10,23 @@ fn export(mut cx: ModuleContext) -> NeonResult<()> {
 
 fn evaluate(mut cx: FunctionContext) -> JsResult<JsArray> {
     let expr = cx.argument::<JsString>(0)?.value(&mut cx);
-    let result = Expression::new(&expr).unwrap().evaluate().unwrap();
+    let result = match Expression::new(&expr) {
+        Ok(expression) => expression.evaluate(),
+        Err(err) => return cx.throw_error(err.to_string()),
+    };
+    let result = match result {
+        Ok(result) => result,
+        Err(err) => return cx.throw_error(err.to_string()),
+    };
+
     let out_arr = cx.empty_array();
-    result.iter().enumerate().for_each(|(i, val)| {
-        if let crate::fhirpath::Value::String(str) = val {
-            let js_str = cx.string(str);
-            out_arr.set(&mut cx, i as u32, js_str).unwrap();
-        } else {
-            todo!()
-        }
-    });
+    for (i, val) in result.iter().enumerate() {
+        let str = match val {
+            crate::fhirpath::Value::String(str) => str.clone(),
+            val => val.to_string(),
+        };
+        let js_str = cx.string(str);
+        out_arr.set(&mut cx, i as u32, js_str)?;
+    }
     Ok(out_arr)
 }
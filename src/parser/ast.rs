@@ -1,14 +1,182 @@
-#[derive(PartialEq, Eq, Debug)]
+use std::fmt;
+
+/// The range of characters of an expression that a token or node was read from
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Nodes that evaluation errors can be reported against carry the span they were parsed from
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum ASTNode {
     BooleanLiteral(bool),
     StringLiteral(String),
-    NumberLiteral(String),
+    NumberLiteral(String, Span),
+    LongNumberLiteral(String, Span),
+    DateLiteral(String, Span),
+    DateTimeLiteral(String, Span),
+    TimeLiteral(String, Span),
+    QuantityLiteral(String, String, Span),
     Identifier(String),
+    Variable(String, Span),
     MemberInvocation(Box<ASTNode>),
     InvocationExpression(Box<ASTNode>, Box<ASTNode>),
-    Function(Box<ASTNode>, Box<ASTNode>),
+    Function(Box<ASTNode>, Box<ASTNode>, Span),
     ParamList(Option<Box<ASTNode>>),
     Union(Box<ASTNode>, Box<ASTNode>),
+    /// The `|` operator, as opposed to the commas separating parameters
+    UnionExpression(Box<ASTNode>, Box<ASTNode>, Span),
+    EqualityExpression(Box<ASTNode>, EqualityOperator, Box<ASTNode>, Span),
+    InequalityExpression(Box<ASTNode>, InequalityOperator, Box<ASTNode>, Span),
+    AdditiveExpression(Box<ASTNode>, AdditiveOperator, Box<ASTNode>, Span),
+    MultiplicativeExpression(Box<ASTNode>, MultiplicativeOperator, Box<ASTNode>, Span),
+    BooleanExpression(Box<ASTNode>, BooleanOperator, Box<ASTNode>, Span),
+    TypeExpression(Box<ASTNode>, TypeOperator, String, Span),
+    MembershipExpression(Box<ASTNode>, MembershipOperator, Box<ASTNode>, Span),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum EqualityOperator {
+    Equal,
+    NotEqual,
+    Equivalent,
+    NotEquivalent,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum InequalityOperator {
+    LessThan,
+    LessOrEqual,
+    GreaterThan,
+    GreaterOrEqual,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum AdditiveOperator {
+    Plus,
+    Minus,
+    Concatenate,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum MultiplicativeOperator {
+    Multiply,
+    Divide,
+    Div,
+    Mod,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum BooleanOperator {
+    And,
+    Or,
+    Xor,
+    Implies,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum TypeOperator {
+    Is,
+    As,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum MembershipOperator {
+    In,
+    Contains,
+}
+
+/// Keywords that may follow a number to form a calendar duration quantity, e.g. `4 days`
+pub const CALENDAR_DURATIONS: [&str; 16] = [
+    "year",
+    "years",
+    "month",
+    "months",
+    "week",
+    "weeks",
+    "day",
+    "days",
+    "hour",
+    "hours",
+    "minute",
+    "minutes",
+    "second",
+    "seconds",
+    "millisecond",
+    "milliseconds",
+];
+
+impl fmt::Display for EqualityOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EqualityOperator::Equal => "=",
+            EqualityOperator::NotEqual => "!=",
+            EqualityOperator::Equivalent => "~",
+            EqualityOperator::NotEquivalent => "!~",
+        })
+    }
+}
+
+impl fmt::Display for InequalityOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InequalityOperator::LessThan => "<",
+            InequalityOperator::LessOrEqual => "<=",
+            InequalityOperator::GreaterThan => ">",
+            InequalityOperator::GreaterOrEqual => ">=",
+        })
+    }
+}
+
+impl fmt::Display for AdditiveOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AdditiveOperator::Plus => "+",
+            AdditiveOperator::Minus => "-",
+            AdditiveOperator::Concatenate => "&",
+        })
+    }
+}
+
+impl fmt::Display for MultiplicativeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MultiplicativeOperator::Multiply => "*",
+            MultiplicativeOperator::Divide => "/",
+            MultiplicativeOperator::Div => "div",
+            MultiplicativeOperator::Mod => "mod",
+        })
+    }
+}
+
+impl fmt::Display for BooleanOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BooleanOperator::And => "and",
+            BooleanOperator::Or => "or",
+            BooleanOperator::Xor => "xor",
+            BooleanOperator::Implies => "implies",
+        })
+    }
+}
+
+impl fmt::Display for TypeOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TypeOperator::Is => "is",
+            TypeOperator::As => "as",
+        })
+    }
+}
+
+impl fmt::Display for MembershipOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MembershipOperator::In => "in",
+            MembershipOperator::Contains => "contains",
+        })
+    }
 }
 
 impl ASTNode {
@@ -21,13 +189,78 @@ impl ASTNode {
     }
 
     pub fn function(left: Box<ASTNode>, right: Box<ASTNode>) -> Box<Self> {
-        Box::new(ASTNode::Function(left, right))
+        Box::new(ASTNode::Function(left, right, Span::default()))
     }
 
     pub fn union(left: Box<ASTNode>, right: Box<ASTNode>) -> Box<Self> {
         Box::new(ASTNode::Union(left, right))
     }
 
+    pub fn union_expression(left: Box<ASTNode>, right: Box<ASTNode>) -> Box<Self> {
+        Box::new(ASTNode::UnionExpression(left, right, Span::default()))
+    }
+
+    pub fn equality(left: Box<ASTNode>, op: EqualityOperator, right: Box<ASTNode>) -> Box<Self> {
+        Box::new(ASTNode::EqualityExpression(
+            left,
+            op,
+            right,
+            Span::default(),
+        ))
+    }
+
+    pub fn inequality(
+        left: Box<ASTNode>,
+        op: InequalityOperator,
+        right: Box<ASTNode>,
+    ) -> Box<Self> {
+        Box::new(ASTNode::InequalityExpression(
+            left,
+            op,
+            right,
+            Span::default(),
+        ))
+    }
+
+    pub fn additive(left: Box<ASTNode>, op: AdditiveOperator, right: Box<ASTNode>) -> Box<Self> {
+        Box::new(ASTNode::AdditiveExpression(
+            left,
+            op,
+            right,
+            Span::default(),
+        ))
+    }
+
+    pub fn multiplicative(
+        left: Box<ASTNode>,
+        op: MultiplicativeOperator,
+        right: Box<ASTNode>,
+    ) -> Box<Self> {
+        Box::new(ASTNode::MultiplicativeExpression(
+            left,
+            op,
+            right,
+            Span::default(),
+        ))
+    }
+
+    pub fn boolean(left: Box<ASTNode>, op: BooleanOperator, right: Box<ASTNode>) -> Box<Self> {
+        Box::new(ASTNode::BooleanExpression(left, op, right, Span::default()))
+    }
+
+    pub fn membership(
+        left: Box<ASTNode>,
+        op: MembershipOperator,
+        right: Box<ASTNode>,
+    ) -> Box<Self> {
+        Box::new(ASTNode::MembershipExpression(
+            left,
+            op,
+            right,
+            Span::default(),
+        ))
+    }
+
     pub fn string(s: impl ToString) -> Box<Self> {
         Box::new(ASTNode::StringLiteral(s.to_string()))
     }
@@ -39,4 +272,48 @@ impl ASTNode {
     pub fn empty_params() -> Box<Self> {
         Box::new(ASTNode::ParamList(None))
     }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ASTNode::NumberLiteral(_, span)
+            | ASTNode::LongNumberLiteral(_, span)
+            | ASTNode::DateLiteral(_, span)
+            | ASTNode::DateTimeLiteral(_, span)
+            | ASTNode::TimeLiteral(_, span)
+            | ASTNode::QuantityLiteral(_, _, span)
+            | ASTNode::Variable(_, span)
+            | ASTNode::Function(_, _, span)
+            | ASTNode::EqualityExpression(_, _, _, span)
+            | ASTNode::InequalityExpression(_, _, _, span)
+            | ASTNode::AdditiveExpression(_, _, _, span)
+            | ASTNode::MultiplicativeExpression(_, _, _, span)
+            | ASTNode::BooleanExpression(_, _, _, span)
+            | ASTNode::TypeExpression(_, _, _, span)
+            | ASTNode::UnionExpression(_, _, span)
+            | ASTNode::MembershipExpression(_, _, _, span) => Some(*span),
+            _ => None,
+        }
+    }
+
+    pub(super) fn set_span(&mut self, new_span: Span) {
+        match self {
+            ASTNode::NumberLiteral(_, span)
+            | ASTNode::LongNumberLiteral(_, span)
+            | ASTNode::DateLiteral(_, span)
+            | ASTNode::DateTimeLiteral(_, span)
+            | ASTNode::TimeLiteral(_, span)
+            | ASTNode::QuantityLiteral(_, _, span)
+            | ASTNode::Variable(_, span)
+            | ASTNode::Function(_, _, span)
+            | ASTNode::EqualityExpression(_, _, _, span)
+            | ASTNode::InequalityExpression(_, _, _, span)
+            | ASTNode::AdditiveExpression(_, _, _, span)
+            | ASTNode::MultiplicativeExpression(_, _, _, span)
+            | ASTNode::BooleanExpression(_, _, _, span)
+            | ASTNode::TypeExpression(_, _, _, span)
+            | ASTNode::UnionExpression(_, _, span)
+            | ASTNode::MembershipExpression(_, _, _, span) => *span = new_span,
+            _ => {}
+        }
+    }
 }
@@ -1,4 +1,5 @@
 use super::*;
+use std::fmt;
 
 #[derive(Debug)]
 pub enum ParserError {
@@ -7,3 +8,16 @@ pub enum ParserError {
     UnexpectedToken(Token),
     EOF,
 }
+
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParserError::InvalidIdentifierCharacter(c) => write!(f, "invalid character '{}'", c),
+            ParserError::InvalidString => write!(f, "unterminated string"),
+            ParserError::UnexpectedToken(token) => write!(f, "unexpected token {:?}", token),
+            ParserError::EOF => write!(f, "unexpected end of expression"),
+        }
+    }
+}
+
+impl std::error::Error for ParserError {}
@@ -4,14 +4,31 @@ use super::*;
 pub enum Token {
     String(String),
     Number(String),
+    LongNumber(String),
+    Date(String),
+    DateTime(String),
+    Time(String),
     Boolean(bool),
     Identifier(String),
+    Variable(String),
     Plus,
     Minus,
+    Ampersand,
+    Star,
+    Slash,
     Dot,
     LeftParen,
     RightParen,
     Comma,
+    Pipe,
+    Equal,
+    NotEqual,
+    Equivalent,
+    NotEquivalent,
+    LessThan,
+    LessOrEqual,
+    GreaterThan,
+    GreaterOrEqual,
 }
 
 impl Token {
@@ -40,41 +57,119 @@ impl Lexer {
     }
 
     pub fn tokenize(&mut self) -> Result<Vec<Token>, ParserError> {
+        let tokens = self.tokenize_with_spans()?;
+        Ok(tokens.into_iter().map(|(token, _)| token).collect())
+    }
+
+    /// Reads the tokens of the input along with the characters each was read from
+    pub fn tokenize_with_spans(&mut self) -> Result<Vec<(Token, Span)>, ParserError> {
         let mut tokens = Vec::with_capacity(self.input.len() / TOKEN_SIZE_ESTIMATE);
 
         while self.position < self.input.len() {
+            let start = self.position;
             let c = self.input[self.position];
             if let Some(token) = match c {
                 ' ' | '\r' | '\n' | '\t' => None, // Skip whitespace
                 '.' => Some(Token::Dot),
                 ',' => Some(Token::Comma),
+                '|' => Some(Token::Pipe),
                 '(' => Some(Token::LeftParen),
                 ')' => Some(Token::RightParen),
                 '+' => Some(Token::Plus),
                 '-' => Some(Token::Minus),
+                '&' => Some(Token::Ampersand),
+                '*' => Some(Token::Star),
+                '/' => Some(Token::Slash),
+                '=' => Some(Token::Equal),
+                '~' => Some(Token::Equivalent),
+                '<' | '>' => {
+                    let or_equal = self.input.get(self.position + 1) == Some(&'=');
+                    if or_equal {
+                        self.position += 1;
+                    }
+                    Some(match (c, or_equal) {
+                        ('<', false) => Token::LessThan,
+                        ('<', true) => Token::LessOrEqual,
+                        ('>', false) => Token::GreaterThan,
+                        _ => Token::GreaterOrEqual,
+                    })
+                }
+                '!' => {
+                    let token = match self.input.get(self.position + 1) {
+                        Some('=') => Token::NotEqual,
+                        Some('~') => Token::NotEquivalent,
+                        _ => return Err(ParserError::InvalidIdentifierCharacter(c)),
+                    };
+                    self.position += 1;
+                    Some(token)
+                }
                 '0'..='9' => {
-                    let str: String = self
-                        .input
-                        .get(self.position..)
-                        .unwrap()
+                    let str = self.scan_number();
+                    self.position += str.len() - 1;
+
+                    // An `L` suffix makes an integer a Long, unless it begins an identifier
+                    let is_long = !str.contains('.')
+                        && self.input.get(self.position + 1) == Some(&'L')
+                        && !self
+                            .input
+                            .get(self.position + 2)
+                            .is_some_and(|&c| is_valid_identifier_char(c));
+                    if is_long {
+                        self.position += 1;
+                        Some(Token::LongNumber(str))
+                    } else {
+                        Some(Token::Number(str))
+                    }
+                }
+                '@' => {
+                    let literal = self.scan_temporal_literal();
+                    self.position += literal.len();
+                    Some(if let Some(time) = literal.strip_prefix('T') {
+                        Token::Time(time.to_string())
+                    } else if literal.contains('T') {
+                        Token::DateTime(literal)
+                    } else {
+                        Token::Date(literal)
+                    })
+                }
+                '%' => {
+                    let (name, length) = self.scan_variable()?;
+                    self.position += length;
+                    Some(Token::Variable(name))
+                }
+                // `$this` is kept with its `$`, apart from the environment variables
+                '$' => {
+                    let name: String = self.input[self.position + 1..]
                         .iter()
-                        .take_while(|&&x| x.is_ascii_digit() || x == '.')
+                        .take_while(|&&c| is_valid_identifier_char(c))
                         .collect();
-                    self.position += str.len();
-                    Some(Token::Number(str))
+                    if name.is_empty() {
+                        return Err(ParserError::InvalidIdentifierCharacter(c));
+                    }
+                    self.position += name.len();
+                    Some(Token::Variable(format!("${}", name)))
                 }
-                '\'' => {
-                    if self.position + 1 >= self.input.len() {
+                // Delimited identifiers may be keywords or hold other characters, e.g. `div`
+                '`' => {
+                    let identifier: String = self.input[self.position + 1..]
+                        .iter()
+                        .take_while(|&&c| c != '`')
+                        .collect();
+                    self.position += identifier.chars().count() + 1;
+                    if self.position >= self.input.len() {
                         return Err(ParserError::InvalidString);
                     }
-                    let str: String = self
-                        .input
-                        .get(self.position + 1..)
-                        .unwrap()
+                    Some(Token::Identifier(identifier))
+                }
+                '\'' => {
+                    let str: String = self.input[self.position + 1..]
                         .iter()
                         .take_while(|&&x| x != '\'')
                         .collect();
-                    self.position += str.len() + 1;
+                    self.position += str.chars().count() + 1;
+                    if self.position >= self.input.len() {
+                        return Err(ParserError::InvalidString);
+                    }
                     Some(Token::String(str))
                 }
                 n => {
@@ -82,10 +177,7 @@ impl Lexer {
                         return Err(ParserError::InvalidIdentifierCharacter(n));
                     }
 
-                    let identifier: String = self
-                        .input
-                        .get(self.position..)
-                        .unwrap()
+                    let identifier: String = self.input[self.position..]
                         .iter()
                         .take_while(|&&x| is_valid_identifier_char(x))
                         .collect();
@@ -102,13 +194,90 @@ impl Lexer {
                     Some(token)
                 }
             } {
-                tokens.push(token);
+                let span = Span {
+                    start,
+                    end: self.position + 1,
+                };
+                tokens.push((token, span));
             }
             self.position += 1;
         }
 
         Ok(tokens)
     }
+
+    /// Collects the digits of a number, including a decimal point only if it is followed by a
+    /// digit, so that functions can be invoked on number literals (e.g. `1.5.round()`)
+    fn scan_number(&self) -> String {
+        let mut end = self.position;
+        let mut seen_point = false;
+        while let Some(&c) = self.input.get(end) {
+            let accept = match c {
+                '0'..='9' => true,
+                '.' if !seen_point => {
+                    seen_point = true;
+                    self.input
+                        .get(end + 1)
+                        .is_some_and(|next| next.is_ascii_digit())
+                }
+                _ => false,
+            };
+            if !accept {
+                break;
+            }
+            end += 1;
+        }
+        self.input[self.position..end].iter().collect()
+    }
+
+    /// Reads the name of an environment variable following a `%`, which may be delimited by
+    /// backticks or quotes to include other characters (e.g. `%'vs-administrative-gender'`).
+    /// Returns the name and the number of characters after the `%`.
+    fn scan_variable(&self) -> Result<(String, usize), ParserError> {
+        let rest = self.input.get(self.position + 1..).unwrap_or_default();
+        match rest.first() {
+            Some(&delimiter) if delimiter == '`' || delimiter == '\'' => {
+                let name: String = rest[1..].iter().take_while(|&&c| c != delimiter).collect();
+                if rest.get(name.chars().count() + 1) != Some(&delimiter) {
+                    return Err(ParserError::InvalidString);
+                }
+                Ok((name.clone(), name.chars().count() + 2))
+            }
+            _ => {
+                let name: String = rest
+                    .iter()
+                    .take_while(|&&c| is_valid_identifier_char(c))
+                    .collect();
+                if name.is_empty() {
+                    return Err(ParserError::InvalidIdentifierCharacter('%'));
+                }
+                Ok((name.clone(), name.len()))
+            }
+        }
+    }
+
+    /// Collects the body of a date, time or datetime literal following an `@`.  Separators
+    /// that may also be operators (`-`, `+`, `.`) only belong to the literal when a digit
+    /// follows them.
+    fn scan_temporal_literal(&self) -> String {
+        let mut end = self.position + 1;
+        while let Some(&c) = self.input.get(end) {
+            let digit_follows = self
+                .input
+                .get(end + 1)
+                .is_some_and(|next| next.is_ascii_digit());
+            let accept = match c {
+                '0'..='9' | ':' | 'T' | 'Z' => true,
+                '-' | '+' | '.' => digit_follows,
+                _ => false,
+            };
+            if !accept {
+                break;
+            }
+            end += 1;
+        }
+        self.input[self.position + 1..end].iter().collect()
+    }
 }
 
 fn is_valid_identifier_char(c: char) -> bool {
@@ -151,6 +320,149 @@ mod tests {
                     Token::Number("67890".to_string()),
                 ],
             },
+            TestCase {
+                expression: "name.given != 'Jim'",
+                expected: vec![
+                    Token::identifier("name"),
+                    Token::Dot,
+                    Token::identifier("given"),
+                    Token::NotEqual,
+                    Token::string("Jim"),
+                ],
+            },
+            TestCase {
+                expression: "1=1!~2",
+                expected: vec![
+                    Token::Number("1".to_string()),
+                    Token::Equal,
+                    Token::Number("1".to_string()),
+                    Token::NotEquivalent,
+                    Token::Number("2".to_string()),
+                ],
+            },
+            TestCase {
+                expression: "1 < 2 <= 3>4>=5",
+                expected: vec![
+                    Token::Number("1".to_string()),
+                    Token::LessThan,
+                    Token::Number("2".to_string()),
+                    Token::LessOrEqual,
+                    Token::Number("3".to_string()),
+                    Token::GreaterThan,
+                    Token::Number("4".to_string()),
+                    Token::GreaterOrEqual,
+                    Token::Number("5".to_string()),
+                ],
+            },
+            TestCase {
+                expression: "@2020-05 < @2020-05-01T10:30:00.5+02:00.toString() = @T14:30",
+                expected: vec![
+                    Token::Date("2020-05".to_string()),
+                    Token::LessThan,
+                    Token::DateTime("2020-05-01T10:30:00.5+02:00".to_string()),
+                    Token::Dot,
+                    Token::identifier("toString"),
+                    Token::LeftParen,
+                    Token::RightParen,
+                    Token::Equal,
+                    Token::Time("14:30".to_string()),
+                ],
+            },
+            TestCase {
+                expression: "@2015T-@2014-01-01T10Z",
+                expected: vec![
+                    Token::DateTime("2015T".to_string()),
+                    Token::Minus,
+                    Token::DateTime("2014-01-01T10Z".to_string()),
+                ],
+            },
+            TestCase {
+                expression: "'a' & 'b' + 4 'mg' - 2 years",
+                expected: vec![
+                    Token::string("a"),
+                    Token::Ampersand,
+                    Token::string("b"),
+                    Token::Plus,
+                    Token::Number("4".to_string()),
+                    Token::string("mg"),
+                    Token::Minus,
+                    Token::Number("2".to_string()),
+                    Token::identifier("years"),
+                ],
+            },
+            TestCase {
+                expression: "6 'kg'/2*3 div 4 mod 5",
+                expected: vec![
+                    Token::Number("6".to_string()),
+                    Token::string("kg"),
+                    Token::Slash,
+                    Token::Number("2".to_string()),
+                    Token::Star,
+                    Token::Number("3".to_string()),
+                    Token::identifier("div"),
+                    Token::Number("4".to_string()),
+                    Token::identifier("mod"),
+                    Token::Number("5".to_string()),
+                ],
+            },
+            TestCase {
+                expression: "1.5.round()",
+                expected: vec![
+                    Token::Number("1.5".to_string()),
+                    Token::Dot,
+                    Token::identifier("round"),
+                    Token::LeftParen,
+                    Token::RightParen,
+                ],
+            },
+            TestCase {
+                expression: "5000000000L+1 'L'",
+                expected: vec![
+                    Token::LongNumber("5000000000".to_string()),
+                    Token::Plus,
+                    Token::Number("1".to_string()),
+                    Token::string("L"),
+                ],
+            },
+            TestCase {
+                expression: "%terminologies.expand(%'vs-administrative-gender'), %`ext-x`",
+                expected: vec![
+                    Token::Variable("terminologies".to_string()),
+                    Token::Dot,
+                    Token::identifier("expand"),
+                    Token::LeftParen,
+                    Token::Variable("vs-administrative-gender".to_string()),
+                    Token::RightParen,
+                    Token::Comma,
+                    Token::Variable("ext-x".to_string()),
+                ],
+            },
+            TestCase {
+                expression: "%limit-1",
+                expected: vec![
+                    Token::Variable("limit".to_string()),
+                    Token::Minus,
+                    Token::Number("1".to_string()),
+                ],
+            },
+            TestCase {
+                expression: "text.`div` | `given name`",
+                expected: vec![
+                    Token::identifier("text"),
+                    Token::Dot,
+                    Token::identifier("div"),
+                    Token::Pipe,
+                    Token::identifier("given name"),
+                ],
+            },
+            TestCase {
+                expression: "1+2",
+                expected: vec![
+                    Token::Number("1".to_string()),
+                    Token::Plus,
+                    Token::Number("2".to_string()),
+                ],
+            },
             TestCase {
                 expression: "true",
                 expected: vec![Token::Boolean(true)],
@@ -165,11 +477,47 @@ mod tests {
             let mut lex = Lexer::new(test.expression);
             let tokens = lex.tokenize();
 
-            if tokens.is_err() {
-                assert!(false, "{:?}", tokens)
-            }
-
+            assert!(tokens.is_ok(), "{:?}", tokens);
             assert_eq!(tokens.unwrap(), test.expected);
         }
     }
+
+    #[test]
+    fn test_spans() {
+        let span = |start, end| Span { start, end };
+        let tokens = Lexer::new("name.given != 'Jim' and 10 'mg'")
+            .tokenize_with_spans()
+            .unwrap();
+        let spans: Vec<_> = tokens.into_iter().map(|(_, span)| span).collect();
+        assert_eq!(
+            spans,
+            vec![
+                span(0, 4),
+                span(4, 5),
+                span(5, 10),
+                span(11, 13),
+                span(14, 19),
+                span(20, 23),
+                span(24, 26),
+                span(27, 31),
+            ]
+        );
+
+        assert!(matches!(
+            Lexer::new("'unterminated").tokenize(),
+            Err(ParserError::InvalidString)
+        ));
+        assert!(matches!(
+            Lexer::new("text.`div").tokenize(),
+            Err(ParserError::InvalidString)
+        ));
+        assert_eq!(
+            Lexer::new("'\u{e9}t\u{e9}' = x").tokenize().unwrap(),
+            vec![
+                Token::string("\u{e9}t\u{e9}"),
+                Token::Equal,
+                Token::identifier("x")
+            ]
+        );
+    }
 }
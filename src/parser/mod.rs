@@ -1,6 +1,7 @@
 mod ast;
 mod errors;
 mod lexer;
+#[allow(clippy::module_inception)]
 mod parser;
 
 pub use ast::*;
@@ -4,7 +4,9 @@ use log::*;
 use std::collections::VecDeque;
 
 pub struct Parser {
-    input: VecDeque<Token>,
+    input: VecDeque<(Token, Span)>,
+    /// Where the last token read ends
+    end: usize,
 }
 
 pub type PrefixFn<'a> =
@@ -23,22 +25,61 @@ pub struct ParseRule<'a> {
 
 iota! {
     const INITIAL_PRECEDENCE: u8 = iota;
-    , PLUS_PRECENDENCE
-    , MINUS_PRECENDENCE
+    , COMMA_PRECENDENCE
+    , IMPLIES_PRECEDENCE
+    , OR_PRECEDENCE
+    , AND_PRECEDENCE
+    , MEMBERSHIP_PRECEDENCE
+    , EQUALITY_PRECEDENCE
+    , INEQUALITY_PRECEDENCE
+    , UNION_PRECEDENCE
+    , TYPE_PRECEDENCE
+    , ADDITIVE_PRECEDENCE
+    , MULTIPLICATIVE_PRECEDENCE
     , DOT_PRECEDENCE
     , LPAREN_PRECENDENCE
-    , RPAREN_PRECENDENCE
-    , COMMA_PRECENDENCE
 }
 
 impl Token {
-    fn parse_rule(&self) -> ParseRule {
+    fn parse_rule(&self) -> ParseRule<'_> {
         match &self {
             Token::Dot => ParseRule {
                 precedence: DOT_PRECEDENCE,
                 prefix_parselet: None,
                 infix_parselet: Some(parse_dot),
             },
+            // `div` and `mod` are only operators in infix position, so that elements with those
+            // names (like Narrative.div) can still be navigated
+            Token::Identifier(name) if name == "div" || name == "mod" => ParseRule {
+                precedence: MULTIPLICATIVE_PRECEDENCE,
+                prefix_parselet: Some(parse_identifier),
+                infix_parselet: Some(parse_multiplicative),
+            },
+            Token::Identifier(name) if name == "and" => ParseRule {
+                precedence: AND_PRECEDENCE,
+                prefix_parselet: Some(parse_identifier),
+                infix_parselet: Some(parse_boolean_expression),
+            },
+            Token::Identifier(name) if name == "or" || name == "xor" => ParseRule {
+                precedence: OR_PRECEDENCE,
+                prefix_parselet: Some(parse_identifier),
+                infix_parselet: Some(parse_boolean_expression),
+            },
+            Token::Identifier(name) if name == "implies" => ParseRule {
+                precedence: IMPLIES_PRECEDENCE,
+                prefix_parselet: Some(parse_identifier),
+                infix_parselet: Some(parse_boolean_expression),
+            },
+            Token::Identifier(name) if name == "in" || name == "contains" => ParseRule {
+                precedence: MEMBERSHIP_PRECEDENCE,
+                prefix_parselet: Some(parse_identifier),
+                infix_parselet: Some(parse_membership),
+            },
+            Token::Identifier(name) if name == "is" || name == "as" => ParseRule {
+                precedence: TYPE_PRECEDENCE,
+                prefix_parselet: Some(parse_identifier),
+                infix_parselet: Some(parse_type_expression),
+            },
             Token::Identifier(_) => ParseRule {
                 precedence: INITIAL_PRECEDENCE,
                 prefix_parselet: Some(parse_identifier),
@@ -46,19 +87,48 @@ impl Token {
             },
             Token::LeftParen => ParseRule {
                 precedence: LPAREN_PRECENDENCE,
-                prefix_parselet: None, // TODO: implement paren group
+                prefix_parselet: Some(parse_group),
                 infix_parselet: Some(parse_function),
             },
             Token::RightParen => ParseRule {
-                precedence: RPAREN_PRECENDENCE,
-                prefix_parselet: Some(|_, _| Ok(ASTNode::empty_params())),
-                infix_parselet: Some(parse_param_list),
+                precedence: INITIAL_PRECEDENCE,
+                prefix_parselet: None,
+                infix_parselet: None,
             },
             Token::Comma => ParseRule {
                 precedence: COMMA_PRECENDENCE,
                 prefix_parselet: None,
                 infix_parselet: Some(parse_union),
             },
+            Token::Pipe => ParseRule {
+                precedence: UNION_PRECEDENCE,
+                prefix_parselet: None,
+                infix_parselet: Some(parse_union_expression),
+            },
+            Token::Equal | Token::NotEqual | Token::Equivalent | Token::NotEquivalent => {
+                ParseRule {
+                    precedence: EQUALITY_PRECEDENCE,
+                    prefix_parselet: None,
+                    infix_parselet: Some(parse_equality),
+                }
+            }
+            Token::LessThan | Token::LessOrEqual | Token::GreaterThan | Token::GreaterOrEqual => {
+                ParseRule {
+                    precedence: INEQUALITY_PRECEDENCE,
+                    prefix_parselet: None,
+                    infix_parselet: Some(parse_inequality),
+                }
+            }
+            Token::Plus | Token::Minus | Token::Ampersand => ParseRule {
+                precedence: ADDITIVE_PRECEDENCE,
+                prefix_parselet: None,
+                infix_parselet: Some(parse_additive),
+            },
+            Token::Star | Token::Slash => ParseRule {
+                precedence: MULTIPLICATIVE_PRECEDENCE,
+                prefix_parselet: None,
+                infix_parselet: Some(parse_multiplicative),
+            },
             Token::Boolean(_) => ParseRule {
                 precedence: INITIAL_PRECEDENCE,
                 prefix_parselet: Some(parse_boolean_literal),
@@ -74,7 +144,21 @@ impl Token {
                 prefix_parselet: Some(parse_number_literal),
                 infix_parselet: None,
             },
-            _ => todo!(),
+            Token::LongNumber(_) => ParseRule {
+                precedence: INITIAL_PRECEDENCE,
+                prefix_parselet: Some(parse_long_number_literal),
+                infix_parselet: None,
+            },
+            Token::Date(_) | Token::DateTime(_) | Token::Time(_) => ParseRule {
+                precedence: INITIAL_PRECEDENCE,
+                prefix_parselet: Some(parse_temporal_literal),
+                infix_parselet: None,
+            },
+            Token::Variable(_) => ParseRule {
+                precedence: INITIAL_PRECEDENCE,
+                prefix_parselet: Some(parse_variable),
+                infix_parselet: None,
+            },
         }
     }
 
@@ -82,19 +166,27 @@ impl Token {
         self.parse_rule().precedence
     }
 
-    fn prefix_parselet(&self) -> Option<PrefixFn> {
+    fn prefix_parselet(&self) -> Option<PrefixFn<'_>> {
         self.parse_rule().prefix_parselet
     }
 
-    fn infix_parselet(&self) -> Option<InfixFn> {
+    fn infix_parselet(&self) -> Option<InfixFn<'_>> {
         self.parse_rule().infix_parselet
     }
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
+        let tokens = tokens.into_iter().map(|token| (token, Span::default()));
+        Parser::with_spans(tokens.collect())
+    }
+
+    /// Creates a parser for tokens read by `Lexer::tokenize_with_spans`, so that the nodes of
+    /// the AST record where in the expression they are from
+    pub fn with_spans(tokens: Vec<(Token, Span)>) -> Self {
         Parser {
             input: VecDeque::from(tokens),
+            end: 0,
         }
     }
 
@@ -103,6 +195,7 @@ impl Parser {
     }
 
     fn parse_expression(&mut self, base_precedence: u8) -> Result<Box<ASTNode>, ParserError> {
+        let start = self.input.front().map_or(self.end, |(_, span)| span.start);
         let token = self.next_token().ok_or(ParserError::EOF)?;
         debug!("expression starts with {:?}", token);
 
@@ -111,6 +204,7 @@ impl Parser {
             .ok_or(ParserError::UnexpectedToken(token.clone()))?;
 
         let mut left = prefix_parselet(self, &token)?;
+        left.set_span(self.span_from(start));
         while let Some(next_token) = self.peek() {
             if next_token.precedence() <= base_precedence {
                 break;
@@ -120,6 +214,7 @@ impl Parser {
             if let Some(infix_parselet) = next_token.infix_parselet() {
                 self.next_token();
                 left = infix_parselet(self, left, &next_token)?;
+                left.set_span(self.span_from(start));
             } else {
                 break;
             }
@@ -128,11 +223,47 @@ impl Parser {
     }
 
     fn next_token(&mut self) -> Option<Token> {
-        self.input.pop_front()
+        let (token, span) = self.input.pop_front()?;
+        self.end = span.end;
+        Some(token)
     }
 
     fn peek(&self) -> Option<Token> {
-        self.input.front().map(|t| t.clone())
+        self.input.front().map(|(token, _)| token.clone())
+    }
+
+    /// The span from a position to the end of the last token read
+    fn span_from(&self, start: usize) -> Span {
+        Span {
+            start,
+            end: self.end,
+        }
+    }
+
+    /// Parses a type specifier, which may be qualified by a namespace (e.g. `FHIR.Patient`)
+    fn parse_type_specifier(&mut self) -> Result<String, ParserError> {
+        let mut specifier = match self.next_token() {
+            Some(Token::Identifier(name)) => name,
+            Some(token) => return Err(ParserError::UnexpectedToken(token)),
+            None => return Err(ParserError::EOF),
+        };
+        if (specifier == "FHIR" || specifier == "System") && self.peek() == Some(Token::Dot) {
+            self.next_token();
+            match self.next_token() {
+                Some(Token::Identifier(name)) => specifier = format!("{}.{}", specifier, name),
+                Some(token) => return Err(ParserError::UnexpectedToken(token)),
+                None => return Err(ParserError::EOF),
+            }
+        }
+        Ok(specifier)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), ParserError> {
+        match self.next_token() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(ParserError::UnexpectedToken(token)),
+            None => Err(ParserError::EOF),
+        }
     }
 }
 
@@ -147,59 +278,232 @@ fn parse_dot(
 
 fn parse_identifier(_: &mut Parser, token: &Token) -> Result<Box<ASTNode>, ParserError> {
     if let Token::Identifier(identifier) = token {
-        return Ok(Box::new(ASTNode::Identifier(identifier.clone())));
+        Ok(Box::new(ASTNode::Identifier(identifier.clone())))
     } else {
-        return Err(ParserError::UnexpectedToken(token.clone()));
+        Err(ParserError::UnexpectedToken(token.clone()))
+    }
+}
+
+fn parse_variable(_: &mut Parser, token: &Token) -> Result<Box<ASTNode>, ParserError> {
+    if let Token::Variable(name) = token {
+        Ok(Box::new(ASTNode::Variable(name.clone(), Span::default())))
+    } else {
+        Err(ParserError::UnexpectedToken(token.clone()))
     }
 }
 
 fn parse_function(
+    parser: &mut Parser,
+    left: Box<ASTNode>,
+    _: &Token,
+) -> Result<Box<ASTNode>, ParserError> {
+    if parser.peek() == Some(Token::RightParen) {
+        parser.next_token();
+        return Ok(ASTNode::function(left, ASTNode::empty_params()));
+    }
+
+    let params = parser.parse_expression(INITIAL_PRECEDENCE)?;
+    parser.expect(Token::RightParen)?;
+    Ok(ASTNode::function(left, ASTNode::params(params)))
+}
+
+/// Parentheses only group an expression, leaving no node of their own
+fn parse_group(parser: &mut Parser, _: &Token) -> Result<Box<ASTNode>, ParserError> {
+    let inner = parser.parse_expression(INITIAL_PRECEDENCE)?;
+    parser.expect(Token::RightParen)?;
+    Ok(inner)
+}
+
+fn parse_union(
     parser: &mut Parser,
     left: Box<ASTNode>,
     token: &Token,
 ) -> Result<Box<ASTNode>, ParserError> {
     let right = parser.parse_expression(token.precedence())?;
-    Ok(Box::new(ASTNode::Function(left, right)))
+    Ok(Box::new(ASTNode::Union(left, right)))
 }
 
-fn parse_param_list(
-    _: &mut Parser,
+fn parse_union_expression(
+    parser: &mut Parser,
     left: Box<ASTNode>,
-    _: &Token,
+    token: &Token,
 ) -> Result<Box<ASTNode>, ParserError> {
-    Ok(Box::new(ASTNode::ParamList(Some(left))))
+    let right = parser.parse_expression(token.precedence())?;
+    Ok(ASTNode::union_expression(left, right))
 }
 
-fn parse_union(
+fn parse_equality(
     parser: &mut Parser,
     left: Box<ASTNode>,
     token: &Token,
 ) -> Result<Box<ASTNode>, ParserError> {
+    let op = match token {
+        Token::Equal => EqualityOperator::Equal,
+        Token::NotEqual => EqualityOperator::NotEqual,
+        Token::Equivalent => EqualityOperator::Equivalent,
+        Token::NotEquivalent => EqualityOperator::NotEquivalent,
+        _ => return Err(ParserError::UnexpectedToken(token.clone())),
+    };
     let right = parser.parse_expression(token.precedence())?;
-    Ok(Box::new(ASTNode::Union(left, right)))
+    Ok(ASTNode::equality(left, op, right))
+}
+
+fn parse_inequality(
+    parser: &mut Parser,
+    left: Box<ASTNode>,
+    token: &Token,
+) -> Result<Box<ASTNode>, ParserError> {
+    let op = match token {
+        Token::LessThan => InequalityOperator::LessThan,
+        Token::LessOrEqual => InequalityOperator::LessOrEqual,
+        Token::GreaterThan => InequalityOperator::GreaterThan,
+        Token::GreaterOrEqual => InequalityOperator::GreaterOrEqual,
+        _ => return Err(ParserError::UnexpectedToken(token.clone())),
+    };
+    let right = parser.parse_expression(token.precedence())?;
+    Ok(ASTNode::inequality(left, op, right))
+}
+
+fn parse_additive(
+    parser: &mut Parser,
+    left: Box<ASTNode>,
+    token: &Token,
+) -> Result<Box<ASTNode>, ParserError> {
+    let op = match token {
+        Token::Plus => AdditiveOperator::Plus,
+        Token::Minus => AdditiveOperator::Minus,
+        Token::Ampersand => AdditiveOperator::Concatenate,
+        _ => return Err(ParserError::UnexpectedToken(token.clone())),
+    };
+    let right = parser.parse_expression(token.precedence())?;
+    Ok(ASTNode::additive(left, op, right))
+}
+
+fn parse_multiplicative(
+    parser: &mut Parser,
+    left: Box<ASTNode>,
+    token: &Token,
+) -> Result<Box<ASTNode>, ParserError> {
+    let op = match token {
+        Token::Star => MultiplicativeOperator::Multiply,
+        Token::Slash => MultiplicativeOperator::Divide,
+        Token::Identifier(name) if name == "div" => MultiplicativeOperator::Div,
+        Token::Identifier(name) if name == "mod" => MultiplicativeOperator::Mod,
+        _ => return Err(ParserError::UnexpectedToken(token.clone())),
+    };
+    let right = parser.parse_expression(token.precedence())?;
+    Ok(ASTNode::multiplicative(left, op, right))
+}
+
+fn parse_boolean_expression(
+    parser: &mut Parser,
+    left: Box<ASTNode>,
+    token: &Token,
+) -> Result<Box<ASTNode>, ParserError> {
+    let op = match token {
+        Token::Identifier(name) if name == "and" => BooleanOperator::And,
+        Token::Identifier(name) if name == "or" => BooleanOperator::Or,
+        Token::Identifier(name) if name == "xor" => BooleanOperator::Xor,
+        Token::Identifier(name) if name == "implies" => BooleanOperator::Implies,
+        _ => return Err(ParserError::UnexpectedToken(token.clone())),
+    };
+    let right = parser.parse_expression(token.precedence())?;
+    Ok(ASTNode::boolean(left, op, right))
+}
+
+fn parse_type_expression(
+    parser: &mut Parser,
+    left: Box<ASTNode>,
+    token: &Token,
+) -> Result<Box<ASTNode>, ParserError> {
+    let op = match token {
+        Token::Identifier(name) if name == "is" => TypeOperator::Is,
+        Token::Identifier(name) if name == "as" => TypeOperator::As,
+        _ => return Err(ParserError::UnexpectedToken(token.clone())),
+    };
+    let specifier = parser.parse_type_specifier()?;
+    Ok(Box::new(ASTNode::TypeExpression(
+        left,
+        op,
+        specifier,
+        Span::default(),
+    )))
+}
+
+fn parse_membership(
+    parser: &mut Parser,
+    left: Box<ASTNode>,
+    token: &Token,
+) -> Result<Box<ASTNode>, ParserError> {
+    let op = match token {
+        Token::Identifier(name) if name == "in" => MembershipOperator::In,
+        Token::Identifier(name) if name == "contains" => MembershipOperator::Contains,
+        _ => return Err(ParserError::UnexpectedToken(token.clone())),
+    };
+    let right = parser.parse_expression(token.precedence())?;
+    Ok(ASTNode::membership(left, op, right))
 }
 
 fn parse_string_literal(_: &mut Parser, token: &Token) -> Result<Box<ASTNode>, ParserError> {
     if let Token::String(s) = token {
-        return Ok(Box::new(ASTNode::StringLiteral(s.clone())));
+        Ok(Box::new(ASTNode::StringLiteral(s.clone())))
     } else {
+        Err(ParserError::UnexpectedToken(token.clone()))
+    }
+}
+
+/// A number may be followed by a unit, either a UCUM string or a calendar duration keyword,
+/// making it a quantity
+fn parse_number_literal(parser: &mut Parser, token: &Token) -> Result<Box<ASTNode>, ParserError> {
+    let Token::Number(s) = token else {
         return Err(ParserError::UnexpectedToken(token.clone()));
+    };
+
+    let unit = match parser.peek() {
+        Some(Token::String(unit)) => Some(unit),
+        Some(Token::Identifier(unit)) if CALENDAR_DURATIONS.contains(&unit.as_str()) => Some(unit),
+        _ => None,
+    };
+    if let Some(unit) = unit {
+        parser.next_token();
+        Ok(Box::new(ASTNode::QuantityLiteral(
+            s.clone(),
+            unit,
+            Span::default(),
+        )))
+    } else {
+        Ok(Box::new(ASTNode::NumberLiteral(s.clone(), Span::default())))
     }
 }
 
-fn parse_number_literal(_: &mut Parser, token: &Token) -> Result<Box<ASTNode>, ParserError> {
-    if let Token::Number(s) = token {
-        return Ok(Box::new(ASTNode::NumberLiteral(s.clone())));
+fn parse_long_number_literal(_: &mut Parser, token: &Token) -> Result<Box<ASTNode>, ParserError> {
+    if let Token::LongNumber(s) = token {
+        Ok(Box::new(ASTNode::LongNumberLiteral(
+            s.clone(),
+            Span::default(),
+        )))
     } else {
-        return Err(ParserError::UnexpectedToken(token.clone()));
+        Err(ParserError::UnexpectedToken(token.clone()))
+    }
+}
+
+fn parse_temporal_literal(_: &mut Parser, token: &Token) -> Result<Box<ASTNode>, ParserError> {
+    match token {
+        Token::Date(s) => Ok(Box::new(ASTNode::DateLiteral(s.clone(), Span::default()))),
+        Token::DateTime(s) => Ok(Box::new(ASTNode::DateTimeLiteral(
+            s.clone(),
+            Span::default(),
+        ))),
+        Token::Time(s) => Ok(Box::new(ASTNode::TimeLiteral(s.clone(), Span::default()))),
+        _ => Err(ParserError::UnexpectedToken(token.clone())),
     }
 }
 
 fn parse_boolean_literal(_: &mut Parser, token: &Token) -> Result<Box<ASTNode>, ParserError> {
     if let Token::Boolean(b) = token {
-        return Ok(Box::new(ASTNode::BooleanLiteral(*b)));
+        Ok(Box::new(ASTNode::BooleanLiteral(*b)))
     } else {
-        return Err(ParserError::UnexpectedToken(token.clone()));
+        Err(ParserError::UnexpectedToken(token.clone()))
     }
 }
 
@@ -216,43 +520,388 @@ mod tests {
             input: Vec<Token>,
             expected: Box<ASTNode>,
         }
-        let test_cases = vec![TestCase {
-            input: vec![
-                Token::identifier("Patient"),
-                Token::Dot,
-                Token::identifier("name"),
-                Token::Dot,
-                Token::identifier("family"),
-                Token::Dot,
-                Token::identifier("replace"),
-                Token::LeftParen,
-                Token::string("er"),
-                Token::Comma,
-                Token::string("iams"),
-                Token::RightParen,
-            ],
-            expected: ASTNode::invocation(
-                ASTNode::invocation(
+        let test_cases = vec![
+            TestCase {
+                input: vec![
+                    Token::identifier("Patient"),
+                    Token::Dot,
+                    Token::identifier("name"),
+                    Token::Dot,
+                    Token::identifier("family"),
+                    Token::Dot,
+                    Token::identifier("replace"),
+                    Token::LeftParen,
+                    Token::string("er"),
+                    Token::Comma,
+                    Token::string("iams"),
+                    Token::RightParen,
+                ],
+                expected: ASTNode::invocation(
                     ASTNode::invocation(
-                        ASTNode::identifier("Patient"),
-                        ASTNode::identifier("name"),
+                        ASTNode::invocation(
+                            ASTNode::identifier("Patient"),
+                            ASTNode::identifier("name"),
+                        ),
+                        ASTNode::identifier("family"),
                     ),
-                    ASTNode::identifier("family"),
+                    ASTNode::function(
+                        ASTNode::identifier("replace"),
+                        ASTNode::params(ASTNode::union(
+                            ASTNode::string("er"),
+                            ASTNode::string("iams"),
+                        )),
+                    ),
+                ),
+            },
+            TestCase {
+                input: vec![
+                    Token::identifier("f"),
+                    Token::LeftParen,
+                    Token::identifier("a"),
+                    Token::Dot,
+                    Token::identifier("b"),
+                    Token::RightParen,
+                    Token::Dot,
+                    Token::identifier("g"),
+                    Token::LeftParen,
+                    Token::RightParen,
+                ],
+                expected: ASTNode::invocation(
+                    ASTNode::function(
+                        ASTNode::identifier("f"),
+                        ASTNode::params(ASTNode::invocation(
+                            ASTNode::identifier("a"),
+                            ASTNode::identifier("b"),
+                        )),
+                    ),
+                    ASTNode::function(ASTNode::identifier("g"), ASTNode::empty_params()),
+                ),
+            },
+            TestCase {
+                input: vec![
+                    Token::identifier("name"),
+                    Token::Dot,
+                    Token::identifier("family"),
+                    Token::NotEqual,
+                    Token::string("Williams"),
+                ],
+                expected: ASTNode::equality(
+                    ASTNode::invocation(ASTNode::identifier("name"), ASTNode::identifier("family")),
+                    EqualityOperator::NotEqual,
+                    ASTNode::string("Williams"),
                 ),
-                ASTNode::function(
-                    ASTNode::identifier("replace"),
+            },
+            TestCase {
+                input: vec![
+                    Token::string("a"),
+                    Token::Dot,
+                    Token::identifier("replace"),
+                    Token::LeftParen,
+                    Token::string("a"),
+                    Token::Comma,
+                    Token::string("b"),
+                    Token::RightParen,
+                    Token::Equivalent,
+                    Token::string("B"),
+                ],
+                expected: ASTNode::equality(
+                    ASTNode::invocation(
+                        ASTNode::string("a"),
+                        ASTNode::function(
+                            ASTNode::identifier("replace"),
+                            ASTNode::params(ASTNode::union(
+                                ASTNode::string("a"),
+                                ASTNode::string("b"),
+                            )),
+                        ),
+                    ),
+                    EqualityOperator::Equivalent,
+                    ASTNode::string("B"),
+                ),
+            },
+            TestCase {
+                input: vec![
+                    Token::identifier("iif"),
+                    Token::LeftParen,
+                    Token::string("a"),
+                    Token::Equal,
+                    Token::string("b"),
+                    Token::Comma,
+                    Token::string("c"),
+                    Token::RightParen,
+                ],
+                expected: ASTNode::function(
+                    ASTNode::identifier("iif"),
                     ASTNode::params(ASTNode::union(
-                        ASTNode::string("er"),
-                        ASTNode::string("iams"),
+                        ASTNode::equality(
+                            ASTNode::string("a"),
+                            EqualityOperator::Equal,
+                            ASTNode::string("b"),
+                        ),
+                        ASTNode::string("c"),
                     )),
                 ),
-            ),
-        }];
+            },
+            TestCase {
+                input: vec![
+                    Token::Number("1".to_string()),
+                    Token::LessThan,
+                    Token::Number("2".to_string()),
+                    Token::Equal,
+                    Token::Boolean(true),
+                ],
+                expected: ASTNode::equality(
+                    ASTNode::inequality(
+                        Box::new(ASTNode::NumberLiteral("1".to_string(), Span::default())),
+                        InequalityOperator::LessThan,
+                        Box::new(ASTNode::NumberLiteral("2".to_string(), Span::default())),
+                    ),
+                    EqualityOperator::Equal,
+                    Box::new(ASTNode::BooleanLiteral(true)),
+                ),
+            },
+            TestCase {
+                input: vec![
+                    Token::identifier("birthDate"),
+                    Token::LessOrEqual,
+                    Token::identifier("today"),
+                    Token::LeftParen,
+                    Token::RightParen,
+                    Token::Minus,
+                    Token::Number("18".to_string()),
+                    Token::identifier("years"),
+                    Token::Plus,
+                    Token::Number("1".to_string()),
+                    Token::string("d"),
+                ],
+                expected: ASTNode::inequality(
+                    ASTNode::identifier("birthDate"),
+                    InequalityOperator::LessOrEqual,
+                    ASTNode::additive(
+                        ASTNode::additive(
+                            ASTNode::function(
+                                ASTNode::identifier("today"),
+                                ASTNode::empty_params(),
+                            ),
+                            AdditiveOperator::Minus,
+                            Box::new(ASTNode::QuantityLiteral(
+                                "18".to_string(),
+                                "years".to_string(),
+                                Span::default(),
+                            )),
+                        ),
+                        AdditiveOperator::Plus,
+                        Box::new(ASTNode::QuantityLiteral(
+                            "1".to_string(),
+                            "d".to_string(),
+                            Span::default(),
+                        )),
+                    ),
+                ),
+            },
+            TestCase {
+                input: vec![
+                    Token::Number("1".to_string()),
+                    Token::Plus,
+                    Token::Number("6".to_string()),
+                    Token::Slash,
+                    Token::Number("2".to_string()),
+                    Token::identifier("div"),
+                    Token::identifier("text"),
+                    Token::Dot,
+                    Token::identifier("div"),
+                ],
+                expected: ASTNode::additive(
+                    Box::new(ASTNode::NumberLiteral("1".to_string(), Span::default())),
+                    AdditiveOperator::Plus,
+                    ASTNode::multiplicative(
+                        ASTNode::multiplicative(
+                            Box::new(ASTNode::NumberLiteral("6".to_string(), Span::default())),
+                            MultiplicativeOperator::Divide,
+                            Box::new(ASTNode::NumberLiteral("2".to_string(), Span::default())),
+                        ),
+                        MultiplicativeOperator::Div,
+                        ASTNode::invocation(
+                            ASTNode::identifier("text"),
+                            ASTNode::identifier("div"),
+                        ),
+                    ),
+                ),
+            },
+            TestCase {
+                input: vec![
+                    Token::identifier("value"),
+                    Token::identifier("is"),
+                    Token::identifier("FHIR"),
+                    Token::Dot,
+                    Token::identifier("Quantity"),
+                    Token::Equal,
+                    Token::identifier("value"),
+                    Token::Dot,
+                    Token::identifier("is"),
+                    Token::LeftParen,
+                    Token::identifier("Quantity"),
+                    Token::RightParen,
+                ],
+                expected: ASTNode::equality(
+                    Box::new(ASTNode::TypeExpression(
+                        ASTNode::identifier("value"),
+                        TypeOperator::Is,
+                        "FHIR.Quantity".to_string(),
+                        Span::default(),
+                    )),
+                    EqualityOperator::Equal,
+                    ASTNode::invocation(
+                        ASTNode::identifier("value"),
+                        ASTNode::function(
+                            ASTNode::identifier("is"),
+                            ASTNode::params(ASTNode::identifier("Quantity")),
+                        ),
+                    ),
+                ),
+            },
+            TestCase {
+                input: vec![
+                    Token::identifier("hasValue"),
+                    Token::LeftParen,
+                    Token::RightParen,
+                    Token::identifier("or"),
+                    Token::LeftParen,
+                    Token::identifier("children"),
+                    Token::LeftParen,
+                    Token::RightParen,
+                    Token::Dot,
+                    Token::identifier("count"),
+                    Token::LeftParen,
+                    Token::RightParen,
+                    Token::GreaterThan,
+                    Token::identifier("id"),
+                    Token::Dot,
+                    Token::identifier("count"),
+                    Token::LeftParen,
+                    Token::RightParen,
+                    Token::RightParen,
+                ],
+                expected: ASTNode::boolean(
+                    ASTNode::function(ASTNode::identifier("hasValue"), ASTNode::empty_params()),
+                    BooleanOperator::Or,
+                    ASTNode::inequality(
+                        ASTNode::invocation(
+                            ASTNode::function(
+                                ASTNode::identifier("children"),
+                                ASTNode::empty_params(),
+                            ),
+                            ASTNode::function(
+                                ASTNode::identifier("count"),
+                                ASTNode::empty_params(),
+                            ),
+                        ),
+                        InequalityOperator::GreaterThan,
+                        ASTNode::invocation(
+                            ASTNode::identifier("id"),
+                            ASTNode::function(
+                                ASTNode::identifier("count"),
+                                ASTNode::empty_params(),
+                            ),
+                        ),
+                    ),
+                ),
+            },
+            TestCase {
+                input: vec![
+                    Token::string("a"),
+                    Token::identifier("in"),
+                    Token::LeftParen,
+                    Token::identifier("given"),
+                    Token::Pipe,
+                    Token::identifier("family"),
+                    Token::RightParen,
+                    Token::Dot,
+                    Token::identifier("distinct"),
+                    Token::LeftParen,
+                    Token::RightParen,
+                    Token::identifier("and"),
+                    Token::identifier("given"),
+                    Token::identifier("contains"),
+                    Token::identifier("family"),
+                    Token::Dot,
+                    Token::identifier("contains"),
+                    Token::LeftParen,
+                    Token::string("b"),
+                    Token::RightParen,
+                ],
+                expected: ASTNode::boolean(
+                    ASTNode::membership(
+                        ASTNode::string("a"),
+                        MembershipOperator::In,
+                        ASTNode::invocation(
+                            ASTNode::union_expression(
+                                ASTNode::identifier("given"),
+                                ASTNode::identifier("family"),
+                            ),
+                            ASTNode::function(
+                                ASTNode::identifier("distinct"),
+                                ASTNode::empty_params(),
+                            ),
+                        ),
+                    ),
+                    BooleanOperator::And,
+                    ASTNode::membership(
+                        ASTNode::identifier("given"),
+                        MembershipOperator::Contains,
+                        ASTNode::invocation(
+                            ASTNode::identifier("family"),
+                            ASTNode::function(
+                                ASTNode::identifier("contains"),
+                                ASTNode::params(ASTNode::string("b")),
+                            ),
+                        ),
+                    ),
+                ),
+            },
+            TestCase {
+                input: vec![
+                    Token::identifier("a"),
+                    Token::Pipe,
+                    Token::identifier("b"),
+                    Token::Equal,
+                    Token::identifier("c"),
+                    Token::Pipe,
+                    Token::identifier("d"),
+                    Token::Plus,
+                    Token::Number("1".to_string()),
+                ],
+                expected: ASTNode::equality(
+                    ASTNode::union_expression(ASTNode::identifier("a"), ASTNode::identifier("b")),
+                    EqualityOperator::Equal,
+                    ASTNode::union_expression(
+                        ASTNode::identifier("c"),
+                        ASTNode::additive(
+                            ASTNode::identifier("d"),
+                            AdditiveOperator::Plus,
+                            Box::new(ASTNode::NumberLiteral("1".to_string(), Span::default())),
+                        ),
+                    ),
+                ),
+            },
+        ];
 
         for test in test_cases {
             let parser = Parser::new(test.input);
             let ast = parser.parse().expect("failed building AST");
             assert_eq!(ast, test.expected, "output AST does not match expected");
         }
+
+        for unbalanced in [
+            vec![Token::LeftParen, Token::identifier("a")],
+            vec![
+                Token::LeftParen,
+                Token::identifier("a"),
+                Token::Pipe,
+                Token::RightParen,
+            ],
+        ] {
+            assert!(Parser::new(unbalanced).parse().is_err());
+        }
     }
 }